@@ -0,0 +1,130 @@
+use std::{net::UdpSocket as StdUdpSocket, time::Duration};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use maat_network::{ConnectionState, MaatNetwork};
+use mio::net::UdpSocket;
+
+const MESSAGES: usize = 1000;
+const IDLE_CONNECTIONS: usize = 10_000;
+const ACCEPT_BATCH: usize = 50;
+
+/// A tcp server and a client connected to it over loopback, with the client
+/// token and the server's token for the accepted connection.
+fn connected_pair() -> (MaatNetwork, usize, MaatNetwork, usize) {
+  let mut server = MaatNetwork::new();
+  let mut client = MaatNetwork::new();
+  let listener = server.host_tcp_server("127.0.0.1", 0, None);
+  server.poll();
+  let port = server.local_addr(listener).unwrap().port();
+
+  let token = client.connect_to_tcp("127.0.0.1", port, None);
+  let mut accepted = None;
+  while accepted.is_none() || client.state(token) != Some(ConnectionState::Connected) {
+    client.poll();
+    let (_, new_connections, _) = server.poll();
+    for connection in new_connections {
+      accepted = Some(connection.token);
+      server.add_exisiting_connection(connection);
+    }
+  }
+  server.poll();
+  client.poll();
+
+  (server, accepted.unwrap(), client, token)
+}
+
+/// Polls both networks until `to` has read `len` bytes.
+fn deliver(to: &mut MaatNetwork, from: &mut MaatNetwork, len: usize) {
+  let mut received = 0;
+  while received < len {
+    from.poll();
+    let (data, _, _) = to.poll();
+    received += data.iter().map(|(_, d)| d.len()).sum::<usize>();
+  }
+}
+
+fn throughput(c: &mut Criterion) {
+  let mut group = c.benchmark_group("throughput");
+  group.throughput(Throughput::Elements(MESSAGES as u64));
+
+  for size in [16, 256, 4096] {
+    let (mut server, _, mut client, token) = connected_pair();
+    let message = vec![7; size];
+    group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+      b.iter(|| {
+        (0..MESSAGES).for_each(|_| client.write_data(token, &message).unwrap());
+        deliver(&mut server, &mut client, MESSAGES * size);
+      })
+    });
+  }
+  group.finish();
+}
+
+fn round_trip(c: &mut Criterion) {
+  let (mut server, accepted, mut client, token) = connected_pair();
+  c.bench_function("round_trip", |b| {
+    b.iter(|| {
+      client.write_data(token, b"ping").unwrap();
+      deliver(&mut server, &mut client, 4);
+      server.write_data(accepted, b"pong").unwrap();
+      deliver(&mut client, &mut server, 4);
+    })
+  });
+}
+
+fn idle_poll(c: &mut Criterion) {
+  let sink = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let port = sink.local_addr().unwrap().port();
+
+  let mut network = MaatNetwork::new();
+  for _ in 0..IDLE_CONNECTIONS {
+    let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(("127.0.0.1", port)).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    network.add_existing_udp_connection(UdpSocket::from_std(socket), "127.0.0.1", port, None);
+  }
+  network.poll();
+
+  c.bench_function("poll_idle_10k", |b| b.iter(|| network.poll()));
+}
+
+fn accept_rate(c: &mut Criterion) {
+  let mut group = c.benchmark_group("accept");
+  group.throughput(Throughput::Elements(ACCEPT_BATCH as u64));
+  group.sample_size(10);
+
+  // A fresh listener each batch so closed connections in TIME_WAIT don't use
+  // up the ephemeral ports for one address.
+  group.bench_function("host_tcp_server", |b| {
+    b.iter_batched(
+      || {
+        let mut server = MaatNetwork::new();
+        let listener = server.host_tcp_server("127.0.0.1", 0, None);
+        server.poll();
+        let port = server.local_addr(listener).unwrap().port();
+        (server, MaatNetwork::new(), port)
+      },
+      |(mut server, mut client, port)| {
+        (0..ACCEPT_BATCH).for_each(|_| {
+          client.connect_to_tcp("127.0.0.1", port, None);
+        });
+        let mut accepted = 0;
+        while accepted < ACCEPT_BATCH {
+          client.poll();
+          let (_, new_connections, _) = server.poll();
+          accepted += new_connections.len();
+        }
+        (server, client)
+      },
+      BatchSize::PerIteration,
+    )
+  });
+  group.finish();
+}
+
+criterion_group! {
+  name = benches;
+  config = Criterion::default().measurement_time(Duration::from_secs(3));
+  targets = throughput, round_trip, idle_poll, accept_rate
+}
+criterion_main!(benches);
@@ -0,0 +1,241 @@
+//! `#[derive(NetMessage)]` for maat-network.
+//!
+//! Structs with `#[net(bits = ...)]` fields also get a `BitPack` impl that
+//! writes those fields in that many bits, for `send_packed`.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize, NetMessage)]
+//! #[net(id = 3, channel = "unreliable")]
+//! struct PlayerState {
+//!   #[net(bits = 7)]
+//!   health: u8,
+//!   #[net(bits = 10)]
+//!   rotation: u16,
+//! }
+//! ```
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+  parse_macro_input, spanned::Spanned, Attribute, Data, DeriveInput, Error, Fields, Index, LitInt,
+  LitStr,
+};
+
+#[proc_macro_derive(NetMessage, attributes(net))]
+pub fn derive_net_message(input: TokenStream) -> TokenStream {
+  let input = parse_macro_input!(input as DeriveInput);
+  expand(&input)
+    .unwrap_or_else(Error::into_compile_error)
+    .into()
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2, Error> {
+  let (id, channel) = message_attributes(input)?;
+  let id = id.ok_or_else(|| {
+    Error::new(
+      input.ident.span(),
+      "NetMessage needs an id, add #[net(id = ...)]",
+    )
+  })?;
+  let channel = match channel.as_deref() {
+    None | Some("reliable") => quote!(::maat_network::message::Channel::Reliable),
+    Some("unreliable") => quote!(::maat_network::message::Channel::Unreliable),
+    Some(other) => {
+      return Err(Error::new(
+        input.ident.span(),
+        format!(
+          "unknown channel `{}`, expected \"reliable\" or \"unreliable\"",
+          other
+        ),
+      ))
+    }
+  };
+  let bits = field_bits(input)?;
+  let fields = hints(input, &bits);
+  let bit_pack = bit_pack(input, &bits);
+
+  let name = &input.ident;
+  let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+  // Generic messages only implement the serde traits for some parameters.
+  let mut where_clause = where_clause.cloned();
+  if !input.generics.params.is_empty() {
+    where_clause
+      .get_or_insert_with(|| syn::parse_quote!(where))
+      .predicates
+      .push(syn::parse_quote!(
+        #name #type_generics: ::maat_network::serde::Serialize
+          + ::maat_network::serde::de::DeserializeOwned
+          + 'static
+      ));
+  }
+  Ok(quote! {
+    impl #impl_generics ::maat_network::message::NetMessage for #name #type_generics #where_clause {
+      const ID: u16 = #id;
+      const CHANNEL: ::maat_network::message::Channel = #channel;
+      const FIELDS: &'static [::maat_network::message::FieldHint] = &[#(#fields),*];
+    }
+
+    #bit_pack
+  })
+}
+
+/// The id and channel from `#[net(...)]` on the type.
+fn message_attributes(input: &DeriveInput) -> Result<(Option<u16>, Option<String>), Error> {
+  let mut id = None;
+  let mut channel = None;
+  for attr in net_attributes(&input.attrs) {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("id") {
+        if id.is_some() {
+          return Err(meta.error("duplicate message id"));
+        }
+        id = Some(meta.value()?.parse::<LitInt>()?.base10_parse::<u16>()?);
+        Ok(())
+      } else if meta.path.is_ident("channel") {
+        channel = Some(meta.value()?.parse::<LitStr>()?.value());
+        Ok(())
+      } else {
+        Err(meta.error("expected `id` or `channel`"))
+      }
+    })?;
+  }
+  Ok((id, channel))
+}
+
+/// The bit count of every struct field, `None` for fields without
+/// `#[net(bits = ...)]`.
+fn field_bits(input: &DeriveInput) -> Result<Vec<Option<u8>>, Error> {
+  let fields = match &input.data {
+    Data::Struct(data) => &data.fields,
+    Data::Enum(data) => {
+      return match data
+        .variants
+        .iter()
+        .flat_map(|v| v.fields.iter())
+        .find(|f| net_attributes(&f.attrs).next().is_some())
+      {
+        Some(field) => Err(Error::new(
+          field.span(),
+          "bit hints are only supported on struct fields",
+        )),
+        None => Ok(Vec::new()),
+      };
+    }
+    Data::Union(_) => {
+      return Err(Error::new(
+        input.ident.span(),
+        "NetMessage can't be derived for unions",
+      ))
+    }
+  };
+
+  let mut all = Vec::new();
+  for field in fields {
+    let mut count = None;
+    for attr in net_attributes(&field.attrs) {
+      attr.parse_nested_meta(|meta| {
+        if !meta.path.is_ident("bits") {
+          return Err(meta.error("expected `bits`"));
+        }
+        let bits = meta.value()?.parse::<LitInt>()?;
+        let value = bits.base10_parse::<u8>()?;
+        if !(1..=64).contains(&value) {
+          return Err(Error::new(bits.span(), "bits has to be between 1 and 64"));
+        }
+        count = Some(value);
+        Ok(())
+      })?;
+    }
+    all.push(count);
+  }
+  Ok(all)
+}
+
+fn struct_fields(input: &DeriveInput) -> Option<&Fields> {
+  match &input.data {
+    Data::Struct(data) => Some(&data.fields),
+    _ => None,
+  }
+}
+
+/// A `FieldHint` for every field with a bit count.
+fn hints(input: &DeriveInput, bits: &[Option<u8>]) -> Vec<TokenStream2> {
+  let Some(fields) = struct_fields(input) else {
+    return Vec::new();
+  };
+  fields
+    .iter()
+    .zip(bits)
+    .enumerate()
+    .filter_map(|(index, (field, bits))| {
+      let bits = (*bits)?;
+      // Tuple struct fields go by their index.
+      let name = field
+        .ident
+        .as_ref()
+        .map_or_else(|| index.to_string(), |ident| ident.to_string());
+      Some(quote! {
+        ::maat_network::message::FieldHint { name: #name, bits: #bits }
+      })
+    })
+    .collect()
+}
+
+/// `BitPack` for structs with at least one bit count. Counted fields are
+/// written in that many bits, the rest with their own `BitPack` impl.
+fn bit_pack(input: &DeriveInput, bits: &[Option<u8>]) -> TokenStream2 {
+  let Some(fields) = struct_fields(input) else {
+    return TokenStream2::new();
+  };
+  if bits.iter().all(Option::is_none) {
+    return TokenStream2::new();
+  }
+
+  let mut packs = Vec::new();
+  let mut unpacks = Vec::new();
+  for (index, (field, bits)) in fields.iter().zip(bits).enumerate() {
+    let member = match &field.ident {
+      Some(ident) => quote!(#ident),
+      None => {
+        let index = Index::from(index);
+        quote!(#index)
+      }
+    };
+    match bits {
+      Some(bits) => {
+        packs.push(quote!(::maat_network::bits::write_packed(writer, &self.#member, #bits);));
+        unpacks.push(quote!(#member: ::maat_network::bits::read_packed(reader, #bits)?));
+      }
+      None => {
+        packs.push(quote!(::maat_network::bits::BitPack::pack(&self.#member, writer);));
+        unpacks.push(quote!(#member: ::maat_network::bits::BitPack::unpack(reader)?));
+      }
+    }
+  }
+
+  let name = &input.ident;
+  let mut generics = input.generics.clone();
+  for param in generics.type_params_mut() {
+    param
+      .bounds
+      .push(syn::parse_quote!(::maat_network::bits::BitPack));
+  }
+  let (impl_generics, type_generics, where_clause) = generics.split_for_impl();
+  quote! {
+    impl #impl_generics ::maat_network::bits::BitPack for #name #type_generics #where_clause {
+      fn pack(&self, writer: &mut ::maat_network::bits::BitWriter) {
+        #(#packs)*
+      }
+
+      fn unpack(
+        reader: &mut ::maat_network::bits::BitReader,
+      ) -> ::std::result::Result<Self, ::maat_network::bits::BitError> {
+        ::std::result::Result::Ok(#name { #(#unpacks),* })
+      }
+    }
+  }
+}
+
+fn net_attributes(attrs: &[Attribute]) -> impl Iterator<Item = &Attribute> {
+  attrs.iter().filter(|a| a.path().is_ident("net"))
+}
@@ -0,0 +1,178 @@
+use std::{
+  net::UdpSocket as StdUdpSocket,
+  time::{Duration, Instant},
+};
+
+use clap::Parser;
+use mio::net::UdpSocket;
+
+use maat_network::{ConnectionState, MaatNetwork};
+
+/// Measures the event loop over loopback and prints the results, see the
+/// criterion benches for tracking regressions.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+  /// Messages written for the throughput run.
+  #[clap(long, default_value_t = 100_000)]
+  messages: usize,
+  /// Bytes in each throughput message.
+  #[clap(long, default_value_t = 64)]
+  size: usize,
+  /// Round trips timed for the latency percentiles.
+  #[clap(long, default_value_t = 10_000)]
+  round_trips: usize,
+  /// Idle udp sockets held while timing `poll`.
+  #[clap(long, default_value_t = 10_000)]
+  idle: usize,
+  /// Connections accepted for the accept rate.
+  #[clap(long, default_value_t = 1000)]
+  accepts: usize,
+}
+
+/// A tcp server and a client connected to it over loopback, with the client
+/// token and the server's token for the accepted connection.
+fn connected_pair() -> (MaatNetwork, usize, MaatNetwork, usize) {
+  let mut server = MaatNetwork::new();
+  let mut client = MaatNetwork::new();
+  let listener = server.host_tcp_server("127.0.0.1", 0, None);
+  server.poll();
+  let port = server.local_addr(listener).unwrap().port();
+
+  let token = client.connect_to_tcp("127.0.0.1", port, None);
+  let mut accepted = None;
+  while accepted.is_none() || client.state(token) != Some(ConnectionState::Connected) {
+    client.poll();
+    let (_, new_connections, _) = server.poll();
+    for connection in new_connections {
+      accepted = Some(connection.token);
+      server.add_exisiting_connection(connection);
+    }
+  }
+  server.poll();
+  client.poll();
+
+  (server, accepted.unwrap(), client, token)
+}
+
+/// Polls both networks until `to` has read `len` bytes.
+fn deliver(to: &mut MaatNetwork, from: &mut MaatNetwork, len: usize) {
+  let mut received = 0;
+  while received < len {
+    from.poll();
+    let (data, _, _) = to.poll();
+    received += data.iter().map(|(_, d)| d.len()).sum::<usize>();
+  }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+  sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+fn throughput(messages: usize, size: usize) {
+  let (mut server, _, mut client, token) = connected_pair();
+  let message = vec![7; size];
+
+  let start = Instant::now();
+  // Written in chunks so the write queue limit is never hit.
+  for chunk in (0..messages).collect::<Vec<usize>>().chunks(1000) {
+    chunk
+      .iter()
+      .for_each(|_| client.write_data(token, &message).unwrap());
+    deliver(&mut server, &mut client, chunk.len() * size);
+  }
+  let elapsed = start.elapsed();
+
+  println!(
+    "throughput: {} messages of {} bytes in {:?}, {:.0} messages/s",
+    messages,
+    size,
+    elapsed,
+    messages as f64 / elapsed.as_secs_f64()
+  );
+}
+
+fn latency(round_trips: usize) {
+  let (mut server, accepted, mut client, token) = connected_pair();
+
+  let mut times = (0..round_trips)
+    .map(|_| {
+      let start = Instant::now();
+      client.write_data(token, b"ping").unwrap();
+      deliver(&mut server, &mut client, 4);
+      server.write_data(accepted, b"pong").unwrap();
+      deliver(&mut client, &mut server, 4);
+      start.elapsed()
+    })
+    .collect::<Vec<Duration>>();
+  times.sort();
+
+  println!(
+    "round trip: p50 {:?}, p99 {:?}, max {:?}",
+    percentile(&times, 0.5),
+    percentile(&times, 0.99),
+    times.last().unwrap()
+  );
+}
+
+fn idle_poll(idle: usize) {
+  let sink = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let port = sink.local_addr().unwrap().port();
+
+  let mut network = MaatNetwork::new();
+  for _ in 0..idle {
+    let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(("127.0.0.1", port)).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    network.add_existing_udp_connection(UdpSocket::from_std(socket), "127.0.0.1", port, None);
+  }
+  network.poll();
+
+  let polls = 1000;
+  let start = Instant::now();
+  (0..polls).for_each(|_| {
+    network.poll();
+  });
+
+  println!(
+    "idle poll: {:?} per poll with {} connections",
+    start.elapsed() / polls,
+    idle
+  );
+}
+
+fn accept_rate(accepts: usize) {
+  let mut server = MaatNetwork::new();
+  let mut client = MaatNetwork::new();
+  let listener = server.host_tcp_server("127.0.0.1", 0, None);
+  server.poll();
+  let port = server.local_addr(listener).unwrap().port();
+
+  let start = Instant::now();
+  (0..accepts).for_each(|_| {
+    client.connect_to_tcp("127.0.0.1", port, None);
+  });
+  let mut accepted = 0;
+  while accepted < accepts {
+    client.poll();
+    let (_, new_connections, _) = server.poll();
+    accepted += new_connections.len();
+  }
+  let elapsed = start.elapsed();
+
+  println!(
+    "accept: {} connections in {:?}, {:.0} connections/s",
+    accepts,
+    elapsed,
+    accepts as f64 / elapsed.as_secs_f64()
+  );
+}
+
+fn main() {
+  let args = Args::parse();
+
+  throughput(args.messages, args.size);
+  latency(args.round_trips);
+  idle_poll(args.idle);
+  accept_rate(args.accepts);
+}
@@ -1,251 +1,1876 @@
-//use std::net::{TcpStream, Shutdown};
-//use std::io::{Read, Write, ErrorKind};
-//use std::time::Duration;
-//use std::net::SocketAddr;
-//
-//#[macro_use]
-//pub extern crate serde_derive;
-//pub extern crate bincode;
-//
-//pub use bincode::{deserialize, serialize};
-//
-//const BUFFER_SIZE: usize = 2048;
-//
-//#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
-//pub enum NetworkError {
-//  ClientAlreadyConnected,
-//  Unknown,
-//}
-//
-//#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
-//pub enum NetworkMessage {
-//  Null,
-//  Success,
-//  Shutdown,
-//  Err(NetworkError),
-//  NewSession(String),
-//  ClientConnected,
-//  Reconnect,
-//  EndSession,
-//  Data(String),
-//  RawData(Vec<u8>)
-//}
-//
-//impl NetworkMessage {
-//  pub fn serialise(&self) -> Vec<u8> {
-//    bincode::serialize(&self).unwrap()
-//  }
-//
-//  pub fn deserialise(serialised: &[u8]) -> NetworkMessage {
-//    match bincode::deserialize(&serialised) {
-//      Ok(msg) => {
-//        msg
-//      },
-//      Err(e) => {
-//        println!("{:?}", e);
-//        NetworkMessage::Null
-//      }
-//    }
-//  }
-//}
-//
-//pub struct StreamHandler {
-//  stream: TcpStream,
-//}
-//
-//impl StreamHandler {
-//  pub fn new(ip: &str) -> StreamHandler {
-//    let mut stream = TcpStream::connect(ip).expect("Couldn't connect to the server...");
-//    //stream.set_keepalive(Some(10));
-//    StreamHandler {
-//      stream,
-//    }
-//  }
-//
-//  pub fn from_stream(stream: TcpStream) -> StreamHandler {
-//    StreamHandler {
-//      stream,
-//    }
-//  }
-//
-//  pub fn internal(&self) -> &TcpStream {
-//    &self.stream
-//  }
-//
-//  pub fn socket(&self) -> SocketAddr {
-//    self.stream.local_addr().unwrap()
-//  }
-//
-//  pub fn peer(&self) -> SocketAddr {
-//    self.stream.peer_addr().unwrap()
-//  }
-//
-//  pub fn non_blocking(mut self) -> StreamHandler {
-//    self.stream.set_nonblocking(true).expect("set_nonblocking call failed");
-//    self
-//  }
-//
-//  pub fn read_timeout(mut self, millis: u64) -> StreamHandler {
-//    self.stream.set_read_timeout(Some(Duration::from_millis(millis)));
-//    self
-//  }
-//
-//  pub fn write_timeout(mut self, millis: u64) -> StreamHandler {
-//    self.stream.set_write_timeout(Some(Duration::from_millis(millis)));
-//    self
-//  }
-//
-//  pub fn read_buffer(&mut self, buffer: &mut [u8; BUFFER_SIZE]) -> bool {
-//    let mut read_successful = false;
-//
-//   // while !read_successful {
-//    match self.stream.read(buffer) {
-//      Ok(size) => {
-//        read_successful = true;
-//      },
-//      Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-//
-//      },
-//      Err(e) => { println!("Error: {}", e); }
-//    }
-//   // }
-//    self.stream.flush().unwrap();
-//
-//    read_successful
-//  }
-//
-//  pub fn write_buffer(&mut self, buffer: &[u8]) -> bool {
-//    let mut write_successful = false;
-//
-//    match self.stream.write(&buffer[..]) {
-//      Ok(size) => {
-//        write_successful = true;
-//      },
-//      Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-//
-//      },
-//      Err(e) => { println!("Error: {}", e); }
-//    }
-//
-//    self.stream.flush().unwrap();
-//
-//    write_successful
-//  }
-//
-//  pub fn shutdown(&mut self) {
-//    self.stream.shutdown(Shutdown::Both).unwrap();
-//    self.stream.flush().unwrap();
-//  }
-//}
-//
-//pub struct Network {
-//  session_id: String,
-//  buffer: [u8; BUFFER_SIZE],
-//  last_message: NetworkMessage,
-//  stream: StreamHandler,
-//}
-//
-//impl Network {
-//  pub fn new(server_ip: &str, session_id: &str) -> Network {
-//    let mut stream = StreamHandler::new(server_ip).non_blocking();//.read_timeout(50).write_timeout(50);
-//    println!("Successfully connected to server");
-//
-//    Network {
-//      session_id: format!("{}", session_id),
-//      buffer: [0; BUFFER_SIZE],
-//      last_message: NetworkMessage::Null,
-//      stream,
-//    }
-//  }
-//
-//  pub fn from_stream(stream: TcpStream) -> Network {
-//    Network {
-//      session_id: "".to_string(),
-//      buffer: [0; BUFFER_SIZE],
-//      last_message: NetworkMessage::Null,
-//      stream: StreamHandler::from_stream(stream),
-//    }
-//  }
-//
-//  pub fn message(&self) -> &NetworkMessage {
-//    &self.last_message
-//  }
-//
-//  pub fn socket(&self) -> SocketAddr {
-//    self.stream.socket()
-//  }
-//
-//  pub fn peer(&self) -> SocketAddr {
-//    self.stream.peer()
-//  }
-//
-//  pub fn peer_connected(&self) -> bool {
-//    match self.stream.internal().peer_addr() {
-//      Ok(_) => {
-//        true
-//      },
-//      Err(_) => {
-//        false
-//      }
-//    }
-//  }
-//
-//  pub fn buffer(&self) -> String {
-//    let chars_to_trim: &[char] = &['\u{0}'];
-//
-//    format!("{}", String::from_utf8_lossy(&self.buffer).trim_matches(chars_to_trim))
-//  }
-//
-//  pub fn connect_session(&mut self) -> bool {
-//    let mut response = bincode::serialize(&NetworkMessage::NewSession(self.session_id.to_string())).unwrap();
-//
-//    self.stream.write_buffer(&response[..]);
-//
-//    let mut session_exists = false;
-//
-//    while !session_exists {
-//      self.read();
-//      match self.message() {
-//        NetworkMessage::Success => {
-//          println!("Session connected");
-//          break;
-//        },
-//        _ => {},
-//      }
-//    }
-//
-//    session_exists
-//  }
-//
-//  pub fn write_network_message(&mut self, msg: NetworkMessage) -> bool {
-//    let mut response = bincode::serialize(&msg).unwrap();
-//
-//    self.stream.write_buffer(&response[..])
-//  }
-//
-//  pub fn send_data(&mut self, data: String) -> bool {
-//    let mut response = bincode::serialize(&NetworkMessage::Data(data)).unwrap();
-//
-//    !self.stream.write_buffer(&response[..])
-//  }
-//
-//  pub fn send_vec(&mut self, data: Vec<u8>) -> bool {
-//    let mut response = bincode::serialize(&NetworkMessage::RawData(data)).unwrap();
-//
-//    !self.stream.write_buffer(&response[..])
-//  }
-//
-//  pub fn read(&mut self) -> bool {
-//    self.buffer = [0; BUFFER_SIZE];
-//
-//    let did_read = self.stream.read_buffer(&mut self.buffer);
-//
-//    self.last_message = NetworkMessage::deserialise(&self.buffer);
-//
-//    did_read
-//  }
-//
-//  pub fn close_connection(&mut self) {
-//    self.stream.shutdown();
-//  }
-//}
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "connect-tokens")]
+use std::time::SystemTime;
+use std::{
+  collections::{HashMap, VecDeque},
+  io::{Error, ErrorKind},
+  net::{IpAddr, Ipv6Addr, SocketAddr},
+  time::{Duration, Instant},
+};
+
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use mio::net::{UnixDatagram, UnixListener, UnixStream};
+use mio::{Interest, Token};
+#[cfg(feature = "tls")]
+use rustls::{ClientConfig, ServerConfig};
+use socket2::{Domain, Socket, Type};
+
+#[cfg(feature = "derive")]
+pub use maat_network_derive::NetMessage;
+#[cfg(feature = "compression")]
+pub use modules::compression::{self, Algorithm, Compression, CompressionStats};
+#[cfg(feature = "connect-tokens")]
+pub use modules::connect_token::{self, ConnectToken, ConnectTokenKey, TokenError};
+#[cfg(feature = "connect-tokens")]
+use modules::connect_token::{Admission, UsedTokens};
+#[cfg(feature = "messages")]
+use modules::message::MessageFramer;
+#[cfg(feature = "messages")]
+pub use modules::message::{self, Channel, Codec, Message, MessageError, NetMessage};
+#[cfg(feature = "messages")]
+pub use modules::registry::{self, MessageRegistry, RegistryError, RegistryEvent};
+#[cfg(feature = "messages")]
+use modules::rpc::Rpc;
+#[cfg(feature = "messages")]
+pub use modules::rpc::{self, RpcFrame};
+#[cfg(feature = "tls")]
+pub use modules::tls::{self, TlsListener, TlsStream};
+#[cfg(feature = "encryption")]
+pub use modules::udp_encryption::{self, EncryptionError, SessionKey, UdpSession};
+pub use modules::{
+  bits::{self, BitError, BitPack, BitReader, BitWriter},
+  checksum::{self, ConnectionStats},
+  link_conditioner::{self, LinkConditions, LinkStats},
+  memory::{self, MemoryListener, MemoryStream},
+  mux::{self, Demultiplexer, Multiplexer},
+  read_functions::{accept_connections, print_data, recieve_data},
+  version::{self, ProtocolVersion, VersionPolicy},
+  ConnectionState, ConnectionType, DisconnectReason, EventHandler, NetworkEvent, NetworkStream,
+  OverflowPolicy, Priority, Resolver, Transport, WriteError, WriteLimits,
+};
+#[cfg(feature = "messages")]
+#[doc(hidden)]
+pub use serde;
+
+pub type ReadFunc = Box<dyn Fn(&mut ConnectionType, &[u8]) -> Vec<(ConnectionType, String)>>;
+
+pub type PollResult = (
+  Vec<(usize, Vec<u8>)>,
+  Vec<NewConnection>,
+  Vec<Option<usize>>,
+);
+
+pub mod modules;
+
+pub const TCP_SERVER_ADDRESS: &str = "0.0.0.0";
+pub const TCP_SERVER_PORT: u16 = 6767;
+pub const UDP_SERVER_ADDRESS: &str = "0.0.0.0";
+pub const UDP_SERVER_PORT: u16 = 6768;
+/// Binding to this address with `ipv6_only` off accepts both ipv4 and ipv6.
+pub const DUAL_STACK_ADDRESS: &str = "::";
+
+pub const DEFAULT_PENDING_DATA_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct NewConnection {
+  pub token: usize,
+  pub connection: ConnectionType,
+  pub addr: String,
+  pub read_func: Option<ReadFunc>,
+  pub connecting: bool,
+  pub error: Option<Error>,
+  /// The verified connect token for connections accepted on a listener that
+  /// requires one.
+  #[cfg(feature = "connect-tokens")]
+  pub connect_token: Option<ConnectToken>,
+}
+
+impl NewConnection {
+  pub fn new(
+    token: usize,
+    connection: ConnectionType,
+    addr: &str,
+    read_func: Option<ReadFunc>,
+  ) -> NewConnection {
+    NewConnection::with_ipv6_only(token, connection, addr, read_func, false)
+  }
+
+  /// Like `new`, `ipv6_only` sets IPV6_V6ONLY on listeners and udp sockets bound
+  /// to an ipv6 address. When false they also accept ipv4 traffic.
+  pub fn with_ipv6_only(
+    token: usize,
+    connection: ConnectionType,
+    addr: &str,
+    read_func: Option<ReadFunc>,
+    ipv6_only: bool,
+  ) -> NewConnection {
+    let connecting = connects_on_create(&connection);
+    let (connection, error) = match create_connection(connection, addr, ipv6_only) {
+      Ok(connection) => (connection, None),
+      Err(e) => (ConnectionType::NewTcpStream, Some(e)),
+    };
+
+    NewConnection {
+      token,
+      connection,
+      addr: addr.into(),
+      read_func,
+      connecting,
+      error,
+      #[cfg(feature = "connect-tokens")]
+      connect_token: None,
+    }
+  }
+}
+
+pub struct NetworkData {
+  token: usize,
+  data: Vec<u8>,
+}
+
+pub struct MaatNetwork {
+  event_handler: EventHandler,
+  connections: Vec<NetworkStream>,
+  new_connections: Vec<NewConnection>,
+  resolver: Resolver,
+  resolving: Vec<(usize, ConnectionType, Option<ReadFunc>)>,
+  /// The other addresses a host resolved to, tried in turn if connecting to
+  /// the first one fails.
+  connect_fallbacks: HashMap<usize, (ConnectionType, VecDeque<SocketAddr>)>,
+  pending_data: Vec<(usize, Vec<u8>, Instant, Priority)>,
+  pending_data_timeout: Duration,
+  connect_timeout: Duration,
+  ipv6_only: bool,
+  write_limits: WriteLimits,
+  token_write_limits: HashMap<usize, WriteLimits>,
+  token_checksums: HashMap<usize, u32>,
+  link_conditions: Option<LinkConditions>,
+  token_link_conditions: HashMap<usize, LinkConditions>,
+  protocol_version: Option<(ProtocolVersion, VersionPolicy)>,
+  /// Streams written to before the connection was added.
+  token_streams: HashMap<usize, Multiplexer>,
+  demux: Demultiplexer,
+  #[cfg(feature = "compression")]
+  token_compression: HashMap<usize, Compression>,
+  #[cfg(feature = "messages")]
+  message_codec: Codec,
+  #[cfg(feature = "messages")]
+  message_framer: MessageFramer,
+  #[cfg(feature = "messages")]
+  rpc: Rpc,
+  #[cfg(feature = "encryption")]
+  encrypted_tokens: HashMap<usize, SessionKey>,
+  #[cfg(feature = "connect-tokens")]
+  token_keys: HashMap<usize, ConnectTokenKey>,
+  #[cfg(feature = "connect-tokens")]
+  admitting: Vec<Admission>,
+  #[cfg(feature = "connect-tokens")]
+  used_tokens: UsedTokens,
+  #[cfg(feature = "connect-tokens")]
+  outgoing_tokens: HashMap<usize, Vec<u8>>,
+  events: Vec<NetworkEvent>,
+}
+
+impl MaatNetwork {
+  pub fn new() -> MaatNetwork {
+    MaatNetwork {
+      event_handler: EventHandler::new(),
+      connections: Vec::new(),
+      new_connections: Vec::new(),
+      resolver: Resolver::new(),
+      resolving: Vec::new(),
+      connect_fallbacks: HashMap::new(),
+      pending_data: Vec::new(),
+      pending_data_timeout: DEFAULT_PENDING_DATA_TIMEOUT,
+      connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+      ipv6_only: false,
+      write_limits: WriteLimits::default(),
+      token_write_limits: HashMap::new(),
+      token_checksums: HashMap::new(),
+      link_conditions: None,
+      token_link_conditions: HashMap::new(),
+      protocol_version: None,
+      token_streams: HashMap::new(),
+      demux: Demultiplexer::new(),
+      #[cfg(feature = "compression")]
+      token_compression: HashMap::new(),
+      #[cfg(feature = "messages")]
+      message_codec: Codec::default(),
+      #[cfg(feature = "messages")]
+      message_framer: MessageFramer::new(),
+      #[cfg(feature = "messages")]
+      rpc: Rpc::new(),
+      #[cfg(feature = "encryption")]
+      encrypted_tokens: HashMap::new(),
+      #[cfg(feature = "connect-tokens")]
+      token_keys: HashMap::new(),
+      #[cfg(feature = "connect-tokens")]
+      admitting: Vec::new(),
+      #[cfg(feature = "connect-tokens")]
+      used_tokens: UsedTokens::new(),
+      #[cfg(feature = "connect-tokens")]
+      outgoing_tokens: HashMap::new(),
+      events: Vec::new(),
+    }
+  }
+
+  pub fn host_tcp_server<S>(&mut self, addr: S, port: u16, read_func: Option<ReadFunc>) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.event_handler.next_token();
+    self.open_connection(
+      token,
+      ConnectionType::NewTcpListener,
+      addr.into(),
+      port,
+      Some(read_func.unwrap_or_else(|| Box::new(accept_connections))),
+    );
+    token
+  }
+
+  pub fn host_tcp_server_addr(&mut self, addr: SocketAddr, read_func: Option<ReadFunc>) -> usize {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(self.new_connection(
+      token,
+      ConnectionType::NewTcpListener,
+      &addr.to_string(),
+      Some(read_func.unwrap_or_else(|| Box::new(accept_connections))),
+    ));
+    token
+  }
+
+  pub fn host_udp_server<S>(&mut self, addr: S, port: u16, read_func: Option<ReadFunc>) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.event_handler.next_token();
+    self.open_connection(
+      token,
+      ConnectionType::NewUdpSocket,
+      addr.into(),
+      port,
+      read_func,
+    );
+    token
+  }
+
+  pub fn host_udp_server_addr(&mut self, addr: SocketAddr, read_func: Option<ReadFunc>) -> usize {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(self.new_connection(
+      token,
+      ConnectionType::NewUdpSocket,
+      &addr.to_string(),
+      read_func,
+    ));
+    token
+  }
+
+  pub fn connect_to_tcp<S>(&mut self, addr: S, port: u16, read_func: Option<ReadFunc>) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.event_handler.next_token();
+    self.open_connection(
+      token,
+      ConnectionType::NewTcpStream,
+      addr.into(),
+      port,
+      read_func,
+    );
+
+    token
+  }
+
+  /// Like `connect_to_tcp`, `sealed` is sent before anything else on the
+  /// connection, for servers that `require_connect_tokens`. It goes out
+  /// ahead of the version exchange and isn't compressed or framed.
+  #[cfg(feature = "connect-tokens")]
+  pub fn connect_to_tcp_with_token<S>(
+    &mut self,
+    addr: S,
+    port: u16,
+    sealed: &[u8],
+    read_func: Option<ReadFunc>,
+  ) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.connect_to_tcp(addr, port, read_func);
+    self.outgoing_tokens.insert(token, sealed.to_vec());
+    token
+  }
+
+  pub fn connect_to_tcp_addr(&mut self, addr: SocketAddr, read_func: Option<ReadFunc>) -> usize {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(self.new_connection(
+      token,
+      ConnectionType::NewTcpStream,
+      &addr.to_string(),
+      read_func,
+    ));
+
+    token
+  }
+
+  fn new_connection(
+    &self,
+    token: usize,
+    connection: ConnectionType,
+    addr: &str,
+    read_func: Option<ReadFunc>,
+  ) -> NewConnection {
+    NewConnection::with_ipv6_only(token, connection, addr, read_func, self.ipv6_only)
+  }
+
+  /// Sets IPV6_V6ONLY on listeners and udp sockets bound to ipv6 addresses from
+  /// now on. Off by default, so binding to `DUAL_STACK_ADDRESS` serves both
+  /// ipv4 and ipv6 clients.
+  pub fn set_ipv6_only(&mut self, ipv6_only: bool) {
+    self.ipv6_only = ipv6_only;
+  }
+
+  /// Listens for connections from networks in the same process under `name`,
+  /// without opening a socket. The name is taken until the listener closes.
+  pub fn host_memory_server(&mut self, name: &str, read_func: Option<ReadFunc>) -> usize {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::NewMemoryListener,
+      name,
+      Some(read_func.unwrap_or_else(|| Box::new(accept_connections))),
+    ));
+    token
+  }
+
+  /// Connects to a `host_memory_server` listener in the same process.
+  pub fn connect_to_memory(&mut self, name: &str, read_func: Option<ReadFunc>) -> usize {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::NewMemoryStream,
+      name,
+      read_func,
+    ));
+    token
+  }
+
+  /// Listens for stream connections on a unix domain socket at `path`, the
+  /// socket file must not exist yet.
+  #[cfg(unix)]
+  pub fn host_unix_server<P>(&mut self, path: P, read_func: Option<ReadFunc>) -> usize
+  where
+    P: AsRef<Path>,
+  {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::NewUnixListener,
+      &path.as_ref().display().to_string(),
+      Some(read_func.unwrap_or_else(|| Box::new(accept_connections))),
+    ));
+    token
+  }
+
+  #[cfg(unix)]
+  pub fn host_unix_datagram<P>(&mut self, path: P, read_func: Option<ReadFunc>) -> usize
+  where
+    P: AsRef<Path>,
+  {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::NewUnixDatagram,
+      &path.as_ref().display().to_string(),
+      read_func,
+    ));
+    token
+  }
+
+  #[cfg(unix)]
+  pub fn connect_to_unix<P>(&mut self, path: P, read_func: Option<ReadFunc>) -> usize
+  where
+    P: AsRef<Path>,
+  {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::NewUnixStream,
+      &path.as_ref().display().to_string(),
+      read_func,
+    ));
+
+    token
+  }
+
+  /// Like `host_tcp_server`, every accepted connection runs a tls handshake
+  /// with `config` before it is `Connected`.
+  #[cfg(feature = "tls")]
+  pub fn host_tls_server<S>(
+    &mut self,
+    addr: S,
+    port: u16,
+    config: Arc<ServerConfig>,
+    read_func: Option<ReadFunc>,
+  ) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.event_handler.next_token();
+    self.open_connection(
+      token,
+      ConnectionType::NewTlsListener(config),
+      addr.into(),
+      port,
+      Some(read_func.unwrap_or_else(|| Box::new(accept_connections))),
+    );
+    token
+  }
+
+  /// Like `connect_to_tcp`, the server's certificate is checked against `config`
+  /// and the host name given.
+  #[cfg(feature = "tls")]
+  pub fn connect_to_tls<S>(
+    &mut self,
+    addr: S,
+    port: u16,
+    config: Arc<ClientConfig>,
+    read_func: Option<ReadFunc>,
+  ) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.event_handler.next_token();
+    let host = addr.into();
+    match tls::server_name(host.trim_start_matches('[').trim_end_matches(']')) {
+      Ok(name) => {
+        self.open_connection(
+          token,
+          ConnectionType::NewTlsStream(config, name),
+          host,
+          port,
+          read_func,
+        );
+      }
+      Err(e) => {
+        self.new_connections.push(NewConnection {
+          token,
+          connection: ConnectionType::NewTcpStream,
+          addr: format_addr(&host, port),
+          read_func,
+          connecting: true,
+          error: Some(e),
+          #[cfg(feature = "connect-tokens")]
+          connect_token: None,
+        });
+      }
+    }
+
+    token
+  }
+
+  /// Ip addresses are used straight away, anything else is looked up in the
+  /// background and the connection is created by `poll` once it resolves.
+  fn open_connection(
+    &mut self,
+    token: usize,
+    connection: ConnectionType,
+    host: String,
+    port: u16,
+    read_func: Option<ReadFunc>,
+  ) {
+    let host = host
+      .trim_start_matches('[')
+      .trim_end_matches(']')
+      .to_string();
+    match host.parse::<IpAddr>() {
+      Ok(ip) => {
+        self.new_connections.push(self.new_connection(
+          token,
+          connection,
+          &SocketAddr::new(ip, port).to_string(),
+          read_func,
+        ));
+      }
+      Err(_) => {
+        self.resolver.resolve(token, host, port);
+        self.resolving.push((token, connection, read_func));
+      }
+    }
+  }
+
+  pub fn add_exisiting_connection(&mut self, connection: NewConnection) {
+    self.new_connections.push(connection);
+  }
+
+  pub fn add_existing_tcp_listener<S>(
+    &mut self,
+    listener: TcpListener,
+    addr: S,
+    port: u16,
+    read_func: Option<ReadFunc>,
+  ) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::from(listener),
+      &format_addr(&addr.into(), port),
+      read_func,
+    ));
+    token
+  }
+
+  pub fn add_existing_udp_connection<S>(
+    &mut self,
+    udp: UdpSocket,
+    addr: S,
+    port: u16,
+    read_func: Option<ReadFunc>,
+  ) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::from(udp),
+      &format_addr(&addr.into(), port),
+      read_func,
+    ));
+    token
+  }
+
+  pub fn add_existing_tcp_connection<S>(
+    &mut self,
+    tcp_connection: TcpStream,
+    addr: S,
+    port: u16,
+    read_func: Option<ReadFunc>,
+  ) -> usize
+  where
+    S: Into<String>,
+  {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::from(tcp_connection),
+      &format_addr(&addr.into(), port),
+      read_func,
+    ));
+
+    token
+  }
+
+  /// Adds a connection over your own `Transport`. Listeners need
+  /// `accept_connections`, or a read function like it, to hand out what
+  /// they accept.
+  pub fn add_existing_transport<T>(
+    &mut self,
+    transport: T,
+    addr: &str,
+    read_func: Option<ReadFunc>,
+  ) -> usize
+  where
+    T: Transport + 'static,
+  {
+    let token = self.event_handler.next_token();
+    self.new_connections.push(NewConnection::new(
+      token,
+      ConnectionType::custom(transport),
+      addr,
+      read_func,
+    ));
+    token
+  }
+
+  pub fn removed_connection(&mut self, token: usize) {
+    self.close_token(token);
+    self
+      .connections
+      .iter_mut()
+      .filter(|x| !x.unregistered())
+      .filter(|x| {
+        if let Some(t) = x.token() {
+          t.0 == token
+        } else {
+          false
+        }
+      })
+      .for_each(|c| c.deregister(self.event_handler.poll.registry()));
+  }
+
+  /// Stops accepting data for the token and closes it once everything already
+  /// queued has been written. A connection that isn't up yet, still being
+  /// resolved, created or connected, is dropped straight away. Either way a
+  /// `Disconnected` event follows.
+  pub fn close_connection(&mut self, token: usize) {
+    if let Some(c) = self
+      .connections
+      .iter_mut()
+      .filter(|c| !c.unregistered())
+      .find(|c| c.token().map(|t| t.0) == Some(token))
+    {
+      if !c.is_connecting() {
+        c.close();
+        return;
+      }
+      c.deregister(self.event_handler.poll.registry());
+    } else if self.event_handler.is_open(token) {
+      self.new_connections.retain(|c| c.token != token);
+    } else {
+      return;
+    }
+
+    self.close_token(token);
+    self
+      .events
+      .push(NetworkEvent::Disconnected(token, DisconnectReason::Closed));
+  }
+
+  /// The state of the connection for the token, `None` if the token was never
+  /// issued.
+  pub fn state(&self, token: usize) -> Option<ConnectionState> {
+    if let Some(c) = self
+      .connections
+      .iter()
+      .filter(|c| !c.unregistered())
+      .find(|c| c.token().map(|t| t.0) == Some(token))
+    {
+      Some(c.state())
+    } else if self.event_handler.is_open(token) {
+      Some(ConnectionState::Pending)
+    } else if self.event_handler.was_issued(token) {
+      Some(ConnectionState::Closed)
+    } else {
+      None
+    }
+  }
+
+  /// The local address of the token's socket, useful to find the port picked
+  /// when binding to port 0.
+  pub fn local_addr(&self, token: usize) -> Option<SocketAddr> {
+    self
+      .connections
+      .iter()
+      .filter(|c| !c.unregistered())
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .map(|c| &c.stream)
+      .chain(
+        self
+          .new_connections
+          .iter()
+          .filter(|c| c.token == token)
+          .map(|c| &c.connection),
+      )
+      .find_map(|c| c.local_addr().ok())
+  }
+
+  /// Sets the write limits used for connections that have no limits of their own.
+  pub fn set_default_write_limits(&mut self, limits: WriteLimits) {
+    self.write_limits = limits;
+  }
+
+  pub fn set_write_limits(&mut self, token: usize, limits: WriteLimits) {
+    self.token_write_limits.insert(token, limits);
+    self
+      .connections
+      .iter_mut()
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .for_each(|c| c.set_write_limits(limits));
+  }
+
+  pub fn write_limits(&self, token: usize) -> WriteLimits {
+    *self
+      .token_write_limits
+      .get(&token)
+      .unwrap_or(&self.write_limits)
+  }
+
+  /// Simulates a bad network on connections registered from now on that have
+  /// no conditions of their own, `None` sends data straight out.
+  pub fn set_default_link_conditions(&mut self, conditions: Option<LinkConditions>) {
+    self.link_conditions = conditions;
+  }
+
+  /// Delays, drops, duplicates and reorders data written to the token as set
+  /// out in `conditions`, for testing against bad networks.
+  pub fn set_link_conditions(&mut self, token: usize, conditions: LinkConditions) {
+    self.token_link_conditions.insert(token, conditions);
+    self
+      .connections
+      .iter_mut()
+      .filter(|c| !c.unregistered())
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .for_each(|c| c.set_link_conditions(conditions));
+  }
+
+  /// What the link conditioner did to data written to the token.
+  pub fn link_stats(&self, token: usize) -> Option<LinkStats> {
+    self
+      .connections
+      .iter()
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .find_map(|c| c.link_stats())
+  }
+
+  /// Bytes waiting to be written to the token, including data queued before
+  /// the connection was registered.
+  pub fn queued_bytes(&self, token: usize) -> usize {
+    self
+      .connections
+      .iter()
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .map(|c| c.queued_bytes())
+      .sum::<usize>()
+      + self.pending_bytes(token)
+  }
+
+  fn pending_bytes(&self, token: usize) -> usize {
+    self
+      .pending_data
+      .iter()
+      .filter(|(t, _, _, _)| *t == token)
+      .map(|(_, d, _, _)| d.len())
+      .sum()
+  }
+
+  /// How long data written to a token may wait for its connection to be
+  /// registered before it is dropped.
+  pub fn set_pending_data_timeout(&mut self, timeout: Duration) {
+    self.pending_data_timeout = timeout;
+  }
+
+  /// Resolves `host` to `addrs` from now on instead of looking it up. They are
+  /// tried in order until one connects.
+  pub fn add_host(&mut self, host: &str, addrs: Vec<IpAddr>) {
+    self.resolver.add_host(host, addrs);
+  }
+
+  /// How long an outgoing tcp connection may stay in progress before it is
+  /// reported as failed. Each address a host resolved to gets this long.
+  pub fn set_connect_timeout(&mut self, timeout: Duration) {
+    self.connect_timeout = timeout;
+  }
+
+  /// Prefixes every packet on a udp or unix datagram socket with `protocol_id`
+  /// and a crc32, the peer has to do the same. Packets from other protocols or
+  /// with a bad checksum are dropped and counted in `connection_stats`. Panics
+  /// if the token isn't a datagram socket.
+  pub fn enable_checksums(&mut self, token: usize, protocol_id: u32) {
+    if let Some(c) = self
+      .connections
+      .iter_mut()
+      .filter(|c| !c.unregistered())
+      .find(|c| c.token().map(|t| t.0) == Some(token))
+    {
+      c.enable_checksums(protocol_id);
+      return;
+    }
+
+    if let Some(c) = self.new_connections.iter().find(|c| c.token == token) {
+      assert!(
+        c.connection.is_datagram(),
+        "MaatNetwork: only datagram sockets can be checksummed, {} isn't",
+        c.addr
+      );
+      self.token_checksums.insert(token, protocol_id);
+    } else if self
+      .resolving
+      .iter()
+      .any(|(t, c, _)| *t == token && matches!(c, ConnectionType::NewUdpSocket))
+    {
+      self.token_checksums.insert(token, protocol_id);
+    }
+  }
+
+  /// Packet counts for a token with checksums enabled.
+  pub fn connection_stats(&self, token: usize) -> Option<ConnectionStats> {
+    self
+      .connections
+      .iter()
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .find_map(|c| c.connection_stats())
+  }
+
+  /// Compresses messages written to the token from now on. Both sides have to
+  /// enable it, and before writing anything, since it also changes how
+  /// messages are framed on stream connections.
+  #[cfg(feature = "compression")]
+  pub fn set_compression(&mut self, token: usize, compression: Compression) {
+    self.token_compression.insert(token, compression);
+    self
+      .connections
+      .iter_mut()
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .for_each(|c| c.set_compression(compression));
+  }
+
+  /// Compression stats for messages sent and received on the token.
+  #[cfg(feature = "compression")]
+  pub fn compression_stats(&self, token: usize) -> Option<(CompressionStats, CompressionStats)> {
+    self
+      .connections
+      .iter()
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .find_map(|c| c.compression_stats())
+  }
+
+  /// Exchanges `version` with the peer as the first bytes on every stream and
+  /// connected udp socket added from now on, accepted ones included. Tls
+  /// streams exchange it right after their handshake. Data is queued until
+  /// both sides accepted the other under their `policy`, otherwise both get a
+  /// `VersionMismatch` disconnect. The peer has to do the same.
+  ///
+  /// Sockets without a single peer, such as `host_udp_server` and unix
+  /// datagram sockets, and custom transports can't exchange versions. Adding
+  /// one fails with `ConnectFailed(token, ErrorKind::Unsupported)`. Listeners
+  /// aren't affected.
+  pub fn set_protocol_version(&mut self, version: ProtocolVersion, policy: VersionPolicy) {
+    self.protocol_version = Some((version, policy));
+  }
+
+  /// The version the peer sent, `None` until its hello arrived or without a
+  /// version exchange.
+  pub fn peer_version(&self, token: usize) -> Option<ProtocolVersion> {
+    self
+      .connections
+      .iter()
+      .filter(|c| !c.unregistered())
+      .find(|c| c.token().map(|t| t.0) == Some(token))
+      .and_then(|c| c.peer_version())
+  }
+
+  /// Splits the token into numbered streams that are written to in turns, so
+  /// a large message on one only holds up the others for a chunk at a time.
+  /// `write_data` goes to stream 0, which is always open, and its messages
+  /// are still returned by `poll`. The other streams are one way, the peer
+  /// gets `StreamOpened`, `StreamMessage` and `StreamClosed` events and opens
+  /// its own to write back. Both sides have to enable it before writing
+  /// anything. Panics if the token isn't a stream.
+  pub fn enable_streams(&mut self, token: usize) {
+    self.demux.enable(token);
+    let limits = self.write_limits(token);
+    match self
+      .connections
+      .iter_mut()
+      .filter(|c| !c.unregistered())
+      .find(|c| c.token().map(|t| t.0) == Some(token))
+    {
+      Some(c) => c.multiplex(Multiplexer::new(limits)),
+      None => {
+        self.token_streams.insert(token, Multiplexer::new(limits));
+      }
+    }
+  }
+
+  /// Opens the numbered stream on the token. `StreamOpen` if it is already
+  /// open or still being closed.
+  pub fn open_stream(&mut self, token: usize, stream: u16) -> Result<(), WriteError> {
+    self.multiplexer(token)?.open(stream)
+  }
+
+  /// Queues a message on an open stream, it arrives whole as a
+  /// `StreamMessage` event. The peer drops the connection for messages over
+  /// `mux::MAX_STREAM_MESSAGE_BYTES`.
+  pub fn write_stream(&mut self, token: usize, stream: u16, data: &[u8]) -> Result<(), WriteError> {
+    self.multiplexer(token)?.write(stream, data)
+  }
+
+  /// Closes the stream once everything queued on it has been written.
+  pub fn close_stream(&mut self, token: usize, stream: u16) -> Result<(), WriteError> {
+    self.multiplexer(token)?.close(stream)
+  }
+
+  fn multiplexer(&mut self, token: usize) -> Result<&mut Multiplexer, WriteError> {
+    if !self.event_handler.is_open(token) {
+      return Err(if self.event_handler.was_issued(token) {
+        WriteError::TokenClosed
+      } else {
+        WriteError::UnknownToken
+      });
+    }
+
+    let mux = match self
+      .connections
+      .iter_mut()
+      .filter(|c| !c.unregistered())
+      .find(|c| c.token().map(|t| t.0) == Some(token))
+    {
+      Some(c) if !c.state().accepts_data() => return Err(WriteError::TokenClosed),
+      Some(c) => c.multiplexer(),
+      None => self.token_streams.get_mut(&token),
+    };
+    mux.ok_or(WriteError::StreamsDisabled)
+  }
+
+  /// Turns what was read from multiplexed tokens into stream events and the
+  /// messages on the default stream. Peers that send too much at once are
+  /// disconnected.
+  fn read_streams(&mut self, data: Vec<(usize, Vec<u8>)>) -> Vec<(usize, Vec<u8>)> {
+    let mut received = Vec::new();
+    let mut dropped = Vec::new();
+    for (token, data) in data {
+      if dropped.contains(&token) {
+        continue;
+      }
+      if !self.demux.is_enabled(token) {
+        received.push((token, data));
+        continue;
+      }
+
+      match self.demux.read(token, &data, &mut self.events) {
+        Some(messages) => received.extend(messages.into_iter().map(|message| (token, message))),
+        None => {
+          dropped.push(token);
+          if let Some(c) = self
+            .connections
+            .iter_mut()
+            .filter(|c| !c.unregistered())
+            .find(|c| c.token().map(|t| t.0) == Some(token))
+          {
+            c.deregister(self.event_handler.poll.registry());
+            self.events.push(NetworkEvent::Disconnected(
+              token,
+              DisconnectReason::Error(ErrorKind::InvalidData),
+            ));
+          }
+        }
+      }
+    }
+    received
+  }
+
+  /// Sets the codec used by `send` and `receive`, the peer has to use the same.
+  #[cfg(feature = "messages")]
+  pub fn set_message_codec(&mut self, codec: Codec) {
+    self.message_codec = codec;
+  }
+
+  /// Serializes the message and writes it to the token as one frame.
+  #[cfg(feature = "messages")]
+  pub fn send<T: serde::Serialize>(
+    &mut self,
+    token: usize,
+    message: &T,
+  ) -> Result<(), MessageError> {
+    let encoded = self.message_codec.encode(message)?;
+    self.write_data(token, &message::frame(&encoded))?;
+    Ok(())
+  }
+
+  /// Writes a message with its id in front, in the format a
+  /// `MessageRegistry` dispatches. Unreliable messages that don't fit in the
+  /// write queue are dropped.
+  #[cfg(feature = "messages")]
+  pub fn send_message<T: NetMessage>(
+    &mut self,
+    token: usize,
+    message: &T,
+  ) -> Result<(), MessageError> {
+    let mut body = T::ID.to_le_bytes().to_vec();
+    body.extend(self.message_codec.encode(message)?);
+    match self.write_data(token, &message::frame(&body)) {
+      Err(WriteError::QueueFull { .. }) if T::CHANNEL == Channel::Unreliable => Ok(()),
+      result => Ok(result?),
+    }
+  }
+
+  /// Decodes the messages in data returned by `poll`. Frames split across
+  /// reads are kept until the rest arrives, so pass in everything read from
+  /// tokens that carry messages.
+  #[cfg(feature = "messages")]
+  pub fn receive<T: serde::de::DeserializeOwned>(
+    &mut self,
+    data: &[(usize, Vec<u8>)],
+  ) -> Vec<Message<T>> {
+    let codec = self.message_codec;
+    self.split_messages(data, |frame| codec.decode(frame))
+  }
+
+  /// Bit packs the message and writes it to the token as one frame.
+  #[cfg(feature = "messages")]
+  pub fn send_packed<T: BitPack>(&mut self, token: usize, message: &T) -> Result<(), MessageError> {
+    self.write_data(token, &message::frame(&bits::pack(message)))?;
+    Ok(())
+  }
+
+  /// `receive` for messages sent with `send_packed`.
+  #[cfg(feature = "messages")]
+  pub fn receive_packed<T: BitPack>(&mut self, data: &[(usize, Vec<u8>)]) -> Vec<Message<T>> {
+    self.split_messages(data, |frame| Ok(bits::unpack(frame)?))
+  }
+
+  #[cfg(feature = "messages")]
+  fn split_messages<T>(
+    &mut self,
+    data: &[(usize, Vec<u8>)],
+    decode: impl Fn(&[u8]) -> Result<T, MessageError>,
+  ) -> Vec<Message<T>> {
+    data
+      .iter()
+      .flat_map(|(token, data)| {
+        self
+          .message_framer
+          .split(*token, data)
+          .into_iter()
+          .map(|frame| match frame.and_then(|f| decode(&f)) {
+            Ok(message) => Message::Received(*token, message),
+            Err(e) => Message::Invalid(*token, e),
+          })
+          .collect::<Vec<Message<T>>>()
+      })
+      .collect()
+  }
+
+  /// Frames everything on the token so requests and responses can be sent
+  /// alongside data from `write_data`, which `poll` still returns as data.
+  /// The peer has to enable it for its side of the connection too.
+  #[cfg(feature = "messages")]
+  pub fn enable_rpc(&mut self, token: usize) {
+    self.rpc.enable(token);
+  }
+
+  /// Sends a request to the token and returns its id. The answer arrives as a
+  /// `Response` event with the id, or a `RequestTimedOut` once `timeout`
+  /// passes. Udp doesn't go through a reliable channel: the request is sent
+  /// again until answered, so it arrives at least once. The peer only
+  /// recognises resends of its last `rpc::RPC_HISTORY` requests, an older one is
+  /// raised again as a new request.
+  /// `RpcDisabled` unless rpc is enabled on the token.
+  #[cfg(feature = "messages")]
+  pub fn request(
+    &mut self,
+    token: usize,
+    payload: &[u8],
+    timeout: Duration,
+  ) -> Result<u64, WriteError> {
+    if !self.rpc.is_enabled(token) {
+      return Err(if self.event_handler.is_open(token) {
+        WriteError::RpcDisabled
+      } else if self.event_handler.was_issued(token) {
+        WriteError::TokenClosed
+      } else {
+        WriteError::UnknownToken
+      });
+    }
+    let (id, frame) = self.rpc.request(token, payload, timeout, Instant::now());
+    if let Err(e) = self.queue_data(token, &frame, Priority::Normal) {
+      self.rpc.cancel(id);
+      return Err(e);
+    }
+    Ok(id)
+  }
+
+  /// Answers the request with the id from a `Request` event.
+  #[cfg(feature = "messages")]
+  pub fn respond(&mut self, token: usize, id: u64, payload: &[u8]) -> Result<(), WriteError> {
+    let frame = self.rpc.respond(token, id, payload);
+    self.queue_data(token, &frame, Priority::Normal)
+  }
+
+  /// Turns what was read from tokens with rpc enabled into events and the
+  /// data that was framed around them.
+  #[cfg(feature = "messages")]
+  fn read_rpc(&mut self, data: Vec<(usize, Vec<u8>)>) -> Vec<(usize, Vec<u8>)> {
+    let mut received = Vec::new();
+    for (token, data) in data {
+      if !self.rpc.is_enabled(token) {
+        received.push((token, data));
+        continue;
+      }
+
+      let (frames, replies) = self.rpc.read(token, &data, &mut self.events);
+      for reply in replies {
+        // A lost reply is asked for again by the next resend.
+        let _ = self.queue_data(token, &reply, Priority::Normal);
+      }
+      received.extend(frames.into_iter().map(|frame| (token, frame)));
+    }
+    received
+  }
+
+  /// Times out requests and sends those on udp again.
+  #[cfg(feature = "messages")]
+  fn resend_requests(&mut self, now: Instant) {
+    let connections = &self.connections;
+    let resends = self.rpc.expire(
+      now,
+      |token| {
+        connections
+          .iter()
+          .filter(|c| !c.unregistered())
+          .filter(|c| c.token().map(|t| t.0) == Some(token))
+          .any(|c| c.stream.is_datagram() || c.stream.is_connected_udp())
+      },
+      &mut self.events,
+    );
+    for (token, frame) in resends {
+      let _ = self.queue_data(token, &frame, Priority::Normal);
+    }
+  }
+
+  /// Encrypts all traffic on a connected udp socket, the peer has to do the
+  /// same with the same key. Data written before the key exchange finishes is
+  /// queued, and packets that aren't from the peer's session are dropped.
+  #[cfg(feature = "encryption")]
+  pub fn encrypt_udp(&mut self, token: usize, key: SessionKey) -> Result<(), EncryptionError> {
+    if let Some(c) = self
+      .connections
+      .iter_mut()
+      .filter(|c| !c.unregistered())
+      .find(|c| c.token().map(|t| t.0) == Some(token))
+    {
+      if !c.stream.is_connected_udp() {
+        return Err(EncryptionError::NotConnectedUdp);
+      }
+      c.enable_encryption(&key);
+      return Ok(());
+    }
+
+    match self.new_connections.iter().find(|c| c.token == token) {
+      Some(c) if c.connection.is_connected_udp() => {
+        self.encrypted_tokens.insert(token, key);
+        Ok(())
+      }
+      // Still being resolved or created, or not udp at all.
+      _ if self.event_handler.is_open(token) => Err(EncryptionError::NotConnectedUdp),
+      _ if self.event_handler.was_issued(token) => Err(EncryptionError::TokenClosed),
+      _ => Err(EncryptionError::UnknownToken),
+    }
+  }
+
+  /// True once the token's key exchange has finished.
+  #[cfg(feature = "encryption")]
+  pub fn is_encrypted(&self, token: usize) -> bool {
+    self
+      .connections
+      .iter()
+      .filter(|c| !c.unregistered())
+      .any(|c| c.token().map(|t| t.0) == Some(token) && c.is_encrypted())
+  }
+
+  /// Packets dropped on an encrypted token for being malformed, forged or
+  /// replayed.
+  #[cfg(feature = "encryption")]
+  pub fn rejected_packets(&self, token: usize) -> usize {
+    self
+      .connections
+      .iter()
+      .filter(|c| c.token().map(|t| t.0) == Some(token))
+      .map(|c| c.rejected_packets())
+      .sum()
+  }
+
+  /// Connections accepted on `listener` are only handed out by `poll` once
+  /// they have sent a connect token sealed with `key`. Peers that send an
+  /// invalid, expired or incomplete token are dropped with a
+  /// `ConnectTokenRejected` event. Clients send theirs with
+  /// `connect_to_tcp_with_token`.
+  #[cfg(feature = "connect-tokens")]
+  pub fn require_connect_tokens(&mut self, listener: usize, key: ConnectTokenKey) {
+    self.token_keys.insert(listener, key);
+  }
+
+  /// Moves connections accepted on listeners that require a connect token
+  /// into admission, registered so they are only read once the token
+  /// arrives. Returns the rest.
+  #[cfg(feature = "connect-tokens")]
+  fn hold_for_connect_tokens(
+    &mut self,
+    accepted: Vec<(usize, ConnectionType, String)>,
+  ) -> Vec<(usize, ConnectionType, String)> {
+    accepted
+      .into_iter()
+      .filter_map(|(listener, c, addr)| match self.token_keys.get(&listener) {
+        Some(key) => {
+          let token = self.event_handler.next_token();
+          let mut admission = Admission::new(token, c, addr, *key);
+          admission.connection.register(
+            self.event_handler.poll.registry(),
+            Token(token),
+            Interest::READABLE,
+          );
+          self.admitting.push(admission);
+          None
+        }
+        None => Some((listener, c, addr)),
+      })
+      .collect()
+  }
+
+  /// Reads the tokens of connections waiting on admission that have
+  /// something to read, returning those that were accepted.
+  #[cfg(feature = "connect-tokens")]
+  fn admit_connections(&mut self, now: Instant) -> Vec<NewConnection> {
+    let wall_clock = SystemTime::now();
+    let timeout = self.connect_timeout;
+    let readable = self
+      .event_handler
+      .events
+      .iter()
+      .filter(|e| e.is_readable())
+      .map(|e| e.token().0)
+      .collect::<Vec<usize>>();
+
+    let mut admitted = Vec::new();
+    for mut admission in std::mem::take(&mut self.admitting) {
+      let ready = readable.contains(&admission.token)
+        || admission
+          .connection
+          .readiness()
+          .is_some_and(|(readable, _)| readable);
+      let result = ready
+        .then(|| admission.read_token(wall_clock, &mut self.used_tokens))
+        .flatten()
+        .or_else(|| {
+          admission
+            .timed_out(now, timeout)
+            .then_some(Err(TokenError::TimedOut))
+        });
+
+      match result {
+        None => self.admitting.push(admission),
+        Some(Ok(token)) => {
+          admission
+            .connection
+            .stop_watching(self.event_handler.poll.registry());
+          let mut connection = NewConnection::new(
+            admission.token,
+            admission.connection,
+            &admission.addr,
+            Some(Box::new(print_data)),
+          );
+          connection.connect_token = Some(token);
+          admitted.push(connection);
+        }
+        Some(Err(e)) => {
+          admission
+            .connection
+            .deregister(self.event_handler.poll.registry());
+          self.close_token(admission.token);
+          self
+            .events
+            .push(NetworkEvent::ConnectTokenRejected(admission.addr, e));
+        }
+      }
+    }
+    admitted
+  }
+
+  fn close_token(&mut self, token: usize) {
+    self.event_handler.close_token(token);
+    #[cfg(feature = "encryption")]
+    self.encrypted_tokens.remove(&token);
+    self.resolving.retain(|(t, _, _)| *t != token);
+    self.connect_fallbacks.remove(&token);
+    self.token_write_limits.remove(&token);
+    self.token_checksums.remove(&token);
+    self.token_link_conditions.remove(&token);
+    self.token_streams.remove(&token);
+    self.demux.remove(token);
+    #[cfg(feature = "compression")]
+    self.token_compression.remove(&token);
+    #[cfg(feature = "messages")]
+    self.message_framer.remove(token);
+    #[cfg(feature = "messages")]
+    self.rpc.remove(token);
+    #[cfg(feature = "connect-tokens")]
+    self.token_keys.remove(&token);
+    #[cfg(feature = "connect-tokens")]
+    self.outgoing_tokens.remove(&token);
+    self.pending_data.retain(|(t, _, _, _)| *t != token);
+  }
+
+  pub fn write_data(&mut self, token: usize, data: &[u8]) -> Result<(), WriteError> {
+    self.write_data_with_priority(token, data, Priority::Normal)
+  }
+
+  /// Like `write_data`, but written ahead of data with a lower priority that
+  /// is still queued. Data that has waited long enough goes first whatever
+  /// its priority, and a write that has started is always finished, so on
+  /// stream connections this reorders whole writes. Ignored on tokens with
+  /// streams enabled.
+  pub fn write_data_with_priority(
+    &mut self,
+    token: usize,
+    data: &[u8],
+    priority: Priority,
+  ) -> Result<(), WriteError> {
+    #[cfg(feature = "messages")]
+    if self.rpc.is_enabled(token) {
+      return self.queue_data(token, &RpcFrame::Data(data.to_vec()).encode(), priority);
+    }
+
+    self.queue_data(token, data, priority)
+  }
+
+  /// Writes bytes to the token as they are, or queues them until it connects.
+  fn queue_data(
+    &mut self,
+    token: usize,
+    data: &[u8],
+    priority: Priority,
+  ) -> Result<(), WriteError> {
+    if let Some(c) = self
+      .connections
+      .iter_mut()
+      .filter(|c| !c.unregistered())
+      .find(|c| c.token().map(|t| t.0) == Some(token))
+    {
+      return c.data_to_write_with_priority(data, priority);
+    }
+
+    if !self.event_handler.is_open(token) {
+      return Err(if self.event_handler.was_issued(token) {
+        WriteError::TokenClosed
+      } else {
+        WriteError::UnknownToken
+      });
+    }
+
+    let limit = self.write_limits(token).max_queued_bytes;
+    let queued = self.pending_bytes(token);
+    if queued.saturating_add(data.len()) > limit {
+      return Err(WriteError::QueueFull { queued, limit });
+    }
+
+    self
+      .pending_data
+      .push((token, data.to_vec(), Instant::now(), priority));
+    Ok(())
+  }
+
+  /// Drains the events raised since the last call.
+  pub fn events(&mut self) -> Vec<NetworkEvent> {
+    self.events.drain(..).collect()
+  }
+
+  pub fn poll(&mut self) -> PollResult {
+    if let Err(e) = self
+      .event_handler
+      .poll
+      .poll(&mut self.event_handler.events, Some(Duration::ZERO))
+    {
+      println!("Error polling events: {}", e);
+    }
+
+    let mut recieved_data = Vec::new();
+
+    // Memory connections and some transports aren't watched by mio, so they
+    // are checked here.
+    let ready = self
+      .event_handler
+      .events
+      .iter()
+      .map(|e| (e.token(), e.is_readable(), e.is_writable()))
+      .chain(
+        self
+          .connections
+          .iter()
+          .filter(|c| !c.unregistered())
+          .filter_map(|c| {
+            c.stream
+              .readiness()
+              .map(|(readable, writable)| (c.token().unwrap(), readable, writable))
+          }),
+      )
+      .collect::<Vec<(Token, bool, bool)>>();
+
+    let new_connections = ready
+      .into_iter()
+      .flat_map(|(ready_token, readable, writable)| {
+        self
+          .connections
+          .iter_mut()
+          .filter(|c| !c.unregistered())
+          .filter(|c| c.token().unwrap() == ready_token)
+          .take(1)
+          .flat_map(|connection| {
+            let mut new_connections = Vec::new();
+            let token = connection.token().unwrap().0;
+            let was_connected = connection.can_send();
+
+            if connection.is_connecting() {
+              if let Err(err) = connection.finish_connecting() {
+                match next_address(&mut self.connect_fallbacks, token, self.ipv6_only) {
+                  Some(attempt) => {
+                    connection.retry_connect(self.event_handler.poll.registry(), attempt)
+                  }
+                  None => {
+                    connection.deregister(self.event_handler.poll.registry());
+                    self
+                      .events
+                      .push(NetworkEvent::ConnectFailed(token, err.kind()));
+                  }
+                }
+              }
+
+              if connection.unregistered() || connection.is_connecting() {
+                return Vec::new();
+              }
+            }
+
+            let mut should_close = None;
+
+            if let Err(err) = connection.stream.flush_pending() {
+              should_close = Some(DisconnectReason::Error(err.kind()));
+            }
+
+            if readable && should_close.is_none() {
+              let (data, close) = connection.read_data();
+              let new_con = &mut NetworkStream::is_readable(connection, &data.concat());
+              new_connections.append(new_con);
+              recieved_data.extend(
+                data
+                  .into_iter()
+                  .filter(|d| !d.is_empty())
+                  .map(|d| (token, d)),
+              );
+
+              should_close = close;
+            }
+
+            connection.finish_handshake();
+            let just_connected = !was_connected && connection.can_send();
+            if just_connected {
+              self.connect_fallbacks.remove(&token);
+              self.events.push(NetworkEvent::Connected(token));
+            }
+
+            if (writable || just_connected) && connection.can_send() && connection.data_pending() {
+              NetworkStream::is_writeable(connection);
+              if let Some(err) = connection.take_write_error() {
+                should_close.get_or_insert(DisconnectReason::Error(err.kind()));
+              }
+            }
+
+            if let Some(reason) = should_close {
+              let established = connection.can_send();
+              connection.deregister(self.event_handler.poll.registry());
+              self.events.push(match (established, reason) {
+                (_, DisconnectReason::VersionMismatch) | (true, _) => {
+                  NetworkEvent::Disconnected(token, reason)
+                }
+                (false, DisconnectReason::Error(kind)) => NetworkEvent::ConnectFailed(token, kind),
+                (false, _) => NetworkEvent::ConnectFailed(token, ErrorKind::ConnectionAborted),
+              });
+            }
+
+            new_connections
+              .into_iter()
+              .map(|(c, addr)| (token, c, addr))
+              .collect::<Vec<(usize, ConnectionType, String)>>()
+          })
+          .collect::<Vec<(usize, ConnectionType, String)>>()
+      })
+      .collect::<Vec<(usize, ConnectionType, String)>>();
+
+    let recieved_data = self.read_streams(recieved_data);
+    #[cfg(feature = "messages")]
+    let recieved_data = self.read_rpc(recieved_data);
+
+    let now = Instant::now();
+    for connection in self.connections.iter_mut().filter(|c| !c.unregistered()) {
+      let token = connection.token().unwrap().0;
+      if connection.connect_timed_out(now, self.connect_timeout) {
+        if connection.is_connecting() {
+          if let Some(attempt) = next_address(&mut self.connect_fallbacks, token, self.ipv6_only) {
+            connection.retry_connect(self.event_handler.poll.registry(), attempt);
+            continue;
+          }
+        }
+        connection.deregister(self.event_handler.poll.registry());
+        self
+          .events
+          .push(NetworkEvent::ConnectFailed(token, ErrorKind::TimedOut));
+        continue;
+      }
+      connection.send_hello(now);
+      #[cfg(feature = "encryption")]
+      {
+        if connection.encryption_timed_out(now, self.connect_timeout) {
+          connection.deregister(self.event_handler.poll.registry());
+          self.events.push(NetworkEvent::Disconnected(
+            token,
+            DisconnectReason::Error(ErrorKind::TimedOut),
+          ));
+          continue;
+        }
+        connection.resend_handshake(now);
+      }
+      if let Err(err) = connection.flush_writes() {
+        connection.deregister(self.event_handler.poll.registry());
+        self.events.push(NetworkEvent::Disconnected(
+          token,
+          DisconnectReason::Error(err.kind()),
+        ));
+        continue;
+      }
+      if connection.write_queue_drained() {
+        self.events.push(NetworkEvent::WriteQueueDrained(token));
+      }
+      if connection.finished_closing() {
+        connection.deregister(self.event_handler.poll.registry());
+        self
+          .events
+          .push(NetworkEvent::Disconnected(token, DisconnectReason::Closed));
+        continue;
+      }
+      if connection.write_queue_overflowed(now) {
+        connection.deregister(self.event_handler.poll.registry());
+        self.events.push(NetworkEvent::Disconnected(
+          token,
+          DisconnectReason::WriteQueueOverflow,
+        ));
+      }
+    }
+
+    #[cfg(feature = "messages")]
+    self.resend_requests(now);
+
+    let timeout = self.pending_data_timeout;
+    let events = &mut self.events;
+    self.pending_data.retain(|(t, d, queued_at, _)| {
+      if now.duration_since(*queued_at) < timeout {
+        true
+      } else {
+        events.push(NetworkEvent::PendingDataExpired(*t, d.len()));
+        false
+      }
+    });
+
+    #[cfg(feature = "connect-tokens")]
+    let new_connections = self.hold_for_connect_tokens(new_connections);
+
+    let new_connections = new_connections
+      .into_iter()
+      .map(|(_, c, addr)| {
+        NewConnection::new(
+          self.event_handler.next_token(),
+          c,
+          &addr,
+          Some(Box::new(print_data)),
+        )
+      })
+      .collect::<Vec<NewConnection>>();
+
+    #[cfg(feature = "connect-tokens")]
+    let new_connections = new_connections
+      .into_iter()
+      .chain(self.admit_connections(now))
+      .collect();
+
+    let removed_connections: Vec<Option<usize>> = self
+      .connections
+      .iter()
+      .filter(|x| x.unregistered())
+      .map(|x| x.token.map(|token| token.0))
+      .collect::<Vec<Option<usize>>>();
+
+    removed_connections
+      .iter()
+      .flatten()
+      .for_each(|t| self.close_token(*t));
+
+    self.connections = self
+      .connections
+      .drain(..)
+      .filter(|x| !x.unregistered())
+      .map(|mut x| {
+        if x.did_write() {
+          x.reregister(
+            &mut self.event_handler,
+            Interest::READABLE.add(Interest::WRITABLE),
+          );
+        }
+        x
+      })
+      .collect::<Vec<NetworkStream>>();
+
+    for (token, result) in self.resolver.finished() {
+      let index = match self.resolving.iter().position(|(t, _, _)| *t == token) {
+        Some(index) => index,
+        None => continue,
+      };
+      let (_, connection, read_func) = self.resolving.remove(index);
+
+      let addrs = result.and_then(|addrs| match addrs.split_first() {
+        Some((first, rest)) => Ok((*first, rest.iter().copied().collect::<VecDeque<_>>())),
+        None => Err(Error::new(ErrorKind::NotFound, "host has no addresses")),
+      });
+      match addrs {
+        Ok((addr, rest)) => {
+          if let (Some(copy), false) = (connection.unconnected_copy(), rest.is_empty()) {
+            self.connect_fallbacks.insert(token, (copy, rest));
+          }
+          self.new_connections.push(self.new_connection(
+            token,
+            connection,
+            &addr.to_string(),
+            read_func,
+          ));
+        }
+        Err(e) => {
+          self
+            .events
+            .push(NetworkEvent::ResolveFailed(token, e.kind()));
+          self.close_token(token);
+        }
+      }
+    }
+
+    if self.protocol_version.is_some() {
+      self
+        .new_connections
+        .iter_mut()
+        .filter(|c| c.error.is_none())
+        .filter(|c| !c.connection.exchanges_versions() && !c.connection.is_listener())
+        .for_each(|c| {
+          c.error = Some(Error::new(
+            ErrorKind::Unsupported,
+            "connection can't exchange protocol versions",
+          ))
+        });
+    }
+
+    let (failed_connections, mut new_streams): (Vec<NewConnection>, Vec<NewConnection>) = self
+      .new_connections
+      .drain(..)
+      .partition(|c| c.error.is_some());
+
+    failed_connections.into_iter().for_each(|c| {
+      match next_address(&mut self.connect_fallbacks, c.token, self.ipv6_only) {
+        Some(mut attempt) => {
+          attempt.read_func = c.read_func;
+          new_streams.push(attempt);
+        }
+        None => {
+          let kind = c.error.as_ref().unwrap().kind();
+          self.events.push(NetworkEvent::ConnectFailed(c.token, kind));
+          self.close_token(c.token);
+        }
+      }
+    });
+
+    self.connections.append(
+      &mut (new_streams
+        .into_iter()
+        .map(NetworkStream::from)
+        .map(|mut x| {
+          if x.unregistered() {
+            self.event_handler.open_token(x.token().unwrap().0);
+            #[cfg(feature = "connect-tokens")]
+            if let Some(sealed) = self.outgoing_tokens.remove(&x.token().unwrap().0) {
+              x.send_connect_token(&sealed);
+            }
+            if let Some((version, policy)) = self.protocol_version {
+              if x.stream.exchanges_versions() {
+                x.negotiate_version(version, policy);
+              }
+            }
+            x.register(
+              self.event_handler.poll.registry(),
+              x.token().unwrap(),
+              Interest::READABLE.add(Interest::WRITABLE),
+            );
+          }
+
+          x
+        })
+        .map(|mut x| {
+          let token = x.token().unwrap().0;
+          x.set_write_limits(
+            *self
+              .token_write_limits
+              .get(&token)
+              .unwrap_or(&self.write_limits),
+          );
+          if let Some(conditions) = self
+            .token_link_conditions
+            .get(&token)
+            .or(self.link_conditions.as_ref())
+          {
+            x.set_link_conditions(*conditions);
+          }
+          if let Some(protocol_id) = self.token_checksums.remove(&token) {
+            x.enable_checksums(protocol_id);
+          }
+          #[cfg(feature = "encryption")]
+          if let Some(key) = self.encrypted_tokens.remove(&token) {
+            x.enable_encryption(&key);
+          }
+          #[cfg(feature = "compression")]
+          if let Some(compression) = self.token_compression.get(&token) {
+            x.set_compression(*compression);
+          }
+          if let Some(mux) = self.token_streams.remove(&token) {
+            x.multiplex(mux);
+          }
+
+          // Pending data was checked against the same limits when it was queued.
+          self.pending_data = self
+            .pending_data
+            .drain(..)
+            .filter_map(|(t, d, queued_at, priority)| {
+              if t == token {
+                let _ = x.data_to_write_with_priority(&d, priority);
+                None
+              } else {
+                Some((t, d, queued_at, priority))
+              }
+            })
+            .collect::<Vec<(usize, Vec<u8>, Instant, Priority)>>();
+
+          x
+        })
+        .collect::<Vec<NetworkStream>>()),
+    );
+
+    (recieved_data, new_connections, removed_connections)
+  }
+}
+
+impl Default for MaatNetwork {
+  fn default() -> MaatNetwork {
+    MaatNetwork::new()
+  }
+}
+
+impl NetworkData {
+  pub fn new(token: usize, data: &[u8]) -> NetworkData {
+    NetworkData {
+      token,
+      data: data.to_vec(),
+    }
+  }
+
+  pub fn debug(&self) {
+    println!(
+      "Main: Token {}: \n    Recieved data: {:?}",
+      self.token, self.data
+    );
+  }
+}
+
+/// Creates a connection to the next address left to try for the token,
+/// skipping any that fail straight away. None once they have all been tried.
+fn next_address(
+  fallbacks: &mut HashMap<usize, (ConnectionType, VecDeque<SocketAddr>)>,
+  token: usize,
+  ipv6_only: bool,
+) -> Option<NewConnection> {
+  let (connection, addrs) = fallbacks.get_mut(&token)?;
+  while let Some(addr) = addrs.pop_front() {
+    let attempt = NewConnection::with_ipv6_only(
+      token,
+      connection.unconnected_copy().unwrap(),
+      &addr.to_string(),
+      None,
+      ipv6_only,
+    );
+    if attempt.error.is_none() {
+      return Some(attempt);
+    }
+  }
+  fallbacks.remove(&token);
+  None
+}
+
+/// True for connection types that are created with a non-blocking connect.
+fn connects_on_create(connection: &ConnectionType) -> bool {
+  match connection {
+    ConnectionType::NewTcpStream => true,
+    ConnectionType::NewMemoryStream => true,
+    #[cfg(unix)]
+    ConnectionType::NewUnixStream => true,
+    #[cfg(feature = "tls")]
+    ConnectionType::NewTlsStream(_, _) => true,
+    _ => false,
+  }
+}
+
+/// Formats a host and port as an address, with brackets around ipv6 literals.
+pub fn format_addr(host: &str, port: u16) -> String {
+  match host.parse::<Ipv6Addr>() {
+    Ok(ip) => SocketAddr::new(IpAddr::V6(ip), port).to_string(),
+    Err(_) => format!("{}:{}", host, port),
+  }
+}
+
+fn create_connection<S>(
+  connection_type: ConnectionType,
+  addr: S,
+  ipv6_only: bool,
+) -> Result<ConnectionType, Error>
+where
+  S: Into<String>,
+{
+  let parse_addr = |addr: String| {
+    addr
+      .parse::<SocketAddr>()
+      .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+  };
+
+  Ok(match connection_type {
+    ConnectionType::NewTcpListener => {
+      ConnectionType::from(bind_tcp_listener(parse_addr(addr.into())?, ipv6_only)?)
+    }
+    ConnectionType::NewTcpStream => {
+      ConnectionType::from(TcpStream::connect(parse_addr(addr.into())?)?)
+    }
+    ConnectionType::NewUdpSocket => {
+      ConnectionType::from(bind_udp_socket(parse_addr(addr.into())?, ipv6_only)?)
+    }
+    ConnectionType::NewMemoryListener => ConnectionType::from(MemoryListener::bind(&addr.into())?),
+    ConnectionType::NewMemoryStream => ConnectionType::from(MemoryStream::connect(&addr.into())?),
+    #[cfg(unix)]
+    ConnectionType::NewUnixListener => ConnectionType::from(UnixListener::bind(addr.into())?),
+    #[cfg(unix)]
+    ConnectionType::NewUnixStream => ConnectionType::from(UnixStream::connect(addr.into())?),
+    #[cfg(unix)]
+    ConnectionType::NewUnixDatagram => ConnectionType::from(UnixDatagram::bind(addr.into())?),
+    #[cfg(feature = "tls")]
+    ConnectionType::NewTlsListener(config) => ConnectionType::TlsListener(TlsListener::new(
+      bind_tcp_listener(parse_addr(addr.into())?, ipv6_only)?,
+      config,
+    )),
+    #[cfg(feature = "tls")]
+    ConnectionType::NewTlsStream(config, name) => {
+      let stream = TcpStream::connect(parse_addr(addr.into())?)?;
+      ConnectionType::TlsStream(Box::new(TlsStream::client(stream, config, name)?))
+    }
+    c => c,
+  })
+}
+
+fn bind_socket(addr: SocketAddr, ty: Type, ipv6_only: bool) -> Result<Socket, Error> {
+  let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+  if addr.is_ipv6() {
+    socket.set_only_v6(ipv6_only)?;
+  }
+  #[cfg(unix)]
+  if ty == Type::STREAM {
+    socket.set_reuse_address(true)?;
+  }
+  socket.set_nonblocking(true)?;
+  socket.bind(&addr.into())?;
+  Ok(socket)
+}
+
+fn bind_tcp_listener(addr: SocketAddr, ipv6_only: bool) -> Result<TcpListener, Error> {
+  let socket = bind_socket(addr, Type::STREAM, ipv6_only)?;
+  socket.listen(1024)?;
+  Ok(TcpListener::from_std(socket.into()))
+}
+
+fn bind_udp_socket(addr: SocketAddr, ipv6_only: bool) -> Result<UdpSocket, Error> {
+  let socket = bind_socket(addr, Type::DGRAM, ipv6_only)?;
+  Ok(UdpSocket::from_std(socket.into()))
+}
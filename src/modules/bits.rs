@@ -0,0 +1,432 @@
+/// Groups of 7 bits a varint may take before it is rejected.
+const MAX_VARINT_GROUPS: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitError {
+  /// Reading needed more bits than were left.
+  UnexpectedEnd,
+  /// A ranged value read back was over the range's maximum.
+  OutOfRange,
+  /// A varint ran over `MAX_VARINT_GROUPS` groups.
+  VarintTooLong,
+}
+
+/// Bits needed for every value from 0 up to and including `max`.
+pub fn bits_for(max: u64) -> u8 {
+  (64 - max.leading_zeros()) as u8
+}
+
+/// Distance from `min` to `value`, panics if the value isn't in the range.
+fn offset_in(value: i64, min: i64, max: i64) -> u64 {
+  assert!(min <= max, "bits: range {}..={} is empty", min, max);
+  assert!(
+    (min..=max).contains(&value),
+    "bits: {} is outside {}..={}",
+    value,
+    min,
+    max
+  );
+  value.wrapping_sub(min) as u64
+}
+
+/// Writes values least significant bit first, without padding between them.
+/// A measuring writer only counts the bits.
+#[derive(Default)]
+pub struct BitWriter {
+  bytes: Vec<u8>,
+  bits: usize,
+  measuring: bool,
+}
+
+impl BitWriter {
+  pub fn new() -> BitWriter {
+    BitWriter::default()
+  }
+
+  /// A writer that keeps nothing, for sizing a message before writing it.
+  pub fn measuring() -> BitWriter {
+    BitWriter {
+      measuring: true,
+      ..BitWriter::default()
+    }
+  }
+
+  pub fn bits_written(&self) -> usize {
+    self.bits
+  }
+
+  /// Bytes the written bits take up, counting a partial last byte.
+  pub fn bytes_written(&self) -> usize {
+    self.bits.div_ceil(8)
+  }
+
+  /// The written bytes, unused bits in the last byte are zero.
+  pub fn finish(self) -> Vec<u8> {
+    self.bytes
+  }
+
+  /// Writes the low `bits` bits of the value. Panics if the value doesn't
+  /// fit.
+  pub fn write_bits(&mut self, value: u64, bits: u8) {
+    assert!(bits <= 64, "BitWriter: can't write {} bits at once", bits);
+    assert!(
+      bits == 64 || value >> bits == 0,
+      "BitWriter: {} doesn't fit in {} bits",
+      value,
+      bits
+    );
+
+    let mut written = 0;
+    while written < bits {
+      let offset = (self.bits % 8) as u8;
+      let take = (8 - offset).min(bits - written);
+      if !self.measuring {
+        if offset == 0 {
+          self.bytes.push(0);
+        }
+        let chunk = ((value >> written) & ((1 << take) - 1)) as u8;
+        *self.bytes.last_mut().unwrap() |= chunk << offset;
+      }
+      self.bits += take as usize;
+      written += take;
+    }
+  }
+
+  pub fn write_bool(&mut self, value: bool) {
+    self.write_bits(value as u64, 1);
+  }
+
+  /// Writes the value in as few bits as the range needs. Panics if it is
+  /// outside the range.
+  pub fn write_ranged(&mut self, value: i64, min: i64, max: i64) {
+    let offset = offset_in(value, min, max);
+    self.write_bits(offset, bits_for(max.wrapping_sub(min) as u64));
+  }
+
+  /// Writes the value clamped to the range and rounded to one of
+  /// `2^bits` evenly spaced steps, `bits` being at most 32.
+  pub fn write_float(&mut self, value: f32, min: f32, max: f32, bits: u8) {
+    assert!(
+      (1..=32).contains(&bits),
+      "BitWriter: floats take 1 to 32 bits"
+    );
+    assert!(min < max, "BitWriter: range {}..{} is empty", min, max);
+
+    let steps = ((1u64 << bits) - 1) as f64;
+    let clamped = (value as f64).clamp(min as f64, max as f64);
+    let step = ((clamped - min as f64) / (max as f64 - min as f64) * steps).round();
+    self.write_bits(step as u64, bits);
+  }
+
+  /// Writes 7 bits at a time with a bit saying if more follow, so small
+  /// values stay small.
+  pub fn write_varint(&mut self, mut value: u64) {
+    loop {
+      let group = value & 0x7f;
+      value >>= 7;
+      self.write_bits(group, 7);
+      self.write_bool(value != 0);
+      if value == 0 {
+        break;
+      }
+    }
+  }
+
+  /// Zigzag encodes the value so small negative numbers stay small too.
+  pub fn write_signed_varint(&mut self, value: i64) {
+    self.write_varint(((value << 1) ^ (value >> 63)) as u64);
+  }
+
+  pub fn write_bytes(&mut self, bytes: &[u8]) {
+    bytes.iter().for_each(|b| self.write_bits(*b as u64, 8));
+  }
+}
+
+pub struct BitReader<'a> {
+  data: &'a [u8],
+  bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+  pub fn new(data: &'a [u8]) -> BitReader<'a> {
+    BitReader { data, bit: 0 }
+  }
+
+  pub fn bits_read(&self) -> usize {
+    self.bit
+  }
+
+  pub fn bits_remaining(&self) -> usize {
+    self.data.len() * 8 - self.bit
+  }
+
+  pub fn read_bits(&mut self, bits: u8) -> Result<u64, BitError> {
+    assert!(bits <= 64, "BitReader: can't read {} bits at once", bits);
+    if self.bits_remaining() < bits as usize {
+      return Err(BitError::UnexpectedEnd);
+    }
+
+    let mut value = 0;
+    let mut read = 0;
+    while read < bits {
+      let offset = (self.bit % 8) as u8;
+      let take = (8 - offset).min(bits - read);
+      let chunk = (self.data[self.bit / 8] >> offset) as u64 & ((1 << take) - 1);
+      value |= chunk << read;
+      self.bit += take as usize;
+      read += take;
+    }
+    Ok(value)
+  }
+
+  pub fn read_bool(&mut self) -> Result<bool, BitError> {
+    Ok(self.read_bits(1)? == 1)
+  }
+
+  pub fn read_ranged(&mut self, min: i64, max: i64) -> Result<i64, BitError> {
+    assert!(min <= max, "BitReader: range {}..={} is empty", min, max);
+    let range = max.wrapping_sub(min) as u64;
+    let offset = self.read_bits(bits_for(range))?;
+    if offset > range {
+      return Err(BitError::OutOfRange);
+    }
+    Ok(min.wrapping_add(offset as i64))
+  }
+
+  pub fn read_float(&mut self, min: f32, max: f32, bits: u8) -> Result<f32, BitError> {
+    assert!(
+      (1..=32).contains(&bits),
+      "BitReader: floats take 1 to 32 bits"
+    );
+    let steps = ((1u64 << bits) - 1) as f64;
+    let step = self.read_bits(bits)? as f64;
+    Ok((min as f64 + step / steps * (max as f64 - min as f64)) as f32)
+  }
+
+  pub fn read_varint(&mut self) -> Result<u64, BitError> {
+    let mut value = 0u64;
+    for group in 0..MAX_VARINT_GROUPS {
+      let bits = self.read_bits(7)?;
+      value |= bits.checked_shl(7 * group as u32).unwrap_or(0);
+      if group == MAX_VARINT_GROUPS - 1 && bits >> 1 != 0 {
+        return Err(BitError::VarintTooLong);
+      }
+      if !self.read_bool()? {
+        return Ok(value);
+      }
+    }
+    Err(BitError::VarintTooLong)
+  }
+
+  pub fn read_signed_varint(&mut self) -> Result<i64, BitError> {
+    let value = self.read_varint()?;
+    Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+  }
+
+  pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, BitError> {
+    if self.bits_remaining() < len * 8 {
+      return Err(BitError::UnexpectedEnd);
+    }
+    (0..len)
+      .map(|_| self.read_bits(8).map(|b| b as u8))
+      .collect()
+  }
+}
+
+/// A type that writes itself field by field with a `BitWriter`.
+pub trait BitPack: Sized {
+  fn pack(&self, writer: &mut BitWriter);
+  fn unpack(reader: &mut BitReader) -> Result<Self, BitError>;
+}
+
+/// The value packed into bytes.
+pub fn pack<T: BitPack>(value: &T) -> Vec<u8> {
+  let mut writer = BitWriter::new();
+  value.pack(&mut writer);
+  writer.finish()
+}
+
+pub fn unpack<T: BitPack>(data: &[u8]) -> Result<T, BitError> {
+  T::unpack(&mut BitReader::new(data))
+}
+
+/// Bits the value takes when packed.
+pub fn measure<T: BitPack>(value: &T) -> usize {
+  let mut writer = BitWriter::measuring();
+  value.pack(&mut writer);
+  writer.bits_written()
+}
+
+impl BitPack for bool {
+  fn pack(&self, writer: &mut BitWriter) {
+    writer.write_bool(*self);
+  }
+
+  fn unpack(reader: &mut BitReader) -> Result<bool, BitError> {
+    reader.read_bool()
+  }
+}
+
+macro_rules! pack_unsigned {
+  ($($t:ty),*) => {$(
+    impl BitPack for $t {
+      fn pack(&self, writer: &mut BitWriter) {
+        writer.write_bits(*self as u64, <$t>::BITS as u8);
+      }
+
+      fn unpack(reader: &mut BitReader) -> Result<$t, BitError> {
+        Ok(reader.read_bits(<$t>::BITS as u8)? as $t)
+      }
+    }
+  )*};
+}
+
+macro_rules! pack_signed {
+  ($($t:ty),*) => {$(
+    impl BitPack for $t {
+      fn pack(&self, writer: &mut BitWriter) {
+        writer.write_ranged(*self as i64, <$t>::MIN as i64, <$t>::MAX as i64);
+      }
+
+      fn unpack(reader: &mut BitReader) -> Result<$t, BitError> {
+        Ok(reader.read_ranged(<$t>::MIN as i64, <$t>::MAX as i64)? as $t)
+      }
+    }
+  )*};
+}
+
+pack_unsigned!(u8, u16, u32, u64);
+pack_signed!(i8, i16, i32, i64);
+
+impl BitPack for f32 {
+  fn pack(&self, writer: &mut BitWriter) {
+    writer.write_bits(self.to_bits() as u64, 32);
+  }
+
+  fn unpack(reader: &mut BitReader) -> Result<f32, BitError> {
+    Ok(f32::from_bits(reader.read_bits(32)? as u32))
+  }
+}
+
+impl BitPack for f64 {
+  fn pack(&self, writer: &mut BitWriter) {
+    writer.write_bits(self.to_bits(), 64);
+  }
+
+  fn unpack(reader: &mut BitReader) -> Result<f64, BitError> {
+    Ok(f64::from_bits(reader.read_bits(64)?))
+  }
+}
+
+macro_rules! pack_tuple {
+  ($(($($t:ident $i:tt),*)),*) => {$(
+    impl<$($t: BitPack),*> BitPack for ($($t,)*) {
+      fn pack(&self, writer: &mut BitWriter) {
+        $(self.$i.pack(writer);)*
+      }
+
+      fn unpack(reader: &mut BitReader) -> Result<($($t,)*), BitError> {
+        Ok(($($t::unpack(reader)?,)*))
+      }
+    }
+  )*};
+}
+
+pack_tuple!((A 0, B 1), (A 0, B 1, C 2), (A 0, B 1, C 2, D 3));
+
+/// Integers written in a fixed number of bits, how `#[net(bits = ...)]`
+/// fields are packed. Signed values are zigzag encoded so small negative
+/// ones fit as well.
+pub trait PackedInt: Sized {
+  fn to_packed(&self) -> u64;
+  /// `OutOfRange` if the value doesn't fit the type.
+  fn from_packed(value: u64) -> Result<Self, BitError>;
+}
+
+macro_rules! packed_unsigned {
+  ($($t:ty),*) => {$(
+    impl PackedInt for $t {
+      fn to_packed(&self) -> u64 {
+        *self as u64
+      }
+
+      fn from_packed(value: u64) -> Result<$t, BitError> {
+        <$t>::try_from(value).map_err(|_| BitError::OutOfRange)
+      }
+    }
+  )*};
+}
+
+macro_rules! packed_signed {
+  ($($t:ty),*) => {$(
+    impl PackedInt for $t {
+      fn to_packed(&self) -> u64 {
+        let value = *self as i64;
+        ((value << 1) ^ (value >> 63)) as u64
+      }
+
+      fn from_packed(value: u64) -> Result<$t, BitError> {
+        let value = (value >> 1) as i64 ^ -((value & 1) as i64);
+        <$t>::try_from(value).map_err(|_| BitError::OutOfRange)
+      }
+    }
+  )*};
+}
+
+packed_unsigned!(u8, u16, u32, u64);
+packed_signed!(i8, i16, i32, i64);
+
+/// Writes the value in `bits` bits. Panics if it doesn't fit.
+pub fn write_packed<T: PackedInt>(writer: &mut BitWriter, value: &T, bits: u8) {
+  writer.write_bits(value.to_packed(), bits);
+}
+
+pub fn read_packed<T: PackedInt>(reader: &mut BitReader, bits: u8) -> Result<T, BitError> {
+  T::from_packed(reader.read_bits(bits)?)
+}
+
+impl<T: BitPack> BitPack for Option<T> {
+  fn pack(&self, writer: &mut BitWriter) {
+    writer.write_bool(self.is_some());
+    if let Some(value) = self {
+      value.pack(writer);
+    }
+  }
+
+  fn unpack(reader: &mut BitReader) -> Result<Option<T>, BitError> {
+    if reader.read_bool()? {
+      Ok(Some(T::unpack(reader)?))
+    } else {
+      Ok(None)
+    }
+  }
+}
+
+impl<T: BitPack> BitPack for Vec<T> {
+  fn pack(&self, writer: &mut BitWriter) {
+    writer.write_varint(self.len() as u64);
+    self.iter().for_each(|item| item.pack(writer));
+  }
+
+  fn unpack(reader: &mut BitReader) -> Result<Vec<T>, BitError> {
+    let len = reader.read_varint()? as usize;
+    // Every item takes at least a bit, so a length past that is garbage.
+    if len > reader.bits_remaining() {
+      return Err(BitError::UnexpectedEnd);
+    }
+    (0..len).map(|_| T::unpack(reader)).collect()
+  }
+}
+
+impl BitPack for String {
+  fn pack(&self, writer: &mut BitWriter) {
+    writer.write_varint(self.len() as u64);
+    writer.write_bytes(self.as_bytes());
+  }
+
+  fn unpack(reader: &mut BitReader) -> Result<String, BitError> {
+    let len = reader.read_varint()? as usize;
+    let bytes = reader.read_bytes(len)?;
+    String::from_utf8(bytes).map_err(|_| BitError::OutOfRange)
+  }
+}
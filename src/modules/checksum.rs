@@ -0,0 +1,76 @@
+use crc32fast::Hasher;
+
+/// Protocol id followed by the crc32, both little endian.
+pub const CHECKSUM_HEADER_BYTES: usize = 8;
+
+/// Packet counts for a datagram socket with checksums enabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+  /// Packets that passed the check and were handed on.
+  pub packets_received: usize,
+  /// Packets dropped for carrying another protocol id, or none at all.
+  pub wrong_protocol: usize,
+  /// Packets dropped because the checksum didn't match their contents.
+  pub bad_checksum: usize,
+}
+
+impl ConnectionStats {
+  pub fn dropped_packets(&self) -> usize {
+    self.wrong_protocol + self.bad_checksum
+  }
+}
+
+/// Prefixes every packet with the protocol id and a crc32 of the id and
+/// payload, so stray and corrupted packets are dropped before they are read
+/// as data.
+pub struct PacketChecksum {
+  protocol_id: u32,
+  stats: ConnectionStats,
+}
+
+impl PacketChecksum {
+  pub fn new(protocol_id: u32) -> PacketChecksum {
+    PacketChecksum {
+      protocol_id,
+      stats: ConnectionStats::default(),
+    }
+  }
+
+  pub fn protocol_id(&self) -> u32 {
+    self.protocol_id
+  }
+
+  pub fn stats(&self) -> ConnectionStats {
+    self.stats
+  }
+
+  pub fn wrap(&self, payload: &[u8]) -> Vec<u8> {
+    let id = self.protocol_id.to_le_bytes();
+    [&id[..], &crc(&id, payload).to_le_bytes(), payload].concat()
+  }
+
+  /// Returns the payload of a packet that passed the check, `None` if it was
+  /// dropped.
+  pub fn check<'a>(&mut self, packet: &'a [u8]) -> Option<&'a [u8]> {
+    if packet.len() < CHECKSUM_HEADER_BYTES || packet[..4] != self.protocol_id.to_le_bytes() {
+      self.stats.wrong_protocol += 1;
+      return None;
+    }
+
+    let (header, payload) = packet.split_at(CHECKSUM_HEADER_BYTES);
+    if header[4..] != crc(&header[..4], payload).to_le_bytes() {
+      self.stats.bad_checksum += 1;
+      return None;
+    }
+
+    self.stats.packets_received += 1;
+    Some(payload)
+  }
+}
+
+fn crc(protocol_id: &[u8], payload: &[u8]) -> u32 {
+  let mut hasher = Hasher::new();
+  hasher.update(protocol_id);
+  hasher.update(payload);
+  hasher.finalize()
+}
@@ -0,0 +1,236 @@
+use std::io::{Error, ErrorKind};
+
+/// Messages shorter than this are sent as they are by default.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 128;
+/// Largest message a peer may send, compressed or not.
+pub const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+const RAW: u8 = 0;
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 2;
+/// Body length on stream connections, followed by the flag.
+const LENGTH_BYTES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+  #[cfg(feature = "lz4")]
+  Lz4,
+  /// Zstd with the given compression level.
+  #[cfg(feature = "zstd")]
+  Zstd(i32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+  pub algorithm: Algorithm,
+  /// Messages shorter than this aren't compressed.
+  pub threshold: usize,
+}
+
+impl Compression {
+  pub fn new(algorithm: Algorithm) -> Compression {
+    Compression {
+      algorithm,
+      threshold: DEFAULT_COMPRESSION_THRESHOLD,
+    }
+  }
+
+  #[cfg(feature = "lz4")]
+  pub fn lz4() -> Compression {
+    Compression::new(Algorithm::Lz4)
+  }
+
+  #[cfg(feature = "zstd")]
+  pub fn zstd(level: i32) -> Compression {
+    Compression::new(Algorithm::Zstd(level))
+  }
+
+  pub fn with_threshold(mut self, threshold: usize) -> Compression {
+    self.threshold = threshold;
+    self
+  }
+}
+
+/// Message counts and sizes for one direction of a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+  pub messages: usize,
+  pub compressed_messages: usize,
+  /// Message bytes before compression.
+  pub original_bytes: usize,
+  /// Message bytes on the wire, not counting framing.
+  pub wire_bytes: usize,
+}
+
+impl CompressionStats {
+  /// Wire bytes per original byte, below 1.0 when compression is paying off.
+  pub fn ratio(&self) -> f64 {
+    if self.original_bytes == 0 {
+      1.0
+    } else {
+      self.wire_bytes as f64 / self.original_bytes as f64
+    }
+  }
+
+  fn record(&mut self, original: usize, wire: usize, compressed: bool) {
+    self.messages += 1;
+    self.compressed_messages += compressed as usize;
+    self.original_bytes += original;
+    self.wire_bytes += wire;
+  }
+}
+
+/// Turns messages into frames and back. Every frame starts with a flag saying
+/// how its body was compressed, on stream connections the flag is preceded by
+/// the body length so messages can be split back out of the byte stream.
+pub struct MessageCodec {
+  compression: Compression,
+  framed: bool,
+  buffer: Vec<u8>,
+  sent: CompressionStats,
+  received: CompressionStats,
+}
+
+impl MessageCodec {
+  /// `framed` is true for stream connections, datagrams carry one message each.
+  pub fn new(compression: Compression, framed: bool) -> MessageCodec {
+    MessageCodec {
+      compression,
+      framed,
+      buffer: Vec::new(),
+      sent: CompressionStats::default(),
+      received: CompressionStats::default(),
+    }
+  }
+
+  pub fn set_compression(&mut self, compression: Compression) {
+    self.compression = compression;
+  }
+
+  pub fn is_framed(&self) -> bool {
+    self.framed
+  }
+
+  pub fn sent(&self) -> CompressionStats {
+    self.sent
+  }
+
+  pub fn received(&self) -> CompressionStats {
+    self.received
+  }
+
+  /// Compresses the message if it is over the threshold and gets smaller.
+  pub fn encode(&self, message: &[u8]) -> Vec<u8> {
+    let compressed = (message.len() >= self.compression.threshold)
+      .then(|| compress(self.compression.algorithm, message))
+      .filter(|(_, body)| body.len() < message.len());
+
+    let (flag, body) = match compressed {
+      Some((flag, ref body)) => (flag, body.as_slice()),
+      None => (RAW, message),
+    };
+
+    let mut frame = Vec::with_capacity(LENGTH_BYTES + 1 + body.len());
+    if self.framed {
+      frame.extend((body.len() as u32).to_le_bytes());
+    }
+    frame.push(flag);
+    frame.extend(body);
+    frame
+  }
+
+  /// Counts a frame from `encode` once it has been queued.
+  pub fn record_sent(&mut self, original: usize, frame: &[u8]) {
+    let header = if self.framed { LENGTH_BYTES + 1 } else { 1 };
+    let flag = frame[header - 1];
+    self
+      .sent
+      .record(original, frame.len() - header, flag != RAW);
+  }
+
+  /// Decodes the messages in `data`. On stream connections a partial frame is
+  /// kept until the rest arrives, otherwise `data` is a single datagram.
+  pub fn decode(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    if !self.framed {
+      return self.decode_body(data).map(|message| vec![message]);
+    }
+
+    self.buffer.extend(data);
+    let mut messages = Vec::new();
+    let mut start = 0;
+    while self.buffer.len() - start > LENGTH_BYTES {
+      let length =
+        u32::from_le_bytes(self.buffer[start..start + LENGTH_BYTES].try_into().unwrap()) as usize;
+      if length > MAX_MESSAGE_BYTES {
+        return Err(invalid("message is too large"));
+      }
+
+      let end = start + LENGTH_BYTES + 1 + length;
+      if self.buffer.len() < end {
+        break;
+      }
+      let body = self.buffer[start + LENGTH_BYTES..end].to_vec();
+      messages.push(self.decode_body(&body)?);
+      start = end;
+    }
+    self.buffer.drain(..start);
+
+    Ok(messages)
+  }
+
+  /// Decodes a flag followed by a body.
+  fn decode_body(&mut self, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let (flag, compressed) = body.split_first().ok_or_else(|| invalid("empty frame"))?;
+    let message = match *flag {
+      RAW => compressed.to_vec(),
+      #[cfg(feature = "lz4")]
+      LZ4 => {
+        let size = compressed
+          .get(..4)
+          .map(|s| u32::from_le_bytes(s.try_into().unwrap()) as usize)
+          .ok_or_else(|| invalid("lz4 body is too short"))?;
+        if size > MAX_MESSAGE_BYTES {
+          return Err(invalid("message is too large"));
+        }
+        lz4_flex::decompress_size_prepended(compressed).map_err(|e| invalid(&e.to_string()))?
+      }
+      #[cfg(feature = "zstd")]
+      ZSTD => {
+        let size = zstd::zstd_safe::get_frame_content_size(compressed)
+          .ok()
+          .flatten()
+          .ok_or_else(|| invalid("zstd body has no content size"))? as usize;
+        if size > MAX_MESSAGE_BYTES {
+          return Err(invalid("message is too large"));
+        }
+        zstd::bulk::decompress(compressed, size)?
+      }
+      _ => return Err(invalid("unknown compression flag")),
+    };
+
+    self
+      .received
+      .record(message.len(), compressed.len(), *flag != RAW);
+    Ok(message)
+  }
+}
+
+// Without lz4 or zstd there are no algorithms to match on.
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn compress(algorithm: Algorithm, message: &[u8]) -> (u8, Vec<u8>) {
+  match algorithm {
+    #[cfg(feature = "lz4")]
+    Algorithm::Lz4 => (LZ4, lz4_flex::compress_prepend_size(message)),
+    #[cfg(feature = "zstd")]
+    Algorithm::Zstd(level) => (
+      ZSTD,
+      zstd::bulk::compress(message, level).expect("MessageCodec: zstd compression failed"),
+    ),
+  }
+}
+
+fn invalid(reason: &str) -> Error {
+  Error::new(ErrorKind::InvalidData, reason.to_string())
+}
@@ -0,0 +1,305 @@
+use std::{
+  collections::HashMap,
+  io::ErrorKind,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use chacha20poly1305::{
+  aead::{Aead, KeyInit, Payload},
+  Key, XChaCha20Poly1305, XNonce,
+};
+use rand_core::{OsRng, RngCore};
+
+use crate::modules::ConnectionType;
+
+/// Every sealed token is exactly this long, clients send it as the first bytes
+/// on the stream.
+pub const CONNECT_TOKEN_BYTES: usize = 1024;
+pub const MAX_SERVER_ADDRESSES: usize = 32;
+pub const MAX_USER_DATA_BYTES: usize = 256;
+
+const VERSION: &[u8] = b"maat-network connect token 1";
+const NONCE_BYTES: usize = 24;
+const EXPIRY_BYTES: usize = 8;
+const TAG_BYTES: usize = 16;
+const PLAINTEXT_BYTES: usize = CONNECT_TOKEN_BYTES - NONCE_BYTES - EXPIRY_BYTES - TAG_BYTES;
+
+/// The private key shared between the auth service and the game servers.
+pub type ConnectTokenKey = [u8; 32];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenError {
+  /// The token has more server addresses or user data than fits.
+  TooLarge,
+  /// The peer closed or failed before sending a whole token.
+  Incomplete,
+  /// The peer didn't send a whole token within the connect timeout.
+  TimedOut,
+  Malformed,
+  /// The token wasn't sealed with the server's key or was altered.
+  InvalidSignature,
+  Expired,
+  /// The token doesn't list the address the peer connected to.
+  WrongServer,
+  /// The token was already used to connect.
+  Replayed,
+}
+
+/// Admission ticket handed out by an auth service and checked by the server
+/// before a connection is accepted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectToken {
+  pub client_id: u64,
+  /// Seconds since the unix epoch.
+  pub expires_at: u64,
+  pub server_addresses: Vec<SocketAddr>,
+  pub user_data: Vec<u8>,
+}
+
+impl ConnectToken {
+  pub fn new(
+    client_id: u64,
+    lifetime: Duration,
+    server_addresses: Vec<SocketAddr>,
+    user_data: Vec<u8>,
+  ) -> ConnectToken {
+    ConnectToken {
+      client_id,
+      expires_at: unix_secs(SystemTime::now()) + lifetime.as_secs(),
+      server_addresses,
+      user_data,
+    }
+  }
+
+  pub fn is_expired(&self, now: SystemTime) -> bool {
+    self.expires_at <= unix_secs(now)
+  }
+
+  /// True if the token lists `addr`, ipv4 mapped ipv6 addresses match their
+  /// ipv4 form.
+  pub fn allows(&self, addr: SocketAddr) -> bool {
+    let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    self.server_addresses.contains(&addr)
+  }
+
+  /// Encrypts and signs the token with `key`, the result is always
+  /// `CONNECT_TOKEN_BYTES` long.
+  pub fn seal(&self, key: &ConnectTokenKey) -> Result<Vec<u8>, TokenError> {
+    if self.server_addresses.len() > MAX_SERVER_ADDRESSES
+      || self.user_data.len() > MAX_USER_DATA_BYTES
+    {
+      return Err(TokenError::TooLarge);
+    }
+
+    let mut plaintext = Vec::with_capacity(PLAINTEXT_BYTES);
+    plaintext.extend(self.client_id.to_le_bytes());
+    plaintext.push(self.server_addresses.len() as u8);
+    for addr in &self.server_addresses {
+      match addr.ip() {
+        IpAddr::V4(ip) => {
+          plaintext.push(4);
+          plaintext.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+          plaintext.push(6);
+          plaintext.extend(ip.octets());
+        }
+      }
+      plaintext.extend(addr.port().to_le_bytes());
+    }
+    plaintext.extend((self.user_data.len() as u16).to_le_bytes());
+    plaintext.extend(&self.user_data);
+    plaintext.resize(PLAINTEXT_BYTES, 0);
+
+    let mut nonce = [0; NONCE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let expiry = self.expires_at.to_le_bytes();
+    let ciphertext = cipher(key)
+      .encrypt(
+        XNonce::from_slice(&nonce),
+        Payload {
+          msg: &plaintext,
+          aad: &aad(&expiry),
+        },
+      )
+      .expect("ConnectToken: encrypting token");
+
+    Ok([&nonce[..], &expiry, &ciphertext].concat())
+  }
+
+  /// Checks the signature and expiry of a sealed token and decodes it.
+  pub fn open(
+    key: &ConnectTokenKey,
+    sealed: &[u8],
+    now: SystemTime,
+  ) -> Result<ConnectToken, TokenError> {
+    if sealed.len() != CONNECT_TOKEN_BYTES {
+      return Err(TokenError::Malformed);
+    }
+
+    let (nonce, rest) = sealed.split_at(NONCE_BYTES);
+    let (expiry, ciphertext) = rest.split_at(EXPIRY_BYTES);
+    let plaintext = cipher(key)
+      .decrypt(
+        XNonce::from_slice(nonce),
+        Payload {
+          msg: ciphertext,
+          aad: &aad(expiry),
+        },
+      )
+      .map_err(|_| TokenError::InvalidSignature)?;
+
+    let mut reader = plaintext.as_slice();
+    let client_id = u64::from_le_bytes(take(&mut reader)?);
+    let [address_count] = take(&mut reader)?;
+    let server_addresses = (0..address_count)
+      .map(|_| {
+        let ip = match take(&mut reader)? {
+          [4] => IpAddr::V4(Ipv4Addr::from(take::<4>(&mut reader)?)),
+          [6] => IpAddr::V6(Ipv6Addr::from(take::<16>(&mut reader)?)),
+          _ => return Err(TokenError::Malformed),
+        };
+        Ok(SocketAddr::new(ip, u16::from_le_bytes(take(&mut reader)?)))
+      })
+      .collect::<Result<Vec<SocketAddr>, TokenError>>()?;
+    let user_data_len = u16::from_le_bytes(take(&mut reader)?) as usize;
+    if user_data_len > MAX_USER_DATA_BYTES || user_data_len > reader.len() {
+      return Err(TokenError::Malformed);
+    }
+
+    let token = ConnectToken {
+      client_id,
+      expires_at: u64::from_le_bytes(expiry.try_into().unwrap()),
+      server_addresses,
+      user_data: reader[..user_data_len].to_vec(),
+    };
+
+    if token.is_expired(now) {
+      return Err(TokenError::Expired);
+    }
+    Ok(token)
+  }
+}
+
+pub fn generate_key() -> ConnectTokenKey {
+  let mut key = [0; 32];
+  OsRng.fill_bytes(&mut key);
+  key
+}
+
+/// Nonces of the tokens that were accepted, kept until the tokens expire so
+/// each one only admits a single connection.
+#[derive(Default)]
+pub struct UsedTokens {
+  used: HashMap<[u8; NONCE_BYTES], u64>,
+}
+
+impl UsedTokens {
+  pub fn new() -> UsedTokens {
+    UsedTokens::default()
+  }
+
+  /// Records a sealed token that opened, false if it was used before.
+  pub fn insert(&mut self, sealed: &[u8], token: &ConnectToken, now: SystemTime) -> bool {
+    let now = unix_secs(now);
+    self.used.retain(|_, expires_at| *expires_at > now);
+    let nonce = sealed[..NONCE_BYTES].try_into().unwrap();
+    self.used.insert(nonce, token.expires_at).is_none()
+  }
+
+  pub fn len(&self) -> usize {
+    self.used.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.used.is_empty()
+  }
+}
+
+/// An accepted connection that hasn't sent its connect token yet. It is
+/// registered under the token it gets once admitted.
+pub struct Admission {
+  pub token: usize,
+  pub connection: ConnectionType,
+  pub addr: String,
+  key: ConnectTokenKey,
+  received: Vec<u8>,
+  started: Instant,
+}
+
+impl Admission {
+  pub fn new(
+    token: usize,
+    connection: ConnectionType,
+    addr: String,
+    key: ConnectTokenKey,
+  ) -> Admission {
+    Admission {
+      token,
+      connection,
+      addr,
+      key,
+      received: Vec::with_capacity(CONNECT_TOKEN_BYTES),
+      started: Instant::now(),
+    }
+  }
+
+  /// Reads as much of the token as has arrived, without reading past it.
+  /// Returns `None` while more of it is still to come.
+  pub fn read_token(
+    &mut self,
+    now: SystemTime,
+    used: &mut UsedTokens,
+  ) -> Option<Result<ConnectToken, TokenError>> {
+    let mut buf = [0; CONNECT_TOKEN_BYTES];
+    while self.received.len() < CONNECT_TOKEN_BYTES {
+      let wanted = CONNECT_TOKEN_BYTES - self.received.len();
+      match self.connection.read(&mut buf[..wanted]) {
+        Ok(0) => return Some(Err(TokenError::Incomplete)),
+        Ok(n) => self.received.extend(&buf[..n]),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return None,
+        Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+        Err(_) => return Some(Err(TokenError::Incomplete)),
+      }
+    }
+
+    // Unix sockets have no address to check against.
+    let local_addr = self.connection.local_addr().ok();
+    Some(
+      ConnectToken::open(&self.key, &self.received, now).and_then(|token| match local_addr {
+        Some(addr) if !token.allows(addr) => Err(TokenError::WrongServer),
+        _ if !used.insert(&self.received, &token, now) => Err(TokenError::Replayed),
+        _ => Ok(token),
+      }),
+    )
+  }
+
+  pub fn timed_out(&self, now: Instant, timeout: Duration) -> bool {
+    now.duration_since(self.started) >= timeout
+  }
+}
+
+fn cipher(key: &ConnectTokenKey) -> XChaCha20Poly1305 {
+  XChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+fn aad(expiry: &[u8]) -> Vec<u8> {
+  [VERSION, expiry].concat()
+}
+
+fn take<const N: usize>(reader: &mut &[u8]) -> Result<[u8; N], TokenError> {
+  if reader.len() < N {
+    return Err(TokenError::Malformed);
+  }
+  let (bytes, rest) = reader.split_at(N);
+  *reader = rest;
+  Ok(bytes.try_into().unwrap())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
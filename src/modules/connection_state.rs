@@ -0,0 +1,47 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+  /// The token has been issued but the connection is not registered yet.
+  Pending,
+  /// A non-blocking connect is in progress.
+  Connecting,
+  /// The transport is up and a handshake is running on top of it.
+  Handshaking,
+  Connected,
+  /// No more data is accepted, queued data is flushed before closing.
+  Closing,
+  Closed,
+}
+
+impl ConnectionState {
+  pub fn can_transition_to(self, next: ConnectionState) -> bool {
+    use ConnectionState::*;
+
+    matches!(
+      (self, next),
+      (Pending, Connecting)
+        | (Pending, Handshaking)
+        | (Pending, Connected)
+        | (Connecting, Handshaking)
+        | (Connecting, Connected)
+        | (Handshaking, Connected)
+        | (Handshaking, Closing)
+        | (Connected, Closing)
+        | (Closing, Closed)
+    ) || (self != Closed && next == Closed)
+  }
+
+  /// True if data written in this state will eventually be sent.
+  pub fn accepts_data(self) -> bool {
+    matches!(
+      self,
+      ConnectionState::Pending
+        | ConnectionState::Connecting
+        | ConnectionState::Handshaking
+        | ConnectionState::Connected
+    )
+  }
+
+  pub fn is_registered(self) -> bool {
+    !matches!(self, ConnectionState::Pending | ConnectionState::Closed)
+  }
+}
@@ -1,7 +1,9 @@
+#[cfg(feature = "tls")]
+use std::sync::Arc;
 use std::{
   convert::From,
   fmt,
-  io::{Error, ErrorKind, Read, Write},
+  io::{Error, ErrorKind},
   net::SocketAddr,
 };
 
@@ -10,9 +12,18 @@ use mio::{
   Interest, Token,
 };
 
+#[cfg(unix)]
+use mio::net::{UnixDatagram, UnixListener, UnixStream};
 use mio::Registry;
+#[cfg(feature = "tls")]
+use rustls::{pki_types::ServerName, ClientConfig, ServerConfig};
 
-use crate::modules::EventHandler;
+#[cfg(feature = "tls")]
+use crate::modules::tls::{TlsListener, TlsStream};
+use crate::modules::{
+  memory::{MemoryListener, MemoryStream},
+  EventHandler, Transport,
+};
 
 impl fmt::Debug for ConnectionType {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -26,6 +37,31 @@ impl fmt::Debug for ConnectionType {
           ConnectionType::TcpListener(_) => &"TcpListener",
           ConnectionType::TcpStream(_) => &"TcpStream",
           ConnectionType::UdpSocket(_) => &"UdpSocket",
+          ConnectionType::NewMemoryListener => &"NewMemoryListener",
+          ConnectionType::NewMemoryStream => &"NewMemoryStream",
+          ConnectionType::MemoryListener(_) => &"MemoryListener",
+          ConnectionType::MemoryStream(_) => &"MemoryStream",
+          ConnectionType::Custom(_) => &"Custom",
+          #[cfg(unix)]
+          ConnectionType::NewUnixListener => &"NewUnixListener",
+          #[cfg(unix)]
+          ConnectionType::NewUnixStream => &"NewUnixStream",
+          #[cfg(unix)]
+          ConnectionType::NewUnixDatagram => &"NewUnixDatagram",
+          #[cfg(unix)]
+          ConnectionType::UnixListener(_) => &"UnixListener",
+          #[cfg(unix)]
+          ConnectionType::UnixStream(_) => &"UnixStream",
+          #[cfg(unix)]
+          ConnectionType::UnixDatagram(_) => &"UnixDatagram",
+          #[cfg(feature = "tls")]
+          ConnectionType::NewTlsListener(_) => &"NewTlsListener",
+          #[cfg(feature = "tls")]
+          ConnectionType::NewTlsStream(_, _) => &"NewTlsStream",
+          #[cfg(feature = "tls")]
+          ConnectionType::TlsListener(_) => &"TlsListener",
+          #[cfg(feature = "tls")]
+          ConnectionType::TlsStream(_) => &"TlsStream",
         },
       )
       .finish()
@@ -39,6 +75,33 @@ pub enum ConnectionType {
   TcpListener(TcpListener),
   TcpStream(TcpStream),
   UdpSocket(UdpSocket),
+  NewMemoryListener,
+  NewMemoryStream,
+  /// Accepts in-process connections, polled by the network rather than mio.
+  MemoryListener(MemoryListener),
+  MemoryStream(MemoryStream),
+  /// Any other way of moving bytes, see `Transport`.
+  Custom(Box<dyn Transport>),
+  #[cfg(unix)]
+  NewUnixListener,
+  #[cfg(unix)]
+  NewUnixStream,
+  #[cfg(unix)]
+  NewUnixDatagram,
+  #[cfg(unix)]
+  UnixListener(UnixListener),
+  #[cfg(unix)]
+  UnixStream(UnixStream),
+  #[cfg(unix)]
+  UnixDatagram(UnixDatagram),
+  #[cfg(feature = "tls")]
+  NewTlsListener(Arc<ServerConfig>),
+  #[cfg(feature = "tls")]
+  NewTlsStream(Arc<ClientConfig>, ServerName<'static>),
+  #[cfg(feature = "tls")]
+  TlsListener(TlsListener),
+  #[cfg(feature = "tls")]
+  TlsStream(Box<TlsStream>),
 }
 
 impl ConnectionType {
@@ -66,110 +129,313 @@ impl ConnectionType {
     ConnectionType::UdpSocket(stream)
   }
 
+  pub fn memory_listener() -> ConnectionType {
+    ConnectionType::NewMemoryListener
+  }
+
+  pub fn memory_stream() -> ConnectionType {
+    ConnectionType::NewMemoryStream
+  }
+
+  pub fn add_existing_memory_stream(stream: MemoryStream) -> ConnectionType {
+    ConnectionType::MemoryStream(stream)
+  }
+
+  pub fn custom<T: Transport + 'static>(transport: T) -> ConnectionType {
+    ConnectionType::Custom(Box::new(transport))
+  }
+
+  #[cfg(unix)]
+  pub fn unix_listener() -> ConnectionType {
+    ConnectionType::NewUnixListener
+  }
+
+  #[cfg(unix)]
+  pub fn unix_stream() -> ConnectionType {
+    ConnectionType::NewUnixStream
+  }
+
+  #[cfg(unix)]
+  pub fn unix_datagram() -> ConnectionType {
+    ConnectionType::NewUnixDatagram
+  }
+
+  #[cfg(unix)]
+  pub fn add_existing_unix_stream(stream: UnixStream) -> ConnectionType {
+    ConnectionType::UnixStream(stream)
+  }
+
+  #[cfg(unix)]
+  pub fn add_existing_unix_listener(stream: UnixListener) -> ConnectionType {
+    ConnectionType::UnixListener(stream)
+  }
+
+  #[cfg(unix)]
+  pub fn add_existing_unix_datagram(stream: UnixDatagram) -> ConnectionType {
+    ConnectionType::UnixDatagram(stream)
+  }
+
+  /// Another connection of the same kind that hasn't been created yet, for
+  /// trying a different address. None for anything else.
+  pub fn unconnected_copy(&self) -> Option<ConnectionType> {
+    match self {
+      ConnectionType::NewTcpStream => Some(ConnectionType::NewTcpStream),
+      ConnectionType::NewUdpSocket => Some(ConnectionType::NewUdpSocket),
+      #[cfg(feature = "tls")]
+      ConnectionType::NewTlsStream(config, name) => {
+        Some(ConnectionType::NewTlsStream(config.clone(), name.clone()))
+      }
+      _ => None,
+    }
+  }
+
   pub fn is_type(&self, connection_type: ConnectionType) -> bool {
-    match (self, connection_type) {
-      (ConnectionType::TcpListener(_), ConnectionType::TcpListener(_))
-      | (ConnectionType::TcpListener(_), ConnectionType::NewTcpListener)
-      | (ConnectionType::TcpStream(_), ConnectionType::TcpStream(_))
-      | (ConnectionType::TcpStream(_), ConnectionType::NewTcpStream)
-      | (ConnectionType::UdpSocket(_), ConnectionType::UdpSocket(_))
-      | (ConnectionType::UdpSocket(_), ConnectionType::NewUdpSocket) => true,
-      _ => false,
+    matches!(
+      (self, &connection_type),
+      (
+        ConnectionType::TcpListener(_),
+        ConnectionType::TcpListener(_)
+      ) | (
+        ConnectionType::TcpListener(_),
+        ConnectionType::NewTcpListener
+      ) | (ConnectionType::TcpStream(_), ConnectionType::TcpStream(_))
+        | (ConnectionType::TcpStream(_), ConnectionType::NewTcpStream)
+        | (ConnectionType::UdpSocket(_), ConnectionType::UdpSocket(_))
+        | (ConnectionType::UdpSocket(_), ConnectionType::NewUdpSocket)
+        | (
+          ConnectionType::MemoryListener(_),
+          ConnectionType::MemoryListener(_)
+        )
+        | (
+          ConnectionType::MemoryListener(_),
+          ConnectionType::NewMemoryListener
+        )
+        | (
+          ConnectionType::MemoryStream(_),
+          ConnectionType::MemoryStream(_)
+        )
+        | (
+          ConnectionType::MemoryStream(_),
+          ConnectionType::NewMemoryStream
+        )
+        | (ConnectionType::Custom(_), ConnectionType::Custom(_))
+    ) || self.is_unix_type(&connection_type)
+  }
+
+  #[cfg(unix)]
+  fn is_unix_type(&self, connection_type: &ConnectionType) -> bool {
+    matches!(
+      (self, connection_type),
+      (
+        ConnectionType::UnixListener(_),
+        ConnectionType::UnixListener(_)
+      ) | (
+        ConnectionType::UnixListener(_),
+        ConnectionType::NewUnixListener
+      ) | (ConnectionType::UnixStream(_), ConnectionType::UnixStream(_))
+        | (ConnectionType::UnixStream(_), ConnectionType::NewUnixStream)
+        | (
+          ConnectionType::UnixDatagram(_),
+          ConnectionType::UnixDatagram(_)
+        )
+        | (
+          ConnectionType::UnixDatagram(_),
+          ConnectionType::NewUnixDatagram
+        )
+    )
+  }
+
+  #[cfg(not(unix))]
+  fn is_unix_type(&self, _connection_type: &ConnectionType) -> bool {
+    false
+  }
+
+  /// The connection as a `Transport`, `None` for connections that haven't
+  /// been created yet.
+  fn transport(&self) -> Option<&dyn Transport> {
+    match self {
+      ConnectionType::TcpListener(stream) => Some(stream),
+      ConnectionType::TcpStream(stream) => Some(stream),
+      ConnectionType::UdpSocket(stream) => Some(stream),
+      ConnectionType::MemoryListener(listener) => Some(listener),
+      ConnectionType::MemoryStream(stream) => Some(stream),
+      ConnectionType::Custom(transport) => Some(transport.as_ref()),
+      #[cfg(unix)]
+      ConnectionType::UnixListener(stream) => Some(stream),
+      #[cfg(unix)]
+      ConnectionType::UnixStream(stream) => Some(stream),
+      #[cfg(unix)]
+      ConnectionType::UnixDatagram(stream) => Some(stream),
+      #[cfg(feature = "tls")]
+      ConnectionType::TlsListener(listener) => Some(listener),
+      #[cfg(feature = "tls")]
+      ConnectionType::TlsStream(tls) => Some(tls.as_ref()),
+      _ => None,
     }
   }
 
-  pub fn register(&mut self, registry: &Registry, token: Token, interest: Interest) {
+  fn transport_mut(&mut self) -> Option<&mut dyn Transport> {
     match self {
-      ConnectionType::TcpStream(stream) => {
-        println!("Register");
-        if let Err(e) = registry.register(stream, token, interest) {
-          panic!("{}", e);
-        }
-      }
-      ConnectionType::TcpListener(stream) => {
-        if let Err(e) = registry.register(stream, token, interest) {
-          panic!("{}", e);
-        }
-      }
-      ConnectionType::UdpSocket(stream) => {
-        if let Err(e) = registry.register(stream, token, interest) {
-          panic!("{}", e);
-        }
-      }
-      _ => {
-        panic!("Attempting to register a connection that doesn't exist");
-      }
+      ConnectionType::TcpListener(stream) => Some(stream),
+      ConnectionType::TcpStream(stream) => Some(stream),
+      ConnectionType::UdpSocket(stream) => Some(stream),
+      ConnectionType::MemoryListener(listener) => Some(listener),
+      ConnectionType::MemoryStream(stream) => Some(stream),
+      ConnectionType::Custom(transport) => Some(transport.as_mut()),
+      #[cfg(unix)]
+      ConnectionType::UnixListener(stream) => Some(stream),
+      #[cfg(unix)]
+      ConnectionType::UnixStream(stream) => Some(stream),
+      #[cfg(unix)]
+      ConnectionType::UnixDatagram(stream) => Some(stream),
+      #[cfg(feature = "tls")]
+      ConnectionType::TlsListener(listener) => Some(listener),
+      #[cfg(feature = "tls")]
+      ConnectionType::TlsStream(tls) => Some(tls.as_mut()),
+      _ => None,
+    }
+  }
+
+  pub fn register(&mut self, registry: &Registry, token: Token, interest: Interest) {
+    let transport = self
+      .transport_mut()
+      .expect("Attempting to register a connection that doesn't exist");
+    if let Err(e) = transport.register(registry, token, interest) {
+      panic!("{}", e);
     }
   }
 
   pub fn reregister(&mut self, handler: &mut EventHandler, token: Token, interest: Interest) {
-    match self {
-      ConnectionType::TcpStream(stream) => {
-        if let Err(e) = handler.poll.registry().reregister(stream, token, interest) {
-          panic!("{}", e);
-        }
-      }
-      ConnectionType::TcpListener(stream) => {
-        if let Err(e) = handler.poll.registry().reregister(stream, token, interest) {
-          panic!("{}", e);
-        }
+    if let Some(transport) = self.transport_mut() {
+      if let Err(e) = transport.reregister(handler.poll.registry(), token, interest) {
+        panic!("{}", e);
       }
-      ConnectionType::UdpSocket(stream) => {
-        if let Err(e) = handler.poll.registry().reregister(stream, token, interest) {
-          panic!("{}", e);
-        }
-      }
-      _ => {}
     }
   }
 
   pub fn deregister(&mut self, registry: &Registry) {
-    match self {
-      ConnectionType::TcpStream(stream) => {
-        println!("Register");
-        if let Err(e) = registry.deregister(stream) {
-          panic!("{}", e);
-        }
-      }
-      ConnectionType::TcpListener(stream) => {
-        if let Err(e) = registry.deregister(stream) {
-          panic!("{}", e);
-        }
-      }
-      ConnectionType::UdpSocket(stream) => {
-        if let Err(e) = registry.deregister(stream) {
-          panic!("{}", e);
-        }
+    self.stop_watching(registry);
+    if let Some(transport) = self.transport_mut() {
+      // The connection is being dropped, there is nobody left to tell.
+      let _ = transport.close();
+    }
+  }
+
+  /// Deregisters the connection without closing it, so it can be registered
+  /// again.
+  pub fn stop_watching(&mut self, registry: &Registry) {
+    if let Some(transport) = self.transport_mut() {
+      if let Err(e) = transport.deregister(registry) {
+        panic!("{}", e);
       }
-      _ => {}
     }
   }
 
   pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-    match self {
-      ConnectionType::TcpStream(stream) => stream.read(buf),
-      ConnectionType::TcpListener(stream) => {
-        let error = Error::new(ErrorKind::WouldBlock, "tcp listener would block");
-        let error2 = Error::new(ErrorKind::WouldBlock, "tcp listener would block");
-        Err(stream.take_error().unwrap_or(Some(error)).unwrap_or(error2))
-      }
-      ConnectionType::UdpSocket(stream) => stream.recv(buf),
-      _ => Err(Error::new(ErrorKind::WouldBlock, "udp would block")),
+    match self.transport_mut() {
+      Some(transport) => transport.read(buf),
+      None => Err(Error::new(ErrorKind::WouldBlock, "not created yet")),
     }
   }
 
   pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    match self.transport_mut() {
+      Some(transport) => transport.write(buf),
+      None => Err(Error::new(ErrorKind::WouldBlock, "not created yet")),
+    }
+  }
+
+  pub fn take_error(&self) -> Result<Option<Error>, Error> {
+    self.transport().map_or(Ok(None), |t| t.take_error())
+  }
+
+  pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+    match self.transport() {
+      Some(transport) => transport.local_addr(),
+      None => Err(Error::new(ErrorKind::NotConnected, "")),
+    }
+  }
+
+  pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+    match self.transport() {
+      Some(transport) => transport.peer_addr(),
+      None => Err(Error::new(ErrorKind::NotConnected, "")),
+    }
+  }
+
+  /// True for connections that send and receive whole packets rather than a
+  /// byte stream.
+  pub fn is_datagram(&self) -> bool {
+    self.transport().is_some_and(|t| t.is_datagram())
+  }
+
+  /// Readable and writable for connections mio can't watch, such as memory
+  /// streams. `None` for everything else.
+  pub fn readiness(&self) -> Option<(bool, bool)> {
+    self.transport().and_then(|t| t.readiness())
+  }
+
+  /// True for udp sockets that were connected to a single peer.
+  pub fn is_connected_udp(&self) -> bool {
+    matches!(self, ConnectionType::UdpSocket(stream) if stream.peer_addr().is_ok())
+  }
+
+  /// True for connections that can exchange protocol versions: stream
+  /// transports and udp sockets connected to a single peer.
+  pub fn exchanges_versions(&self) -> bool {
+    match self {
+      ConnectionType::TcpStream(_) | ConnectionType::MemoryStream(_) => true,
+      #[cfg(unix)]
+      ConnectionType::UnixStream(_) => true,
+      #[cfg(feature = "tls")]
+      ConnectionType::TlsStream(_) => true,
+      _ => self.is_connected_udp(),
+    }
+  }
+
+  /// True for listeners, which only accept connections.
+  pub fn is_listener(&self) -> bool {
+    match self {
+      ConnectionType::TcpListener(_) | ConnectionType::MemoryListener(_) => true,
+      #[cfg(unix)]
+      ConnectionType::UnixListener(_) => true,
+      #[cfg(feature = "tls")]
+      ConnectionType::TlsListener(_) => true,
+      _ => false,
+    }
+  }
+
+  /// True while a tls session is still negotiating, always false for plain
+  /// connections.
+  pub fn is_handshaking(&self) -> bool {
     match self {
-      ConnectionType::TcpStream(stream) => stream.write(buf),
-      ConnectionType::UdpSocket(stream) => stream.send(buf), // TODO: Check its send and not send to
-      _ => Err(Error::new(ErrorKind::WouldBlock, "")),
+      #[cfg(feature = "tls")]
+      ConnectionType::TlsStream(tls) => tls.is_handshaking(),
+      _ => false,
     }
   }
 
-  pub fn accept(&self) -> Result<(TcpStream, SocketAddr), Error> {
+  /// Writes out anything the connection buffered internally, such as tls
+  /// handshake records.
+  pub fn flush_pending(&mut self) -> Result<(), Error> {
     match self {
-      ConnectionType::TcpListener(stream) => stream.accept(),
-      _ => Err(Error::new(ErrorKind::WouldBlock, "")),
+      #[cfg(feature = "tls")]
+      ConnectionType::TlsStream(tls) => tls.flush_tls(),
+      _ => Ok(()),
+    }
+  }
+
+  /// Ok(true) once a stream has a peer, Ok(false) while a non-blocking connect
+  /// is still in progress.
+  pub fn is_connected(&self) -> Result<bool, Error> {
+    self.transport().map_or(Ok(true), |t| t.is_connected())
+  }
+
+  /// Accepts a connection from a listener, returning it with the peer's address.
+  pub fn accept(&mut self) -> Result<(ConnectionType, String), Error> {
+    match self.transport_mut() {
+      Some(transport) => transport.accept(),
+      None => Err(Error::new(ErrorKind::WouldBlock, "not created yet")),
     }
   }
 }
@@ -191,3 +457,36 @@ impl From<UdpSocket> for ConnectionType {
     ConnectionType::add_existing_udp_stream(stream)
   }
 }
+
+impl From<MemoryStream> for ConnectionType {
+  fn from(stream: MemoryStream) -> Self {
+    ConnectionType::add_existing_memory_stream(stream)
+  }
+}
+
+impl From<MemoryListener> for ConnectionType {
+  fn from(listener: MemoryListener) -> Self {
+    ConnectionType::MemoryListener(listener)
+  }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for ConnectionType {
+  fn from(stream: UnixStream) -> Self {
+    ConnectionType::add_existing_unix_stream(stream)
+  }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for ConnectionType {
+  fn from(stream: UnixListener) -> Self {
+    ConnectionType::add_existing_unix_listener(stream)
+  }
+}
+
+#[cfg(unix)]
+impl From<UnixDatagram> for ConnectionType {
+  fn from(stream: UnixDatagram) -> Self {
+    ConnectionType::add_existing_unix_datagram(stream)
+  }
+}
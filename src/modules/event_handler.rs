@@ -1,14 +1,12 @@
-use mio::{
-  net::{TcpListener, TcpStream, UdpSocket},
-  {Events, Poll},
-};
+use std::collections::HashSet;
 
-use crate::modules::ConnectionType;
+use mio::{Events, Poll};
 
 pub struct EventHandler {
   pub poll: Poll,
   pub events: Events,
   pub next_token: usize,
+  open_tokens: HashSet<usize>,
 }
 
 impl EventHandler {
@@ -18,12 +16,39 @@ impl EventHandler {
       poll,
       events: Events::with_capacity(128),
       next_token: 0,
+      open_tokens: HashSet::new(),
     }
   }
 
   pub fn next_token(&mut self) -> usize {
     let token = self.next_token;
     self.next_token += 1;
-    return token;
+    self.open_tokens.insert(token);
+    token
+  }
+
+  /// Marks a token as in use, for connections created with a token that did not
+  /// come from `next_token`.
+  pub fn open_token(&mut self, token: usize) {
+    self.open_tokens.insert(token);
+  }
+
+  pub fn close_token(&mut self, token: usize) -> bool {
+    self.open_tokens.remove(&token)
+  }
+
+  /// True if the token has been issued and not yet closed.
+  pub fn is_open(&self, token: usize) -> bool {
+    self.open_tokens.contains(&token)
+  }
+
+  pub fn was_issued(&self, token: usize) -> bool {
+    token < self.next_token || self.is_open(token)
+  }
+}
+
+impl Default for EventHandler {
+  fn default() -> EventHandler {
+    EventHandler::new()
   }
 }
@@ -0,0 +1,233 @@
+use std::{
+  collections::VecDeque,
+  io,
+  time::{Duration, Instant},
+};
+
+use crate::modules::{write_functions::write_data, ConnectionType};
+
+/// Extra delay given to packets picked for reordering, on top of the jitter.
+pub const REORDER_DELAY: Duration = Duration::from_millis(20);
+/// Sending stalls once the bandwidth cap has this much data waiting on the link.
+pub const MAX_BANDWIDTH_BACKLOG: Duration = Duration::from_secs(1);
+
+/// Simulated network conditions for outgoing data. Loss, duplication and
+/// reordering only apply to datagram sockets, streams are only delayed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConditions {
+  pub latency: Duration,
+  /// Each packet's latency is moved by up to this much either way.
+  pub jitter: Duration,
+  /// Chance from 0.0 to 1.0 that a packet is dropped.
+  pub loss: f64,
+  /// Chance that a packet is sent twice.
+  pub duplication: f64,
+  /// Chance that a packet is held back behind the ones sent after it.
+  pub reordering: f64,
+  /// Bytes per second, `None` for no cap.
+  pub bandwidth: Option<usize>,
+  pub seed: u64,
+}
+
+impl LinkConditions {
+  pub fn new(latency: Duration, loss: f64) -> LinkConditions {
+    LinkConditions {
+      latency,
+      jitter: Duration::ZERO,
+      loss,
+      duplication: 0.0,
+      reordering: 0.0,
+      bandwidth: None,
+      seed: 0,
+    }
+  }
+
+  pub fn with_jitter(mut self, jitter: Duration) -> LinkConditions {
+    self.jitter = jitter;
+    self
+  }
+
+  pub fn with_duplication(mut self, duplication: f64) -> LinkConditions {
+    self.duplication = duplication;
+    self
+  }
+
+  pub fn with_reordering(mut self, reordering: f64) -> LinkConditions {
+    self.reordering = reordering;
+    self
+  }
+
+  pub fn with_bandwidth(mut self, bytes_per_second: usize) -> LinkConditions {
+    self.bandwidth = Some(bytes_per_second);
+    self
+  }
+
+  /// The same seed gives the same drops and delays for the same traffic.
+  pub fn with_seed(mut self, seed: u64) -> LinkConditions {
+    self.seed = seed;
+    self
+  }
+}
+
+impl Default for LinkConditions {
+  fn default() -> LinkConditions {
+    LinkConditions::new(Duration::ZERO, 0.0)
+  }
+}
+
+/// Counts of what the conditioner did to the packets given to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+  pub packets: usize,
+  pub dropped: usize,
+  pub duplicated: usize,
+  pub reordered: usize,
+}
+
+/// Holds outgoing data back until the simulated link would deliver it.
+pub struct LinkConditioner {
+  conditions: LinkConditions,
+  datagram: bool,
+  rng: SplitMix64,
+  /// Release time and data, in release order.
+  in_flight: VecDeque<(Instant, Vec<u8>)>,
+  link_free_at: Option<Instant>,
+  stats: LinkStats,
+}
+
+impl LinkConditioner {
+  /// `salt` is mixed into the seed so connections sharing conditions don't
+  /// drop the same packets.
+  pub fn new(conditions: LinkConditions, datagram: bool, salt: u64) -> LinkConditioner {
+    LinkConditioner {
+      conditions,
+      datagram,
+      rng: SplitMix64(conditions.seed ^ SplitMix64(salt).next_u64()),
+      in_flight: VecDeque::new(),
+      link_free_at: None,
+      stats: LinkStats::default(),
+    }
+  }
+
+  pub fn conditions(&self) -> LinkConditions {
+    self.conditions
+  }
+
+  pub fn set_conditions(&mut self, conditions: LinkConditions) {
+    self.conditions = conditions;
+  }
+
+  pub fn stats(&self) -> LinkStats {
+    self.stats
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.in_flight.is_empty()
+  }
+
+  /// Takes `data` onto the link, returns 0 while the bandwidth cap has a full
+  /// backlog and `data.len()` otherwise, even if the packet is then lost.
+  pub fn send(&mut self, data: &[u8], now: Instant) -> usize {
+    let mut sent_at = now;
+    if let Some(bandwidth) = self.conditions.bandwidth {
+      let free_at = self.link_free_at.unwrap_or(now).max(now);
+      if free_at.duration_since(now) >= MAX_BANDWIDTH_BACKLOG {
+        return 0;
+      }
+      sent_at = free_at + Duration::from_secs_f64(data.len() as f64 / bandwidth.max(1) as f64);
+      self.link_free_at = Some(sent_at);
+    }
+
+    self.stats.packets += 1;
+    if !self.datagram {
+      // Streams keep their order, so no packet may overtake the last one.
+      let release = self
+        .in_flight
+        .back()
+        .map_or(now, |(last, _)| *last)
+        .max(sent_at + self.delay());
+      self.in_flight.push_back((release, data.to_vec()));
+      return data.len();
+    }
+
+    if self.rng.chance(self.conditions.loss) {
+      self.stats.dropped += 1;
+      return data.len();
+    }
+
+    let copies = if self.rng.chance(self.conditions.duplication) {
+      self.stats.duplicated += 1;
+      2
+    } else {
+      1
+    };
+    for _ in 0..copies {
+      let mut release = sent_at + self.delay();
+      if self.rng.chance(self.conditions.reordering) {
+        self.stats.reordered += 1;
+        release += REORDER_DELAY + self.conditions.jitter;
+      }
+      let index = self.in_flight.partition_point(|(t, _)| *t <= release);
+      self.in_flight.insert(index, (release, data.to_vec()));
+    }
+
+    data.len()
+  }
+
+  /// Writes the data whose time has come, returns true if any was written.
+  pub fn release(&mut self, connection: &mut ConnectionType, now: Instant) -> io::Result<bool> {
+    let mut did_write = false;
+
+    while let Some((release, data)) = self.in_flight.front_mut() {
+      if *release > now {
+        break;
+      }
+
+      let written = write_data(connection, data)?;
+      if written == 0 {
+        break;
+      }
+
+      did_write = true;
+      if written < data.len() && !self.datagram {
+        data.drain(..written);
+        break;
+      }
+      self.in_flight.pop_front();
+    }
+
+    Ok(did_write)
+  }
+
+  /// Latency with jitter applied.
+  fn delay(&mut self) -> Duration {
+    let jitter = self.conditions.jitter.mul_f64(self.rng.next_f64());
+    if self.rng.chance(0.5) {
+      self.conditions.latency + jitter
+    } else {
+      self.conditions.latency.saturating_sub(jitter)
+    }
+  }
+}
+
+/// Small seedable generator, good enough to pick which packets to drop.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+  fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// Uniform in `[0, 1)`.
+  fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  fn chance(&mut self, probability: f64) -> bool {
+    probability > 0.0 && self.next_f64() < probability
+  }
+}
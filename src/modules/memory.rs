@@ -0,0 +1,171 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  io::{Error, ErrorKind},
+  sync::{Arc, Mutex, MutexGuard, OnceLock},
+};
+
+/// Bytes a pipe holds before writes to it would block.
+pub const MEMORY_PIPE_CAPACITY: usize = 1024 * 1024;
+
+type Backlog = Arc<Mutex<VecDeque<MemoryStream>>>;
+
+/// Listeners by name, shared by every network in the process.
+fn listeners() -> MutexGuard<'static, HashMap<String, Backlog>> {
+  static LISTENERS: OnceLock<Mutex<HashMap<String, Backlog>>> = OnceLock::new();
+  LISTENERS
+    .get_or_init(|| Mutex::new(HashMap::new()))
+    .lock()
+    .unwrap()
+}
+
+/// One direction of a memory connection.
+#[derive(Default)]
+struct Pipe {
+  data: VecDeque<u8>,
+  /// Set once either end is dropped.
+  closed: bool,
+}
+
+/// One end of an in-process byte stream, behaves like a non-blocking tcp
+/// stream without touching the os.
+pub struct MemoryStream {
+  name: String,
+  incoming: Arc<Mutex<Pipe>>,
+  outgoing: Arc<Mutex<Pipe>>,
+}
+
+impl MemoryStream {
+  /// Two ends of a new connection, `name` is only used for display.
+  pub fn pair(name: &str) -> (MemoryStream, MemoryStream) {
+    let a = Arc::new(Mutex::new(Pipe::default()));
+    let b = Arc::new(Mutex::new(Pipe::default()));
+    (
+      MemoryStream {
+        name: name.to_string(),
+        incoming: a.clone(),
+        outgoing: b.clone(),
+      },
+      MemoryStream {
+        name: name.to_string(),
+        incoming: b,
+        outgoing: a,
+      },
+    )
+  }
+
+  /// Connects to the listener bound to `name` in this process.
+  pub fn connect(name: &str) -> Result<MemoryStream, Error> {
+    let backlog = listeners().get(name).cloned().ok_or_else(|| {
+      Error::new(
+        ErrorKind::ConnectionRefused,
+        format!("no memory listener named {}", name),
+      )
+    })?;
+
+    let (client, server) = MemoryStream::pair(name);
+    backlog.lock().unwrap().push_back(server);
+    Ok(client)
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Ok(0) once the peer has gone and everything it sent has been read.
+  pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut pipe = self.incoming.lock().unwrap();
+    if pipe.data.is_empty() {
+      if pipe.closed {
+        return Ok(0);
+      }
+      return Err(Error::new(ErrorKind::WouldBlock, "memory stream is empty"));
+    }
+
+    let n = buf.len().min(pipe.data.len());
+    for (b, d) in buf.iter_mut().zip(pipe.data.drain(..n)) {
+      *b = d;
+    }
+    Ok(n)
+  }
+
+  pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    let mut pipe = self.outgoing.lock().unwrap();
+    if pipe.closed {
+      return Err(Error::new(
+        ErrorKind::BrokenPipe,
+        "memory stream peer is gone",
+      ));
+    }
+
+    let n = buf
+      .len()
+      .min(MEMORY_PIPE_CAPACITY.saturating_sub(pipe.data.len()));
+    if n == 0 && !buf.is_empty() {
+      return Err(Error::new(ErrorKind::WouldBlock, "memory stream is full"));
+    }
+    pipe.data.extend(&buf[..n]);
+    Ok(n)
+  }
+
+  /// True if a read would return data or the end of the stream.
+  pub fn is_readable(&self) -> bool {
+    let pipe = self.incoming.lock().unwrap();
+    !pipe.data.is_empty() || pipe.closed
+  }
+}
+
+impl Drop for MemoryStream {
+  fn drop(&mut self) {
+    self.incoming.lock().unwrap().closed = true;
+    self.outgoing.lock().unwrap().closed = true;
+  }
+}
+
+/// Accepts memory streams connecting to its name, which stays taken until the
+/// listener is dropped.
+pub struct MemoryListener {
+  name: String,
+  backlog: Backlog,
+}
+
+impl MemoryListener {
+  pub fn bind(name: &str) -> Result<MemoryListener, Error> {
+    let mut listeners = listeners();
+    if listeners.contains_key(name) {
+      return Err(Error::new(
+        ErrorKind::AddrInUse,
+        format!("memory listener {} already exists", name),
+      ));
+    }
+
+    let backlog = Backlog::default();
+    listeners.insert(name.to_string(), backlog.clone());
+    Ok(MemoryListener {
+      name: name.to_string(),
+      backlog,
+    })
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn accept(&self) -> Result<MemoryStream, Error> {
+    self
+      .backlog
+      .lock()
+      .unwrap()
+      .pop_front()
+      .ok_or_else(|| Error::new(ErrorKind::WouldBlock, "no memory streams waiting"))
+  }
+
+  pub fn is_readable(&self) -> bool {
+    !self.backlog.lock().unwrap().is_empty()
+  }
+}
+
+impl Drop for MemoryListener {
+  fn drop(&mut self) {
+    listeners().remove(&self.name);
+  }
+}
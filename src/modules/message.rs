@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::modules::{bits::BitError, WriteError};
+
+/// Largest encoded message a peer may send.
+pub const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+/// Encoded length in front of every message.
+const LENGTH_BYTES: usize = 4;
+
+/// How messages are turned into bytes, both sides have to use the same one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+  #[cfg(feature = "bincode")]
+  Bincode,
+  #[cfg(feature = "postcard")]
+  Postcard,
+  #[cfg(feature = "json")]
+  Json,
+}
+
+impl Default for Codec {
+  /// Bincode, which the `messages` feature always includes.
+  fn default() -> Codec {
+    Codec::Bincode
+  }
+}
+
+impl Codec {
+  pub fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, MessageError> {
+    let encoded = match self {
+      #[cfg(feature = "bincode")]
+      Codec::Bincode => bincode::serialize(message).map_err(|e| e.to_string()),
+      #[cfg(feature = "postcard")]
+      Codec::Postcard => postcard::to_allocvec(message).map_err(|e| e.to_string()),
+      #[cfg(feature = "json")]
+      Codec::Json => serde_json::to_vec(message).map_err(|e| e.to_string()),
+    };
+    encoded.map_err(MessageError::Encode)
+  }
+
+  pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, MessageError> {
+    let decoded = match self {
+      #[cfg(feature = "bincode")]
+      Codec::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+      #[cfg(feature = "postcard")]
+      Codec::Postcard => postcard::from_bytes(bytes).map_err(|e| e.to_string()),
+      #[cfg(feature = "json")]
+      Codec::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+    };
+    decoded.map_err(MessageError::Decode)
+  }
+}
+
+/// How a message type wants to be delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+  Reliable,
+  /// Fine to lose, so dropped instead of failing when the write queue is full.
+  Unreliable,
+}
+
+/// How many bits a field is packed in, from `#[net(bits = ...)]`. The derive
+/// also generates a `BitPack` impl that uses them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FieldHint {
+  pub name: &'static str,
+  pub bits: u8,
+}
+
+/// A message type with a stable id, usually from `#[derive(NetMessage)]`.
+pub trait NetMessage: Serialize + DeserializeOwned + 'static {
+  const ID: u16;
+  const CHANNEL: Channel;
+  /// Fields marked with a bit count, in declaration order.
+  const FIELDS: &'static [FieldHint];
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MessageError {
+  /// The message couldn't be serialized.
+  Encode(String),
+  /// A frame didn't hold a valid message of the expected type.
+  Decode(String),
+  /// A peer announced a message over `MAX_MESSAGE_BYTES`, with its length.
+  /// Whatever else was buffered for the token is dropped.
+  TooLarge(usize),
+  /// A frame didn't unpack as the expected `BitPack` type.
+  Unpack(BitError),
+  Write(WriteError),
+}
+
+impl From<BitError> for MessageError {
+  fn from(error: BitError) -> MessageError {
+    MessageError::Unpack(error)
+  }
+}
+
+impl From<WriteError> for MessageError {
+  fn from(error: WriteError) -> MessageError {
+    MessageError::Write(error)
+  }
+}
+
+/// A typed message read from a token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message<T> {
+  Received(usize, T),
+  /// A frame from the token that didn't decode.
+  Invalid(usize, MessageError),
+}
+
+/// Puts the encoded length in front of a message. Every transport gets the
+/// prefix, so datagrams that are read back to back split the same way as a
+/// byte stream.
+pub fn frame(encoded: &[u8]) -> Vec<u8> {
+  let mut frame = Vec::with_capacity(LENGTH_BYTES + encoded.len());
+  frame.extend((encoded.len() as u32).to_le_bytes());
+  frame.extend(encoded);
+  frame
+}
+
+/// Splits frames back out of the bytes read from one connection, keeping a
+/// partial frame until the rest arrives.
+#[derive(Default)]
+pub struct FrameBuffer {
+  buffer: Vec<u8>,
+}
+
+impl FrameBuffer {
+  pub fn new() -> FrameBuffer {
+    FrameBuffer::default()
+  }
+
+  /// The whole frames in `data`, in order. An oversized frame ends the list
+  /// since the rest of the stream can't be split.
+  pub fn split(&mut self, data: &[u8]) -> Vec<Result<Vec<u8>, MessageError>> {
+    self.buffer.extend(data);
+
+    let mut frames = Vec::new();
+    let mut start = 0;
+    while self.buffer.len() - start >= LENGTH_BYTES {
+      let length =
+        u32::from_le_bytes(self.buffer[start..start + LENGTH_BYTES].try_into().unwrap()) as usize;
+      if length > MAX_MESSAGE_BYTES {
+        frames.push(Err(MessageError::TooLarge(length)));
+        start = self.buffer.len();
+        break;
+      }
+
+      let end = start + LENGTH_BYTES + length;
+      if self.buffer.len() < end {
+        break;
+      }
+      frames.push(Ok(self.buffer[start + LENGTH_BYTES..end].to_vec()));
+      start = end;
+    }
+    self.buffer.drain(..start);
+
+    frames
+  }
+}
+
+/// A `FrameBuffer` per token.
+#[derive(Default)]
+pub struct MessageFramer {
+  buffers: HashMap<usize, FrameBuffer>,
+}
+
+impl MessageFramer {
+  pub fn new() -> MessageFramer {
+    MessageFramer::default()
+  }
+
+  pub fn split(&mut self, token: usize, data: &[u8]) -> Vec<Result<Vec<u8>, MessageError>> {
+    self.buffers.entry(token).or_default().split(data)
+  }
+
+  pub fn remove(&mut self, token: usize) {
+    self.buffers.remove(&token);
+  }
+}
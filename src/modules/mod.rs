@@ -1,10 +1,42 @@
+pub use self::connection_state::ConnectionState;
 pub use self::connection_type::ConnectionType;
 pub use self::event_handler::EventHandler;
+pub use self::network_event::{DisconnectReason, NetworkEvent};
 pub use self::network_stream::NetworkStream;
+pub use self::resolver::Resolver;
+pub use self::transport::Transport;
+pub use self::write_queue::{
+  OverflowPolicy, Priority, WriteError, WriteLimits, WriteQueue, PRIORITY_AGING_TURNS,
+};
 
+pub mod bits;
+pub mod checksum;
+#[cfg(feature = "compression")]
+pub mod compression;
+#[cfg(feature = "connect-tokens")]
+pub mod connect_token;
+pub mod link_conditioner;
+pub mod memory;
+#[cfg(feature = "messages")]
+pub mod message;
+pub mod mux;
 pub mod read_functions;
+#[cfg(feature = "messages")]
+pub mod registry;
+#[cfg(feature = "messages")]
+pub mod rpc;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "encryption")]
+pub mod udp_encryption;
+pub mod version;
 pub mod write_functions;
 
+mod connection_state;
 mod connection_type;
 mod event_handler;
+mod network_event;
 mod network_stream;
+mod resolver;
+mod transport;
+mod write_queue;
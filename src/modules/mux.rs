@@ -0,0 +1,293 @@
+use std::{
+  collections::{BTreeMap, HashMap, HashSet},
+  time::Instant,
+};
+
+use crate::modules::{NetworkEvent, WriteError, WriteLimits, WriteQueue};
+
+/// Messages are cut into chunks of at most this many bytes, so a large one
+/// only holds up the other streams for a chunk at a time.
+pub const MUX_CHUNK_BYTES: usize = 16 * 1024;
+/// Largest message a peer may send on a stream, the connection is dropped if
+/// it goes over.
+pub const MAX_STREAM_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+/// Most bytes of unfinished messages a peer may have across all of its
+/// streams, the connection is dropped if it goes over.
+pub const MAX_BUFFERED_STREAM_BYTES: usize = 4 * MAX_STREAM_MESSAGE_BYTES;
+/// The stream `write_data` writes to, it is always open.
+pub const DEFAULT_STREAM: u16 = 0;
+
+const OPEN: u8 = 0;
+/// A chunk with more of the same message to follow.
+const PART: u8 = 1;
+/// The last chunk of a message.
+const END: u8 = 2;
+const CLOSE: u8 = 3;
+/// Frame kind, then the little endian stream number and payload length.
+const HEADER_BYTES: usize = 7;
+
+fn mux_frame(kind: u8, stream: u16, payload: &[u8]) -> Vec<u8> {
+  let mut frame = Vec::with_capacity(HEADER_BYTES + payload.len());
+  frame.push(kind);
+  frame.extend(stream.to_le_bytes());
+  frame.extend((payload.len() as u32).to_le_bytes());
+  frame.extend(payload);
+  frame
+}
+
+struct SubStream {
+  queue: WriteQueue,
+  /// Closed by us, it is dropped once the close frame is written.
+  closing: bool,
+}
+
+/// The sending side of the numbered streams on one connection. Each stream
+/// has its own queue and limits, and they take turns a frame at a time.
+pub struct Multiplexer {
+  limits: WriteLimits,
+  streams: BTreeMap<u16, SubStream>,
+  /// Where the next turn starts looking.
+  next: u16,
+}
+
+impl Multiplexer {
+  pub fn new(limits: WriteLimits) -> Multiplexer {
+    let mut mux = Multiplexer {
+      limits,
+      streams: BTreeMap::new(),
+      next: DEFAULT_STREAM,
+    };
+    mux.add(DEFAULT_STREAM);
+    mux
+  }
+
+  /// Every stream's queue gets the same limits.
+  pub fn set_limits(&mut self, limits: WriteLimits) {
+    self.limits = limits;
+    self
+      .streams
+      .values_mut()
+      .for_each(|stream| stream.queue.set_limits(limits));
+  }
+
+  pub fn is_open(&self, stream: u16) -> bool {
+    self.streams.get(&stream).is_some_and(|s| !s.closing)
+  }
+
+  /// Opens the stream and tells the peer.
+  pub fn open(&mut self, stream: u16) -> Result<(), WriteError> {
+    if self.streams.contains_key(&stream) {
+      return Err(WriteError::StreamOpen);
+    }
+    self.add(stream);
+    let result = self.queue(stream).push(&mux_frame(OPEN, stream, &[]));
+    if result.is_err() {
+      self.streams.remove(&stream);
+    }
+    result
+  }
+
+  /// Queues a message on the stream, all of it or nothing.
+  pub fn write(&mut self, stream: u16, data: &[u8]) -> Result<(), WriteError> {
+    if !self.is_open(stream) {
+      return Err(WriteError::StreamClosed);
+    }
+
+    let mut frames = data
+      .chunks(MUX_CHUNK_BYTES)
+      .map(|chunk| mux_frame(PART, stream, chunk))
+      .collect::<Vec<Vec<u8>>>();
+    match frames.last_mut() {
+      Some(last) => last[0] = END,
+      None => frames.push(mux_frame(END, stream, &[])),
+    }
+    self.queue(stream).push_all(frames)
+  }
+
+  /// Closes the stream once what is queued on it has been written. Panics for
+  /// the default stream.
+  pub fn close(&mut self, stream: u16) -> Result<(), WriteError> {
+    assert!(
+      stream != DEFAULT_STREAM,
+      "Multiplexer: the default stream can't be closed"
+    );
+    if !self.is_open(stream) {
+      return Err(WriteError::StreamClosed);
+    }
+
+    let sub_stream = self.streams.get_mut(&stream).unwrap();
+    sub_stream.closing = true;
+    sub_stream.queue.force_push(&mux_frame(CLOSE, stream, &[]));
+    Ok(())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.streams.values().all(|stream| stream.queue.is_empty())
+  }
+
+  pub fn queued_bytes(&self) -> usize {
+    self
+      .streams
+      .values()
+      .map(|stream| stream.queue.queued_bytes())
+      .sum()
+  }
+
+  /// The next frame to write, from the first stream after the last one served
+  /// that has anything queued.
+  pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+    let id = self
+      .streams
+      .range(self.next..)
+      .chain(self.streams.range(..self.next))
+      .find(|(_, stream)| !stream.queue.is_empty())
+      .map(|(id, _)| *id)?;
+
+    let stream = self.streams.get_mut(&id).unwrap();
+    let frame = stream.queue.pop();
+    if stream.closing && stream.queue.is_empty() {
+      self.streams.remove(&id);
+    }
+    self.next = id.wrapping_add(1);
+    frame
+  }
+
+  /// True once after any stream's queue rejected data and has drained below
+  /// its low-water mark.
+  pub fn take_drained(&mut self) -> bool {
+    // Every queue has to be checked to reset it, so this can't stop early.
+    let drained = self
+      .streams
+      .values_mut()
+      .map(|stream| stream.queue.take_drained())
+      .collect::<Vec<bool>>();
+    drained.contains(&true)
+  }
+
+  pub fn overflow_expired(&self, now: Instant) -> bool {
+    self
+      .streams
+      .values()
+      .any(|stream| stream.queue.overflow_expired(now))
+  }
+
+  fn add(&mut self, stream: u16) {
+    self.streams.insert(
+      stream,
+      SubStream {
+        queue: WriteQueue::new(self.limits),
+        closing: false,
+      },
+    );
+  }
+
+  fn queue(&mut self, stream: u16) -> &mut WriteQueue {
+    &mut self.streams.get_mut(&stream).unwrap().queue
+  }
+}
+
+/// The receiving side of the streams on every multiplexed token. Messages on
+/// the default stream are returned as data, the rest are raised as events.
+#[derive(Default)]
+pub struct Demultiplexer {
+  tokens: HashSet<usize>,
+  buffers: HashMap<usize, Vec<u8>>,
+  /// The streams the peer has open, with the message being put together.
+  open: HashMap<(usize, u16), Vec<u8>>,
+  /// Bytes held in `open` for each token.
+  buffered: HashMap<usize, usize>,
+}
+
+impl Demultiplexer {
+  pub fn new() -> Demultiplexer {
+    Demultiplexer::default()
+  }
+
+  pub fn enable(&mut self, token: usize) {
+    self.tokens.insert(token);
+    self.open.entry((token, DEFAULT_STREAM)).or_default();
+  }
+
+  pub fn is_enabled(&self, token: usize) -> bool {
+    self.tokens.contains(&token)
+  }
+
+  pub fn remove(&mut self, token: usize) {
+    self.tokens.remove(&token);
+    self.buffers.remove(&token);
+    self.buffered.remove(&token);
+    self.open.retain(|(t, _), _| *t != token);
+  }
+
+  /// Splits what was read from the token into frames, returns the messages
+  /// on the default stream. `None` if the peer sent a frame over
+  /// `MUX_CHUNK_BYTES`, a message over `MAX_STREAM_MESSAGE_BYTES` or more
+  /// than `MAX_BUFFERED_STREAM_BYTES` of unfinished messages.
+  pub fn read(
+    &mut self,
+    token: usize,
+    data: &[u8],
+    events: &mut Vec<NetworkEvent>,
+  ) -> Option<Vec<Vec<u8>>> {
+    let buffer = self.buffers.entry(token).or_default();
+    buffer.extend(data);
+
+    let mut frames = Vec::new();
+    while buffer.len() >= HEADER_BYTES {
+      let length = u32::from_le_bytes(buffer[3..HEADER_BYTES].try_into().unwrap()) as usize;
+      if length > MUX_CHUNK_BYTES {
+        return None;
+      }
+      if buffer.len() < HEADER_BYTES + length {
+        break;
+      }
+      frames.push(buffer.drain(..HEADER_BYTES + length).collect::<Vec<u8>>());
+    }
+
+    let mut received = Vec::new();
+    for frame in frames {
+      let stream = u16::from_le_bytes([frame[1], frame[2]]);
+      let payload = &frame[HEADER_BYTES..];
+      let key = (token, stream);
+
+      let buffered = self.buffered.entry(token).or_default();
+      if let Some(message) = self.open.get(&key) {
+        if message.len() + payload.len() > MAX_STREAM_MESSAGE_BYTES
+          || *buffered + payload.len() > MAX_BUFFERED_STREAM_BYTES
+        {
+          return None;
+        }
+      }
+
+      match (frame[0], self.open.get_mut(&key)) {
+        (OPEN, None) => {
+          self.open.insert(key, Vec::new());
+          events.push(NetworkEvent::StreamOpened(token, stream));
+        }
+        (PART, Some(message)) => {
+          message.extend(payload);
+          *buffered += payload.len();
+        }
+        (END, Some(message)) => {
+          *buffered -= message.len();
+          message.extend(payload);
+          let message = std::mem::take(message);
+          if stream == DEFAULT_STREAM {
+            received.push(message);
+          } else {
+            events.push(NetworkEvent::StreamMessage(token, stream, message));
+          }
+        }
+        (CLOSE, Some(message)) if stream != DEFAULT_STREAM => {
+          *buffered -= message.len();
+          self.open.remove(&key);
+          events.push(NetworkEvent::StreamClosed(token, stream));
+        }
+        // Frames for streams that aren't open, or of unknown kinds.
+        _ => {}
+      }
+    }
+
+    Some(received)
+  }
+}
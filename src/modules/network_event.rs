@@ -0,0 +1,55 @@
+use std::io::ErrorKind;
+
+#[cfg(feature = "connect-tokens")]
+use crate::modules::connect_token::TokenError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+  /// Closed locally with `MaatNetwork::close_connection`.
+  Closed,
+  PeerClosed,
+  WriteQueueOverflow,
+  /// One side didn't accept the other's protocol version, or the peer
+  /// doesn't exchange versions at all.
+  VersionMismatch,
+  /// Reading from the connection failed, for example a tls session error.
+  Error(ErrorKind),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetworkEvent {
+  /// An outgoing connection has been established, for tls streams once the
+  /// handshake has finished on either side.
+  Connected(usize),
+  /// The connection for the token could not be established, `TimedOut` if the
+  /// connect timeout passed first.
+  ConnectFailed(usize, ErrorKind),
+  /// The host name given for the token could not be looked up.
+  ResolveFailed(usize, ErrorKind),
+  /// A write queue that rejected data has drained below its low-water mark.
+  WriteQueueDrained(usize),
+  Disconnected(usize, DisconnectReason),
+  /// Data written to a token that never connected was dropped, with the number
+  /// of bytes discarded.
+  PendingDataExpired(usize, usize),
+  /// The peer opened the numbered stream on a multiplexed token.
+  StreamOpened(usize, u16),
+  /// A whole message the peer wrote to the stream.
+  StreamMessage(usize, u16, Vec<u8>),
+  /// The peer closed the stream, after everything it wrote to it.
+  StreamClosed(usize, u16),
+  /// A peer at the address was dropped before getting a token because its
+  /// connect token didn't check out.
+  #[cfg(feature = "connect-tokens")]
+  ConnectTokenRejected(String, TokenError),
+  /// A request from the peer with its id, answer it with
+  /// `MaatNetwork::respond`.
+  #[cfg(feature = "messages")]
+  Request(usize, u64, Vec<u8>),
+  /// The peer's answer to the request with the id.
+  #[cfg(feature = "messages")]
+  Response(usize, u64, Vec<u8>),
+  /// The request with the id wasn't answered within its timeout.
+  #[cfg(feature = "messages")]
+  RequestTimedOut(usize, u64),
+}
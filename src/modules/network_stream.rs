@@ -1,9 +1,25 @@
+use std::{
+  io::{self, Error},
+  time::{Duration, Instant},
+};
+
 use mio::Registry;
 use mio::{Interest, Token};
 
+#[cfg(feature = "compression")]
+use crate::modules::compression::{Compression, CompressionStats, MessageCodec};
+#[cfg(feature = "encryption")]
+use crate::modules::udp_encryption::{Opened, SessionKey, UdpSession};
 use crate::{
   modules::{
-    read_functions::print_data, write_functions::write_data, ConnectionType, EventHandler,
+    checksum::{ConnectionStats, PacketChecksum},
+    link_conditioner::{LinkConditioner, LinkConditions, LinkStats},
+    mux::{Multiplexer, DEFAULT_STREAM, MUX_CHUNK_BYTES},
+    read_functions::{print_data, recieve_data, recieve_datagrams},
+    version::{Negotiation, ProtocolVersion, VersionHandshake, VersionPolicy},
+    write_functions::write_data,
+    ConnectionState, ConnectionType, DisconnectReason, EventHandler, Priority, WriteError,
+    WriteLimits, WriteQueue,
   },
   NewConnection, ReadFunc,
 };
@@ -13,9 +29,23 @@ pub struct NetworkStream {
   pub token: Option<Token>,
   pub stream: ConnectionType,
   pub is_readable: ReadFunc,
-  data_to_write: Vec<Vec<u8>>,
-  registered: bool,
+  data_to_write: WriteQueue,
+  /// Version packets, written ahead of data and before the connection is up.
+  control: WriteQueue,
+  state: ConnectionState,
+  state_since: Instant,
   did_write: bool,
+  /// The error that stopped the last write, the connection is closed for it.
+  write_error: Option<io::Error>,
+  connect_on_register: bool,
+  checksum: Option<PacketChecksum>,
+  link: Option<LinkConditioner>,
+  version: Option<VersionHandshake>,
+  mux: Option<Multiplexer>,
+  #[cfg(feature = "encryption")]
+  encryption: Option<UdpSession>,
+  #[cfg(feature = "compression")]
+  codec: Option<MessageCodec>,
 }
 
 impl NetworkStream {
@@ -28,15 +58,45 @@ impl NetworkStream {
       addr: addr.into(),
       token: None,
       stream: connection,
-      is_readable: read_func.unwrap_or(Box::new(print_data)),
-      data_to_write: Vec::new(),
-      registered: false,
+      is_readable: read_func.unwrap_or_else(|| Box::new(print_data)),
+      data_to_write: WriteQueue::new(WriteLimits::default()),
+      control: WriteQueue::new(WriteLimits::default()),
+      state: ConnectionState::Pending,
+      state_since: Instant::now(),
       did_write: false,
+      write_error: None,
+      connect_on_register: false,
+      checksum: None,
+      link: None,
+      version: None,
+      mux: None,
+      #[cfg(feature = "encryption")]
+      encryption: None,
+      #[cfg(feature = "compression")]
+      codec: None,
     }
   }
 
   pub fn unregistered(&self) -> bool {
-    !self.registered
+    !self.state.is_registered()
+  }
+
+  pub fn state(&self) -> ConnectionState {
+    self.state
+  }
+
+  /// Moves the connection to `next`, panics if the state machine doesn't allow
+  /// the transition.
+  pub fn set_state(&mut self, next: ConnectionState) {
+    assert!(
+      self.state.can_transition_to(next),
+      "NetworkStream: illegal state transition {:?} -> {:?} for {}",
+      self.state,
+      next,
+      self.addr
+    );
+    self.state = next;
+    self.state_since = Instant::now();
   }
 
   pub fn token(&self) -> Option<Token> {
@@ -51,17 +111,108 @@ impl NetworkStream {
     self.did_write
   }
 
+  pub fn take_write_error(&mut self) -> Option<io::Error> {
+    self.write_error.take()
+  }
+
+  pub fn is_connecting(&self) -> bool {
+    self.state == ConnectionState::Connecting
+  }
+
+  /// Registering the connection moves it to `Connecting` instead of `Connected`.
+  pub fn connect_on_register(&mut self) {
+    self.connect_on_register = true;
+  }
+
+  /// Checks whether a non-blocking connect has completed. Returns `Ok(false)`
+  /// while the connect is still in progress.
+  pub fn finish_connecting(&mut self) -> Result<bool, Error> {
+    if let Some(err) = self.stream.take_error()? {
+      return Err(err);
+    }
+
+    let connected = self.stream.is_connected()?;
+    if connected {
+      self.set_state(if self.is_handshaking() {
+        ConnectionState::Handshaking
+      } else {
+        ConnectionState::Connected
+      });
+    }
+    Ok(connected)
+  }
+
+  /// Moves a handshaking connection to `Connected` once the handshake is done,
+  /// returns true if it did.
+  pub fn finish_handshake(&mut self) -> bool {
+    if self.state == ConnectionState::Handshaking && !self.is_handshaking() {
+      self.set_state(ConnectionState::Connected);
+      true
+    } else {
+      false
+    }
+  }
+
+  /// True while a tls handshake or the version exchange is running.
+  fn is_handshaking(&self) -> bool {
+    self.stream.is_handshaking() || self.version_pending()
+  }
+
+  fn version_pending(&self) -> bool {
+    self
+      .version
+      .as_ref()
+      .is_some_and(|version| !version.is_done())
+  }
+
+  /// True once queued data can be written to the connection.
+  pub fn can_send(&self) -> bool {
+    matches!(
+      self.state,
+      ConnectionState::Connected | ConnectionState::Closing
+    )
+  }
+
+  /// True if connecting, including any handshake, has taken longer than `timeout`.
+  pub fn connect_timed_out(&self, now: Instant, timeout: Duration) -> bool {
+    matches!(
+      self.state,
+      ConnectionState::Connecting | ConnectionState::Handshaking
+    ) && now.duration_since(self.state_since) >= timeout
+  }
+
   pub fn register(&mut self, register: &Registry, token: Token, interest: Interest) {
     debug_assert!(self.unregistered());
     println!("Registering Address: {}", self.addr);
-    self.registered = true;
     self.stream.register(register, token, interest);
+    self.set_state(if self.connect_on_register {
+      ConnectionState::Connecting
+    } else if self.is_handshaking() {
+      ConnectionState::Handshaking
+    } else {
+      ConnectionState::Connected
+    });
     self.token = Some(token);
   }
 
+  /// Replaces a connection attempt that failed with one to another address,
+  /// keeping the token and everything queued on it.
+  pub fn retry_connect(&mut self, register: &Registry, connection: NewConnection) {
+    debug_assert!(self.is_connecting());
+    self.stream.deregister(register);
+    self.stream = connection.connection;
+    self.addr = connection.addr;
+    self.stream.register(
+      register,
+      self.token.unwrap(),
+      Interest::READABLE.add(Interest::WRITABLE),
+    );
+    self.state_since = Instant::now();
+  }
+
   pub fn deregister(&mut self, register: &Registry) {
     debug_assert!(!self.unregistered());
-    self.registered = false;
+    self.set_state(ConnectionState::Closed);
     println!("Deregistering Address: {}", self.addr);
     self.stream.deregister(register);
   }
@@ -74,13 +225,432 @@ impl NetworkStream {
     }
   }
 
-  pub fn data_to_write(&mut self, data: &[u8]) {
-    println!("data passed to write :{:?}", data);
-    self.data_to_write.push(data.to_vec());
+  pub fn write_limits(&self) -> WriteLimits {
+    self.data_to_write.limits()
+  }
+
+  pub fn set_write_limits(&mut self, limits: WriteLimits) {
+    self.data_to_write.set_limits(limits);
+    if let Some(mux) = self.mux.as_mut() {
+      mux.set_limits(limits);
+    }
+  }
+
+  /// Stops accepting data, the connection is closed once the queued data has
+  /// been written.
+  pub fn close(&mut self) {
+    if self.state.can_transition_to(ConnectionState::Closing) {
+      self.set_state(ConnectionState::Closing);
+    }
+  }
+
+  /// True once a closing connection has nothing left to write.
+  pub fn finished_closing(&self) -> bool {
+    self.state == ConnectionState::Closing
+      && !self.data_pending()
+      && self.link.as_ref().is_none_or(|link| link.is_empty())
+  }
+
+  /// Sends data from now on through a simulated link with the given
+  /// conditions. Data already on the link keeps its delays.
+  pub fn set_link_conditions(&mut self, conditions: LinkConditions) {
+    match self.link.as_mut() {
+      Some(link) => link.set_conditions(conditions),
+      None => {
+        let salt = self.token.map_or(0, |t| t.0 as u64);
+        self.link = Some(LinkConditioner::new(
+          conditions,
+          self.stream.is_datagram(),
+          salt,
+        ));
+      }
+    }
+  }
+
+  /// What the link conditioner did to sent packets, `None` without one.
+  pub fn link_stats(&self) -> Option<LinkStats> {
+    self.link.as_ref().map(|link| link.stats())
+  }
+
+  /// Writes queued data, and whatever the link conditioner has delivered.
+  /// Needed on every poll since neither raises an event on an idle connection.
+  pub fn flush_writes(&mut self) -> io::Result<()> {
+    if self.can_send() && (self.data_pending() || self.link.is_some()) {
+      self.is_writeable();
+    } else if self.state == ConnectionState::Handshaking {
+      if let Err(err) = self.write_handshake(Instant::now()) {
+        self.write_error = Some(err);
+      }
+    }
+    match self.take_write_error() {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+
+  /// Queues data on the connection, on the default stream if it is
+  /// multiplexed.
+  pub fn data_to_write(&mut self, data: &[u8]) -> Result<(), WriteError> {
+    self.data_to_write_with_priority(data, Priority::Normal)
+  }
+
+  /// Queues data ahead of or behind other queued data. Streams already take
+  /// turns, so on a multiplexed connection the priority is ignored.
+  pub fn data_to_write_with_priority(
+    &mut self,
+    data: &[u8],
+    priority: Priority,
+  ) -> Result<(), WriteError> {
+    if !self.state.accepts_data() {
+      return Err(WriteError::TokenClosed);
+    }
+
+    if let Some(mux) = self.mux.as_mut() {
+      return mux.write(DEFAULT_STREAM, data);
+    }
+
+    #[cfg(feature = "compression")]
+    if let Some(codec) = self.codec.as_mut() {
+      let frame = codec.encode(data);
+      self.data_to_write.push_with_priority(&frame, priority)?;
+      codec.record_sent(data.len(), &frame);
+      return Ok(());
+    }
+
+    self.data_to_write.push_with_priority(data, priority)
   }
 
   pub fn data_pending(&self) -> bool {
-    self.data_to_write.len() > 0
+    !self.data_to_write.is_empty()
+      || !self.control.is_empty()
+      || self.mux.as_ref().is_some_and(|mux| !mux.is_empty())
+  }
+
+  pub fn queued_bytes(&self) -> usize {
+    self.data_to_write.queued_bytes() + self.mux.as_ref().map_or(0, |mux| mux.queued_bytes())
+  }
+
+  /// Returns true once the write queue, or a stream's queue, has drained below
+  /// its low-water mark after rejecting data.
+  pub fn write_queue_drained(&mut self) -> bool {
+    let drained = self.data_to_write.take_drained();
+    self.mux.as_mut().is_some_and(|mux| mux.take_drained()) || drained
+  }
+
+  pub fn write_queue_overflowed(&self, now: Instant) -> bool {
+    self.data_to_write.overflow_expired(now)
+      || self
+        .mux
+        .as_ref()
+        .is_some_and(|mux| mux.overflow_expired(now))
+  }
+
+  /// Splits writes into numbered streams, `mux` holds whatever was written
+  /// to them before the connection was added. The peer has to demultiplex
+  /// them. Panics unless the connection is a stream.
+  pub fn multiplex(&mut self, mut mux: Multiplexer) {
+    assert!(
+      !self.stream.is_datagram() && !self.stream.is_connected_udp(),
+      "NetworkStream: only streams can be multiplexed, {} isn't",
+      self.addr
+    );
+    mux.set_limits(self.write_limits());
+    self.mux = Some(mux);
+  }
+
+  /// The connection's streams, `None` unless it is multiplexed.
+  pub fn multiplexer(&mut self) -> Option<&mut Multiplexer> {
+    self.mux.as_mut()
+  }
+
+  /// Reads everything available on the connection. Checksummed and encrypted
+  /// udp sockets return one entry per datagram and compressed connections one
+  /// per message.
+  pub fn read_data(&mut self) -> (Vec<Vec<u8>>, Option<DisconnectReason>) {
+    let (data, reason) = self.read_packets();
+
+    #[cfg(feature = "compression")]
+    if let Some(codec) = self.codec.as_mut() {
+      return decode_messages(codec, data, reason);
+    }
+
+    (data, reason)
+  }
+
+  fn read_packets(&mut self) -> (Vec<Vec<u8>>, Option<DisconnectReason>) {
+    if self.version_pending() {
+      return self.read_version();
+    }
+
+    #[cfg(feature = "encryption")]
+    if self.encryption.is_some() {
+      return self.read_encrypted();
+    }
+
+    if self.checksum.is_some() || (self.version.is_some() && self.stream.is_connected_udp()) {
+      return self.read_datagrams();
+    }
+
+    #[cfg(feature = "compression")]
+    if self.codec.is_some() && self.stream.is_datagram() {
+      return self.read_datagrams();
+    }
+
+    let (data, reason) = recieve_data(&mut self.stream);
+    (vec![data], reason)
+  }
+
+  /// Reads each waiting datagram, dropping those that fail the checksum.
+  fn read_datagrams(&mut self) -> (Vec<Vec<u8>>, Option<DisconnectReason>) {
+    let (datagrams, reason) = recieve_datagrams(&mut self.stream);
+    let datagrams = self.check_datagrams(datagrams);
+    (self.answer_hellos(datagrams), reason)
+  }
+
+  /// Version packets the peer sends after the exchange finished, because one
+  /// of our answers was lost, are answered again and not returned as data.
+  fn answer_hellos(&mut self, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let version = match self.version.as_mut() {
+      Some(version) if version.is_done() => version,
+      _ => return datagrams,
+    };
+
+    let datagrams = datagrams
+      .into_iter()
+      .filter(|datagram| !version.read_repeated(datagram))
+      .collect();
+    for reply in version.take_replies() {
+      self.control.force_push(&reply);
+    }
+    datagrams
+  }
+
+  fn check_datagrams(&mut self, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    match self.checksum.as_mut() {
+      Some(checksum) => datagrams
+        .iter()
+        .filter_map(|packet| checksum.check(packet))
+        .map(|payload| payload.to_vec())
+        .collect(),
+      None => datagrams,
+    }
+  }
+
+  /// Reads the peer's version packets, answering them as they arrive.
+  /// Anything read after the exchange finished is handled as usual.
+  fn read_version(&mut self) -> (Vec<Vec<u8>>, Option<DisconnectReason>) {
+    let datagram = self.stream.is_connected_udp();
+    let (negotiation, reason) = if datagram {
+      let (datagrams, reason) = recieve_datagrams(&mut self.stream);
+      let datagrams = self.check_datagrams(datagrams);
+      (
+        self.version.as_mut().unwrap().read_datagrams(datagrams),
+        reason,
+      )
+    } else {
+      let (data, reason) = recieve_data(&mut self.stream);
+      (self.version.as_mut().unwrap().read_stream(&data), reason)
+    };
+
+    // A lost reply leaves the peer to resend its hello or time out.
+    for reply in self.version.as_mut().unwrap().take_replies() {
+      self.control.force_push(&reply);
+    }
+
+    match negotiation {
+      Negotiation::Pending => (Vec::new(), reason),
+      Negotiation::Mismatch => {
+        // The connection is dropped, so the reject has to go out now. It's of
+        // no use if that fails.
+        let _ = self.flush_control(Instant::now());
+        (Vec::new(), Some(DisconnectReason::VersionMismatch))
+      }
+      Negotiation::Done(rest) if datagram => (self.open_datagrams(rest), reason),
+      Negotiation::Done(rest) => (rest, reason),
+    }
+  }
+
+  /// Handles datagrams that arrived after the version exchange in the same
+  /// read like any others.
+  fn open_datagrams(&mut self, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let datagrams = self.answer_hellos(datagrams);
+
+    #[cfg(feature = "encryption")]
+    if self.encryption.is_some() {
+      return self.open_encrypted(datagrams);
+    }
+
+    datagrams
+  }
+
+  /// Exchanges versions with the peer before anything else is sent, data is
+  /// queued until both sides accepted. Tls streams exchange them once their
+  /// handshake is done. Panics unless the connection is a stream or a
+  /// connected udp socket that isn't registered yet.
+  pub fn negotiate_version(&mut self, version: ProtocolVersion, policy: VersionPolicy) {
+    assert!(
+      self.stream.exchanges_versions(),
+      "NetworkStream: only streams and connected udp sockets exchange versions, {} isn't",
+      self.addr
+    );
+    debug_assert!(self.unregistered());
+    self.version = Some(VersionHandshake::new(
+      version,
+      policy,
+      self.stream.is_connected_udp(),
+    ));
+  }
+
+  /// Queues a connect token ahead of anything else on the connection,
+  /// version packets included.
+  #[cfg(feature = "connect-tokens")]
+  pub fn send_connect_token(&mut self, sealed: &[u8]) {
+    debug_assert!(self.unregistered());
+    self.control.force_push(sealed);
+  }
+
+  /// The version the peer sent, `None` without a version exchange.
+  pub fn peer_version(&self) -> Option<ProtocolVersion> {
+    self
+      .version
+      .as_ref()
+      .and_then(|version| version.peer_version())
+  }
+
+  /// Sends our version once the transport is up, and again over udp while
+  /// the peer hasn't answered.
+  pub fn send_hello(&mut self, now: Instant) {
+    if self.state != ConnectionState::Handshaking || self.stream.is_handshaking() {
+      return;
+    }
+    if let Some(hello) = self
+      .version
+      .as_mut()
+      .and_then(|version| version.hello_due(now))
+    {
+      self.control.force_push(&hello);
+    }
+  }
+
+  /// Prefixes every packet from now on with `protocol_id` and a crc32, packets
+  /// that don't carry both are dropped. Panics unless the connection is a
+  /// datagram socket.
+  pub fn enable_checksums(&mut self, protocol_id: u32) {
+    assert!(
+      self.stream.is_datagram(),
+      "NetworkStream: only datagram sockets can be checksummed, {} isn't",
+      self.addr
+    );
+    self.checksum = Some(PacketChecksum::new(protocol_id));
+  }
+
+  /// Packet counts, `None` without checksums.
+  pub fn connection_stats(&self) -> Option<ConnectionStats> {
+    self.checksum.as_ref().map(|checksum| checksum.stats())
+  }
+
+  /// Compresses messages written from now on, the peer has to do the same.
+  #[cfg(feature = "compression")]
+  pub fn set_compression(&mut self, compression: Compression) {
+    match self.codec.as_mut() {
+      Some(codec) => codec.set_compression(compression),
+      None => self.codec = Some(MessageCodec::new(compression, !self.stream.is_datagram())),
+    }
+  }
+
+  /// Stats for messages sent and received, `None` without compression.
+  #[cfg(feature = "compression")]
+  pub fn compression_stats(&self) -> Option<(CompressionStats, CompressionStats)> {
+    self
+      .codec
+      .as_ref()
+      .map(|codec| (codec.sent(), codec.received()))
+  }
+
+  /// Starts an encrypted session, panics unless the connection is a connected
+  /// udp socket.
+  #[cfg(feature = "encryption")]
+  pub fn enable_encryption(&mut self, key: &SessionKey) {
+    assert!(
+      self.stream.is_connected_udp(),
+      "NetworkStream: only connected udp sockets can be encrypted, {} isn't",
+      self.addr
+    );
+    if self.encryption.is_none() {
+      self.encryption = Some(UdpSession::new(key));
+    }
+  }
+
+  #[cfg(feature = "encryption")]
+  pub fn is_encrypted(&self) -> bool {
+    self
+      .encryption
+      .as_ref()
+      .is_some_and(|session| session.is_established())
+  }
+
+  #[cfg(feature = "encryption")]
+  pub fn rejected_packets(&self) -> usize {
+    self
+      .encryption
+      .as_ref()
+      .map_or(0, |session| session.rejected_packets())
+  }
+
+  /// Sends the handshake again while the peer hasn't answered.
+  #[cfg(feature = "encryption")]
+  pub fn resend_handshake(&mut self, now: Instant) {
+    if self.version_pending() {
+      return;
+    }
+    if let Some(packet) = self
+      .encryption
+      .as_mut()
+      .and_then(|session| session.handshake_due(now))
+    {
+      self.control.force_push(&packet);
+    }
+  }
+
+  #[cfg(feature = "encryption")]
+  pub fn encryption_timed_out(&self, now: Instant, timeout: Duration) -> bool {
+    self
+      .encryption
+      .as_ref()
+      .is_some_and(|session| session.timed_out(now, timeout))
+  }
+
+  #[cfg(feature = "encryption")]
+  fn read_encrypted(&mut self) -> (Vec<Vec<u8>>, Option<DisconnectReason>) {
+    let (datagrams, reason) = self.read_datagrams();
+    (self.open_encrypted(datagrams), reason)
+  }
+
+  #[cfg(feature = "encryption")]
+  fn open_encrypted(&mut self, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let session = self.encryption.as_mut().unwrap();
+    let was_established = session.is_established();
+
+    let data = datagrams
+      .iter()
+      .filter_map(|packet| match session.open(packet) {
+        Opened::Data(data) => Some(data),
+        Opened::Handshake(reply) => {
+          if let Some(reply) = reply {
+            self.control.force_push(&reply);
+          }
+          None
+        }
+        Opened::Rejected => None,
+      })
+      .collect();
+
+    // Data queued while waiting on the key exchange can go out now.
+    if !was_established && session.is_established() && self.can_send() {
+      self.is_writeable();
+    }
+
+    data
   }
 
   pub fn is_readable(&mut self, data: &[u8]) -> Vec<(ConnectionType, String)> {
@@ -91,26 +661,169 @@ impl NetworkStream {
 
   pub fn is_writeable(&mut self) -> Vec<NetworkStream> {
     debug_assert!(!self.unregistered());
-    let connection = &mut self.stream;
+    debug_assert!(self.can_send());
 
-    println!("connection is writable");
-    self.data_to_write = self
-      .data_to_write
-      .drain(..)
-      .map(|d| {
-        if write_data(connection, &d) {
-          self.did_write = true;
-          None
-        } else {
-          Some(d)
-        }
-      })
-      .filter(|d| d.is_some())
-      .map(|d| d.unwrap())
-      .collect::<Vec<Vec<u8>>>();
+    if let Err(err) = self.write_queued(Instant::now()) {
+      self.write_error = Some(err);
+    }
 
     Vec::new()
   }
+
+  fn write_queued(&mut self, now: Instant) -> io::Result<()> {
+    let mut flushed = self.flush_control(now)?;
+    loop {
+      self.fill_from_streams();
+      if !self.flush_queue(now)? {
+        break;
+      }
+      flushed = true;
+      // Everything went out, so the streams get another turn.
+      if self.mux.is_none() || !self.data_to_write.is_empty() {
+        break;
+      }
+    }
+    if self.release_link(now)? || flushed {
+      self.did_write = true;
+    }
+    Ok(())
+  }
+
+  /// Writes version packets before the connection is up, data waits.
+  fn write_handshake(&mut self, now: Instant) -> io::Result<()> {
+    let flushed = self.flush_control(now)?;
+    if self.release_link(now)? || flushed {
+      self.did_write = true;
+    }
+    Ok(())
+  }
+
+  /// Writes whatever the link conditioner has delivered.
+  fn release_link(&mut self, now: Instant) -> io::Result<bool> {
+    match self.link.as_mut() {
+      Some(link) => link.release(&mut self.stream, now),
+      None => Ok(false),
+    }
+  }
+
+  /// Moves frames from the streams into the write queue, a turn each, while
+  /// it holds less than a chunk.
+  fn fill_from_streams(&mut self) {
+    while self.data_to_write.queued_bytes() < MUX_CHUNK_BYTES {
+      let frame = match self.mux.as_mut().and_then(|mux| mux.next_frame()) {
+        Some(frame) => frame,
+        None => break,
+      };
+
+      // Already checked against the stream's limits.
+      #[cfg(feature = "compression")]
+      if let Some(codec) = self.codec.as_mut() {
+        let encoded = codec.encode(&frame);
+        codec.record_sent(frame.len(), &encoded);
+        self.data_to_write.force_push(&encoded);
+        continue;
+      }
+
+      self.data_to_write.force_push(&frame);
+    }
+  }
+
+  /// Writes queued data to the connection, or onto the simulated link.
+  fn flush_queue(&mut self, now: Instant) -> io::Result<bool> {
+    let stream = &mut self.stream;
+    let link = &mut self.link;
+
+    #[cfg(feature = "encryption")]
+    if let Some(session) = self.encryption.as_mut() {
+      let checksum = &self.checksum;
+      if !session.is_established() {
+        return Ok(false);
+      }
+      return self.data_to_write.flush_datagrams(|data| {
+        send(
+          stream,
+          link,
+          &wrap_packet(checksum, session.seal(data)),
+          now,
+        )
+        .map(|n| n > 0)
+      });
+    }
+
+    flush(&mut self.data_to_write, stream, link, &self.checksum, now)
+  }
+
+  /// Version and key exchange packets come before there are session keys, so
+  /// they are never encrypted.
+  fn flush_control(&mut self, now: Instant) -> io::Result<bool> {
+    flush(
+      &mut self.control,
+      &mut self.stream,
+      &mut self.link,
+      &self.checksum,
+      now,
+    )
+  }
+}
+
+/// Writes the queue to the connection, checksumming each datagram if there is
+/// a checksum.
+fn flush(
+  queue: &mut WriteQueue,
+  stream: &mut ConnectionType,
+  link: &mut Option<LinkConditioner>,
+  checksum: &Option<PacketChecksum>,
+  now: Instant,
+) -> io::Result<bool> {
+  match checksum {
+    Some(checksum) => {
+      queue.flush_datagrams(|data| send(stream, link, &checksum.wrap(data), now).map(|n| n > 0))
+    }
+    None => queue.flush_with(|data| send(stream, link, data, now)),
+  }
+}
+
+/// Writes to the connection, through the link conditioner if there is one.
+fn send(
+  stream: &mut ConnectionType,
+  link: &mut Option<LinkConditioner>,
+  data: &[u8],
+  now: Instant,
+) -> io::Result<usize> {
+  match link {
+    Some(link) => Ok(link.send(data, now)),
+    None => write_data(stream, data),
+  }
+}
+
+#[cfg(feature = "encryption")]
+fn wrap_packet(checksum: &Option<PacketChecksum>, packet: Vec<u8>) -> Vec<u8> {
+  match checksum {
+    Some(checksum) => checksum.wrap(&packet),
+    None => packet,
+  }
+}
+
+/// Splits what was read into messages. A bad frame on a stream loses track of
+/// the framing so the connection is closed, bad datagrams are just dropped.
+#[cfg(feature = "compression")]
+fn decode_messages(
+  codec: &mut MessageCodec,
+  data: Vec<Vec<u8>>,
+  mut reason: Option<DisconnectReason>,
+) -> (Vec<Vec<u8>>, Option<DisconnectReason>) {
+  let mut messages = Vec::new();
+  for chunk in data {
+    match codec.decode(&chunk) {
+      Ok(decoded) => messages.extend(decoded),
+      Err(_) if !codec.is_framed() => {}
+      Err(e) => {
+        reason = Some(DisconnectReason::Error(e.kind()));
+        break;
+      }
+    }
+  }
+  (messages, reason)
 }
 
 impl From<NewConnection> for NetworkStream {
@@ -118,6 +831,9 @@ impl From<NewConnection> for NetworkStream {
     let mut n =
       NetworkStream::from_connection(connection.connection, connection.addr, connection.read_func);
     n.set_token(connection.token);
+    if connection.connecting {
+      n.connect_on_register();
+    }
     n
   }
 }
@@ -1,11 +1,11 @@
 use std::io::ErrorKind;
 
-use mio::Interest;
+use crate::modules::{ConnectionType, DisconnectReason};
 
-use crate::modules::{write_functions::write_ones, ConnectionType, EventHandler, NetworkStream};
-
-pub fn recieve_data(stream: &mut ConnectionType) -> (Vec<u8>, bool) {
-  let mut should_close = false;
+/// Reads everything available on the connection. The reason is set if the
+/// connection was closed by the peer or failed while reading.
+pub fn recieve_data(stream: &mut ConnectionType) -> (Vec<u8>, Option<DisconnectReason>) {
+  let mut should_close = None;
 
   let mut bytes_read = 0;
   let mut recieved_data = vec![0; 4096];
@@ -13,7 +13,7 @@ pub fn recieve_data(stream: &mut ConnectionType) -> (Vec<u8>, bool) {
   loop {
     match stream.read(&mut recieved_data[bytes_read..]) {
       Ok(0) => {
-        should_close = true;
+        should_close = Some(DisconnectReason::PeerClosed);
         break;
       }
       Ok(n) => {
@@ -29,7 +29,8 @@ pub fn recieve_data(stream: &mut ConnectionType) -> (Vec<u8>, bool) {
         continue;
       }
       Err(e) => {
-        panic!("An error occured: {}", e);
+        should_close = Some(DisconnectReason::Error(e.kind()));
+        break;
       }
     }
   }
@@ -37,25 +38,53 @@ pub fn recieve_data(stream: &mut ConnectionType) -> (Vec<u8>, bool) {
   (recieved_data[..bytes_read].to_vec(), should_close)
 }
 
-pub fn print_data(connection: &mut ConnectionType, data: &[u8]) -> Vec<(ConnectionType, String)> {
-  let data = recieve_data(connection);
+/// Reads each waiting datagram on its own so packet boundaries are kept.
+pub fn recieve_datagrams(stream: &mut ConnectionType) -> (Vec<Vec<u8>>, Option<DisconnectReason>) {
+  let mut should_close = None;
+  let mut datagrams = Vec::new();
+
+  loop {
+    let mut datagram = vec![0; 65536];
+    match stream.read(&mut datagram) {
+      Ok(n) => {
+        datagram.truncate(n);
+        datagrams.push(datagram);
+      }
+      Err(ref e) if ErrorKind::WouldBlock == e.kind() => {
+        break;
+      }
+      Err(ref e) if ErrorKind::Interrupted == e.kind() => {
+        continue;
+      }
+      Err(e) => {
+        should_close = Some(DisconnectReason::Error(e.kind()));
+        break;
+      }
+    }
+  }
+
+  (datagrams, should_close)
+}
+
+pub fn print_data(connection: &mut ConnectionType, _data: &[u8]) -> Vec<(ConnectionType, String)> {
+  let _data = recieve_data(connection);
   Vec::new()
 }
 
-pub fn udp_read(connection: &mut ConnectionType) -> Vec<(ConnectionType, String)> {
+pub fn udp_read(_connection: &mut ConnectionType) -> Vec<(ConnectionType, String)> {
   Vec::new()
 }
 
 pub fn accept_connections(
   connection: &mut ConnectionType,
-  data: &[u8],
+  _data: &[u8],
 ) -> Vec<(ConnectionType, String)> {
   let mut streams = Vec::new();
 
   loop {
     match connection.accept() {
-      Ok((new_connection, address)) => {
-        streams.push((ConnectionType::from(new_connection), address.to_string()));
+      Ok(new_connection) => {
+        streams.push(new_connection);
       }
       Err(e) if e.kind() == ErrorKind::WouldBlock => {
         break;
@@ -0,0 +1,159 @@
+use std::{
+  any::{type_name, TypeId},
+  cell::RefCell,
+  collections::HashMap,
+  rc::Rc,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::modules::{
+  message::{self, Codec, FrameBuffer, MessageError, NetMessage},
+  ConnectionType,
+};
+use crate::ReadFunc;
+
+/// Bytes of the id in front of every registered message.
+const ID_BYTES: usize = 2;
+
+type Handler = Box<dyn Fn(&mut ConnectionType, &[u8]) -> Result<(), MessageError>>;
+
+/// Something that went wrong dispatching a frame, drained with
+/// `MessageRegistry::events`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistryEvent {
+  /// A frame carried an id nothing is registered under.
+  UnknownId(u16),
+  /// A frame didn't decode as the type registered for its id.
+  Invalid(u16, MessageError),
+  /// A frame was too large, or too short to hold an id.
+  Malformed(MessageError),
+}
+
+/// Why `MessageRegistry::register` refused a type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistryError {
+  /// The id is taken, with the name of the type registered under it.
+  DuplicateId(u16, &'static str),
+  /// The type is already registered, with its name and the id it has.
+  DuplicateType(&'static str, u16),
+}
+
+/// Message types registered once under a stable id, with a handler each.
+/// Incoming frames are dispatched to the handler for their id by the read
+/// function from `read_func`.
+pub struct MessageRegistry {
+  codec: Codec,
+  ids: HashMap<TypeId, u16>,
+  handlers: HashMap<u16, (&'static str, Handler)>,
+  events: RefCell<Vec<RegistryEvent>>,
+}
+
+impl MessageRegistry {
+  pub fn new(codec: Codec) -> MessageRegistry {
+    MessageRegistry {
+      codec,
+      ids: HashMap::new(),
+      handlers: HashMap::new(),
+      events: RefCell::new(Vec::new()),
+    }
+  }
+
+  /// Registers `T` under `id`, unless the id or the type is already
+  /// registered.
+  pub fn register<T, F>(&mut self, id: u16, handler: F) -> Result<(), RegistryError>
+  where
+    T: Serialize + DeserializeOwned + 'static,
+    F: Fn(&mut ConnectionType, T) + 'static,
+  {
+    if let Some((name, _)) = self.handlers.get(&id) {
+      return Err(RegistryError::DuplicateId(id, name));
+    }
+    if let Some(existing) = self.ids.get(&TypeId::of::<T>()) {
+      return Err(RegistryError::DuplicateType(type_name::<T>(), *existing));
+    }
+
+    let codec = self.codec;
+    self.ids.insert(TypeId::of::<T>(), id);
+    self.handlers.insert(
+      id,
+      (
+        type_name::<T>(),
+        Box::new(move |connection, body| {
+          handler(connection, codec.decode(body)?);
+          Ok(())
+        }),
+      ),
+    );
+    Ok(())
+  }
+
+  /// Registers `T` under its own id.
+  pub fn register_message<T, F>(&mut self, handler: F) -> Result<(), RegistryError>
+  where
+    T: NetMessage,
+    F: Fn(&mut ConnectionType, T) + 'static,
+  {
+    self.register(T::ID, handler)
+  }
+
+  /// The id `T` was registered under.
+  pub fn id_of<T: 'static>(&self) -> Option<u16> {
+    self.ids.get(&TypeId::of::<T>()).copied()
+  }
+
+  /// A frame holding the message and its id, ready for `write_data`. Panics
+  /// if `T` isn't registered.
+  pub fn encode<T: Serialize + 'static>(&self, message: &T) -> Result<Vec<u8>, MessageError> {
+    let id = self
+      .id_of::<T>()
+      .unwrap_or_else(|| panic!("MessageRegistry: {} isn't registered", type_name::<T>()));
+
+    let mut body = id.to_le_bytes().to_vec();
+    body.extend(self.codec.encode(message)?);
+    Ok(message::frame(&body))
+  }
+
+  /// Runs the handler for the id at the start of `frame`.
+  pub fn dispatch(&self, connection: &mut ConnectionType, frame: &[u8]) {
+    if frame.len() < ID_BYTES {
+      return self.push(RegistryEvent::Malformed(MessageError::Decode(
+        "frame is too short to hold an id".to_string(),
+      )));
+    }
+
+    let id = u16::from_le_bytes(frame[..ID_BYTES].try_into().unwrap());
+    match self.handlers.get(&id) {
+      Some((_, handler)) => {
+        if let Err(e) = handler(connection, &frame[ID_BYTES..]) {
+          self.push(RegistryEvent::Invalid(id, e));
+        }
+      }
+      None => self.push(RegistryEvent::UnknownId(id)),
+    }
+  }
+
+  /// A read function that splits incoming frames and dispatches each one.
+  /// Every connection needs its own, since it holds on to partial frames.
+  pub fn read_func(self: &Rc<Self>) -> ReadFunc {
+    let registry = self.clone();
+    let frames = RefCell::new(FrameBuffer::new());
+    Box::new(move |connection, data| {
+      let split = frames.borrow_mut().split(data);
+      split.into_iter().for_each(|frame| match frame {
+        Ok(frame) => registry.dispatch(connection, &frame),
+        Err(e) => registry.push(RegistryEvent::Malformed(e)),
+      });
+      Vec::new()
+    })
+  }
+
+  /// Drains the events raised since the last call.
+  pub fn events(&self) -> Vec<RegistryEvent> {
+    self.events.borrow_mut().drain(..).collect()
+  }
+
+  fn push(&self, event: RegistryEvent) {
+    self.events.borrow_mut().push(event);
+  }
+}
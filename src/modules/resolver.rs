@@ -0,0 +1,61 @@
+use std::{
+  collections::HashMap,
+  io::Error,
+  net::{IpAddr, SocketAddr, ToSocketAddrs},
+  sync::mpsc::{channel, Receiver, Sender},
+  thread,
+};
+
+pub type Resolved = (usize, Result<Vec<SocketAddr>, Error>);
+
+/// Looks up host names on background threads so `MaatNetwork::poll` never
+/// blocks on DNS.
+pub struct Resolver {
+  sender: Sender<Resolved>,
+  receiver: Receiver<Resolved>,
+  /// Hosts answered without a lookup, like entries in a hosts file.
+  hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Resolver {
+  pub fn new() -> Resolver {
+    let (sender, receiver) = channel();
+    Resolver {
+      sender,
+      receiver,
+      hosts: HashMap::new(),
+    }
+  }
+
+  /// Resolves `host` to `addrs`, in that order, instead of looking it up.
+  pub fn add_host(&mut self, host: &str, addrs: Vec<IpAddr>) {
+    self.hosts.insert(host.to_string(), addrs);
+  }
+
+  pub fn resolve(&self, token: usize, host: String, port: u16) {
+    if let Some(addrs) = self.hosts.get(&host) {
+      let addrs = addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+      let _ = self.sender.send((token, Ok(addrs)));
+      return;
+    }
+
+    let sender = self.sender.clone();
+    thread::spawn(move || {
+      let result = (host.as_str(), port)
+        .to_socket_addrs()
+        .map(|addrs| addrs.collect::<Vec<SocketAddr>>());
+      let _ = sender.send((token, result));
+    });
+  }
+
+  /// Lookups that have finished since the last call.
+  pub fn finished(&self) -> Vec<Resolved> {
+    self.receiver.try_iter().collect()
+  }
+}
+
+impl Default for Resolver {
+  fn default() -> Resolver {
+    Resolver::new()
+  }
+}
@@ -0,0 +1,238 @@
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  time::{Duration, Instant},
+};
+
+use crate::modules::{
+  message::{self, MessageError, MessageFramer},
+  NetworkEvent,
+};
+
+/// How often a request over udp is sent again while it hasn't been answered.
+pub const RPC_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+/// Requests per token remembered for answering resent ones. A resend of an
+/// older request would be raised again, which the resend interval and request
+/// timeouts keep from happening in practice.
+pub const RPC_HISTORY: usize = 256;
+
+const DATA: u8 = 0;
+const REQUEST: u8 = 1;
+const RESPONSE: u8 = 2;
+/// Frame kind followed by the little endian request id.
+const HEADER_BYTES: usize = 9;
+
+/// What one frame on a token with rpc enabled carries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RpcFrame {
+  /// Anything written with `write_data`.
+  Data(Vec<u8>),
+  Request(u64, Vec<u8>),
+  Response(u64, Vec<u8>),
+}
+
+impl RpcFrame {
+  /// The frame with its length in front, ready to be written.
+  pub fn encode(&self) -> Vec<u8> {
+    let (kind, id, payload) = match self {
+      RpcFrame::Data(payload) => (DATA, 0, payload),
+      RpcFrame::Request(id, payload) => (REQUEST, *id, payload),
+      RpcFrame::Response(id, payload) => (RESPONSE, *id, payload),
+    };
+
+    let mut body = Vec::with_capacity(HEADER_BYTES + payload.len());
+    body.push(kind);
+    body.extend(id.to_le_bytes());
+    body.extend(payload);
+    message::frame(&body)
+  }
+
+  /// Decodes a frame body split out by a `FrameBuffer`.
+  pub fn decode(body: &[u8]) -> Result<RpcFrame, MessageError> {
+    if body.len() < HEADER_BYTES {
+      return Err(MessageError::Decode("rpc frame is too short".to_string()));
+    }
+
+    let id = u64::from_le_bytes(body[1..HEADER_BYTES].try_into().unwrap());
+    let payload = body[HEADER_BYTES..].to_vec();
+    match body[0] {
+      DATA => Ok(RpcFrame::Data(payload)),
+      REQUEST => Ok(RpcFrame::Request(id, payload)),
+      RESPONSE => Ok(RpcFrame::Response(id, payload)),
+      kind => Err(MessageError::Decode(format!(
+        "unknown rpc frame kind {}",
+        kind
+      ))),
+    }
+  }
+}
+
+/// Request ids from one peer, with the response once there is one.
+type Answered = VecDeque<(u64, Option<Vec<u8>>)>;
+
+struct Outstanding {
+  token: usize,
+  frame: Vec<u8>,
+  deadline: Instant,
+  sent_at: Instant,
+}
+
+/// Request state for every token with rpc enabled. Requests get ids unique
+/// to this side, responses are matched back to them by id and token. Each
+/// request id from a peer is raised once, resends of it get the stored
+/// response, or nothing while it is unanswered.
+#[derive(Default)]
+pub struct Rpc {
+  tokens: HashSet<usize>,
+  framer: MessageFramer,
+  next_id: u64,
+  outstanding: HashMap<u64, Outstanding>,
+  /// Recent requests from each peer.
+  answered: HashMap<usize, Answered>,
+}
+
+impl Rpc {
+  pub fn new() -> Rpc {
+    Rpc::default()
+  }
+
+  pub fn enable(&mut self, token: usize) {
+    self.tokens.insert(token);
+  }
+
+  pub fn is_enabled(&self, token: usize) -> bool {
+    self.tokens.contains(&token)
+  }
+
+  /// Forgets the token, its outstanding requests are left to time out.
+  pub fn remove(&mut self, token: usize) {
+    self.tokens.remove(&token);
+    self.framer.remove(token);
+    self.answered.remove(&token);
+  }
+
+  /// A new request to the token, with the frame to write.
+  pub fn request(
+    &mut self,
+    token: usize,
+    payload: &[u8],
+    timeout: Duration,
+    now: Instant,
+  ) -> (u64, Vec<u8>) {
+    let id = self.next_id;
+    self.next_id += 1;
+
+    let frame = RpcFrame::Request(id, payload.to_vec()).encode();
+    self.outstanding.insert(
+      id,
+      Outstanding {
+        token,
+        frame: frame.clone(),
+        deadline: now + timeout,
+        sent_at: now,
+      },
+    );
+    (id, frame)
+  }
+
+  /// Forgets a request that couldn't be written.
+  pub fn cancel(&mut self, id: u64) {
+    self.outstanding.remove(&id);
+  }
+
+  /// The frame answering request `id` from the token. It is kept to answer
+  /// the request again if the peer resends it.
+  pub fn respond(&mut self, token: usize, id: u64, payload: &[u8]) -> Vec<u8> {
+    let frame = RpcFrame::Response(id, payload.to_vec()).encode();
+    if let Some((_, response)) = self
+      .answered
+      .get_mut(&token)
+      .and_then(|answered| answered.iter_mut().find(|(i, _)| *i == id))
+    {
+      *response = Some(frame.clone());
+    }
+    frame
+  }
+
+  /// Splits what was read from the token into frames. Returns the data
+  /// frames, with requests and responses pushed as events and responses to
+  /// resent requests returned for writing again.
+  pub fn read(
+    &mut self,
+    token: usize,
+    data: &[u8],
+    events: &mut Vec<NetworkEvent>,
+  ) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let mut received = Vec::new();
+    let mut replies = Vec::new();
+
+    for frame in self.framer.split(token, data) {
+      match frame.and_then(|body| RpcFrame::decode(&body)) {
+        Ok(RpcFrame::Data(data)) => received.push(data),
+        Ok(RpcFrame::Request(id, payload)) => {
+          let answered = self.answered.entry(token).or_default();
+          match answered.iter().find(|(i, _)| *i == id) {
+            Some((_, Some(response))) => replies.push(response.clone()),
+            Some((_, None)) => {}
+            None => {
+              if answered.len() == RPC_HISTORY {
+                answered.pop_front();
+              }
+              answered.push_back((id, None));
+              events.push(NetworkEvent::Request(token, id, payload));
+            }
+          }
+        }
+        Ok(RpcFrame::Response(id, payload)) => {
+          if self
+            .outstanding
+            .get(&id)
+            .is_some_and(|request| request.token == token)
+          {
+            self.outstanding.remove(&id);
+            events.push(NetworkEvent::Response(token, id, payload));
+          }
+        }
+        // Frames that aren't rpc frames are dropped.
+        Err(_) => {}
+      }
+    }
+
+    (received, replies)
+  }
+
+  /// Times out requests past their deadline, returns the ones due to be
+  /// sent again on tokens where `resends` is true.
+  pub fn expire<F>(
+    &mut self,
+    now: Instant,
+    resends: F,
+    events: &mut Vec<NetworkEvent>,
+  ) -> Vec<(usize, Vec<u8>)>
+  where
+    F: Fn(usize) -> bool,
+  {
+    let mut expired = self
+      .outstanding
+      .iter()
+      .filter(|(_, request)| now >= request.deadline)
+      .map(|(id, request)| (*id, request.token))
+      .collect::<Vec<(u64, usize)>>();
+    expired.sort();
+    for (id, token) in expired {
+      self.outstanding.remove(&id);
+      events.push(NetworkEvent::RequestTimedOut(token, id));
+    }
+
+    self
+      .outstanding
+      .values_mut()
+      .filter(|request| {
+        now.duration_since(request.sent_at) >= RPC_RESEND_INTERVAL && resends(request.token)
+      })
+      .map(|request| {
+        request.sent_at = now;
+        (request.token, request.frame.clone())
+      })
+      .collect()
+  }
+}
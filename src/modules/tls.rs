@@ -0,0 +1,176 @@
+use std::{
+  fs::File,
+  io::{BufReader, Error, ErrorKind, Read, Write},
+  net::{Shutdown, SocketAddr},
+  path::Path,
+  sync::Arc,
+};
+
+use mio::net::{TcpListener, TcpStream};
+use rustls::{
+  crypto::ring::default_provider,
+  pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+  ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+
+/// Plaintext rustls will hold on to before `write` starts returning 0.
+const TLS_BUFFER_LIMIT: usize = 64 * 1024;
+
+pub fn load_certs<P: AsRef<Path>>(path: P) -> Result<Vec<CertificateDer<'static>>, Error> {
+  let mut reader = BufReader::new(File::open(path)?);
+  rustls_pemfile::certs(&mut reader).collect()
+}
+
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'static>, Error> {
+  let mut reader = BufReader::new(File::open(path)?);
+  rustls_pemfile::private_key(&mut reader)?
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no private key found"))
+}
+
+/// Server config presenting the certificate chain and key from PEM files.
+pub fn server_config<C, K>(cert_path: C, key_path: K) -> Result<Arc<ServerConfig>, Error>
+where
+  C: AsRef<Path>,
+  K: AsRef<Path>,
+{
+  let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+    .with_no_client_auth()
+    .with_single_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+  Ok(Arc::new(config))
+}
+
+/// Client config trusting only the certificates in the PEM file at `ca_path`.
+pub fn client_config<P: AsRef<Path>>(ca_path: P) -> Result<Arc<ClientConfig>, Error> {
+  let mut roots = RootCertStore::empty();
+  for cert in load_certs(ca_path)? {
+    roots
+      .add(cert)
+      .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+  }
+
+  let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+  Ok(Arc::new(config))
+}
+
+pub fn server_name(host: &str) -> Result<ServerName<'static>, Error> {
+  ServerName::try_from(host.to_string()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+/// A tcp listener that wraps every accepted stream in a tls session.
+pub struct TlsListener {
+  pub listener: TcpListener,
+  config: Arc<ServerConfig>,
+}
+
+impl TlsListener {
+  pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> TlsListener {
+    TlsListener { listener, config }
+  }
+
+  pub fn accept(&self) -> Result<(TlsStream, SocketAddr), Error> {
+    let (stream, addr) = self.listener.accept()?;
+    Ok((TlsStream::server(stream, self.config.clone())?, addr))
+  }
+}
+
+/// A tcp stream with a rustls session on top. Reads and writes never block,
+/// the handshake is driven by whichever of them runs first.
+pub struct TlsStream {
+  pub stream: TcpStream,
+  session: Connection,
+}
+
+impl TlsStream {
+  pub fn client(
+    stream: TcpStream,
+    config: Arc<ClientConfig>,
+    name: ServerName<'static>,
+  ) -> Result<TlsStream, Error> {
+    let session =
+      ClientConnection::new(config, name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    Ok(TlsStream::new(stream, Connection::from(session)))
+  }
+
+  pub fn server(stream: TcpStream, config: Arc<ServerConfig>) -> Result<TlsStream, Error> {
+    let session =
+      ServerConnection::new(config).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    Ok(TlsStream::new(stream, Connection::from(session)))
+  }
+
+  fn new(stream: TcpStream, mut session: Connection) -> TlsStream {
+    session.set_buffer_limit(Some(TLS_BUFFER_LIMIT));
+    TlsStream { stream, session }
+  }
+
+  pub fn is_handshaking(&self) -> bool {
+    self.session.is_handshaking()
+  }
+
+  /// Sends close_notify and any records still waiting, then shuts down the
+  /// write side so the peer sees the session end cleanly.
+  pub fn close_notify(&mut self) -> Result<(), Error> {
+    self.session.send_close_notify();
+    self.flush_tls()?;
+    self.stream.shutdown(Shutdown::Write)
+  }
+
+  /// Sends any records rustls has ready until the socket would block.
+  pub fn flush_tls(&mut self) -> Result<(), Error> {
+    while self.session.wants_write() {
+      match self.session.write_tls(&mut self.stream) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Read for TlsStream {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    loop {
+      match self.session.reader().read(buf) {
+        Ok(n) => return Ok(n),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+        Err(e) => return Err(e),
+      }
+
+      if self.session.read_tls(&mut self.stream)? == 0 {
+        // Ok(0) after a close_notify, `UnexpectedEof` if the socket just
+        // closed.
+        return self.session.reader().read(buf);
+      }
+
+      if let Err(e) = self.session.process_new_packets() {
+        // Let the peer know why the handshake or session failed.
+        let _ = self.flush_tls();
+        return Err(Error::new(ErrorKind::InvalidData, e));
+      }
+      self.flush_tls()?;
+    }
+  }
+}
+
+impl Write for TlsStream {
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    self.flush_tls()?;
+    let n = self.session.writer().write(buf)?;
+    self.flush_tls()?;
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> Result<(), Error> {
+    self.flush_tls()
+  }
+}
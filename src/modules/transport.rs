@@ -0,0 +1,506 @@
+use std::{
+  io::{Error, ErrorKind, Read, Write},
+  net::SocketAddr,
+};
+
+#[cfg(unix)]
+use mio::net::{UnixDatagram, UnixListener, UnixStream};
+use mio::{
+  net::{TcpListener, TcpStream, UdpSocket},
+  Interest, Registry, Token,
+};
+
+#[cfg(feature = "tls")]
+use crate::modules::tls::{TlsListener, TlsStream};
+use crate::modules::{
+  memory::{MemoryListener, MemoryStream},
+  ConnectionType,
+};
+
+/// A way of moving bytes that the network can poll, read, write and accept
+/// connections on. Plug in your own with `ConnectionType::Custom`.
+///
+/// Transports backed by a mio source register it in `register`. Anything else
+/// leaves the registration methods alone and reports its readiness from
+/// `readiness`, which is checked on every poll. A transport that does neither
+/// is never read from or written to.
+pub trait Transport {
+  fn register(
+    &mut self,
+    _registry: &Registry,
+    _token: Token,
+    _interest: Interest,
+  ) -> Result<(), Error> {
+    Ok(())
+  }
+
+  fn reregister(
+    &mut self,
+    _registry: &Registry,
+    _token: Token,
+    _interest: Interest,
+  ) -> Result<(), Error> {
+    Ok(())
+  }
+
+  fn deregister(&mut self, _registry: &Registry) -> Result<(), Error> {
+    Ok(())
+  }
+
+  /// Same contract as a non-blocking socket: `WouldBlock` when there is
+  /// nothing to read, Ok(0) once the peer has closed.
+  fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Error> {
+    Err(Error::new(
+      ErrorKind::WouldBlock,
+      "transport has nothing to read",
+    ))
+  }
+
+  fn write(&mut self, _buf: &[u8]) -> Result<usize, Error> {
+    Err(Error::new(
+      ErrorKind::WouldBlock,
+      "transport can't be written to",
+    ))
+  }
+
+  /// Accepts a waiting connection along with the peer's address, `WouldBlock`
+  /// once there are none left.
+  fn accept(&mut self) -> Result<(ConnectionType, String), Error> {
+    Err(Error::new(
+      ErrorKind::WouldBlock,
+      "transport doesn't accept",
+    ))
+  }
+
+  /// Called when the network drops the connection.
+  fn close(&mut self) -> Result<(), Error> {
+    Ok(())
+  }
+
+  fn peer_addr(&self) -> Result<SocketAddr, Error> {
+    Err(Error::new(ErrorKind::NotConnected, ""))
+  }
+
+  fn local_addr(&self) -> Result<SocketAddr, Error> {
+    Err(Error::new(ErrorKind::NotConnected, ""))
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    Ok(None)
+  }
+
+  /// Ok(false) while a connect is still in progress.
+  fn is_connected(&self) -> Result<bool, Error> {
+    Ok(true)
+  }
+
+  /// True if every read and write is a whole packet.
+  fn is_datagram(&self) -> bool {
+    false
+  }
+
+  /// Readable and writable, for transports that aren't registered with mio.
+  fn readiness(&self) -> Option<(bool, bool)> {
+    None
+  }
+}
+
+impl Transport for TcpListener {
+  fn register(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.register(self, token, interest)
+  }
+
+  fn reregister(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.reregister(self, token, interest)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+    registry.deregister(self)
+  }
+
+  fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Error> {
+    let error = Error::new(ErrorKind::WouldBlock, "tcp listener would block");
+    let error2 = Error::new(ErrorKind::WouldBlock, "tcp listener would block");
+    Err(
+      TcpListener::take_error(self)
+        .unwrap_or(Some(error))
+        .unwrap_or(error2),
+    )
+  }
+
+  fn accept(&mut self) -> Result<(ConnectionType, String), Error> {
+    TcpListener::accept(self).map(|(s, addr)| (ConnectionType::from(s), addr.to_string()))
+  }
+
+  fn local_addr(&self) -> Result<SocketAddr, Error> {
+    TcpListener::local_addr(self)
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    TcpListener::take_error(self)
+  }
+}
+
+impl Transport for TcpStream {
+  fn register(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.register(self, token, interest)
+  }
+
+  fn reregister(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.reregister(self, token, interest)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+    registry.deregister(self)
+  }
+
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    Read::read(self, buf)
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    Write::write(self, buf)
+  }
+
+  fn peer_addr(&self) -> Result<SocketAddr, Error> {
+    TcpStream::peer_addr(self)
+  }
+
+  fn local_addr(&self) -> Result<SocketAddr, Error> {
+    TcpStream::local_addr(self)
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    TcpStream::take_error(self)
+  }
+
+  fn is_connected(&self) -> Result<bool, Error> {
+    match TcpStream::peer_addr(self) {
+      Ok(_) => Ok(true),
+      Err(ref e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+impl Transport for UdpSocket {
+  fn register(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.register(self, token, interest)
+  }
+
+  fn reregister(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.reregister(self, token, interest)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+    registry.deregister(self)
+  }
+
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    self.recv(buf)
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    self.send(buf)
+  }
+
+  fn peer_addr(&self) -> Result<SocketAddr, Error> {
+    UdpSocket::peer_addr(self)
+  }
+
+  fn local_addr(&self) -> Result<SocketAddr, Error> {
+    UdpSocket::local_addr(self)
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    UdpSocket::take_error(self)
+  }
+
+  fn is_datagram(&self) -> bool {
+    true
+  }
+}
+
+impl Transport for MemoryListener {
+  fn accept(&mut self) -> Result<(ConnectionType, String), Error> {
+    MemoryListener::accept(self).map(|s| (ConnectionType::from(s), self.name().to_string()))
+  }
+
+  fn readiness(&self) -> Option<(bool, bool)> {
+    Some((self.is_readable(), false))
+  }
+}
+
+impl Transport for MemoryStream {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    MemoryStream::read(self, buf)
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    MemoryStream::write(self, buf)
+  }
+
+  fn readiness(&self) -> Option<(bool, bool)> {
+    Some((self.is_readable(), true))
+  }
+}
+
+#[cfg(unix)]
+impl Transport for UnixListener {
+  fn register(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.register(self, token, interest)
+  }
+
+  fn reregister(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.reregister(self, token, interest)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+    registry.deregister(self)
+  }
+
+  fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Error> {
+    let error = Error::new(ErrorKind::WouldBlock, "unix listener would block");
+    let error2 = Error::new(ErrorKind::WouldBlock, "unix listener would block");
+    Err(
+      UnixListener::take_error(self)
+        .unwrap_or(Some(error))
+        .unwrap_or(error2),
+    )
+  }
+
+  fn accept(&mut self) -> Result<(ConnectionType, String), Error> {
+    UnixListener::accept(self).map(|(s, addr)| {
+      let addr = match addr.as_pathname() {
+        Some(path) => path.display().to_string(),
+        None => "unnamed".to_string(),
+      };
+      (ConnectionType::from(s), addr)
+    })
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    UnixListener::take_error(self)
+  }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+  fn register(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.register(self, token, interest)
+  }
+
+  fn reregister(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.reregister(self, token, interest)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+    registry.deregister(self)
+  }
+
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    Read::read(self, buf)
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    Write::write(self, buf)
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    UnixStream::take_error(self)
+  }
+
+  fn is_connected(&self) -> Result<bool, Error> {
+    match UnixStream::peer_addr(self) {
+      Ok(_) => Ok(true),
+      Err(ref e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+#[cfg(unix)]
+impl Transport for UnixDatagram {
+  fn register(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.register(self, token, interest)
+  }
+
+  fn reregister(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.reregister(self, token, interest)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+    registry.deregister(self)
+  }
+
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    self.recv(buf)
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    self.send(buf)
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    UnixDatagram::take_error(self)
+  }
+
+  fn is_datagram(&self) -> bool {
+    true
+  }
+}
+
+#[cfg(feature = "tls")]
+impl Transport for TlsListener {
+  fn register(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.register(&mut self.listener, token, interest)
+  }
+
+  fn reregister(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.reregister(&mut self.listener, token, interest)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+    registry.deregister(&mut self.listener)
+  }
+
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    Transport::read(&mut self.listener, buf)
+  }
+
+  fn accept(&mut self) -> Result<(ConnectionType, String), Error> {
+    TlsListener::accept(self)
+      .map(|(s, addr)| (ConnectionType::TlsStream(Box::new(s)), addr.to_string()))
+  }
+
+  fn local_addr(&self) -> Result<SocketAddr, Error> {
+    self.listener.local_addr()
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    self.listener.take_error()
+  }
+}
+
+#[cfg(feature = "tls")]
+impl Transport for TlsStream {
+  fn register(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.register(&mut self.stream, token, interest)
+  }
+
+  fn reregister(
+    &mut self,
+    registry: &Registry,
+    token: Token,
+    interest: Interest,
+  ) -> Result<(), Error> {
+    registry.reregister(&mut self.stream, token, interest)
+  }
+
+  fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+    registry.deregister(&mut self.stream)
+  }
+
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    Read::read(self, buf)
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    Write::write(self, buf)
+  }
+
+  fn close(&mut self) -> Result<(), Error> {
+    self.close_notify()
+  }
+
+  fn peer_addr(&self) -> Result<SocketAddr, Error> {
+    self.stream.peer_addr()
+  }
+
+  fn local_addr(&self) -> Result<SocketAddr, Error> {
+    self.stream.local_addr()
+  }
+
+  fn take_error(&self) -> Result<Option<Error>, Error> {
+    self.stream.take_error()
+  }
+
+  fn is_connected(&self) -> Result<bool, Error> {
+    Transport::is_connected(&self.stream)
+  }
+}
@@ -0,0 +1,384 @@
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::{
+  aead::{Aead, KeyInit, Payload},
+  ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// A handshake from a side that still needs the peer's, it gets one back.
+const HANDSHAKE_PACKET: u8 = 0;
+const DATA_PACKET: u8 = 1;
+/// A handshake from a side that has the peer's already, it isn't answered.
+const FINISHED_PACKET: u8 = 2;
+/// Packet kind followed by the little endian sequence number.
+const HEADER_LEN: usize = 9;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 16;
+/// Public key, our nonce and the last nonce seen from the peer, followed by a
+/// mac over them and the packet kind.
+const HANDSHAKE_LEN: usize = 32 + 2 * NONCE_LEN + 32;
+const KEY_INFO: &[u8] = b"maat-network udp session";
+const HANDSHAKE_INFO: &[u8] = b"maat-network udp handshake";
+
+/// Shared by both sides ahead of time, only peers that have it can complete
+/// the key exchange.
+pub type SessionKey = [u8; 32];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionError {
+  UnknownToken,
+  TokenClosed,
+  /// Only connected udp sockets can be encrypted.
+  NotConnectedUdp,
+}
+
+/// How far behind the newest sequence number a packet may arrive and still be
+/// accepted.
+pub const REPLAY_WINDOW: u64 = 64;
+/// How often the handshake is sent again while waiting on the peer's.
+pub const HANDSHAKE_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+
+pub enum Opened {
+  /// A data packet that decrypted and hasn't been seen before.
+  Data(Vec<u8>),
+  /// The peer's handshake, with ours to send back if the peer still needs it.
+  Handshake(Option<Vec<u8>>),
+  /// Not a valid packet for this session, it should be dropped.
+  Rejected,
+}
+
+/// One side of an encrypted udp session. Both sides send an x25519 public key
+/// and a random nonce with a mac from the shared `SessionKey`, then every
+/// packet is sealed with ChaCha20-Poly1305 using a key per direction and a
+/// nonce built from the packet's sequence number. The session keys are derived
+/// from the shared key too, so a peer without it can neither read nor forge
+/// packets.
+///
+/// A peer's key is only taken from a handshake that echoes our nonce, so
+/// handshakes replayed from other sessions are answered but change nothing.
+/// A later one with a new key replaces the peer, for peers that restart.
+pub struct UdpSession {
+  key: SessionKey,
+  secret: StaticSecret,
+  public: PublicKey,
+  nonce: [u8; NONCE_LEN],
+  peer: Option<PublicKey>,
+  /// Echoed in our handshakes, zeros until the peer's first arrives.
+  peer_nonce: [u8; NONCE_LEN],
+  keys: Option<SessionKeys>,
+  send_sequence: u64,
+  replay: ReplayWindow,
+  rejected: usize,
+  started: Instant,
+  last_handshake: Option<Instant>,
+}
+
+struct SessionKeys {
+  send: ChaCha20Poly1305,
+  recv: ChaCha20Poly1305,
+}
+
+impl UdpSession {
+  pub fn new(key: &SessionKey) -> UdpSession {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+
+    UdpSession {
+      key: *key,
+      secret,
+      public,
+      nonce: random_nonce(),
+      peer: None,
+      peer_nonce: [0; NONCE_LEN],
+      keys: None,
+      send_sequence: 0,
+      replay: ReplayWindow::default(),
+      rejected: 0,
+      started: Instant::now(),
+      last_handshake: None,
+    }
+  }
+
+  pub fn handshake_packet(&self) -> Vec<u8> {
+    let kind = if self.is_established() {
+      FINISHED_PACKET
+    } else {
+      HANDSHAKE_PACKET
+    };
+    self.handshake(kind, &self.peer_nonce)
+  }
+
+  fn handshake(&self, kind: u8, echo: &[u8; NONCE_LEN]) -> Vec<u8> {
+    let mac = handshake_mac(&self.key, kind, &self.public, &self.nonce, echo)
+      .finalize()
+      .into_bytes();
+    [
+      &[kind],
+      self.public.as_bytes().as_slice(),
+      self.nonce.as_slice(),
+      echo.as_slice(),
+      mac.as_slice(),
+    ]
+    .concat()
+  }
+
+  pub fn is_established(&self) -> bool {
+    self.keys.is_some()
+  }
+
+  /// Packets dropped because they were malformed, forged or replayed.
+  pub fn rejected_packets(&self) -> usize {
+    self.rejected
+  }
+
+  /// The handshake to send if the session is still waiting on the peer and
+  /// the last one went out long enough ago.
+  pub fn handshake_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+    if self.is_established()
+      || self
+        .last_handshake
+        .is_some_and(|sent| now.duration_since(sent) < HANDSHAKE_RESEND_INTERVAL)
+    {
+      return None;
+    }
+
+    self.last_handshake = Some(now);
+    Some(self.handshake_packet())
+  }
+
+  /// True if the peer hasn't completed the key exchange within `timeout`.
+  pub fn timed_out(&self, now: Instant, timeout: Duration) -> bool {
+    !self.is_established() && now.duration_since(self.started) >= timeout
+  }
+
+  pub fn open(&mut self, packet: &[u8]) -> Opened {
+    let opened = match packet.first() {
+      Some(&kind @ (HANDSHAKE_PACKET | FINISHED_PACKET)) => {
+        self.accept_handshake(kind, &packet[1..])
+      }
+      Some(&DATA_PACKET) => self.decrypt(packet),
+      _ => None,
+    };
+
+    opened.unwrap_or_else(|| {
+      self.rejected += 1;
+      Opened::Rejected
+    })
+  }
+
+  /// Encrypts `data` into a packet with the next sequence number, panics if
+  /// the key exchange hasn't finished.
+  pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+    let keys = self
+      .keys
+      .as_ref()
+      .expect("UdpSession: sealing before the key exchange finished");
+
+    let sequence = self.send_sequence;
+    self.send_sequence = sequence
+      .checked_add(1)
+      .expect("UdpSession: ran out of sequence numbers");
+
+    let mut packet = header(sequence).to_vec();
+    let ciphertext = keys
+      .send
+      .encrypt(
+        &nonce(sequence),
+        Payload {
+          msg: data,
+          aad: &packet,
+        },
+      )
+      .expect("UdpSession: encrypting packet");
+    packet.extend(ciphertext);
+    packet
+  }
+
+  fn accept_handshake(&mut self, kind: u8, handshake: &[u8]) -> Option<Opened> {
+    if handshake.len() != HANDSHAKE_LEN {
+      return None;
+    }
+    let (key, rest) = handshake.split_at(32);
+    let (nonce, rest) = rest.split_at(NONCE_LEN);
+    let (echo, mac) = rest.split_at(NONCE_LEN);
+    let theirs = PublicKey::from(<[u8; 32]>::try_from(key).ok()?);
+    let nonce = <[u8; NONCE_LEN]>::try_from(nonce).ok()?;
+    handshake_mac(&self.key, kind, &theirs, &nonce, echo)
+      .verify_slice(mac)
+      .ok()?;
+    // A reflected copy of our own key would give both directions the same key.
+    if theirs == self.public {
+      return None;
+    }
+    self.peer_nonce = nonce;
+
+    if echo == self.nonce && self.peer != Some(theirs) {
+      let keys = derive_keys(&self.key, &self.secret, &self.public, &theirs)?;
+      if self.peer.is_some() {
+        // Handshakes from the old key echo the old nonce, so they can't switch
+        // back to it.
+        self.nonce = random_nonce();
+      }
+      self.keys = Some(keys);
+      self.peer = Some(theirs);
+      self.send_sequence = 0;
+      self.replay = ReplayWindow::default();
+    }
+
+    // Answered while either side is still missing the other's key.
+    let finished = self.peer == Some(theirs);
+    let reply = (kind == HANDSHAKE_PACKET || !finished).then(|| {
+      let kind = if finished {
+        FINISHED_PACKET
+      } else {
+        HANDSHAKE_PACKET
+      };
+      self.handshake(kind, &nonce)
+    });
+    Some(Opened::Handshake(reply))
+  }
+
+  fn decrypt(&mut self, packet: &[u8]) -> Option<Opened> {
+    let keys = self.keys.as_ref()?;
+    if packet.len() < HEADER_LEN + TAG_LEN {
+      return None;
+    }
+
+    let (aad, ciphertext) = packet.split_at(HEADER_LEN);
+    let sequence = u64::from_le_bytes(aad[1..].try_into().ok()?);
+    if !self.replay.is_fresh(sequence) {
+      return None;
+    }
+
+    let data = keys
+      .recv
+      .decrypt(
+        &nonce(sequence),
+        Payload {
+          msg: ciphertext,
+          aad,
+        },
+      )
+      .ok()?;
+    self.replay.mark(sequence);
+    Some(Opened::Data(data))
+  }
+}
+
+fn handshake_mac(
+  key: &SessionKey,
+  kind: u8,
+  public: &PublicKey,
+  nonce: &[u8],
+  echo: &[u8],
+) -> Hmac<Sha256> {
+  let mut mac =
+    <Hmac<Sha256> as Mac>::new_from_slice(key).expect("UdpSession: hmac takes keys of any length");
+  mac.update(HANDSHAKE_INFO);
+  mac.update(&[kind]);
+  mac.update(public.as_bytes());
+  mac.update(nonce);
+  mac.update(echo);
+  mac
+}
+
+fn random_nonce() -> [u8; NONCE_LEN] {
+  let mut nonce = [0; NONCE_LEN];
+  OsRng.fill_bytes(&mut nonce);
+  nonce
+}
+
+/// Derives one key per direction from the exchange and the shared key, the
+/// side with the lower public key sends with the first one.
+fn derive_keys(
+  key: &SessionKey,
+  secret: &StaticSecret,
+  ours: &PublicKey,
+  theirs: &PublicKey,
+) -> Option<SessionKeys> {
+  let shared = secret.diffie_hellman(theirs);
+  if !shared.was_contributory() {
+    return None;
+  }
+
+  let ours_is_low = ours.as_bytes() < theirs.as_bytes();
+  let (low, high) = if ours_is_low {
+    (ours, theirs)
+  } else {
+    (theirs, ours)
+  };
+  let info = [KEY_INFO, low.as_bytes(), high.as_bytes()].concat();
+
+  let mut okm = [0; 64];
+  Hkdf::<Sha256>::new(Some(key), shared.as_bytes())
+    .expand(&info, &mut okm)
+    .expect("UdpSession: 64 bytes is a valid hkdf output length");
+
+  let (low_key, high_key) = okm.split_at(32);
+  let (send, recv) = if ours_is_low {
+    (low_key, high_key)
+  } else {
+    (high_key, low_key)
+  };
+
+  Some(SessionKeys {
+    send: ChaCha20Poly1305::new(Key::from_slice(send)),
+    recv: ChaCha20Poly1305::new(Key::from_slice(recv)),
+  })
+}
+
+fn header(sequence: u64) -> [u8; HEADER_LEN] {
+  let mut header = [DATA_PACKET; HEADER_LEN];
+  header[1..].copy_from_slice(&sequence.to_le_bytes());
+  header
+}
+
+fn nonce(sequence: u64) -> Nonce {
+  let mut nonce = [0; 12];
+  nonce[4..].copy_from_slice(&sequence.to_le_bytes());
+  Nonce::from(nonce)
+}
+
+/// Sequence numbers seen within `REPLAY_WINDOW` of the newest one, bit `n` is
+/// `newest - n`.
+#[derive(Default)]
+struct ReplayWindow {
+  newest: Option<u64>,
+  seen: u64,
+}
+
+impl ReplayWindow {
+  fn is_fresh(&self, sequence: u64) -> bool {
+    match self.newest {
+      Some(newest) if sequence <= newest => {
+        let age = newest - sequence;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+      }
+      _ => true,
+    }
+  }
+
+  fn mark(&mut self, sequence: u64) {
+    match self.newest {
+      Some(newest) if sequence <= newest => self.seen |= 1 << (newest - sequence),
+      Some(newest) => {
+        let shift = sequence - newest;
+        self.seen = if shift >= REPLAY_WINDOW {
+          1
+        } else {
+          (self.seen << shift) | 1
+        };
+        self.newest = Some(sequence);
+      }
+      None => {
+        self.seen = 1;
+        self.newest = Some(sequence);
+      }
+    }
+  }
+}
@@ -0,0 +1,239 @@
+use std::time::{Duration, Instant};
+
+/// Every version packet starts with these bytes, so peers that speak
+/// something else are told apart from ones on another version.
+pub const VERSION_MAGIC: [u8; 4] = *b"MAAT";
+/// How often a udp hello is sent again while the peer hasn't answered.
+pub const HELLO_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+
+const HELLO: u8 = 0;
+const ACCEPT: u8 = 1;
+const REJECT: u8 = 2;
+/// Magic and packet kind.
+const HEADER_BYTES: usize = 5;
+/// Header, then the little endian protocol id, major and minor version.
+const HELLO_BYTES: usize = HEADER_BYTES + 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ProtocolVersion {
+  /// Tells apart applications, peers with a different id are always rejected.
+  pub protocol_id: u32,
+  pub major: u16,
+  pub minor: u16,
+}
+
+impl ProtocolVersion {
+  pub const fn new(protocol_id: u32, major: u16, minor: u16) -> ProtocolVersion {
+    ProtocolVersion {
+      protocol_id,
+      major,
+      minor,
+    }
+  }
+}
+
+/// Which peer versions a connection accepts, once the protocol ids match.
+#[derive(Clone, Copy, Debug)]
+pub enum VersionPolicy {
+  Exact,
+  /// Any minor version of the same major version.
+  SameMajor,
+  /// Called with our version and the peer's.
+  Custom(fn(ProtocolVersion, ProtocolVersion) -> bool),
+}
+
+impl VersionPolicy {
+  pub fn accepts(self, local: ProtocolVersion, peer: ProtocolVersion) -> bool {
+    local.protocol_id == peer.protocol_id
+      && match self {
+        VersionPolicy::Exact => local == peer,
+        VersionPolicy::SameMajor => local.major == peer.major,
+        VersionPolicy::Custom(accepts) => accepts(local, peer),
+      }
+  }
+}
+
+pub enum Negotiation {
+  /// Still waiting on the peer's hello or its answer to ours.
+  Pending,
+  /// Both sides accepted, with whatever was read after the version packets.
+  Done(Vec<Vec<u8>>),
+  /// One side rejected the other, or the peer doesn't speak the protocol.
+  Mismatch,
+}
+
+/// One side of the version exchange. Both sides send a hello with their
+/// version, answer the peer's with an accept or a reject, and the connection
+/// is up once each has accepted the other.
+pub struct VersionHandshake {
+  local: ProtocolVersion,
+  policy: VersionPolicy,
+  datagram: bool,
+  peer: Option<ProtocolVersion>,
+  accepted_by_peer: bool,
+  buffer: Vec<u8>,
+  replies: Vec<Vec<u8>>,
+  last_hello: Option<Instant>,
+}
+
+impl VersionHandshake {
+  /// Datagram connections check every packet on its own and resend their
+  /// hello until answered.
+  pub fn new(local: ProtocolVersion, policy: VersionPolicy, datagram: bool) -> VersionHandshake {
+    VersionHandshake {
+      local,
+      policy,
+      datagram,
+      peer: None,
+      accepted_by_peer: false,
+      buffer: Vec::new(),
+      replies: Vec::new(),
+      last_hello: None,
+    }
+  }
+
+  pub fn is_done(&self) -> bool {
+    self.accepted_by_peer && self.peer.is_some()
+  }
+
+  /// The version from the peer's hello, once it arrived.
+  pub fn peer_version(&self) -> Option<ProtocolVersion> {
+    self.peer
+  }
+
+  pub fn hello_packet(&self) -> Vec<u8> {
+    let mut packet = version_packet(HELLO);
+    packet.extend(self.local.protocol_id.to_le_bytes());
+    packet.extend(self.local.major.to_le_bytes());
+    packet.extend(self.local.minor.to_le_bytes());
+    packet
+  }
+
+  /// The hello to send if it hasn't gone out yet, or for datagrams if the
+  /// peer hasn't answered the last one in time.
+  pub fn hello_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+    let waiting = match self.last_hello {
+      None => true,
+      Some(sent) => {
+        self.datagram && !self.is_done() && now.duration_since(sent) >= HELLO_RESEND_INTERVAL
+      }
+    };
+    if !waiting {
+      return None;
+    }
+
+    self.last_hello = Some(now);
+    Some(self.hello_packet())
+  }
+
+  /// Accepts and rejects to write back to the peer, in order.
+  pub fn take_replies(&mut self) -> Vec<Vec<u8>> {
+    self.replies.drain(..).collect()
+  }
+
+  /// Reads bytes from a stream, version packets may be split across reads.
+  pub fn read_stream(&mut self, data: &[u8]) -> Negotiation {
+    self.buffer.extend(data);
+
+    while !self.is_done() {
+      let known = self.buffer.len().min(VERSION_MAGIC.len());
+      if self.buffer[..known] != VERSION_MAGIC[..known] {
+        return Negotiation::Mismatch;
+      }
+      let length = match self.buffer.get(VERSION_MAGIC.len()) {
+        Some(&HELLO) => HELLO_BYTES,
+        Some(_) => HEADER_BYTES,
+        None => return Negotiation::Pending,
+      };
+      if self.buffer.len() < length {
+        return Negotiation::Pending;
+      }
+
+      let packet = self.buffer.drain(..length).collect::<Vec<u8>>();
+      if !self.read_packet(&packet) {
+        return Negotiation::Mismatch;
+      }
+    }
+
+    let rest = self.buffer.drain(..).collect::<Vec<u8>>();
+    Negotiation::Done(if rest.is_empty() {
+      Vec::new()
+    } else {
+      vec![rest]
+    })
+  }
+
+  /// Reads whole datagrams. Other packets before the peer's hello mean it
+  /// doesn't speak the protocol, after it they were reordered and are dropped.
+  pub fn read_datagrams(&mut self, datagrams: Vec<Vec<u8>>) -> Negotiation {
+    let mut datagrams = datagrams.into_iter();
+
+    for datagram in datagrams.by_ref() {
+      if !is_version_datagram(&datagram) {
+        if self.peer.is_none() {
+          return Negotiation::Mismatch;
+        }
+      } else if !self.read_packet(&datagram) {
+        return Negotiation::Mismatch;
+      }
+
+      if self.is_done() {
+        return Negotiation::Done(datagrams.collect());
+      }
+    }
+
+    Negotiation::Pending
+  }
+
+  /// After the exchange, answers a hello the peer sent again because our
+  /// accept was lost, and drops other repeated version packets. Returns false
+  /// for datagrams that aren't version packets.
+  pub fn read_repeated(&mut self, datagram: &[u8]) -> bool {
+    if !is_version_datagram(datagram) {
+      return false;
+    }
+    if datagram[VERSION_MAGIC.len()] == HELLO {
+      self.read_packet(datagram);
+    }
+    true
+  }
+
+  /// Handles one whole version packet, false on a mismatch.
+  fn read_packet(&mut self, packet: &[u8]) -> bool {
+    match packet[VERSION_MAGIC.len()] {
+      HELLO => {
+        let field = |at: usize, len: usize| &packet[HEADER_BYTES + at..HEADER_BYTES + at + len];
+        let peer = ProtocolVersion::new(
+          u32::from_le_bytes(field(0, 4).try_into().unwrap()),
+          u16::from_le_bytes(field(4, 2).try_into().unwrap()),
+          u16::from_le_bytes(field(6, 2).try_into().unwrap()),
+        );
+        self.peer = Some(peer);
+
+        let accepted = self.policy.accepts(self.local, peer);
+        self
+          .replies
+          .push(version_packet(if accepted { ACCEPT } else { REJECT }));
+        accepted
+      }
+      ACCEPT => {
+        self.accepted_by_peer = true;
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+fn is_version_datagram(datagram: &[u8]) -> bool {
+  datagram.starts_with(&VERSION_MAGIC)
+    && match datagram.get(VERSION_MAGIC.len()) {
+      Some(&HELLO) => datagram.len() == HELLO_BYTES,
+      Some(_) => datagram.len() == HEADER_BYTES,
+      None => false,
+    }
+}
+
+fn version_packet(kind: u8) -> Vec<u8> {
+  [&VERSION_MAGIC[..], &[kind]].concat()
+}
@@ -1,29 +1,19 @@
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
 
-use mio::Interest;
+use crate::modules::ConnectionType;
 
-use crate::modules::{ConnectionType, EventHandler};
-
-pub fn write_data(connection: &mut ConnectionType, data: &[u8]) -> bool {
-  println!("writing data");
+/// Writes as much of `data` as the connection accepts and returns the number of
+/// bytes written, 0 if the connection would block.
+pub fn write_data(connection: &mut ConnectionType, data: &[u8]) -> io::Result<usize> {
   match connection.write(data) {
-    Ok(n) if n < data.len() => {
-      dbg!(ErrorKind::WriteZero);
-      todo!()
-    }
-    Ok(_) => {
-      // reregister to read
-      true
-    }
-    Err(ref err) if err.kind() == ErrorKind::WouldBlock => false,
+    Ok(n) => Ok(n),
+    Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
     Err(ref err) if err.kind() == ErrorKind::Interrupted => write_data(connection, data),
-    Err(e) => {
-      panic!("Write Functions: Error writting: {}", e);
-    }
+    Err(e) => Err(e),
   }
 }
 
-pub fn empty_write(connection: &mut ConnectionType, data: &mut Vec<u8>) {}
+pub fn empty_write(_connection: &mut ConnectionType, _data: &mut [u8]) {}
 
 pub fn write_ones(connection: &mut ConnectionType, data: &mut Vec<u8>) {
   data.append(&mut [1, 2, 3, 4, 5, 6].to_vec());
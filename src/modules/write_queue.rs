@@ -0,0 +1,294 @@
+use std::{
+  collections::VecDeque,
+  io,
+  time::{Duration, Instant},
+};
+
+use crate::modules::{write_functions::write_data, ConnectionType};
+
+pub const DEFAULT_MAX_QUEUED_BYTES: usize = 4 * 1024 * 1024;
+pub const DEFAULT_LOW_WATER_MARK: usize = 1024 * 1024;
+/// How many times queued data can be passed over for data with a higher
+/// priority before it is written next.
+pub const PRIORITY_AGING_TURNS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+  /// Writes that would go over the limit are rejected with `WriteError::QueueFull`.
+  Reject,
+  /// Writes are rejected like `Reject`, and the connection is dropped if the
+  /// queue stays over the limit for longer than the given duration.
+  Disconnect(Duration),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteLimits {
+  pub max_queued_bytes: usize,
+  pub low_water_mark: usize,
+  pub policy: OverflowPolicy,
+}
+
+impl WriteLimits {
+  pub fn new(max_queued_bytes: usize, low_water_mark: usize) -> WriteLimits {
+    WriteLimits {
+      max_queued_bytes,
+      low_water_mark: low_water_mark.min(max_queued_bytes),
+      policy: OverflowPolicy::Reject,
+    }
+  }
+
+  pub fn unlimited() -> WriteLimits {
+    WriteLimits::new(usize::MAX, usize::MAX)
+  }
+
+  pub fn with_policy(mut self, policy: OverflowPolicy) -> WriteLimits {
+    self.policy = policy;
+    self
+  }
+}
+
+impl Default for WriteLimits {
+  fn default() -> WriteLimits {
+    WriteLimits::new(DEFAULT_MAX_QUEUED_BYTES, DEFAULT_LOW_WATER_MARK)
+  }
+}
+
+/// Which queued data is written first. Data with the same priority is written
+/// in order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+  Low,
+  #[default]
+  Normal,
+  High,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteError {
+  /// The write queue for the token already holds `queued` bytes and accepting
+  /// the data would take it over `limit`.
+  QueueFull { queued: usize, limit: usize },
+  /// The token was never handed out by the network.
+  UnknownToken,
+  /// The connection for the token has been closed.
+  TokenClosed,
+  /// The sub-stream isn't open, or is being closed.
+  StreamClosed,
+  /// The sub-stream is already open, or still being closed.
+  StreamOpen,
+  /// `enable_streams` hasn't been called for the token.
+  StreamsDisabled,
+  /// `enable_rpc` hasn't been called for the token.
+  RpcDisabled,
+}
+
+/// Queued data, one queue per priority. The highest priority queue is written
+/// from first, but a queue that has been passed over `PRIORITY_AGING_TURNS`
+/// times goes next so low priority data still flows. Data that was partly
+/// written is always finished first.
+pub struct WriteQueue {
+  limits: WriteLimits,
+  queues: [VecDeque<Vec<u8>>; 3],
+  /// Turns the front of each queue has been passed over.
+  waited: [usize; 3],
+  /// The queue whose front has been partly written.
+  partial: Option<usize>,
+  queued_bytes: usize,
+  backpressured: bool,
+  over_limit_since: Option<Instant>,
+}
+
+impl WriteQueue {
+  pub fn new(limits: WriteLimits) -> WriteQueue {
+    WriteQueue {
+      limits,
+      queues: Default::default(),
+      waited: [0; 3],
+      partial: None,
+      queued_bytes: 0,
+      backpressured: false,
+      over_limit_since: None,
+    }
+  }
+
+  pub fn limits(&self) -> WriteLimits {
+    self.limits
+  }
+
+  pub fn set_limits(&mut self, limits: WriteLimits) {
+    self.limits = limits;
+  }
+
+  pub fn queued_bytes(&self) -> usize {
+    self.queued_bytes
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.queues.iter().all(|queue| queue.is_empty())
+  }
+
+  pub fn push(&mut self, data: &[u8]) -> Result<(), WriteError> {
+    self.push_with_priority(data, Priority::Normal)
+  }
+
+  /// Empty data is dropped, there is nothing to write and a write of it
+  /// would look like a connection that isn't ready.
+  pub fn push_with_priority(&mut self, data: &[u8], priority: Priority) -> Result<(), WriteError> {
+    if data.is_empty() {
+      return Ok(());
+    }
+    self.check_limit(data.len())?;
+    self.queued_bytes += data.len();
+    self.queues[priority as usize].push_back(data.to_vec());
+    Ok(())
+  }
+
+  /// Queues all of the chunks, or none of them if together they don't fit.
+  pub fn push_all(&mut self, chunks: Vec<Vec<u8>>) -> Result<(), WriteError> {
+    self.check_limit(chunks.iter().map(|chunk| chunk.len()).sum())?;
+    for chunk in chunks.into_iter().filter(|chunk| !chunk.is_empty()) {
+      self.queued_bytes += chunk.len();
+      self.queues[Priority::Normal as usize].push_back(chunk);
+    }
+    Ok(())
+  }
+
+  /// Queues data without checking the limits, for data that was already
+  /// checked against another queue's.
+  pub fn force_push(&mut self, data: &[u8]) {
+    if data.is_empty() {
+      return;
+    }
+    self.queued_bytes += data.len();
+    self.queues[Priority::Normal as usize].push_back(data.to_vec());
+  }
+
+  /// Takes the next chunk to write off the queue.
+  pub fn pop(&mut self) -> Option<Vec<u8>> {
+    let level = self.next_queue()?;
+    let data = self.queues[level].pop_front()?;
+    self.queued_bytes -= data.len();
+    self.served(level);
+    Some(data)
+  }
+
+  /// The queue to write from next.
+  fn next_queue(&self) -> Option<usize> {
+    if self.partial.is_some() {
+      return self.partial;
+    }
+
+    let mut waiting = (0..self.queues.len())
+      .rev()
+      .filter(|level| !self.queues[*level].is_empty());
+    waiting
+      .clone()
+      .find(|level| self.waited[*level] >= PRIORITY_AGING_TURNS)
+      .or_else(|| waiting.next())
+  }
+
+  /// The front of `level` was written, every other queue with data waited.
+  fn served(&mut self, level: usize) {
+    self.partial = None;
+    for other in 0..self.queues.len() {
+      if other == level || self.queues[other].is_empty() {
+        self.waited[other] = 0;
+      } else {
+        self.waited[other] += 1;
+      }
+    }
+  }
+
+  fn check_limit(&mut self, len: usize) -> Result<(), WriteError> {
+    if self.queued_bytes.saturating_add(len) > self.limits.max_queued_bytes {
+      self.backpressured = true;
+      if self.over_limit_since.is_none() {
+        self.over_limit_since = Some(Instant::now());
+      }
+      return Err(WriteError::QueueFull {
+        queued: self.queued_bytes,
+        limit: self.limits.max_queued_bytes,
+      });
+    }
+    Ok(())
+  }
+
+  /// Writes as much queued data as the connection accepts, returns true if any
+  /// bytes were written.
+  pub fn flush(&mut self, connection: &mut ConnectionType) -> io::Result<bool> {
+    self.flush_with(|data| write_data(connection, data))
+  }
+
+  /// Like `flush`, but hands the data to `write`, which returns the number of
+  /// bytes it took.
+  pub fn flush_with<F>(&mut self, mut write: F) -> io::Result<bool>
+  where
+    F: FnMut(&[u8]) -> io::Result<usize>,
+  {
+    let mut did_write = false;
+
+    while let Some(level) = self.next_queue() {
+      let data = self.queues[level].front_mut().unwrap();
+      let written = write(data)?;
+      if written == 0 {
+        break;
+      }
+
+      did_write = true;
+      self.queued_bytes -= written;
+      if written < data.len() {
+        data.drain(..written);
+        self.partial = Some(level);
+        break;
+      }
+      self.queues[level].pop_front();
+      self.served(level);
+    }
+
+    Ok(did_write)
+  }
+
+  /// Hands each queued chunk to `send` as a single datagram, `send` returns
+  /// false if it couldn't be sent yet. Returns true if any were sent.
+  pub fn flush_datagrams<F>(&mut self, mut send: F) -> io::Result<bool>
+  where
+    F: FnMut(&[u8]) -> io::Result<bool>,
+  {
+    let mut did_write = false;
+
+    while let Some(level) = self.next_queue() {
+      let data = self.queues[level].front().unwrap();
+      if !send(data)? {
+        break;
+      }
+
+      did_write = true;
+      self.queued_bytes -= data.len();
+      self.queues[level].pop_front();
+      self.served(level);
+    }
+
+    Ok(did_write)
+  }
+
+  /// Returns true once after a rejected write, as soon as the queue has drained
+  /// below the low-water mark.
+  pub fn take_drained(&mut self) -> bool {
+    if self.backpressured && self.queued_bytes <= self.limits.low_water_mark {
+      self.backpressured = false;
+      self.over_limit_since = None;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// True if the queue has stayed full, without draining to the low-water mark,
+  /// for longer than the disconnect policy allows.
+  pub fn overflow_expired(&self, now: Instant) -> bool {
+    match (self.limits.policy, self.over_limit_since) {
+      (OverflowPolicy::Disconnect(grace), Some(since)) => now.duration_since(since) >= grace,
+      _ => false,
+    }
+  }
+}
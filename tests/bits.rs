@@ -0,0 +1,363 @@
+#[cfg(feature = "messages")]
+mod common;
+
+#[cfg(feature = "messages")]
+use common::Harness;
+use maat_network::{
+  bits::{self, bits_for},
+  BitError, BitPack, BitReader, BitWriter,
+};
+#[cfg(feature = "messages")]
+use maat_network::{Message, MessageError};
+
+#[derive(Clone, Debug, PartialEq)]
+struct PlayerState {
+  health: u8,
+  rotation: f32,
+  position: (f32, f32),
+  crouching: bool,
+  score: u64,
+  target: Option<u16>,
+}
+
+impl BitPack for PlayerState {
+  fn pack(&self, writer: &mut BitWriter) {
+    writer.write_ranged(self.health as i64, 0, 100);
+    writer.write_float(self.rotation, 0.0, 360.0, 10);
+    writer.write_float(self.position.0, -512.0, 512.0, 16);
+    writer.write_float(self.position.1, -512.0, 512.0, 16);
+    writer.write_bool(self.crouching);
+    writer.write_varint(self.score);
+    self.target.pack(writer);
+  }
+
+  fn unpack(reader: &mut BitReader) -> Result<PlayerState, BitError> {
+    Ok(PlayerState {
+      health: reader.read_ranged(0, 100)? as u8,
+      rotation: reader.read_float(0.0, 360.0, 10)?,
+      position: (
+        reader.read_float(-512.0, 512.0, 16)?,
+        reader.read_float(-512.0, 512.0, 16)?,
+      ),
+      crouching: reader.read_bool()?,
+      score: reader.read_varint()?,
+      target: Option::unpack(reader)?,
+    })
+  }
+}
+
+fn player(health: u8) -> PlayerState {
+  PlayerState {
+    health,
+    rotation: 90.0,
+    position: (-12.5, 256.0),
+    crouching: true,
+    score: 1_000,
+    target: Some(7),
+  }
+}
+
+/// Values spread over every width up to 64 bits, edges included.
+fn samples(bits: u8) -> Vec<u64> {
+  let max = if bits == 64 {
+    u64::MAX
+  } else {
+    (1 << bits) - 1
+  };
+  let mut values = vec![0, 1, max, max / 2, max / 3, max - 1];
+  values.retain(|v| *v <= max);
+  values
+}
+
+#[test]
+fn every_width_round_trips() {
+  let mut writer = BitWriter::new();
+  for bits in 1..=64 {
+    samples(bits)
+      .iter()
+      .for_each(|v| writer.write_bits(*v, bits));
+  }
+  let expected = writer.bits_written();
+  let data = writer.finish();
+  assert_eq!(data.len(), expected.div_ceil(8));
+
+  let mut reader = BitReader::new(&data);
+  for bits in 1..=64 {
+    for value in samples(bits) {
+      assert_eq!(reader.read_bits(bits), Ok(value), "{} bits", bits);
+    }
+  }
+  assert_eq!(reader.bits_read(), expected);
+  assert!(reader.bits_remaining() < 8);
+}
+
+#[test]
+fn every_value_of_small_ranges_round_trips() {
+  for (min, max) in [(0, 1), (0, 100), (-1, 1), (-512, 511), (10, 10), (-3, 1000)] {
+    let mut writer = BitWriter::new();
+    (min..=max).for_each(|v| writer.write_ranged(v, min, max));
+    let bits = bits_for((max - min) as u64) as usize;
+    assert_eq!(writer.bits_written(), bits * (max - min + 1) as usize);
+
+    let data = writer.finish();
+    let mut reader = BitReader::new(&data);
+    for value in min..=max {
+      assert_eq!(reader.read_ranged(min, max), Ok(value));
+    }
+  }
+}
+
+#[test]
+fn ranges_take_as_few_bits_as_they_need() {
+  assert_eq!(bits_for(0), 0);
+  assert_eq!(bits_for(1), 1);
+  assert_eq!(bits_for(100), 7);
+  assert_eq!(bits_for(1023), 10);
+  assert_eq!(bits_for(1024), 11);
+  assert_eq!(bits_for(u64::MAX), 64);
+
+  let mut writer = BitWriter::new();
+  writer.write_ranged(i64::MIN, i64::MIN, i64::MAX);
+  writer.write_ranged(i64::MAX, i64::MIN, i64::MAX);
+  assert_eq!(writer.bits_written(), 128);
+  let data = writer.finish();
+  let mut reader = BitReader::new(&data);
+  assert_eq!(reader.read_ranged(i64::MIN, i64::MAX), Ok(i64::MIN));
+  assert_eq!(reader.read_ranged(i64::MIN, i64::MAX), Ok(i64::MAX));
+}
+
+#[test]
+fn quantized_floats_stay_within_a_step() {
+  for bits in 1..=24 {
+    let (min, max) = (-100.0f32, 250.0f32);
+    let step = (max - min) as f64 / ((1u64 << bits) - 1) as f64;
+    let values = (0..=200)
+      .map(|i| min + (max - min) * i as f32 / 200.0)
+      .collect::<Vec<f32>>();
+
+    let mut writer = BitWriter::new();
+    values
+      .iter()
+      .for_each(|v| writer.write_float(*v, min, max, bits));
+    assert_eq!(writer.bits_written(), bits as usize * values.len());
+
+    let data = writer.finish();
+    let mut reader = BitReader::new(&data);
+    for value in values {
+      let read = reader.read_float(min, max, bits).unwrap();
+      assert!(
+        ((read - value) as f64).abs() <= step / 2.0 + 1e-4,
+        "{} read back as {} with {} bits",
+        value,
+        read,
+        bits
+      );
+    }
+  }
+}
+
+#[test]
+fn floats_are_clamped_and_hit_the_ends_exactly() {
+  let mut writer = BitWriter::new();
+  [-5.0, 0.0, 360.0, 1000.0]
+    .iter()
+    .for_each(|v| writer.write_float(*v, 0.0, 360.0, 10));
+  let data = writer.finish();
+  let mut reader = BitReader::new(&data);
+  let read = (0..4)
+    .map(|_| reader.read_float(0.0, 360.0, 10).unwrap())
+    .collect::<Vec<f32>>();
+  assert_eq!(read, vec![0.0, 0.0, 360.0, 360.0]);
+}
+
+#[test]
+fn varints_round_trip_at_every_group_boundary() {
+  let mut values = vec![0, 1, u64::MAX];
+  for shift in (7..64).step_by(7) {
+    values.extend([(1 << shift) - 1, 1 << shift, (1 << shift) + 1]);
+  }
+  let signed = values
+    .iter()
+    .flat_map(|v| [*v as i64, (*v as i64).wrapping_neg(), (*v >> 1) as i64])
+    .chain([i64::MIN, i64::MAX])
+    .collect::<Vec<i64>>();
+
+  let mut writer = BitWriter::new();
+  values.iter().for_each(|v| writer.write_varint(*v));
+  signed.iter().for_each(|v| writer.write_signed_varint(*v));
+  let data = writer.finish();
+
+  let mut reader = BitReader::new(&data);
+  for value in values {
+    assert_eq!(reader.read_varint(), Ok(value));
+  }
+  for value in signed {
+    assert_eq!(reader.read_signed_varint(), Ok(value));
+  }
+}
+
+#[test]
+fn small_varints_stay_small() {
+  for (value, bits) in [(0, 8), (127, 8), (128, 16), (16_383, 16), (u64::MAX, 80)] {
+    let mut writer = BitWriter::new();
+    writer.write_varint(value);
+    assert_eq!(writer.bits_written(), bits, "{}", value);
+  }
+  let mut writer = BitWriter::new();
+  writer.write_signed_varint(-1);
+  writer.write_signed_varint(63);
+  assert_eq!(writer.bits_written(), 16);
+}
+
+#[test]
+fn mixed_values_round_trip_unaligned() {
+  let states = (0..=100).map(player).collect::<Vec<PlayerState>>();
+  let data = bits::pack(&states);
+  let read = bits::unpack::<Vec<PlayerState>>(&data).unwrap();
+
+  assert_eq!(read.len(), states.len());
+  for (read, state) in read.iter().zip(&states) {
+    assert_eq!(read.health, state.health);
+    assert!((read.rotation - state.rotation).abs() < 0.2);
+    assert!((read.position.0 - state.position.0).abs() < 0.01);
+    assert!((read.position.1 - state.position.1).abs() < 0.01);
+    assert_eq!(read.crouching, state.crouching);
+    assert_eq!(read.score, state.score);
+    assert_eq!(read.target, state.target);
+  }
+}
+
+fn round_trip<T: BitPack + PartialEq + std::fmt::Debug>(value: T) {
+  assert_eq!(bits::unpack::<T>(&bits::pack(&value)), Ok(value));
+}
+
+#[test]
+fn builtin_types_round_trip() {
+  round_trip(vec![true, false, true]);
+  [0, 1, u8::MAX].into_iter().for_each(round_trip);
+  [0, 1, u16::MAX].into_iter().for_each(round_trip);
+  [0, 1, u32::MAX].into_iter().for_each(round_trip);
+  [0, 1, u64::MAX].into_iter().for_each(round_trip);
+  [i8::MIN, -1, 0, i8::MAX].into_iter().for_each(round_trip);
+  [i16::MIN, -1, 0, i16::MAX].into_iter().for_each(round_trip);
+  [i32::MIN, -1, 0, i32::MAX].into_iter().for_each(round_trip);
+  [i64::MIN, -1, 0, i64::MAX].into_iter().for_each(round_trip);
+  [1.5f32, -0.0, f32::MAX].into_iter().for_each(round_trip);
+  [-0.1f64, f64::MIN_POSITIVE]
+    .into_iter()
+    .for_each(round_trip);
+  round_trip(Some("maat ✓".to_string()));
+  round_trip(String::new());
+  round_trip(None::<u32>);
+  round_trip(vec![Some(vec![1u16, 2]), None]);
+}
+
+#[test]
+fn measure_matches_what_is_written() {
+  for state in [
+    player(0),
+    player(100),
+    PlayerState {
+      target: None,
+      ..player(50)
+    },
+  ] {
+    let mut writer = BitWriter::new();
+    state.pack(&mut writer);
+    assert_eq!(bits::measure(&state), writer.bits_written());
+    assert_eq!(bits::pack(&state).len(), writer.bytes_written());
+  }
+  // health in 7 bits, rotation in 10, position in 32, then 1 + 16 + 1 + 16.
+  assert_eq!(bits::measure(&player(1)), 83);
+
+  let mut measuring = BitWriter::measuring();
+  measuring.write_bits(u64::MAX, 64);
+  measuring.write_bool(true);
+  assert_eq!(measuring.bits_written(), 65);
+  assert!(measuring.finish().is_empty());
+}
+
+#[test]
+fn short_and_bad_data_are_errors() {
+  let data = bits::pack(&player(42));
+  assert_eq!(
+    bits::unpack::<PlayerState>(&data[..data.len() - 2]),
+    Err(BitError::UnexpectedEnd)
+  );
+  assert_eq!(
+    BitReader::new(&[]).read_bool(),
+    Err(BitError::UnexpectedEnd)
+  );
+
+  // 127 doesn't fit in 0..=100 even though it fits in the 7 bits.
+  assert_eq!(
+    BitReader::new(&[0x7f]).read_ranged(0, 100),
+    Err(BitError::OutOfRange)
+  );
+  assert_eq!(
+    BitReader::new(&[0xff; 12]).read_varint(),
+    Err(BitError::VarintTooLong)
+  );
+  // A length that can't possibly be followed by that many items.
+  let mut writer = BitWriter::new();
+  writer.write_varint(1_000);
+  assert_eq!(
+    bits::unpack::<Vec<bool>>(&writer.finish()),
+    Err(BitError::UnexpectedEnd)
+  );
+}
+
+#[test]
+#[should_panic(expected = "101 is outside 0..=100")]
+fn writing_outside_a_range_panics() {
+  BitWriter::new().write_ranged(101, 0, 100);
+}
+
+#[test]
+#[should_panic(expected = "doesn't fit in 7 bits")]
+fn writing_too_wide_a_value_panics() {
+  BitWriter::new().write_bits(128, 7);
+}
+
+#[cfg(feature = "messages")]
+#[test]
+fn packed_messages_are_sent_and_received() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.connect_tcp(port)[0];
+
+  let sent = (90..100).map(player).collect::<Vec<PlayerState>>();
+  sent
+    .iter()
+    .for_each(|s| harness.clients[0].send_packed(token, s).unwrap());
+  let length = sent.iter().map(|s| bits::pack(s).len() + 4).sum::<usize>();
+  assert!(harness.drive(|h| h
+    .server_log
+    .data
+    .iter()
+    .map(|(_, d)| d.len())
+    .sum::<usize>()
+    == length));
+
+  let data = harness.server_log.data.clone();
+  let mut messages = harness.server.receive_packed::<PlayerState>(&data);
+  assert_eq!(messages.len(), sent.len());
+  let healths = messages
+    .drain(..)
+    .map(|m| match m {
+      Message::Received(_, state) => state.health,
+      Message::Invalid(token, e) => panic!("invalid message from {}: {:?}", token, e),
+    })
+    .collect::<Vec<u8>>();
+  assert_eq!(healths, (90..100).collect::<Vec<u8>>());
+
+  // One frame that ends before the message does.
+  let frame = maat_network::message::frame(&[0x7f]);
+  let bad = harness.server.receive_packed::<PlayerState>(&[(0, frame)]);
+  assert_eq!(
+    bad,
+    vec![Message::Invalid(
+      0,
+      MessageError::Unpack(BitError::OutOfRange)
+    )]
+  );
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use std::net::UdpSocket as StdUdpSocket;
+
+use common::{add_udp_socket, Harness};
+use maat_network::{checksum::PacketChecksum, ConnectionStats, MaatNetwork};
+
+const PROTOCOL_ID: u32 = 0x6d61_6174;
+
+#[test]
+fn packets_round_trip_through_the_check() {
+  let sender = PacketChecksum::new(PROTOCOL_ID);
+  let mut receiver = PacketChecksum::new(PROTOCOL_ID);
+
+  let packet = sender.wrap(b"snapshot 12");
+  assert_eq!(&packet[..4], &PROTOCOL_ID.to_le_bytes());
+  assert_eq!(receiver.check(&packet), Some(&b"snapshot 12"[..]));
+  assert_eq!(receiver.check(&sender.wrap(b"")), Some(&b""[..]));
+  assert_eq!(receiver.stats().packets_received, 2);
+}
+
+#[test]
+fn stray_and_corrupted_packets_are_counted() {
+  let mut receiver = PacketChecksum::new(PROTOCOL_ID);
+
+  let mut corrupted = PacketChecksum::new(PROTOCOL_ID).wrap(b"input left");
+  corrupted[9] ^= 0x20;
+  assert_eq!(receiver.check(&corrupted), None);
+
+  let other = PacketChecksum::new(PROTOCOL_ID + 1).wrap(b"input left");
+  assert_eq!(receiver.check(&other), None);
+  assert_eq!(receiver.check(b"GET / HTTP/1.1\r\n"), None);
+  assert_eq!(receiver.check(&[1, 2]), None);
+
+  assert_eq!(
+    receiver.stats(),
+    ConnectionStats {
+      packets_received: 0,
+      wrong_protocol: 3,
+      bad_checksum: 1,
+    }
+  );
+  assert_eq!(receiver.stats().dropped_packets(), 4);
+}
+
+#[test]
+fn server_drops_packets_that_fail_the_check() {
+  let server_socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let server_addr = server_socket.local_addr().unwrap();
+  let client_socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  client_socket.connect(server_addr).unwrap();
+  let scanner = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+
+  let mut harness = Harness::new(1);
+  let server_token = add_udp_socket(&mut harness.server, server_socket);
+  let client_token = add_udp_socket(&mut harness.clients[0], client_socket);
+  harness.server.enable_checksums(server_token, PROTOCOL_ID);
+  harness.clients[0].enable_checksums(client_token, PROTOCOL_ID);
+
+  scanner
+    .send_to(b"\x00\x00\x00\x00probe", server_addr)
+    .unwrap();
+  let mut corrupted = PacketChecksum::new(PROTOCOL_ID).wrap(b"join");
+  corrupted[8] ^= 1;
+  scanner.send_to(&corrupted, server_addr).unwrap();
+  harness.clients[0]
+    .write_data(client_token, b"join")
+    .unwrap();
+  harness.clients[0]
+    .write_data(client_token, b"ready")
+    .unwrap();
+
+  // Polls until every packet has been received or dropped.
+  assert!(harness.drive(|h| {
+    h.server
+      .connection_stats(server_token)
+      .map_or(0, |s| s.packets_received + s.dropped_packets())
+      >= 4
+  }));
+  assert_eq!(
+    harness.server_log.data,
+    vec![
+      (server_token, b"join".to_vec()),
+      (server_token, b"ready".to_vec())
+    ]
+  );
+  assert_eq!(
+    harness.server.connection_stats(server_token),
+    Some(ConnectionStats {
+      packets_received: 2,
+      wrong_protocol: 1,
+      bad_checksum: 1,
+    })
+  );
+}
+
+#[test]
+fn tokens_without_checksums_have_no_stats() {
+  let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let mut network = MaatNetwork::new();
+  let token = add_udp_socket(&mut network, socket);
+  network.poll();
+  assert_eq!(network.connection_stats(token), None);
+}
+
+#[test]
+#[should_panic(expected = "only datagram sockets can be checksummed")]
+fn checksums_need_a_datagram_socket() {
+  let mut network = MaatNetwork::new();
+  let listener = network.host_tcp_server("127.0.0.1", 0, None);
+  network.enable_checksums(listener, PROTOCOL_ID);
+}
@@ -0,0 +1,206 @@
+//! Drives a server `MaatNetwork` and any number of clients against each other
+//! on loopback, recording what each side sees.
+#![allow(dead_code)]
+
+use std::{
+  net::UdpSocket as StdUdpSocket,
+  time::{Duration, Instant},
+};
+
+#[cfg(feature = "connect-tokens")]
+use maat_network::ConnectToken;
+use maat_network::{MaatNetwork, NetworkEvent, NewConnection};
+use mio::net::UdpSocket;
+
+/// How long `drive` keeps polling before giving up.
+const DEADLINE: Duration = Duration::from_secs(5);
+
+/// Everything one network has returned from `poll` and `events`.
+#[derive(Default)]
+pub struct Log {
+  pub data: Vec<(usize, Vec<u8>)>,
+  pub events: Vec<NetworkEvent>,
+  /// Tokens of connections accepted on a listener.
+  pub accepted: Vec<usize>,
+  /// Peer address of each accepted connection, in the same order.
+  pub peers: Vec<String>,
+  /// Connect token each accepted connection was admitted with, in the same
+  /// order.
+  #[cfg(feature = "connect-tokens")]
+  pub connect_tokens: Vec<Option<ConnectToken>>,
+}
+
+impl Log {
+  /// All bytes read from the token, in the order they arrived.
+  pub fn bytes_from(&self, token: usize) -> Vec<u8> {
+    self
+      .data
+      .iter()
+      .filter(|(t, _)| *t == token)
+      .flat_map(|(_, d)| d.clone())
+      .collect()
+  }
+
+  pub fn has(&self, event: &NetworkEvent) -> bool {
+    self.events.contains(event)
+  }
+
+  pub fn disconnected(&self, token: usize) -> bool {
+    self
+      .events
+      .iter()
+      .any(|e| matches!(e, NetworkEvent::Disconnected(t, _) if *t == token))
+  }
+}
+
+pub struct Harness {
+  pub server: MaatNetwork,
+  pub server_log: Log,
+  pub clients: Vec<MaatNetwork>,
+  pub client_logs: Vec<Log>,
+  /// Keeps connections accepted on the server in `held` instead of adding
+  /// them.
+  pub hold_accepted: bool,
+  pub held: Vec<NewConnection>,
+}
+
+impl Harness {
+  pub fn new(clients: usize) -> Harness {
+    Harness {
+      server: MaatNetwork::new(),
+      server_log: Log::default(),
+      clients: (0..clients).map(|_| MaatNetwork::new()).collect(),
+      client_logs: (0..clients).map(|_| Log::default()).collect(),
+      hold_accepted: false,
+      held: Vec::new(),
+    }
+  }
+
+  /// A tcp server on an ephemeral loopback port, with the port.
+  pub fn tcp(clients: usize) -> (Harness, u16) {
+    let mut harness = Harness::new(clients);
+    let listener = harness.server.host_tcp_server("127.0.0.1", 0, None);
+    harness.server.poll();
+    let port = harness.server.local_addr(listener).unwrap().port();
+    (harness, port)
+  }
+
+  /// A memory server under `name`.
+  pub fn memory(name: &str, clients: usize) -> Harness {
+    let mut harness = Harness::new(clients);
+    harness.server.host_memory_server(name, None);
+    harness.server.poll();
+    harness
+  }
+
+  /// One client, with each network holding one end of a connected udp socket
+  /// pair. Returns the server's token and then the client's.
+  pub fn udp_pair() -> (Harness, usize, usize) {
+    let mut harness = Harness::new(1);
+    let (a, b) = udp_socket_pair();
+    let server_token = add_udp_socket(&mut harness.server, a);
+    let client_token = add_udp_socket(&mut harness.clients[0], b);
+    (harness, server_token, client_token)
+  }
+
+  /// Connects every client to the tcp server on `port` and waits until all of
+  /// them are connected and accepted, returns the client tokens.
+  pub fn connect_tcp(&mut self, port: u16) -> Vec<usize> {
+    self.connect(|c| c.connect_to_tcp("127.0.0.1", port, None))
+  }
+
+  /// Connects every client with `connect` and waits until all of them are
+  /// connected and accepted, returns the client tokens.
+  pub fn connect<F>(&mut self, connect: F) -> Vec<usize>
+  where
+    F: FnMut(&mut MaatNetwork) -> usize,
+  {
+    let tokens = self.clients.iter_mut().map(connect).collect::<Vec<usize>>();
+
+    let connected = self.drive(|h| {
+      h.server_log.accepted.len() == tokens.len()
+        && h
+          .client_logs
+          .iter()
+          .zip(&tokens)
+          .all(|(log, token)| log.has(&NetworkEvent::Connected(*token)))
+    });
+    assert!(connected, "clients didn't connect before the deadline");
+    tokens
+  }
+
+  /// Polls the server then each client until `done` is true or the deadline
+  /// passes, accepting new connections on the server as they arrive. `done`
+  /// runs before every poll, so it can also keep feeding the networks. Returns
+  /// whether `done` was reached.
+  pub fn drive<F>(&mut self, mut done: F) -> bool
+  where
+    F: FnMut(&mut Harness) -> bool,
+  {
+    let start = Instant::now();
+    while start.elapsed() < DEADLINE {
+      if done(self) {
+        return true;
+      }
+      self.tick();
+    }
+    done(self)
+  }
+
+  /// Polls the server and then each client once.
+  pub fn tick(&mut self) {
+    let (data, new_connections, _) = self.server.poll();
+    self.server_log.data.extend(data);
+    self.server_log.events.extend(self.server.events());
+    for connection in new_connections {
+      self.server_log.accepted.push(connection.token);
+      self.server_log.peers.push(connection.addr.clone());
+      #[cfg(feature = "connect-tokens")]
+      self
+        .server_log
+        .connect_tokens
+        .push(connection.connect_token.clone());
+      if self.hold_accepted {
+        self.held.push(connection);
+      } else {
+        self.server.add_exisiting_connection(connection);
+      }
+    }
+
+    for (client, log) in self.clients.iter_mut().zip(&mut self.client_logs) {
+      let (data, _, _) = client.poll();
+      log.data.extend(data);
+      log.events.extend(client.events());
+    }
+  }
+}
+
+/// Two udp sockets on loopback, connected to each other.
+pub fn udp_socket_pair() -> (StdUdpSocket, StdUdpSocket) {
+  let a = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let b = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  a.connect(b.local_addr().unwrap()).unwrap();
+  b.connect(a.local_addr().unwrap()).unwrap();
+  (a, b)
+}
+
+/// Adds the socket to the network under its peer address, or its own address
+/// if it isn't connected, returns the token.
+pub fn add_udp_socket(network: &mut MaatNetwork, socket: StdUdpSocket) -> usize {
+  socket.set_nonblocking(true).unwrap();
+  let addr = socket.peer_addr().or_else(|_| socket.local_addr()).unwrap();
+  network.add_existing_udp_connection(
+    UdpSocket::from_std(socket),
+    addr.ip().to_string(),
+    addr.port(),
+    None,
+  )
+}
+
+/// A non-blocking udp socket on loopback connected to `port`.
+pub fn udp_client(port: u16) -> UdpSocket {
+  let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  socket.connect(("127.0.0.1", port)).unwrap();
+  socket.set_nonblocking(true).unwrap();
+  UdpSocket::from_std(socket)
+}
@@ -0,0 +1,161 @@
+#![cfg(any(feature = "lz4", feature = "zstd"))]
+
+mod common;
+
+use common::Harness;
+use maat_network::{
+  compression::{MessageCodec, MAX_MESSAGE_BYTES},
+  Compression,
+};
+
+/// Something shaped like a world snapshot, large and repetitive.
+fn snapshot() -> Vec<u8> {
+  (0..2000)
+    .flat_map(|i: u32| [b"entity".as_slice(), &(i % 16).to_le_bytes(), &[0; 6]].concat())
+    .collect()
+}
+
+fn compressions() -> Vec<Compression> {
+  vec![
+    #[cfg(feature = "lz4")]
+    Compression::lz4(),
+    #[cfg(feature = "zstd")]
+    Compression::zstd(3),
+  ]
+}
+
+#[test]
+fn framed_messages_survive_any_split() {
+  for compression in compressions() {
+    let sender = MessageCodec::new(compression, true);
+    let mut receiver = MessageCodec::new(compression, true);
+
+    let messages = vec![snapshot(), b"input".to_vec(), Vec::new(), snapshot()];
+    let stream = messages
+      .iter()
+      .flat_map(|m| sender.encode(m))
+      .collect::<Vec<u8>>();
+
+    let decoded = stream
+      .chunks(7)
+      .flat_map(|chunk| receiver.decode(chunk).unwrap())
+      .collect::<Vec<Vec<u8>>>();
+    assert_eq!(decoded, messages);
+  }
+}
+
+#[test]
+fn small_and_incompressible_messages_are_sent_raw() {
+  for compression in compressions() {
+    let codec = MessageCodec::new(compression.with_threshold(64), false);
+
+    let small = [7; 63];
+    assert_eq!(codec.encode(&small), [&[0], small.as_slice()].concat());
+
+    let noise = (0..1024)
+      .scan(0x9e3779b9u32, |x, _| {
+        *x ^= *x << 13;
+        *x ^= *x >> 17;
+        *x ^= *x << 5;
+        Some(*x as u8)
+      })
+      .collect::<Vec<u8>>();
+    assert_eq!(codec.encode(&noise)[0], 0);
+
+    assert_ne!(codec.encode(&snapshot())[0], 0);
+  }
+}
+
+#[test]
+fn stats_track_the_compression_ratio() {
+  for compression in compressions() {
+    let mut sender = MessageCodec::new(compression, true);
+    let mut receiver = MessageCodec::new(compression, true);
+
+    for message in [snapshot(), b"ack".to_vec()] {
+      let frame = sender.encode(&message);
+      sender.record_sent(message.len(), &frame);
+      receiver.decode(&frame).unwrap();
+    }
+
+    let sent = sender.sent();
+    assert_eq!(sent.messages, 2);
+    assert_eq!(sent.compressed_messages, 1);
+    assert_eq!(sent.original_bytes, snapshot().len() + 3);
+    assert!(sent.ratio() < 0.2, "ratio was {}", sent.ratio());
+    assert_eq!(receiver.received(), sent);
+  }
+}
+
+#[test]
+fn bad_frames_are_errors() {
+  for compression in compressions() {
+    let mut codec = MessageCodec::new(compression, true);
+    assert!(codec.decode(&[0, 0, 0, 0, 9]).is_err());
+
+    let mut codec = MessageCodec::new(compression, true);
+    let too_long = (MAX_MESSAGE_BYTES as u32 + 1).to_le_bytes();
+    assert!(codec.decode(&[&too_long[..], &[0]].concat()).is_err());
+
+    // A compressed body claiming to expand past the limit is refused up front.
+    let mut codec = MessageCodec::new(compression, false);
+    let mut bomb = codec.encode(&snapshot());
+    match bomb[0] {
+      1 => bomb[1..5].copy_from_slice(&u32::MAX.to_le_bytes()),
+      _ => bomb.truncate(3),
+    }
+    assert!(codec.decode(&bomb).is_err());
+  }
+}
+
+#[test]
+fn compressed_messages_over_tcp() {
+  for compression in compressions() {
+    let (mut harness, port) = Harness::tcp(1);
+    let token = harness.connect_tcp(port)[0];
+    let accepted = harness.server_log.accepted[0];
+    harness.server.set_compression(accepted, compression);
+    harness.clients[0].set_compression(token, compression);
+
+    harness.clients[0].write_data(token, &snapshot()).unwrap();
+    harness.clients[0].write_data(token, b"chat: gg").unwrap();
+    assert!(harness.drive(|h| h.server_log.data.len() == 2));
+
+    assert_eq!(
+      harness.server_log.data,
+      vec![(accepted, snapshot()), (accepted, b"chat: gg".to_vec())]
+    );
+
+    let (sent, _) = harness.clients[0].compression_stats(token).unwrap();
+    let (_, server_received) = harness.server.compression_stats(accepted).unwrap();
+    assert_eq!(sent.messages, 2);
+    assert!(sent.ratio() < 0.2);
+    assert_eq!(server_received, sent);
+  }
+}
+
+#[test]
+fn compressed_datagrams_keep_their_boundaries() {
+  for compression in compressions() {
+    let (mut harness, server_token, client_token) = Harness::udp_pair();
+    harness.server.set_compression(server_token, compression);
+    harness.clients[0].set_compression(client_token, compression);
+
+    let small_snapshot = snapshot()[..1200].to_vec();
+    harness.clients[0]
+      .write_data(client_token, &small_snapshot)
+      .unwrap();
+    harness.clients[0]
+      .write_data(client_token, b"ping")
+      .unwrap();
+    assert!(harness.drive(|h| h.server_log.data.len() == 2));
+
+    assert_eq!(
+      harness.server_log.data,
+      vec![
+        (server_token, small_snapshot),
+        (server_token, b"ping".to_vec())
+      ]
+    );
+  }
+}
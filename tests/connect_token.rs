@@ -0,0 +1,308 @@
+#![cfg(feature = "connect-tokens")]
+
+mod common;
+
+use std::{
+  net::SocketAddr,
+  time::{Duration, SystemTime},
+};
+
+use common::Harness;
+use maat_network::{
+  connect_token::{generate_key, UsedTokens, CONNECT_TOKEN_BYTES, MAX_SERVER_ADDRESSES},
+  ConnectToken, ConnectTokenKey, NetworkEvent, ProtocolVersion, TokenError, VersionPolicy,
+};
+const LIFETIME: Duration = Duration::from_secs(30);
+
+fn addr(s: &str) -> SocketAddr {
+  s.parse().unwrap()
+}
+
+/// A listener on an ephemeral loopback port that requires tokens sealed with
+/// `key`, returns its address.
+fn token_server(harness: &mut Harness, key: ConnectTokenKey) -> SocketAddr {
+  let listener = harness.server.host_tcp_server("127.0.0.1", 0, None);
+  harness.server.require_connect_tokens(listener, key);
+  harness.server.poll();
+  harness.server.local_addr(listener).unwrap()
+}
+
+/// Drives the harness until the server accepts another connection or raises
+/// another event. Returns how many events it had raised before.
+fn admit(harness: &mut Harness) -> usize {
+  let accepted = harness.server_log.accepted.len();
+  let events = harness.server_log.events.len();
+  assert!(harness
+    .drive(|h| { h.server_log.accepted.len() > accepted || h.server_log.events.len() > events }));
+  events
+}
+
+#[test]
+fn sealed_token_opens_with_the_same_key() {
+  let key = generate_key();
+  let token = ConnectToken::new(
+    42,
+    LIFETIME,
+    vec![addr("203.0.113.7:6767"), addr("[2001:db8::1]:6767")],
+    b"rank=gold".to_vec(),
+  );
+
+  let sealed = token.seal(&key).unwrap();
+  assert_eq!(sealed.len(), CONNECT_TOKEN_BYTES);
+  assert!(!sealed
+    .windows(b"rank=gold".len())
+    .any(|w| w == b"rank=gold"));
+
+  let opened = ConnectToken::open(&key, &sealed, SystemTime::now()).unwrap();
+  assert_eq!(opened, token);
+  assert!(opened.allows(addr("203.0.113.7:6767")));
+  assert!(opened.allows(addr("[::ffff:203.0.113.7]:6767")));
+  assert!(!opened.allows(addr("203.0.113.7:6768")));
+}
+
+#[test]
+fn invalid_tokens_are_rejected() {
+  let key = generate_key();
+  let sealed = ConnectToken::new(1, LIFETIME, vec![], vec![])
+    .seal(&key)
+    .unwrap();
+  let now = SystemTime::now();
+
+  assert_eq!(
+    ConnectToken::open(&generate_key(), &sealed, now),
+    Err(TokenError::InvalidSignature)
+  );
+
+  // The expiry is sent in the clear but covered by the signature.
+  let mut extended = sealed.clone();
+  extended[24] ^= 0xff;
+  assert_eq!(
+    ConnectToken::open(&key, &extended, now),
+    Err(TokenError::InvalidSignature)
+  );
+
+  assert_eq!(
+    ConnectToken::open(&key, &sealed[..100], now),
+    Err(TokenError::Malformed)
+  );
+  assert_eq!(
+    ConnectToken::open(&key, &sealed, now + LIFETIME * 2),
+    Err(TokenError::Expired)
+  );
+}
+
+#[test]
+fn oversized_tokens_cannot_be_sealed() {
+  let key = generate_key();
+  let addresses = vec![addr("127.0.0.1:1"); MAX_SERVER_ADDRESSES + 1];
+  assert_eq!(
+    ConnectToken::new(1, LIFETIME, addresses, vec![]).seal(&key),
+    Err(TokenError::TooLarge)
+  );
+  assert_eq!(
+    ConnectToken::new(1, LIFETIME, vec![], vec![0; 257]).seal(&key),
+    Err(TokenError::TooLarge)
+  );
+
+  let addresses = vec![addr("[2001:db8::1]:1"); MAX_SERVER_ADDRESSES];
+  let token = ConnectToken::new(1, LIFETIME, addresses, vec![7; 256]);
+  let sealed = token.seal(&key).unwrap();
+  assert_eq!(
+    ConnectToken::open(&key, &sealed, SystemTime::now()),
+    Ok(token)
+  );
+}
+
+#[test]
+fn server_admits_a_valid_token() {
+  let key = generate_key();
+  let mut harness = Harness::new(1);
+  let server_addr = token_server(&mut harness, key);
+
+  let sealed = ConnectToken::new(7, LIFETIME, vec![server_addr], b"guild=3".to_vec())
+    .seal(&key)
+    .unwrap();
+  let token =
+    harness.clients[0].connect_to_tcp_with_token("127.0.0.1", server_addr.port(), &sealed, None);
+  harness.clients[0].write_data(token, b"hello").unwrap();
+
+  admit(&mut harness);
+  assert!(harness.server_log.events.is_empty());
+  assert_eq!(harness.server_log.accepted.len(), 1);
+
+  let connect_token = harness.server_log.connect_tokens[0].clone().unwrap();
+  assert_eq!(connect_token.client_id, 7);
+  assert_eq!(connect_token.user_data, b"guild=3");
+
+  // Data sent after the token is left for the connection.
+  let accepted = harness.server_log.accepted[0];
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted).len() == 5));
+  assert_eq!(harness.server_log.bytes_from(accepted), b"hello");
+}
+
+#[test]
+fn server_drops_bad_tokens_before_handing_out_a_connection() {
+  let key = generate_key();
+  let mut harness = Harness::new(1);
+  let server_addr = token_server(&mut harness, key);
+
+  let cases = [
+    (
+      ConnectToken::new(1, LIFETIME, vec![server_addr], vec![])
+        .seal(&generate_key())
+        .unwrap(),
+      TokenError::InvalidSignature,
+    ),
+    (
+      ConnectToken::new(2, LIFETIME, vec![addr("127.0.0.1:1")], vec![])
+        .seal(&key)
+        .unwrap(),
+      TokenError::WrongServer,
+    ),
+    (
+      ConnectToken::new(3, Duration::ZERO, vec![server_addr], vec![])
+        .seal(&key)
+        .unwrap(),
+      TokenError::Expired,
+    ),
+  ];
+
+  for (sealed, error) in cases {
+    harness.clients[0].connect_to_tcp_with_token("127.0.0.1", server_addr.port(), &sealed, None);
+
+    let events = admit(&mut harness);
+    assert!(harness.server_log.accepted.is_empty());
+    assert!(matches!(
+      &harness.server_log.events[events..],
+      [NetworkEvent::ConnectTokenRejected(_, e)] if *e == error
+    ));
+  }
+}
+
+#[test]
+fn peers_that_never_send_a_token_time_out() {
+  let mut harness = Harness::new(1);
+  harness
+    .server
+    .set_connect_timeout(Duration::from_millis(50));
+  let server_addr = token_server(&mut harness, generate_key());
+
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", server_addr.port(), None);
+  harness.clients[0].write_data(token, &[0; 10]).unwrap();
+
+  admit(&mut harness);
+  assert!(harness.server_log.accepted.is_empty());
+  assert!(matches!(
+    harness.server_log.events.as_slice(),
+    [NetworkEvent::ConnectTokenRejected(_, TokenError::TimedOut)]
+  ));
+}
+
+#[test]
+fn each_token_admits_a_single_connection() {
+  let key = generate_key();
+  let mut harness = Harness::new(1);
+  let server_addr = token_server(&mut harness, key);
+  let sealed = ConnectToken::new(5, LIFETIME, vec![server_addr], vec![])
+    .seal(&key)
+    .unwrap();
+
+  harness.clients[0].connect_to_tcp_with_token("127.0.0.1", server_addr.port(), &sealed, None);
+  admit(&mut harness);
+  assert_eq!(harness.server_log.accepted.len(), 1);
+  assert!(harness.server_log.events.is_empty());
+
+  harness.clients[0].connect_to_tcp_with_token("127.0.0.1", server_addr.port(), &sealed, None);
+  admit(&mut harness);
+  assert_eq!(harness.server_log.accepted.len(), 1);
+  assert!(matches!(
+    harness.server_log.events.as_slice(),
+    [NetworkEvent::ConnectTokenRejected(_, TokenError::Replayed)]
+  ));
+}
+
+#[test]
+fn tokens_go_out_ahead_of_the_version_exchange() {
+  let key = generate_key();
+  let mut harness = Harness::new(1);
+  let version = ProtocolVersion::new(0x6d61_6174, 1, 0);
+  harness
+    .server
+    .set_protocol_version(version, VersionPolicy::Exact);
+  harness.clients[0].set_protocol_version(version, VersionPolicy::Exact);
+  let server_addr = token_server(&mut harness, key);
+
+  let sealed = ConnectToken::new(4, LIFETIME, vec![server_addr], vec![])
+    .seal(&key)
+    .unwrap();
+  let token =
+    harness.clients[0].connect_to_tcp_with_token("127.0.0.1", server_addr.port(), &sealed, None);
+  harness.clients[0].write_data(token, b"hello").unwrap();
+
+  admit(&mut harness);
+  assert!(harness.server_log.events.is_empty());
+  assert_eq!(harness.server_log.accepted.len(), 1);
+  assert_eq!(
+    harness.server_log.connect_tokens[0]
+      .as_ref()
+      .unwrap()
+      .client_id,
+    4
+  );
+
+  let accepted = harness.server_log.accepted[0];
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted).len() == 5));
+  assert_eq!(harness.server_log.bytes_from(accepted), b"hello");
+  assert_eq!(harness.server.peer_version(accepted), Some(version));
+  assert_eq!(harness.clients[0].peer_version(token), Some(version));
+}
+
+#[test]
+fn used_tokens_are_forgotten_once_they_expire() {
+  let key = generate_key();
+  let token = ConnectToken::new(1, LIFETIME, vec![], vec![]);
+  let sealed = token.seal(&key).unwrap();
+  let other = ConnectToken::new(2, LIFETIME * 4, vec![], vec![]);
+  let other_sealed = other.seal(&key).unwrap();
+  let now = SystemTime::now();
+
+  let mut used = UsedTokens::new();
+  assert!(used.insert(&sealed, &token, now));
+  assert!(!used.insert(&sealed, &token, now));
+  assert!(used.insert(&other_sealed, &other, now));
+  assert_eq!(used.len(), 2);
+
+  assert!(!used.insert(&other_sealed, &other, now + LIFETIME * 2));
+  assert_eq!(used.len(), 1);
+}
+
+#[test]
+fn tokens_arriving_in_pieces_are_admitted() {
+  let key = generate_key();
+  let mut harness = Harness::new(1);
+  let server_addr = token_server(&mut harness, key);
+  let sealed = ConnectToken::new(9, LIFETIME, vec![server_addr], vec![])
+    .seal(&key)
+    .unwrap();
+
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", server_addr.port(), None);
+  harness.clients[0]
+    .write_data(token, &sealed[..300])
+    .unwrap();
+  (0..20).for_each(|_| harness.tick());
+  assert!(harness.server_log.accepted.is_empty());
+  assert!(harness.server_log.events.is_empty());
+
+  harness.clients[0]
+    .write_data(token, &sealed[300..])
+    .unwrap();
+  admit(&mut harness);
+  assert!(harness.server_log.events.is_empty());
+  assert_eq!(
+    harness.server_log.connect_tokens[0]
+      .as_ref()
+      .unwrap()
+      .client_id,
+    9
+  );
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use common::Harness;
+use maat_network::{ConnectionState, DisconnectReason, NetworkEvent, WriteError};
+
+use ConnectionState::*;
+
+const STATES: [ConnectionState; 6] = [Pending, Connecting, Handshaking, Connected, Closing, Closed];
+
+fn closed(token: usize) -> NetworkEvent {
+  NetworkEvent::Disconnected(token, DisconnectReason::Closed)
+}
+
+#[test]
+fn only_the_documented_transitions_are_legal() {
+  let legal = [
+    (Pending, Connecting),
+    (Pending, Handshaking),
+    (Pending, Connected),
+    (Connecting, Handshaking),
+    (Connecting, Connected),
+    (Handshaking, Connected),
+    (Handshaking, Closing),
+    (Connected, Closing),
+  ];
+
+  for from in STATES {
+    for to in STATES {
+      let expected = legal.contains(&(from, to)) || (from != Closed && to == Closed);
+      assert_eq!(
+        from.can_transition_to(to),
+        expected,
+        "{:?} -> {:?}",
+        from,
+        to
+      );
+    }
+  }
+}
+
+#[test]
+fn a_tcp_connection_walks_the_states_to_closed() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  assert_eq!(harness.clients[0].state(token), Some(Pending));
+
+  harness.tick();
+  assert_eq!(harness.clients[0].state(token), Some(Connecting));
+
+  assert!(harness.drive(|h| h.client_logs[0].has(&NetworkEvent::Connected(token))));
+  assert_eq!(harness.clients[0].state(token), Some(Connected));
+
+  harness.clients[0].write_data(token, b"bye").unwrap();
+  harness.clients[0].close_connection(token);
+  assert_eq!(harness.clients[0].state(token), Some(Closing));
+
+  assert!(harness.drive(|h| h.client_logs[0].has(&closed(token))));
+  assert_eq!(harness.clients[0].state(token), Some(Closed));
+  let accepted = harness.server_log.accepted[0];
+  assert!(harness.drive(|h| h.server_log.disconnected(accepted)));
+  assert_eq!(harness.server_log.bytes_from(accepted), b"bye");
+}
+
+#[test]
+fn closing_while_connecting_drops_the_connection() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  harness.clients[0].write_data(token, b"never sent").unwrap();
+  harness.tick();
+  assert_eq!(harness.clients[0].state(token), Some(Connecting));
+
+  harness.clients[0].close_connection(token);
+  assert_eq!(harness.clients[0].state(token), Some(Closed));
+  assert_eq!(
+    harness.clients[0].write_data(token, b"x"),
+    Err(WriteError::TokenClosed)
+  );
+
+  assert!(harness.drive(|h| h.client_logs[0].has(&closed(token))));
+  harness.drive(|h| {
+    h.server_log
+      .accepted
+      .iter()
+      .any(|t| h.server_log.disconnected(*t))
+  });
+  assert_eq!(harness.client_logs[0].events, vec![closed(token)]);
+  assert!(harness
+    .server_log
+    .accepted
+    .iter()
+    .all(|t| harness.server_log.bytes_from(*t).is_empty()));
+}
+
+#[test]
+fn closing_before_the_connection_is_created_drops_it() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  assert_eq!(harness.clients[0].state(token), Some(Pending));
+
+  harness.clients[0].close_connection(token);
+  assert_eq!(harness.clients[0].state(token), Some(Closed));
+
+  assert!(harness.drive(|h| h.client_logs[0].has(&closed(token))));
+  harness.tick();
+  assert_eq!(harness.client_logs[0].events, vec![closed(token)]);
+}
+
+#[test]
+fn closing_while_resolving_drops_the_connection() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("localhost", port, None);
+
+  harness.clients[0].close_connection(token);
+  assert_eq!(harness.clients[0].state(token), Some(Closed));
+
+  assert!(harness.drive(|h| h.client_logs[0].has(&closed(token))));
+  for _ in 0..10 {
+    harness.tick();
+  }
+  assert_eq!(harness.client_logs[0].events, vec![closed(token)]);
+  assert!(harness.server_log.accepted.is_empty());
+}
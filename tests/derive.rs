@@ -0,0 +1,189 @@
+#![cfg(feature = "derive")]
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+
+use common::Harness;
+use maat_network::{
+  bits::{self, BitError},
+  message::FieldHint,
+  Channel, Codec, Message, MessageError, MessageRegistry, NetMessage, WriteError, WriteLimits,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, NetMessage)]
+#[net(id = 1)]
+struct Join {
+  name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, NetMessage)]
+#[net(id = 2, channel = "unreliable")]
+struct PlayerState {
+  #[net(bits = 7)]
+  health: u8,
+  position: (f32, f32),
+  #[net(bits = 10)]
+  rotation: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, NetMessage)]
+#[net(id = 3, channel = "reliable")]
+struct Score(#[net(bits = 12)] u16, u16);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, NetMessage)]
+#[net(id = 400)]
+enum Command {
+  Attack { target: u32 },
+  Retreat,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, NetMessage)]
+#[net(id = 5)]
+struct Batch<T> {
+  items: Vec<T>,
+}
+
+#[test]
+fn ids_and_channels_come_from_the_attributes() {
+  assert_eq!(Join::ID, 1);
+  assert_eq!(Join::CHANNEL, Channel::Reliable);
+  assert_eq!(PlayerState::ID, 2);
+  assert_eq!(PlayerState::CHANNEL, Channel::Unreliable);
+  assert_eq!(Score::CHANNEL, Channel::Reliable);
+  assert_eq!(Command::ID, 400);
+  assert_eq!(<Batch<u8>>::ID, 5);
+}
+
+#[test]
+fn bit_hints_are_listed_in_field_order() {
+  assert_eq!(
+    PlayerState::FIELDS,
+    &[
+      FieldHint {
+        name: "health",
+        bits: 7
+      },
+      FieldHint {
+        name: "rotation",
+        bits: 10
+      },
+    ]
+  );
+  assert_eq!(
+    Score::FIELDS,
+    &[FieldHint {
+      name: "0",
+      bits: 12
+    }]
+  );
+  assert!(Join::FIELDS.is_empty());
+  assert!(Command::FIELDS.is_empty());
+}
+
+#[test]
+fn derived_messages_are_dispatched_by_a_registry() {
+  let received = Rc::new(RefCell::new(Vec::new()));
+  let mut registry = MessageRegistry::new(Codec::default());
+  let r = received.clone();
+  registry
+    .register_message(move |_, state: PlayerState| r.borrow_mut().push(format!("{:?}", state)))
+    .unwrap();
+  let r = received.clone();
+  registry
+    .register_message(move |_, command: Command| r.borrow_mut().push(format!("{:?}", command)))
+    .unwrap();
+  let registry = Rc::new(registry);
+  assert_eq!(registry.id_of::<Command>(), Some(400));
+
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, Some(registry.read_func()));
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1));
+  let accepted = harness.server_log.accepted[0];
+
+  let state = PlayerState {
+    health: 90,
+    position: (1.0, 2.0),
+    rotation: 512,
+  };
+  let command = Command::Attack { target: 7 };
+  harness.server.send_message(accepted, &state).unwrap();
+  harness.server.send_message(accepted, &command).unwrap();
+  harness
+    .server
+    .send_message(accepted, &Command::Retreat)
+    .unwrap();
+
+  assert!(harness.drive(|_| received.borrow().len() == 3));
+  assert_eq!(
+    *received.borrow(),
+    vec![
+      format!("{:?}", state),
+      format!("{:?}", command),
+      format!("{:?}", Command::Retreat)
+    ]
+  );
+  assert!(!harness.client_logs[0].bytes_from(token).is_empty());
+  assert!(registry.events().is_empty());
+}
+
+#[test]
+fn unreliable_messages_are_dropped_when_the_queue_is_full() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  // Not connected yet, so everything waits in a queue of a few bytes.
+  harness.clients[0].set_write_limits(token, WriteLimits::new(8, 8));
+
+  let state = PlayerState {
+    health: 1,
+    position: (0.0, 0.0),
+    rotation: 0,
+  };
+  assert_eq!(harness.clients[0].send_message(token, &state), Ok(()));
+  assert!(matches!(
+    harness.clients[0].send_message(
+      token,
+      &Join {
+        name: "late".to_string()
+      }
+    ),
+    Err(MessageError::Write(WriteError::QueueFull { .. }))
+  ));
+}
+
+#[test]
+fn bit_hints_pack_fields_in_that_many_bits() {
+  let state = PlayerState {
+    health: 90,
+    position: (1.5, -2.0),
+    rotation: 1000,
+  };
+  assert_eq!(bits::measure(&state), 7 + 64 + 10);
+  assert_eq!(bits::unpack::<PlayerState>(&bits::pack(&state)), Ok(state));
+
+  let score = Score(4000, 9);
+  assert_eq!(bits::measure(&score), 12 + 16);
+  assert_eq!(bits::unpack::<Score>(&bits::pack(&score)), Ok(score));
+  assert_eq!(bits::unpack::<Score>(&[0xff]), Err(BitError::UnexpectedEnd));
+}
+
+#[test]
+fn derived_messages_can_be_sent_packed() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1));
+
+  let state = PlayerState {
+    health: 127,
+    position: (3.0, 4.0),
+    rotation: 1023,
+  };
+  harness.clients[0].send_packed(token, &state).unwrap();
+
+  let accepted = harness.server_log.accepted[0];
+  assert!(harness.drive(|h| !h.server_log.bytes_from(accepted).is_empty()));
+  let data = vec![(accepted, harness.server_log.bytes_from(accepted))];
+  let received = harness.server.receive_packed::<PlayerState>(&data);
+  assert_eq!(received, vec![Message::Received(accepted, state)]);
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use std::{
+  io::ErrorKind,
+  net::{SocketAddr, UdpSocket},
+};
+
+use common::Harness;
+use maat_network::{ConnectionState, MaatNetwork, NetworkEvent, DUAL_STACK_ADDRESS};
+
+fn bound_port(network: &mut MaatNetwork, token: usize) -> u16 {
+  network.poll();
+  network.local_addr(token).expect("listener is bound").port()
+}
+
+#[test]
+fn tcp_over_ipv6_loopback() {
+  let mut harness = Harness::new(1);
+
+  let listener = harness.server.host_tcp_server("::1", 0, None);
+  let port = bound_port(&mut harness.server, listener);
+  assert!(harness.server.local_addr(listener).unwrap().is_ipv6());
+
+  let token = harness.clients[0].connect_to_tcp("::1", port, None);
+  harness.clients[0].write_data(token, b"hello").unwrap();
+
+  let received = |h: &Harness| {
+    h.server_log
+      .data
+      .iter()
+      .map(|(_, d)| d.len())
+      .sum::<usize>()
+  };
+  assert!(harness.drive(|h| received(h) == 5));
+
+  assert!(harness.client_logs[0].has(&NetworkEvent::Connected(token)));
+  assert_eq!(harness.server_log.accepted.len(), 1);
+
+  let peer = harness.server_log.peers[0]
+    .parse::<SocketAddr>()
+    .expect("peer address parses back");
+  assert!(peer.is_ipv6());
+  assert!(harness.server_log.peers[0].starts_with("[::1]:"));
+
+  let accepted = harness.server_log.accepted[0];
+  assert_eq!(harness.server_log.bytes_from(accepted), b"hello");
+}
+
+#[test]
+fn bracketed_ipv6_host_is_accepted() {
+  let mut harness = Harness::new(1);
+
+  let listener = harness.server.host_tcp_server("[::1]", 0, None);
+  let port = bound_port(&mut harness.server, listener);
+
+  let token = harness.clients[0].connect_to_tcp("[::1]", port, None);
+  assert!(harness.drive(|h| !h.client_logs[0].events.is_empty()));
+
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::Connected(token)]
+  );
+  assert_eq!(
+    harness.clients[0].state(token),
+    Some(ConnectionState::Connected)
+  );
+}
+
+#[test]
+fn dual_stack_listener_accepts_ipv4() {
+  let mut harness = Harness::new(1);
+
+  let listener = harness.server.host_tcp_server(DUAL_STACK_ADDRESS, 0, None);
+  let port = bound_port(&mut harness.server, listener);
+
+  let v4 = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  let v6 = harness.clients[0].connect_to_tcp("::1", port, None);
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 2 && h.client_logs[0].events.len() == 2));
+
+  assert!(harness.client_logs[0].has(&NetworkEvent::Connected(v4)));
+  assert!(harness.client_logs[0].has(&NetworkEvent::Connected(v6)));
+}
+
+#[test]
+fn ipv6_only_listener_refuses_ipv4() {
+  let mut harness = Harness::new(1);
+
+  harness.server.set_ipv6_only(true);
+  let listener = harness.server.host_tcp_server(DUAL_STACK_ADDRESS, 0, None);
+  let port = bound_port(&mut harness.server, listener);
+
+  let v4 = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+
+  assert!(harness.drive(|h| !h.client_logs[0].events.is_empty()));
+
+  assert!(harness.server_log.accepted.is_empty());
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::ConnectFailed(
+      v4,
+      ErrorKind::ConnectionRefused
+    )]
+  );
+}
+
+#[test]
+fn udp_over_ipv6_loopback() {
+  let mut harness = Harness::new(0);
+
+  let socket = harness.server.host_udp_server("::1", 0, None);
+  let port = bound_port(&mut harness.server, socket);
+  assert_eq!(
+    harness.server.state(socket),
+    Some(ConnectionState::Connected)
+  );
+
+  let sender = UdpSocket::bind("[::1]:0").unwrap();
+  sender.send_to(b"ping", ("::1", port)).unwrap();
+
+  assert!(harness.drive(|h| !h.server_log.data.is_empty()));
+
+  assert_eq!(harness.server_log.data, vec![(socket, b"ping".to_vec())]);
+}
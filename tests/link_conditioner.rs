@@ -0,0 +1,119 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::Harness;
+use maat_network::{link_conditioner::LinkConditioner, LinkConditions, LinkStats};
+
+/// Everything the server has received, one entry per read.
+fn received(harness: &Harness) -> Vec<Vec<u8>> {
+  harness
+    .server_log
+    .data
+    .iter()
+    .map(|(_, d)| d.clone())
+    .collect()
+}
+
+fn stats_for(conditions: LinkConditions, salt: u64) -> LinkStats {
+  let mut link = LinkConditioner::new(conditions, true, salt);
+  let now = Instant::now();
+  for i in 0..10_000u32 {
+    assert_eq!(link.send(&i.to_le_bytes(), now), 4);
+  }
+  link.stats()
+}
+
+#[test]
+fn loss_follows_the_configured_rate_and_seed() {
+  let conditions = LinkConditions::new(Duration::from_millis(200), 0.05).with_seed(7);
+
+  let stats = stats_for(conditions, 1);
+  assert_eq!(stats.packets, 10_000);
+  assert!(
+    (400..600).contains(&stats.dropped),
+    "dropped {}",
+    stats.dropped
+  );
+
+  assert_eq!(stats_for(conditions, 1), stats);
+  assert_ne!(stats_for(conditions.with_seed(8), 1), stats);
+  assert_ne!(stats_for(conditions, 2), stats);
+}
+
+#[test]
+fn bandwidth_cap_stalls_once_the_backlog_is_full() {
+  let mut link = LinkConditioner::new(LinkConditions::default().with_bandwidth(1000), false, 0);
+  let now = Instant::now();
+
+  assert_eq!(link.send(&[0; 600], now), 600);
+  assert_eq!(link.send(&[0; 600], now), 600);
+  // A second and a fifth of data is already waiting to go out.
+  assert_eq!(link.send(&[0; 10], now), 0);
+  assert_eq!(link.send(&[0; 10], now + Duration::from_millis(300)), 10);
+}
+
+#[test]
+fn latency_delays_delivery() {
+  let (mut harness, _, token) = Harness::udp_pair();
+  harness.clients[0]
+    .set_link_conditions(token, LinkConditions::new(Duration::from_millis(200), 0.0));
+  harness.tick();
+
+  let start = Instant::now();
+  harness.clients[0].write_data(token, b"ping").unwrap();
+  assert!(harness.drive(|h| !h.server_log.data.is_empty()));
+
+  assert_eq!(received(&harness), vec![b"ping".to_vec()]);
+  assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn datagrams_are_duplicated_and_reordered() {
+  let (mut harness, _, token) = Harness::udp_pair();
+  let conditions = LinkConditions::default()
+    .with_duplication(1.0)
+    .with_reordering(0.5)
+    .with_seed(3);
+  harness.clients[0].set_link_conditions(token, conditions);
+  harness.tick();
+
+  let sent = (0..20u8).collect::<Vec<u8>>();
+  sent
+    .iter()
+    .for_each(|packet| harness.clients[0].write_data(token, &[*packet]).unwrap());
+  // Plain udp reads run the datagrams together, each one is a single byte.
+  assert!(harness.drive(|h| received(h).concat().len() == 40));
+  let mut received = received(&harness).concat();
+
+  let stats = harness.clients[0].link_stats(token).unwrap();
+  assert_eq!(stats.duplicated, 20);
+  assert!(stats.reordered > 0);
+  let mut in_order = received.clone();
+  in_order.sort();
+  assert_ne!(received, in_order);
+
+  received.sort();
+  received.dedup();
+  assert_eq!(received, sent);
+}
+
+#[test]
+fn streams_keep_their_order_under_jitter() {
+  let (mut harness, port) = Harness::tcp(1);
+  harness.clients[0].set_default_link_conditions(Some(
+    LinkConditions::new(Duration::from_millis(20), 0.5)
+      .with_jitter(Duration::from_millis(20))
+      .with_reordering(0.5),
+  ));
+  let token = harness.connect_tcp(port)[0];
+
+  let sent = (0..50u8).collect::<Vec<u8>>();
+  sent
+    .chunks(5)
+    .for_each(|chunk| harness.clients[0].write_data(token, chunk).unwrap());
+  assert!(harness.drive(|h| received(h).concat().len() == sent.len()));
+
+  assert_eq!(received(&harness).concat(), sent);
+  assert_eq!(harness.clients[0].link_stats(token).unwrap().dropped, 0);
+}
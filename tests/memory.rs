@@ -0,0 +1,98 @@
+mod common;
+
+use std::io::ErrorKind;
+
+use common::Harness;
+use maat_network::{
+  memory::MEMORY_PIPE_CAPACITY, ConnectionState, DisconnectReason, MaatNetwork, NetworkEvent,
+};
+
+/// A memory server and a client connected to it, with the client's token.
+fn connected(name: &str) -> (Harness, usize) {
+  let mut harness = Harness::memory(name, 1);
+  let token = harness.connect(|c| c.connect_to_memory(name, None))[0];
+  (harness, token)
+}
+
+#[test]
+fn memory_connections_carry_data_both_ways() {
+  let (mut harness, token) = connected("memory-both-ways");
+  let accepted = harness.server_log.accepted[0];
+  assert_eq!(
+    harness.clients[0].state(token),
+    Some(ConnectionState::Connected)
+  );
+
+  harness.clients[0].write_data(token, b"join lobby").unwrap();
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted).len() == 10));
+  assert_eq!(harness.server_log.bytes_from(accepted), b"join lobby");
+
+  harness.server.write_data(accepted, b"welcome").unwrap();
+  assert!(harness.drive(|h| h.client_logs[0].bytes_from(token).len() == 7));
+  assert_eq!(harness.client_logs[0].bytes_from(token), b"welcome");
+}
+
+#[test]
+fn writes_larger_than_the_pipe_arrive_whole() {
+  let (mut harness, token) = connected("memory-large");
+  let accepted = harness.server_log.accepted[0];
+
+  let snapshot = (0..MEMORY_PIPE_CAPACITY * 3)
+    .map(|i| i as u8)
+    .collect::<Vec<u8>>();
+  harness.clients[0].write_data(token, &snapshot).unwrap();
+
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted).len() == snapshot.len()));
+  assert_eq!(harness.server_log.bytes_from(accepted), snapshot);
+}
+
+#[test]
+fn closing_one_end_disconnects_the_other() {
+  let (mut harness, token) = connected("memory-close");
+
+  harness
+    .server
+    .close_connection(harness.server_log.accepted[0]);
+  assert!(harness.drive(|h| h.client_logs[0].disconnected(token)));
+
+  assert!(harness.client_logs[0].has(&NetworkEvent::Disconnected(
+    token,
+    DisconnectReason::PeerClosed
+  )));
+}
+
+#[test]
+fn connecting_to_a_missing_name_fails() {
+  let mut client = MaatNetwork::new();
+  let token = client.connect_to_memory("memory-nobody-home", None);
+  client.poll();
+
+  assert_eq!(
+    client.events(),
+    vec![NetworkEvent::ConnectFailed(
+      token,
+      ErrorKind::ConnectionRefused
+    )]
+  );
+}
+
+#[test]
+fn names_are_released_when_the_listener_closes() {
+  let mut first = MaatNetwork::new();
+  let mut second = MaatNetwork::new();
+
+  let listener = first.host_memory_server("memory-reuse", None);
+  first.poll();
+  let taken = second.host_memory_server("memory-reuse", None);
+  second.poll();
+  assert_eq!(
+    second.events(),
+    vec![NetworkEvent::ConnectFailed(taken, ErrorKind::AddrInUse)]
+  );
+
+  first.close_connection(listener);
+  first.poll();
+  let token = second.host_memory_server("memory-reuse", None);
+  second.poll();
+  assert_eq!(second.state(token), Some(ConnectionState::Connected));
+}
@@ -0,0 +1,190 @@
+#![cfg(feature = "messages")]
+
+mod common;
+
+use common::{udp_client, Harness};
+use maat_network::{
+  message::{self, MAX_MESSAGE_BYTES},
+  Codec, MaatNetwork, Message, MessageError,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum NetworkMessage {
+  Join { name: String },
+  Move { x: f32, y: f32 },
+  Chat(String),
+  Snapshot(Vec<u32>),
+  Leave,
+}
+
+fn codecs() -> Vec<Codec> {
+  vec![
+    #[cfg(feature = "bincode")]
+    Codec::Bincode,
+    #[cfg(feature = "postcard")]
+    Codec::Postcard,
+    #[cfg(feature = "json")]
+    Codec::Json,
+  ]
+}
+
+fn game_messages() -> Vec<NetworkMessage> {
+  vec![
+    NetworkMessage::Join {
+      name: "maat".to_string(),
+    },
+    NetworkMessage::Move { x: 1.5, y: -3.0 },
+    NetworkMessage::Chat("gg".to_string()),
+    NetworkMessage::Snapshot((0..20_000).collect()),
+    NetworkMessage::Leave,
+  ]
+}
+
+fn received(messages: Vec<Message<NetworkMessage>>) -> Vec<NetworkMessage> {
+  messages
+    .into_iter()
+    .map(|m| match m {
+      Message::Received(_, message) => message,
+      Message::Invalid(token, e) => panic!("invalid message from {}: {:?}", token, e),
+    })
+    .collect()
+}
+
+fn message_frame(message: &NetworkMessage) -> Vec<u8> {
+  message::frame(&Codec::default().encode(message).unwrap())
+}
+
+fn received_bytes(harness: &Harness) -> usize {
+  harness.server_log.data.iter().map(|(_, d)| d.len()).sum()
+}
+
+#[test]
+fn every_codec_round_trips() {
+  for codec in codecs() {
+    for message in game_messages() {
+      let encoded = codec.encode(&message).unwrap();
+      assert_eq!(codec.decode::<NetworkMessage>(&encoded).unwrap(), message);
+    }
+  }
+}
+
+#[test]
+fn typed_messages_arrive_whole_and_in_order_over_tcp() {
+  for codec in codecs() {
+    let (mut harness, port) = Harness::tcp(1);
+    harness.server.set_message_codec(codec);
+    harness.clients[0].set_message_codec(codec);
+    let token = harness.connect_tcp(port)[0];
+
+    let sent = game_messages();
+    sent
+      .iter()
+      .for_each(|m| harness.clients[0].send(token, m).unwrap());
+
+    let length = sent
+      .iter()
+      .map(|m| codec.encode(m).unwrap().len() + 4)
+      .sum::<usize>();
+    assert!(harness.drive(|h| received_bytes(h) == length));
+
+    let data = harness.server_log.data.clone();
+    let messages = harness.server.receive::<NetworkMessage>(&data);
+    assert!(messages
+      .iter()
+      .all(|m| matches!(m, Message::Received(t, _) if *t == harness.server_log.accepted[0])));
+    assert_eq!(received(messages), sent);
+  }
+}
+
+#[test]
+fn typed_messages_over_udp() {
+  let mut harness = Harness::new(1);
+  let server = harness.server.host_udp_server("127.0.0.1", 0, None);
+  harness.server.poll();
+  let port = harness.server.local_addr(server).unwrap().port();
+  let token =
+    harness.clients[0].add_existing_udp_connection(udp_client(port), "127.0.0.1", port, None);
+
+  let sent = game_messages()
+    .into_iter()
+    .filter(|m| !matches!(m, NetworkMessage::Snapshot(_)))
+    .collect::<Vec<NetworkMessage>>();
+  sent
+    .iter()
+    .for_each(|m| harness.clients[0].send(token, m).unwrap());
+
+  let length = sent.iter().map(|m| message_frame(m).len()).sum::<usize>();
+  assert!(harness.drive(|h| received_bytes(h) == length));
+
+  let data = harness.server_log.data.clone();
+  assert_eq!(
+    received(harness.server.receive::<NetworkMessage>(&data)),
+    sent
+  );
+}
+
+#[test]
+fn frames_that_dont_decode_are_reported() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.connect_tcp(port)[0];
+
+  // A well framed body that isn't a `NetworkMessage`, then a real one.
+  let mut garbage = 3u32.to_le_bytes().to_vec();
+  garbage.extend([0xff, 0xff, 0xff]);
+  harness.clients[0].write_data(token, &garbage).unwrap();
+  harness.clients[0]
+    .send(token, &NetworkMessage::Leave)
+    .unwrap();
+
+  let length = garbage.len() + message_frame(&NetworkMessage::Leave).len();
+  assert!(harness.drive(|h| received_bytes(h) == length));
+
+  let data = harness.server_log.data.clone();
+  let accepted = harness.server_log.accepted[0];
+  let messages = harness.server.receive::<NetworkMessage>(&data);
+  assert_eq!(messages.len(), 2);
+  assert!(matches!(&messages[0], Message::Invalid(t, MessageError::Decode(_)) if *t == accepted));
+  assert_eq!(
+    messages[1],
+    Message::Received(accepted, NetworkMessage::Leave)
+  );
+}
+
+#[test]
+fn frames_split_across_reads_are_put_back_together() {
+  let mut network = MaatNetwork::new();
+  let sent = game_messages();
+  let bytes = sent.iter().flat_map(message_frame).collect::<Vec<u8>>();
+
+  let data = bytes
+    .chunks(7)
+    .map(|chunk| (3, chunk.to_vec()))
+    .collect::<Vec<(usize, Vec<u8>)>>();
+  let (first, rest) = data.split_at(data.len() / 2);
+  let mut messages = network.receive::<NetworkMessage>(first);
+  messages.extend(network.receive::<NetworkMessage>(rest));
+
+  assert_eq!(received(messages), sent);
+}
+
+#[test]
+fn oversized_frames_are_rejected() {
+  let mut network = MaatNetwork::new();
+  let length = (MAX_MESSAGE_BYTES as u32 + 1).to_le_bytes().to_vec();
+
+  let messages = network.receive::<NetworkMessage>(&[(7, length)]);
+  assert_eq!(
+    messages,
+    vec![Message::Invalid(
+      7,
+      MessageError::TooLarge(MAX_MESSAGE_BYTES + 1)
+    )]
+  );
+  // Nothing is left buffered for the token.
+  let leave = message_frame(&NetworkMessage::Leave);
+  assert_eq!(
+    network.receive::<NetworkMessage>(&[(7, leave)]),
+    vec![Message::Received(7, NetworkMessage::Leave)]
+  );
+}
@@ -0,0 +1,342 @@
+mod common;
+
+use common::Harness;
+use maat_network::{
+  mux::{
+    Demultiplexer, Multiplexer, MAX_BUFFERED_STREAM_BYTES, MAX_STREAM_MESSAGE_BYTES,
+    MUX_CHUNK_BYTES,
+  },
+  DisconnectReason, NetworkEvent, WriteError, WriteLimits,
+};
+
+const CHAT: u16 = 1;
+const ASSETS: u16 = 2;
+
+/// A connected tcp client and server with streams enabled on both ends,
+/// returns the client's token and the server's.
+fn stream_pair() -> (Harness, usize, usize) {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.connect_tcp(port)[0];
+  let accepted = harness.server_log.accepted[0];
+  harness.clients[0].enable_streams(token);
+  harness.server.enable_streams(accepted);
+  (harness, token, accepted)
+}
+
+fn stream_events(harness: &Harness) -> Vec<NetworkEvent> {
+  harness
+    .server_log
+    .events
+    .iter()
+    .filter(|e| {
+      matches!(
+        e,
+        NetworkEvent::StreamOpened(..)
+          | NetworkEvent::StreamMessage(..)
+          | NetworkEvent::StreamClosed(..)
+      )
+    })
+    .cloned()
+    .collect()
+}
+
+#[test]
+fn streams_are_opened_written_and_closed_in_order() {
+  let (mut harness, token, accepted) = stream_pair();
+
+  harness.clients[0].open_stream(token, CHAT).unwrap();
+  harness.clients[0]
+    .write_stream(token, CHAT, b"hello")
+    .unwrap();
+  harness.clients[0]
+    .write_stream(token, CHAT, b"bye")
+    .unwrap();
+  harness.clients[0].close_stream(token, CHAT).unwrap();
+
+  assert!(harness.drive(|h| stream_events(h).len() == 4));
+  assert_eq!(
+    stream_events(&harness),
+    vec![
+      NetworkEvent::StreamOpened(accepted, CHAT),
+      NetworkEvent::StreamMessage(accepted, CHAT, b"hello".to_vec()),
+      NetworkEvent::StreamMessage(accepted, CHAT, b"bye".to_vec()),
+      NetworkEvent::StreamClosed(accepted, CHAT),
+    ]
+  );
+}
+
+#[test]
+fn write_data_goes_to_the_default_stream() {
+  let (mut harness, token, accepted) = stream_pair();
+
+  harness.clients[0].write_data(token, b"plain").unwrap();
+  harness.clients[0].open_stream(token, CHAT).unwrap();
+  harness.clients[0].write_data(token, b" data").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted) == b"plain data"));
+  assert_eq!(
+    stream_events(&harness),
+    vec![NetworkEvent::StreamOpened(accepted, CHAT)]
+  );
+}
+
+#[test]
+fn a_large_message_doesnt_hold_up_other_streams() {
+  let (mut harness, token, accepted) = stream_pair();
+  let asset = vec![7; 64 * MUX_CHUNK_BYTES];
+
+  harness.clients[0].open_stream(token, ASSETS).unwrap();
+  harness.clients[0].open_stream(token, CHAT).unwrap();
+  harness.clients[0]
+    .write_stream(token, ASSETS, &asset)
+    .unwrap();
+  harness.clients[0]
+    .write_stream(token, CHAT, b"move left")
+    .unwrap();
+
+  assert!(harness.drive(|h| stream_events(h).len() == 4));
+  let events = stream_events(&harness);
+  assert_eq!(
+    events[2..],
+    [
+      NetworkEvent::StreamMessage(accepted, CHAT, b"move left".to_vec()),
+      NetworkEvent::StreamMessage(accepted, ASSETS, asset),
+    ]
+  );
+}
+
+#[test]
+fn streams_can_be_used_before_the_connection_is_added() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  harness.clients[0].enable_streams(token);
+  harness.clients[0].open_stream(token, CHAT).unwrap();
+  harness.clients[0]
+    .write_stream(token, CHAT, b"early")
+    .unwrap();
+  harness.clients[0].write_data(token, b"default").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1));
+  let accepted = harness.server_log.accepted[0];
+  harness.server.enable_streams(accepted);
+
+  assert!(harness
+    .drive(|h| stream_events(h).len() == 2 && h.server_log.bytes_from(accepted) == b"default"));
+  assert_eq!(
+    stream_events(&harness),
+    vec![
+      NetworkEvent::StreamOpened(accepted, CHAT),
+      NetworkEvent::StreamMessage(accepted, CHAT, b"early".to_vec()),
+    ]
+  );
+}
+
+#[test]
+fn streams_are_one_way() {
+  let (mut harness, token, accepted) = stream_pair();
+
+  harness.clients[0].open_stream(token, CHAT).unwrap();
+  assert!(harness.drive(|h| !stream_events(h).is_empty()));
+  assert_eq!(
+    harness.server.write_stream(accepted, CHAT, b"reply"),
+    Err(WriteError::StreamClosed)
+  );
+
+  // The same number can be opened the other way.
+  harness.server.open_stream(accepted, CHAT).unwrap();
+  harness
+    .server
+    .write_stream(accepted, CHAT, b"reply")
+    .unwrap();
+  assert!(
+    harness.drive(|h| h.client_logs[0].has(&NetworkEvent::StreamMessage(
+      token,
+      CHAT,
+      b"reply".to_vec()
+    )))
+  );
+}
+
+#[test]
+fn writes_to_closed_streams_are_rejected() {
+  let (mut harness, token, _) = stream_pair();
+
+  assert_eq!(
+    harness.clients[0].write_stream(token, CHAT, b"x"),
+    Err(WriteError::StreamClosed)
+  );
+  harness.clients[0].open_stream(token, CHAT).unwrap();
+  assert_eq!(
+    harness.clients[0].open_stream(token, CHAT),
+    Err(WriteError::StreamOpen)
+  );
+  harness.clients[0].close_stream(token, CHAT).unwrap();
+  // Still being closed.
+  assert_eq!(
+    harness.clients[0].open_stream(token, CHAT),
+    Err(WriteError::StreamOpen)
+  );
+  assert_eq!(
+    harness.clients[0].write_stream(token, CHAT, b"x"),
+    Err(WriteError::StreamClosed)
+  );
+  assert_eq!(
+    harness.clients[0].close_stream(token, CHAT),
+    Err(WriteError::StreamClosed)
+  );
+
+  harness.clients[0].close_connection(token);
+  assert_eq!(
+    harness.clients[0].write_stream(token, 0, b"x"),
+    Err(WriteError::TokenClosed)
+  );
+}
+
+#[test]
+fn streams_take_turns_and_have_their_own_limits() {
+  let mut mux = Multiplexer::new(WriteLimits::new(3 * MUX_CHUNK_BYTES, 0));
+  mux.open(ASSETS).unwrap();
+  mux.open(CHAT).unwrap();
+  mux
+    .write(ASSETS, &vec![0; 2 * MUX_CHUNK_BYTES + 1])
+    .unwrap();
+  assert!(matches!(
+    mux.write(ASSETS, &vec![0; MUX_CHUNK_BYTES]),
+    Err(WriteError::QueueFull { .. })
+  ));
+  mux.write(CHAT, b"a").unwrap();
+  mux.write(CHAT, b"b").unwrap();
+
+  // A frame from each stream in turn, the opens first.
+  let streams = std::iter::from_fn(|| mux.next_frame())
+    .map(|frame| u16::from_le_bytes([frame[1], frame[2]]))
+    .collect::<Vec<u16>>();
+  assert_eq!(
+    streams,
+    vec![CHAT, ASSETS, CHAT, ASSETS, CHAT, ASSETS, ASSETS]
+  );
+  assert!(mux.is_empty());
+  assert!(mux.take_drained());
+}
+
+#[test]
+fn frames_split_across_reads_are_put_back_together() {
+  let mut mux = Multiplexer::new(WriteLimits::default());
+  mux.open(CHAT).unwrap();
+  let message = (0..=255)
+    .cycle()
+    .take(MUX_CHUNK_BYTES + 10)
+    .collect::<Vec<u8>>();
+  mux.write(CHAT, &message).unwrap();
+  mux.write(0, b"default").unwrap();
+  mux.close(CHAT).unwrap();
+  let bytes = std::iter::from_fn(|| mux.next_frame())
+    .flatten()
+    .collect::<Vec<u8>>();
+
+  let mut demux = Demultiplexer::new();
+  demux.enable(3);
+  let mut events = Vec::new();
+  let received = bytes
+    .chunks(1000)
+    .flat_map(|chunk| demux.read(3, chunk, &mut events).unwrap())
+    .collect::<Vec<Vec<u8>>>();
+  assert_eq!(received, vec![b"default".to_vec()]);
+  assert_eq!(
+    events,
+    vec![
+      NetworkEvent::StreamOpened(3, CHAT),
+      NetworkEvent::StreamMessage(3, CHAT, message),
+      NetworkEvent::StreamClosed(3, CHAT),
+    ]
+  );
+}
+
+#[test]
+fn streams_need_enabling() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.connect_tcp(port)[0];
+  assert_eq!(
+    harness.clients[0].open_stream(token, CHAT),
+    Err(WriteError::StreamsDisabled)
+  );
+}
+
+fn frame(kind: u8, stream: u16, length: u32) -> Vec<u8> {
+  let mut frame = vec![kind];
+  frame.extend(stream.to_le_bytes());
+  frame.extend(length.to_le_bytes());
+  frame
+}
+
+#[test]
+fn oversized_frames_and_messages_are_rejected() {
+  let mut demux = Demultiplexer::new();
+  demux.enable(3);
+  let mut events = Vec::new();
+  let too_long = frame(1, 0, MUX_CHUNK_BYTES as u32 + 1);
+  assert_eq!(demux.read(3, &too_long, &mut events), None);
+
+  demux.enable(4);
+  let mut part = frame(1, 0, MUX_CHUNK_BYTES as u32);
+  part.resize(part.len() + MUX_CHUNK_BYTES, 0);
+  for _ in 0..MAX_STREAM_MESSAGE_BYTES / MUX_CHUNK_BYTES {
+    assert_eq!(demux.read(4, &part, &mut events), Some(Vec::new()));
+  }
+  assert_eq!(demux.read(4, &part, &mut events), None);
+  assert!(events.is_empty());
+}
+
+#[test]
+fn unfinished_messages_across_streams_are_capped() {
+  let mut demux = Demultiplexer::new();
+  demux.enable(3);
+  let mut events = Vec::new();
+  let streams = (MAX_BUFFERED_STREAM_BYTES / MAX_STREAM_MESSAGE_BYTES + 1) as u16;
+  for stream in 1..=streams {
+    demux.read(3, &frame(0, stream, 0), &mut events).unwrap();
+  }
+
+  let part = |stream: u16| {
+    let mut part = frame(1, stream, MUX_CHUNK_BYTES as u32);
+    part.resize(part.len() + MUX_CHUNK_BYTES, 0);
+    part
+  };
+  let parts_per_stream = MAX_STREAM_MESSAGE_BYTES / MUX_CHUNK_BYTES;
+  for stream in 1..streams {
+    for _ in 0..parts_per_stream {
+      assert_eq!(demux.read(3, &part(stream), &mut events), Some(Vec::new()));
+    }
+  }
+  // Every stream is within its own limit, but together they are full.
+  assert_eq!(demux.read(3, &part(streams), &mut events), None);
+}
+
+#[test]
+fn finished_messages_no_longer_count_towards_the_cap() {
+  let mut demux = Demultiplexer::new();
+  demux.enable(3);
+  let mut events = Vec::new();
+  let mut end = frame(2, 0, MUX_CHUNK_BYTES as u32);
+  end.resize(end.len() + MUX_CHUNK_BYTES, 0);
+  for _ in 0..MAX_BUFFERED_STREAM_BYTES / MUX_CHUNK_BYTES + 1 {
+    assert_eq!(demux.read(3, &end, &mut events).unwrap().len(), 1);
+  }
+}
+
+#[test]
+fn peers_sending_oversized_frames_are_disconnected() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.connect_tcp(port)[0];
+  let accepted = harness.server_log.accepted[0];
+  harness.server.enable_streams(accepted);
+
+  harness.clients[0]
+    .write_data(token, &frame(2, 0, u32::MAX))
+    .unwrap();
+  assert!(harness.drive(|h| h.server_log.disconnected(accepted)));
+  assert!(harness.server_log.has(&NetworkEvent::Disconnected(
+    accepted,
+    DisconnectReason::Error(std::io::ErrorKind::InvalidData)
+  )));
+}
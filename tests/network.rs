@@ -0,0 +1,290 @@
+mod common;
+
+use std::{
+  io::ErrorKind,
+  net::{SocketAddr, TcpListener as StdTcpListener, TcpStream as StdTcpStream},
+  time::{Duration, Instant},
+};
+
+use common::{udp_client, Harness};
+use maat_network::{accept_connections, DisconnectReason, NetworkEvent, WriteError};
+use mio::net::TcpListener;
+use socket2::{Domain, Socket, Type};
+
+const CLIENTS: usize = 3;
+
+/// Numbered little endian counters, starting at `first`.
+fn counters(first: u32, count: u32) -> Vec<u8> {
+  (first..first + count)
+    .flat_map(|n| n.to_le_bytes())
+    .collect()
+}
+
+#[test]
+fn tcp_clients_connect_and_are_accepted() {
+  let (mut harness, port) = Harness::tcp(CLIENTS);
+  let tokens = harness.connect_tcp(port);
+
+  assert_eq!(harness.server_log.accepted.len(), CLIENTS);
+  for (log, token) in harness.client_logs.iter().zip(&tokens) {
+    assert_eq!(log.events, vec![NetworkEvent::Connected(*token)]);
+  }
+}
+
+#[test]
+fn tcp_data_arrives_in_order_from_every_client() {
+  let (mut harness, port) = Harness::tcp(CLIENTS);
+  let tokens = harness.connect_tcp(port);
+
+  // Lots of small writes so they span several polls on the server.
+  for (i, (client, token)) in harness.clients.iter_mut().zip(&tokens).enumerate() {
+    (0..500u32)
+      .map(|n| (i as u32 * 1000 + n).to_le_bytes())
+      .for_each(|counter| client.write_data(*token, &counter).unwrap());
+  }
+
+  let accepted = harness.server_log.accepted.clone();
+  let arrived = harness.drive(|h| {
+    accepted
+      .iter()
+      .all(|t| h.server_log.bytes_from(*t).len() == 500 * 4)
+  });
+  assert!(arrived);
+
+  let mut firsts = accepted
+    .iter()
+    .map(|t| {
+      let data = harness.server_log.bytes_from(*t);
+      let first = u32::from_le_bytes(data[..4].try_into().unwrap());
+      assert_eq!(data, counters(first, 500));
+      first
+    })
+    .collect::<Vec<u32>>();
+  firsts.sort();
+  assert_eq!(firsts, vec![0, 1000, 2000]);
+}
+
+#[test]
+fn server_replies_reach_the_right_client() {
+  let (mut harness, port) = Harness::tcp(CLIENTS);
+  let tokens = harness.connect_tcp(port);
+
+  // Clients say who they are, the server echoes it back.
+  for (i, (client, token)) in harness.clients.iter_mut().zip(&tokens).enumerate() {
+    client.write_data(*token, &[i as u8]).unwrap();
+  }
+  let accepted = harness.server_log.accepted.clone();
+  assert!(harness.drive(|h| accepted
+    .iter()
+    .all(|t| !h.server_log.bytes_from(*t).is_empty())));
+  for token in &accepted {
+    let id = harness.server_log.bytes_from(*token);
+    harness.server.write_data(*token, &[id[0], id[0]]).unwrap();
+  }
+
+  assert!(harness.drive(|h| h
+    .client_logs
+    .iter()
+    .zip(&tokens)
+    .all(|(log, t)| log.bytes_from(*t).len() == 2)));
+  for (i, (log, token)) in harness.client_logs.iter().zip(&tokens).enumerate() {
+    assert_eq!(log.bytes_from(*token), vec![i as u8, i as u8]);
+  }
+}
+
+#[test]
+fn client_closing_disconnects_it_on_the_server() {
+  let (mut harness, port) = Harness::tcp(2);
+  let tokens = harness.connect_tcp(port);
+
+  harness.clients[0].write_data(tokens[0], b"bye").unwrap();
+  harness.clients[0].close_connection(tokens[0]);
+  let closed = harness.drive(|h| {
+    h.server_log.events.iter().any(|e| {
+      matches!(
+        e,
+        NetworkEvent::Disconnected(_, DisconnectReason::PeerClosed)
+      )
+    })
+  });
+  assert!(closed);
+
+  // Data queued before the close still arrives.
+  let gone = harness
+    .server_log
+    .accepted
+    .iter()
+    .find(|t| harness.server_log.disconnected(**t))
+    .copied()
+    .unwrap();
+  assert_eq!(harness.server_log.bytes_from(gone), b"bye");
+  assert!(harness.client_logs[0].has(&NetworkEvent::Disconnected(
+    tokens[0],
+    DisconnectReason::Closed
+  )));
+
+  // The other client is unaffected.
+  assert!(!harness.client_logs[1].disconnected(tokens[1]));
+  assert_eq!(
+    harness.clients[0].write_data(tokens[0], b"late"),
+    Err(WriteError::TokenClosed)
+  );
+  harness.clients[1]
+    .write_data(tokens[1], b"still here")
+    .unwrap();
+  let other = harness
+    .server_log
+    .accepted
+    .iter()
+    .find(|t| **t != gone)
+    .copied()
+    .unwrap();
+  assert!(harness.drive(|h| h.server_log.bytes_from(other) == b"still here"));
+}
+
+#[test]
+fn server_closing_disconnects_the_client() {
+  let (mut harness, port) = Harness::tcp(1);
+  let tokens = harness.connect_tcp(port);
+
+  let accepted = harness.server_log.accepted[0];
+  harness.server.close_connection(accepted);
+  assert!(harness.drive(|h| h.client_logs[0].disconnected(tokens[0])));
+
+  assert!(harness.client_logs[0].has(&NetworkEvent::Disconnected(
+    tokens[0],
+    DisconnectReason::PeerClosed
+  )));
+  assert!(harness.server_log.has(&NetworkEvent::Disconnected(
+    accepted,
+    DisconnectReason::Closed
+  )));
+}
+
+#[test]
+fn connecting_to_a_closed_port_fails() {
+  let port = {
+    let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+  };
+  let mut harness = Harness::new(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+
+  assert!(harness.drive(|h| !h.client_logs[0].events.is_empty()));
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::ConnectFailed(
+      token,
+      ErrorKind::ConnectionRefused
+    )]
+  );
+  assert_eq!(
+    harness.clients[0].write_data(token + 1, b"nobody"),
+    Err(WriteError::UnknownToken)
+  );
+}
+
+#[test]
+fn connects_that_never_complete_time_out() {
+  // Once its backlog is full, the listener drops new connection attempts.
+  let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+  let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+  listener.bind(&addr.into()).unwrap();
+  listener.listen(0).unwrap();
+  let addr = listener.local_addr().unwrap().as_socket().unwrap();
+  let backlog = (0..64)
+    .map_while(|_| StdTcpStream::connect_timeout(&addr, Duration::from_millis(100)).ok())
+    .collect::<Vec<StdTcpStream>>();
+  assert!(backlog.len() < 64, "the listener never stopped accepting");
+
+  let timeout = Duration::from_millis(300);
+  let mut harness = Harness::new(1);
+  harness.clients[0].set_connect_timeout(timeout);
+  let start = Instant::now();
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", addr.port(), None);
+
+  assert!(harness.drive(|h| !h.client_logs[0].events.is_empty()));
+  assert!(start.elapsed() >= timeout);
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::ConnectFailed(token, ErrorKind::TimedOut)]
+  );
+}
+
+#[test]
+fn data_for_connections_that_are_never_added_expires() {
+  let timeout = Duration::from_millis(200);
+  let (mut harness, port) = Harness::tcp(0);
+  harness.server.set_pending_data_timeout(timeout);
+  harness.hold_accepted = true;
+  let _client = StdTcpStream::connect(("127.0.0.1", port)).unwrap();
+
+  assert!(harness.drive(|h| !h.held.is_empty()));
+  // Accepted, but never handed back with `add_exisiting_connection`.
+  let token = harness.held[0].token;
+  harness.server.write_data(token, b"lost").unwrap();
+  harness.server.write_data(token, b"too").unwrap();
+  assert_eq!(harness.server.queued_bytes(token), 7);
+
+  let written = Instant::now();
+  assert!(harness.drive(|h| h.server_log.events.len() == 2));
+  assert!(written.elapsed() >= timeout);
+  assert_eq!(
+    harness.server_log.events,
+    vec![
+      NetworkEvent::PendingDataExpired(token, 4),
+      NetworkEvent::PendingDataExpired(token, 3)
+    ]
+  );
+  assert_eq!(harness.server.queued_bytes(token), 0);
+}
+
+#[test]
+fn existing_tcp_listeners_accept_clients() {
+  let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+  let port = listener.local_addr().unwrap().port();
+  let mut harness = Harness::new(CLIENTS);
+  harness.server.add_existing_tcp_listener(
+    listener,
+    "127.0.0.1",
+    port,
+    Some(Box::new(accept_connections)),
+  );
+
+  let tokens = harness.connect_tcp(port);
+  harness.clients[2].write_data(tokens[2], b"third").unwrap();
+  assert!(harness.drive(|h| h
+    .server_log
+    .accepted
+    .iter()
+    .any(|t| h.server_log.bytes_from(*t) == b"third")));
+}
+
+#[test]
+fn udp_server_receives_datagrams_from_every_client() {
+  let mut harness = Harness::new(CLIENTS);
+  let server = harness.server.host_udp_server("127.0.0.1", 0, None);
+  harness.server.poll();
+  let port = harness.server.local_addr(server).unwrap().port();
+
+  let tokens = harness
+    .clients
+    .iter_mut()
+    .map(|c| c.add_existing_udp_connection(udp_client(port), "127.0.0.1", port, None))
+    .collect::<Vec<usize>>();
+  for (i, (client, token)) in harness.clients.iter_mut().zip(&tokens).enumerate() {
+    (0..20u8).for_each(|n| client.write_data(*token, &[i as u8, n]).unwrap());
+  }
+
+  assert!(harness.drive(|h| h.server_log.bytes_from(server).len() == CLIENTS * 20 * 2));
+  // Plain udp reads run datagrams together, each one is two bytes.
+  let received = harness.server_log.bytes_from(server);
+  for i in 0..CLIENTS as u8 {
+    let from_client = received
+      .chunks(2)
+      .filter(|d| d[0] == i)
+      .map(|d| d[1])
+      .collect::<Vec<u8>>();
+    assert_eq!(from_client, (0..20).collect::<Vec<u8>>());
+  }
+}
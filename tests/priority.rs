@@ -0,0 +1,102 @@
+mod common;
+
+use common::Harness;
+use maat_network::{
+  modules::{WriteQueue, PRIORITY_AGING_TURNS},
+  Priority, WriteLimits,
+};
+
+/// Every chunk in the order the queue hands them out.
+fn written(queue: &mut WriteQueue) -> Vec<Vec<u8>> {
+  let mut chunks = Vec::new();
+  queue
+    .flush_datagrams(|data| {
+      chunks.push(data.to_vec());
+      Ok(true)
+    })
+    .unwrap();
+  chunks
+}
+
+#[test]
+fn higher_priorities_are_written_first_and_in_order() {
+  let mut queue = WriteQueue::new(WriteLimits::default());
+  queue.push_with_priority(b"map 1", Priority::Low).unwrap();
+  queue.push(b"move").unwrap();
+  queue.push_with_priority(b"map 2", Priority::Low).unwrap();
+  queue.push_with_priority(b"died", Priority::High).unwrap();
+  queue
+    .push_with_priority(b"respawn", Priority::High)
+    .unwrap();
+
+  assert_eq!(
+    written(&mut queue),
+    vec![
+      b"died".to_vec(),
+      b"respawn".to_vec(),
+      b"move".to_vec(),
+      b"map 1".to_vec(),
+      b"map 2".to_vec(),
+    ]
+  );
+  assert!(queue.is_empty());
+  assert_eq!(queue.queued_bytes(), 0);
+}
+
+#[test]
+fn low_priority_data_goes_out_after_waiting_long_enough() {
+  let mut queue = WriteQueue::new(WriteLimits::default());
+  queue.push_with_priority(b"map", Priority::Low).unwrap();
+  for _ in 0..PRIORITY_AGING_TURNS * 2 {
+    queue.push_with_priority(b"state", Priority::High).unwrap();
+  }
+
+  let chunks = written(&mut queue);
+  assert_eq!(
+    chunks.iter().position(|chunk| chunk == b"map"),
+    Some(PRIORITY_AGING_TURNS)
+  );
+}
+
+#[test]
+fn partly_written_data_is_finished_first() {
+  let mut queue = WriteQueue::new(WriteLimits::default());
+  queue
+    .push_with_priority(b"map chunk", Priority::Low)
+    .unwrap();
+
+  let mut stream = Vec::<u8>::new();
+  queue
+    .flush_with(|data| {
+      stream.extend(&data[..3]);
+      Ok(3)
+    })
+    .unwrap();
+  queue.push_with_priority(b"|died|", Priority::High).unwrap();
+  queue
+    .flush_with(|data| {
+      stream.extend(data);
+      Ok(data.len())
+    })
+    .unwrap();
+
+  assert_eq!(stream, b"map chunk|died|");
+}
+
+#[test]
+fn priorities_carry_over_data_queued_before_connecting() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  harness.clients[0]
+    .write_data_with_priority(token, b"map", Priority::Low)
+    .unwrap();
+  harness.clients[0].write_data(token, b"move").unwrap();
+  harness.clients[0]
+    .write_data_with_priority(token, b"died", Priority::High)
+    .unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1
+    && h.server_log.bytes_from(h.server_log.accepted[0]).len() == 11));
+  let accepted = harness.server_log.accepted[0];
+  assert_eq!(harness.server_log.bytes_from(accepted), b"diedmovemap");
+}
@@ -0,0 +1,166 @@
+#![cfg(feature = "messages")]
+
+mod common;
+
+use std::{any::type_name, cell::RefCell, rc::Rc};
+
+use common::Harness;
+use maat_network::{
+  message, Codec, ConnectionType, MemoryStream, MessageError, MessageRegistry, RegistryError,
+  RegistryEvent,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Join {
+  name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Move {
+  x: i32,
+  y: i32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Chat(String);
+
+#[derive(Debug, PartialEq)]
+enum Handled {
+  Join(Join),
+  Move(Move),
+}
+
+/// A registry for `Join` and `Move` recording what its handlers were given.
+fn game_registry() -> (Rc<MessageRegistry>, Rc<RefCell<Vec<Handled>>>) {
+  let handled = Rc::new(RefCell::new(Vec::new()));
+  let mut registry = MessageRegistry::new(Codec::default());
+
+  let h = handled.clone();
+  registry
+    .register(1, move |_, join: Join| {
+      h.borrow_mut().push(Handled::Join(join))
+    })
+    .unwrap();
+  let h = handled.clone();
+  registry
+    .register(2, move |_, m: Move| h.borrow_mut().push(Handled::Move(m)))
+    .unwrap();
+
+  (Rc::new(registry), handled)
+}
+
+fn connection() -> ConnectionType {
+  ConnectionType::from(MemoryStream::pair("registry").0)
+}
+
+#[test]
+fn frames_are_dispatched_to_their_handlers_over_tcp() {
+  let (registry, handled) = game_registry();
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, Some(registry.read_func()));
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1));
+  let accepted = harness.server_log.accepted[0];
+
+  let frames = [
+    registry
+      .encode(&Join {
+        name: "maat".to_string(),
+      })
+      .unwrap(),
+    registry.encode(&Move { x: 3, y: -4 }).unwrap(),
+    registry.encode(&Move { x: 0, y: 1 }).unwrap(),
+  ];
+  frames
+    .iter()
+    .for_each(|f| harness.server.write_data(accepted, f).unwrap());
+
+  let length = frames.iter().map(|f| f.len()).sum::<usize>();
+  assert!(harness.drive(|h| h.client_logs[0].bytes_from(token).len() == length));
+  assert_eq!(
+    *handled.borrow(),
+    vec![
+      Handled::Join(Join {
+        name: "maat".to_string()
+      }),
+      Handled::Move(Move { x: 3, y: -4 }),
+      Handled::Move(Move { x: 0, y: 1 }),
+    ]
+  );
+  assert!(registry.events().is_empty());
+}
+
+#[test]
+fn frames_split_across_reads_are_dispatched_once_whole() {
+  let (registry, handled) = game_registry();
+  let read = registry.read_func();
+  let mut connection = connection();
+
+  let frame = registry.encode(&Move { x: 7, y: 8 }).unwrap();
+  let (first, rest) = frame.split_at(3);
+  read(&mut connection, first);
+  assert!(handled.borrow().is_empty());
+  read(&mut connection, rest);
+
+  assert_eq!(*handled.borrow(), vec![Handled::Move(Move { x: 7, y: 8 })]);
+}
+
+#[test]
+fn unknown_ids_raise_events() {
+  let (registry, handled) = game_registry();
+  let mut sender = MessageRegistry::new(Codec::default());
+  sender.register(9, |_, _: Chat| {}).unwrap();
+  let read = registry.read_func();
+
+  let mut data = sender.encode(&Chat("hi".to_string())).unwrap();
+  data.extend(registry.encode(&Move { x: 1, y: 1 }).unwrap());
+  read(&mut connection(), &data);
+
+  assert_eq!(registry.events(), vec![RegistryEvent::UnknownId(9)]);
+  assert_eq!(*handled.borrow(), vec![Handled::Move(Move { x: 1, y: 1 })]);
+  assert!(registry.events().is_empty());
+}
+
+#[test]
+fn bad_frames_raise_events() {
+  let (registry, handled) = game_registry();
+  let read = registry.read_func();
+
+  let mut data = message::frame(&[1]);
+  data.extend(message::frame(&[2, 0, 0xff, 0xff, 0xff]));
+  read(&mut connection(), &data);
+
+  let events = registry.events();
+  assert_eq!(events.len(), 2);
+  assert!(matches!(
+    events[0],
+    RegistryEvent::Malformed(MessageError::Decode(_))
+  ));
+  assert!(matches!(
+    events[1],
+    RegistryEvent::Invalid(2, MessageError::Decode(_))
+  ));
+  assert!(handled.borrow().is_empty());
+}
+
+#[test]
+fn duplicate_ids_are_rejected() {
+  let mut registry = MessageRegistry::new(Codec::default());
+  registry.register(1, |_, _: Join| {}).unwrap();
+  assert_eq!(
+    registry.register(1, |_, _: Move| {}),
+    Err(RegistryError::DuplicateId(1, type_name::<Join>()))
+  );
+  assert_eq!(registry.id_of::<Move>(), None);
+}
+
+#[test]
+fn types_are_registered_once() {
+  let mut registry = MessageRegistry::new(Codec::default());
+  registry.register(1, |_, _: Join| {}).unwrap();
+  assert_eq!(
+    registry.register(2, |_, _: Join| {}),
+    Err(RegistryError::DuplicateType(type_name::<Join>(), 1))
+  );
+  assert_eq!(registry.id_of::<Join>(), Some(1));
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use common::Harness;
+use maat_network::{NetworkEvent, DUAL_STACK_ADDRESS};
+
+const V4_LOOPBACK: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+/// Also loopback on linux, but nothing listens on it.
+const OTHER_LOOPBACK: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+const V6_LOOPBACK: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+fn resolve_failed(harness: &Harness, token: usize) -> bool {
+  harness.client_logs[0]
+    .events
+    .iter()
+    .any(|e| matches!(e, NetworkEvent::ResolveFailed(t, _) if *t == token))
+}
+
+fn connect_failed(harness: &Harness, token: usize) -> bool {
+  harness.client_logs[0]
+    .events
+    .iter()
+    .any(|e| matches!(e, NetworkEvent::ConnectFailed(t, _) if *t == token))
+}
+
+#[test]
+fn host_names_are_resolved_before_connecting() {
+  let mut harness = Harness::new(1);
+  let listener = harness.server.host_tcp_server(DUAL_STACK_ADDRESS, 0, None);
+  harness.server.poll();
+  let port = harness.server.local_addr(listener).unwrap().port();
+
+  // Whichever family localhost resolves to, the dual stack server takes it.
+  let token = harness.clients[0].connect_to_tcp("localhost", port, None);
+  harness.clients[0].write_data(token, b"hello").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1
+    && h.server_log.bytes_from(h.server_log.accepted[0]) == b"hello"));
+  assert!(harness.client_logs[0].has(&NetworkEvent::Connected(token)));
+}
+
+#[test]
+fn the_next_address_is_tried_when_connecting_fails() {
+  let (mut harness, port) = Harness::tcp(1);
+  harness.clients[0].add_host("game.test", vec![V6_LOOPBACK, OTHER_LOOPBACK, V4_LOOPBACK]);
+
+  let token = harness.clients[0].connect_to_tcp("game.test", port, None);
+  harness.clients[0].write_data(token, b"hello").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1
+    && h.server_log.bytes_from(h.server_log.accepted[0]) == b"hello"));
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::Connected(token)]
+  );
+}
+
+#[test]
+fn connecting_fails_once_every_address_has() {
+  let (mut harness, port) = Harness::tcp(1);
+  harness.clients[0].add_host("game.test", vec![V6_LOOPBACK, OTHER_LOOPBACK]);
+
+  let token = harness.clients[0].connect_to_tcp("game.test", port, None);
+
+  assert!(harness.drive(|h| connect_failed(h, token)));
+  assert_eq!(harness.client_logs[0].events.len(), 1);
+  assert!(harness.server_log.accepted.is_empty());
+}
+
+#[test]
+fn unknown_hosts_fail_to_resolve() {
+  let mut harness = Harness::new(1);
+  let token = harness.clients[0].connect_to_tcp("maat.invalid", 6767, None);
+
+  assert!(harness.drive(|h| resolve_failed(h, token)));
+  assert_eq!(harness.client_logs[0].events.len(), 1);
+  assert!(harness.clients[0].write_data(token, b"x").is_err());
+}
+
+#[test]
+fn hosts_without_addresses_fail_to_resolve() {
+  let mut harness = Harness::new(1);
+  harness.clients[0].add_host("empty.test", Vec::new());
+  let token = harness.clients[0].connect_to_tcp("empty.test", 6767, None);
+
+  assert!(harness.drive(|h| resolve_failed(h, token)));
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::ResolveFailed(
+      token,
+      std::io::ErrorKind::NotFound
+    )]
+  );
+}
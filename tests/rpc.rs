@@ -0,0 +1,266 @@
+#![cfg(feature = "messages")]
+
+mod common;
+
+use std::{
+  net::UdpSocket as StdUdpSocket,
+  time::{Duration, Instant},
+};
+
+use common::Harness;
+use maat_network::{
+  rpc::{Rpc, RPC_RESEND_INTERVAL},
+  MessageError, NetworkEvent, RpcFrame, WriteError,
+};
+use mio::net::UdpSocket;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A connected tcp client and server with rpc enabled on both ends, returns
+/// the client's token and the server's.
+fn rpc_pair() -> (Harness, usize, usize) {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.connect_tcp(port)[0];
+  let accepted = harness.server_log.accepted[0];
+  harness.clients[0].enable_rpc(token);
+  harness.server.enable_rpc(accepted);
+  (harness, token, accepted)
+}
+
+fn requests(harness: &Harness) -> Vec<(usize, u64, Vec<u8>)> {
+  harness
+    .server_log
+    .events
+    .iter()
+    .filter_map(|e| match e {
+      NetworkEvent::Request(token, id, payload) => Some((*token, *id, payload.clone())),
+      _ => None,
+    })
+    .collect()
+}
+
+fn responses(harness: &Harness) -> Vec<(usize, u64, Vec<u8>)> {
+  harness.client_logs[0]
+    .events
+    .iter()
+    .filter_map(|e| match e {
+      NetworkEvent::Response(token, id, payload) => Some((*token, *id, payload.clone())),
+      _ => None,
+    })
+    .collect()
+}
+
+#[test]
+fn requests_are_answered_by_id_over_tcp() {
+  let (mut harness, token, accepted) = rpc_pair();
+
+  let id = harness.clients[0]
+    .request(token, b"buy sword", TIMEOUT)
+    .unwrap();
+  assert!(harness.drive(|h| !requests(h).is_empty()));
+  assert_eq!(
+    requests(&harness),
+    vec![(accepted, id, b"buy sword".to_vec())]
+  );
+
+  harness.server.respond(accepted, id, b"sold").unwrap();
+  assert!(harness.drive(|h| !responses(h).is_empty()));
+  assert_eq!(responses(&harness), vec![(token, id, b"sold".to_vec())]);
+}
+
+#[test]
+fn responses_are_matched_out_of_order() {
+  let (mut harness, token, accepted) = rpc_pair();
+
+  let ids = ["join red", "join blue", "join green"]
+    .iter()
+    .map(|r| {
+      harness.clients[0]
+        .request(token, r.as_bytes(), TIMEOUT)
+        .unwrap()
+    })
+    .collect::<Vec<u64>>();
+  assert!(harness.drive(|h| requests(h).len() == 3));
+
+  for (_, id, payload) in requests(&harness).into_iter().rev() {
+    let mut reply = b"ok ".to_vec();
+    reply.extend(payload);
+    harness.server.respond(accepted, id, &reply).unwrap();
+  }
+  assert!(harness.drive(|h| responses(h).len() == 3));
+
+  let mut answered = responses(&harness)
+    .into_iter()
+    .map(|(_, id, payload)| (id, payload))
+    .collect::<Vec<(u64, Vec<u8>)>>();
+  answered.sort();
+  assert_eq!(
+    answered,
+    vec![
+      (ids[0], b"ok join red".to_vec()),
+      (ids[1], b"ok join blue".to_vec()),
+      (ids[2], b"ok join green".to_vec()),
+    ]
+  );
+}
+
+#[test]
+fn data_still_flows_between_requests() {
+  let (mut harness, token, accepted) = rpc_pair();
+
+  harness.clients[0].write_data(token, b"before").unwrap();
+  let id = harness.clients[0].request(token, b"ping", TIMEOUT).unwrap();
+  harness.clients[0].write_data(token, b" after").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted) == b"before after"));
+  assert_eq!(requests(&harness), vec![(accepted, id, b"ping".to_vec())]);
+}
+
+#[test]
+fn unanswered_requests_time_out() {
+  let (mut harness, token, _) = rpc_pair();
+
+  let id = harness.clients[0]
+    .request(token, b"anyone?", Duration::from_millis(50))
+    .unwrap();
+  assert!(harness.drive(|h| h.client_logs[0].has(&NetworkEvent::RequestTimedOut(token, id))));
+}
+
+#[test]
+fn late_responses_are_dropped() {
+  let (mut harness, token, accepted) = rpc_pair();
+
+  let id = harness.clients[0]
+    .request(token, b"slow", Duration::from_millis(50))
+    .unwrap();
+  assert!(harness.drive(|h| h.client_logs[0].has(&NetworkEvent::RequestTimedOut(token, id))));
+  assert_eq!(requests(&harness).len(), 1);
+
+  harness.server.respond(accepted, id, b"too late").unwrap();
+  harness.server.write_data(accepted, b"marker").unwrap();
+  assert!(harness.drive(|h| h.client_logs[0].bytes_from(token) == b"marker"));
+  assert!(responses(&harness).is_empty());
+}
+
+#[test]
+fn udp_requests_are_sent_again_until_answered() {
+  let mut harness = Harness::new(1);
+  let a = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let b = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let (a_port, b_port) = (
+    a.local_addr().unwrap().port(),
+    b.local_addr().unwrap().port(),
+  );
+  a.connect(("127.0.0.1", b_port)).unwrap();
+  b.connect(("127.0.0.1", a_port)).unwrap();
+  a.set_nonblocking(true).unwrap();
+  b.set_nonblocking(true).unwrap();
+
+  let server =
+    harness
+      .server
+      .add_existing_udp_connection(UdpSocket::from_std(a), "127.0.0.1", b_port, None);
+  let client = harness.clients[0].add_existing_udp_connection(
+    UdpSocket::from_std(b),
+    "127.0.0.1",
+    a_port,
+    None,
+  );
+  harness.server.enable_rpc(server);
+  harness.clients[0].enable_rpc(client);
+
+  let id = harness.clients[0]
+    .request(client, b"join", TIMEOUT)
+    .unwrap();
+  assert!(harness.drive(|h| !requests(h).is_empty()));
+
+  // Answer only after the request has been sent again, it is only raised once.
+  let resent = Instant::now() + RPC_RESEND_INTERVAL * 2;
+  assert!(harness.drive(|_| Instant::now() >= resent));
+  assert_eq!(requests(&harness), vec![(server, id, b"join".to_vec())]);
+
+  harness.server.respond(server, id, b"welcome").unwrap();
+  assert!(harness.drive(|h| !responses(h).is_empty()));
+  assert_eq!(responses(&harness), vec![(client, id, b"welcome".to_vec())]);
+}
+
+#[test]
+fn resent_requests_are_answered_from_the_last_response() {
+  let mut requester = Rpc::new();
+  let mut responder = Rpc::new();
+  let now = Instant::now();
+  let (id, frame) = requester.request(1, b"team", TIMEOUT, now);
+
+  let mut events = Vec::new();
+  let (data, replies) = responder.read(2, &frame, &mut events);
+  assert!(data.is_empty() && replies.is_empty());
+  assert_eq!(events, vec![NetworkEvent::Request(2, id, b"team".to_vec())]);
+
+  // Resent before there is an answer, nothing happens.
+  let resends = requester.expire(now + RPC_RESEND_INTERVAL, |_| true, &mut events);
+  assert_eq!(resends, vec![(1, frame.clone())]);
+  let (_, replies) = responder.read(2, &frame, &mut events);
+  assert!(replies.is_empty());
+  assert_eq!(events.len(), 1);
+
+  // Resent after, the same answer goes out again.
+  let response = responder.respond(2, id, b"blue");
+  let (_, replies) = responder.read(2, &frame, &mut events);
+  assert_eq!(replies, vec![response.clone()]);
+  assert_eq!(events.len(), 1);
+
+  let (_, replies) = requester.read(1, &[response.clone(), response].concat(), &mut events);
+  assert!(replies.is_empty());
+  assert_eq!(
+    events[1..],
+    [NetworkEvent::Response(1, id, b"blue".to_vec())]
+  );
+  assert!(requester
+    .expire(now + TIMEOUT, |_| true, &mut events)
+    .is_empty());
+  assert_eq!(events.len(), 2);
+
+  // Tokens that aren't udp only time out.
+  let (id, _) = requester.request(1, b"again", TIMEOUT, now);
+  assert!(requester
+    .expire(now + RPC_RESEND_INTERVAL, |_| false, &mut events)
+    .is_empty());
+  requester.expire(now + TIMEOUT, |_| false, &mut events);
+  assert_eq!(events.last(), Some(&NetworkEvent::RequestTimedOut(1, id)));
+}
+
+#[test]
+fn rpc_frames_round_trip() {
+  let frames = [
+    RpcFrame::Data(b"state".to_vec()),
+    RpcFrame::Request(7, b"buy".to_vec()),
+    RpcFrame::Response(u64::MAX, Vec::new()),
+  ];
+  for frame in frames {
+    let encoded = frame.encode();
+    assert_eq!(RpcFrame::decode(&encoded[4..]), Ok(frame));
+  }
+
+  assert!(matches!(
+    RpcFrame::decode(&[1, 0, 0]),
+    Err(MessageError::Decode(_))
+  ));
+  assert!(matches!(
+    RpcFrame::decode(&[9; 12]),
+    Err(MessageError::Decode(_))
+  ));
+}
+
+#[test]
+fn requests_need_rpc_enabled() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.connect_tcp(port)[0];
+  assert_eq!(
+    harness.clients[0].request(token, b"", TIMEOUT),
+    Err(WriteError::RpcDisabled)
+  );
+  assert_eq!(
+    harness.clients[0].request(99, b"", TIMEOUT),
+    Err(WriteError::UnknownToken)
+  );
+}
@@ -0,0 +1,176 @@
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::{io::ErrorKind, path::PathBuf, sync::Arc};
+
+use common::Harness;
+use maat_network::{
+  tls::{self, client_config, server_config},
+  ConnectionState, DisconnectReason, MaatNetwork, NetworkEvent,
+};
+use rustls::ClientConfig;
+
+fn cert(name: &str) -> PathBuf {
+  PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("tests")
+    .join("certs")
+    .join(name)
+}
+
+/// A server listening on an ephemeral port of `addr`, returns the port.
+fn tls_server_on(server: &mut MaatNetwork, addr: &str) -> u16 {
+  let config = server_config(cert("server.pem"), cert("server.key")).unwrap();
+  let listener = server.host_tls_server(addr, 0, config, None);
+  server.poll();
+  server.local_addr(listener).unwrap().port()
+}
+
+fn tls_server(server: &mut MaatNetwork) -> u16 {
+  tls_server_on(server, "127.0.0.1")
+}
+
+fn trusting_client() -> Arc<ClientConfig> {
+  client_config(cert("ca.pem")).unwrap()
+}
+
+#[test]
+fn tls_handshake_and_data_round_trip() {
+  let mut harness = Harness::new(1);
+  let port = tls_server(&mut harness.server);
+
+  let token = harness.clients[0].connect_to_tls("127.0.0.1", port, trusting_client(), None);
+  harness.clients[0]
+    .write_data(token, b"login:player")
+    .unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1
+    && h.server_log.bytes_from(h.server_log.accepted[0]).len() == 12));
+  let accepted = harness.server_log.accepted[0];
+  assert_eq!(harness.server_log.bytes_from(accepted), b"login:player");
+  assert!(harness.server_log.has(&NetworkEvent::Connected(accepted)));
+  assert_eq!(
+    harness.server.state(accepted),
+    Some(ConnectionState::Connected)
+  );
+
+  harness.server.write_data(accepted, b"welcome").unwrap();
+  assert!(harness.drive(|h| h.client_logs[0].bytes_from(token).len() == 7));
+  assert_eq!(harness.client_logs[0].bytes_from(token), b"welcome");
+  assert_eq!(
+    harness.clients[0].state(token),
+    Some(ConnectionState::Connected)
+  );
+}
+
+#[test]
+fn tls_client_reports_connected_after_handshake() {
+  let mut harness = Harness::new(1);
+  let port = tls_server(&mut harness.server);
+
+  let token = harness.clients[0].connect_to_tls("127.0.0.1", port, trusting_client(), None);
+
+  assert!(harness.drive(|h| !h.client_logs[0].events.is_empty()));
+
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::Connected(token)]
+  );
+  assert_eq!(
+    harness.clients[0].state(token),
+    Some(ConnectionState::Connected)
+  );
+}
+
+#[test]
+fn tls_large_payload_is_delivered_in_order() {
+  let mut harness = Harness::new(1);
+  let port = tls_server(&mut harness.server);
+
+  let payload = (0..512 * 1024)
+    .map(|i| (i % 251) as u8)
+    .collect::<Vec<u8>>();
+  let token = harness.clients[0].connect_to_tls("127.0.0.1", port, trusting_client(), None);
+  harness.clients[0].write_data(token, &payload).unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1
+    && h.server_log.bytes_from(h.server_log.accepted[0]).len() >= payload.len()));
+
+  let accepted = harness.server_log.accepted[0];
+  assert_eq!(harness.server_log.bytes_from(accepted), payload);
+}
+
+#[test]
+fn untrusted_certificate_fails_the_connect() {
+  let mut harness = Harness::new(1);
+  let port = tls_server(&mut harness.server);
+
+  let untrusting = client_config(cert("other_ca.pem")).unwrap();
+  let token = harness.clients[0].connect_to_tls("127.0.0.1", port, untrusting, None);
+  harness.clients[0].write_data(token, b"secret").unwrap();
+
+  assert!(harness.drive(|h| !h.client_logs[0].events.is_empty() && !h.server_log.events.is_empty()));
+
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::ConnectFailed(token, ErrorKind::InvalidData)]
+  );
+  assert_eq!(
+    harness.clients[0].state(token),
+    Some(ConnectionState::Closed)
+  );
+  assert!(harness.server_log.data.is_empty());
+  assert!(matches!(
+    harness.server_log.events.as_slice(),
+    [NetworkEvent::ConnectFailed(_, _)]
+  ));
+}
+
+#[test]
+fn wrong_host_name_fails_the_connect() {
+  let mut harness = Harness::new(1);
+  // 127.0.0.2 is loopback but not one of the certificate's names.
+  let port = tls_server_on(&mut harness.server, "0.0.0.0");
+
+  let token = harness.clients[0].connect_to_tls("127.0.0.2", port, trusting_client(), None);
+
+  assert!(harness.drive(|h| !h.client_logs[0].events.is_empty()));
+
+  assert_eq!(
+    harness.client_logs[0].events,
+    vec![NetworkEvent::ConnectFailed(token, ErrorKind::InvalidData)]
+  );
+}
+
+#[test]
+fn closing_ends_the_session_cleanly() {
+  let mut harness = Harness::new(1);
+  let port = tls_server(&mut harness.server);
+  let token = harness.clients[0].connect_to_tls("127.0.0.1", port, trusting_client(), None);
+  assert!(harness
+    .drive(|h| h.client_logs[0].has(&NetworkEvent::Connected(token))
+      && !h.server_log.accepted.is_empty()));
+
+  let accepted = harness.server_log.accepted[0];
+  harness.server.close_connection(accepted);
+  assert!(harness.drive(|h| h.client_logs[0].disconnected(token)));
+
+  assert!(harness.client_logs[0].has(&NetworkEvent::Disconnected(
+    token,
+    DisconnectReason::PeerClosed
+  )));
+}
+
+#[test]
+fn pem_loading_errors() {
+  assert_eq!(
+    tls::load_certs(cert("missing.pem")).unwrap_err().kind(),
+    ErrorKind::NotFound
+  );
+  assert_eq!(
+    tls::load_private_key(cert("ca.pem")).unwrap_err().kind(),
+    ErrorKind::InvalidData
+  );
+  assert_eq!(tls::load_certs(cert("server.pem")).unwrap().len(), 1);
+  assert!(server_config(cert("server.pem"), cert("missing.key")).is_err());
+}
@@ -0,0 +1,177 @@
+mod common;
+
+use std::{
+  cell::{Cell, RefCell},
+  collections::VecDeque,
+  io::{Error, ErrorKind},
+  rc::Rc,
+};
+
+use common::{Harness, Log};
+use maat_network::{accept_connections, ConnectionType, MaatNetwork, NetworkEvent, Transport};
+
+type Queue = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+/// Stand-in for a relay service, delivers whole packets between two ends.
+struct RelaySocket {
+  incoming: Queue,
+  outgoing: Queue,
+  closed: Rc<Cell<bool>>,
+}
+
+impl RelaySocket {
+  fn pair() -> (RelaySocket, RelaySocket) {
+    let a = Queue::default();
+    let b = Queue::default();
+    (
+      RelaySocket {
+        incoming: a.clone(),
+        outgoing: b.clone(),
+        closed: Rc::default(),
+      },
+      RelaySocket {
+        incoming: b,
+        outgoing: a,
+        closed: Rc::default(),
+      },
+    )
+  }
+}
+
+impl Transport for RelaySocket {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    let packet = self
+      .incoming
+      .borrow_mut()
+      .pop_front()
+      .ok_or_else(|| Error::new(ErrorKind::WouldBlock, "relay is empty"))?;
+    buf[..packet.len()].copy_from_slice(&packet);
+    Ok(packet.len())
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    self.outgoing.borrow_mut().push_back(buf.to_vec());
+    Ok(buf.len())
+  }
+
+  fn close(&mut self) -> Result<(), Error> {
+    self.closed.set(true);
+    Ok(())
+  }
+
+  fn is_datagram(&self) -> bool {
+    true
+  }
+
+  fn readiness(&self) -> Option<(bool, bool)> {
+    Some((!self.incoming.borrow().is_empty(), true))
+  }
+}
+
+/// Hands out relay sockets queued up by `connect`.
+struct RelayListener {
+  waiting: Rc<RefCell<Vec<RelaySocket>>>,
+}
+
+impl RelayListener {
+  fn connect(&self) -> RelaySocket {
+    let (client, server) = RelaySocket::pair();
+    self.waiting.borrow_mut().push(server);
+    client
+  }
+}
+
+impl Transport for RelayListener {
+  fn accept(&mut self) -> Result<(ConnectionType, String), Error> {
+    self
+      .waiting
+      .borrow_mut()
+      .pop()
+      .map(|socket| (ConnectionType::custom(socket), "relay peer".to_string()))
+      .ok_or_else(|| Error::new(ErrorKind::WouldBlock, "nobody waiting"))
+  }
+
+  fn readiness(&self) -> Option<(bool, bool)> {
+    Some((!self.waiting.borrow().is_empty(), false))
+  }
+}
+
+/// Each read from the token, in order.
+fn packets(log: &Log, token: usize) -> Vec<Vec<u8>> {
+  log
+    .data
+    .iter()
+    .filter(|(t, _)| *t == token)
+    .map(|(_, d)| d.clone())
+    .collect()
+}
+
+#[test]
+fn custom_transports_carry_data_through_the_poll_loop() {
+  let (a_socket, b_socket) = RelaySocket::pair();
+  let mut harness = Harness::new(1);
+  let a_token = harness.clients[0].add_existing_transport(a_socket, "relay a", None);
+  let b_token = harness
+    .server
+    .add_existing_transport(b_socket, "relay b", None);
+  // Datagram layers work on top of any transport that says it is one.
+  harness.clients[0].enable_checksums(a_token, 99);
+  harness.server.enable_checksums(b_token, 99);
+
+  harness.clients[0].write_data(a_token, b"move 3 4").unwrap();
+  harness.clients[0].write_data(a_token, b"fire").unwrap();
+  assert!(harness.drive(|h| h.server_log.data.len() == 2));
+  assert_eq!(
+    packets(&harness.server_log, b_token),
+    vec![b"move 3 4".to_vec(), b"fire".to_vec()]
+  );
+
+  harness.server.write_data(b_token, b"ack").unwrap();
+  assert!(harness.drive(|h| !h.client_logs[0].data.is_empty()));
+  assert_eq!(
+    packets(&harness.client_logs[0], a_token),
+    vec![b"ack".to_vec()]
+  );
+}
+
+#[test]
+fn custom_listeners_hand_out_accepted_connections() {
+  let listener = RelayListener {
+    waiting: Rc::default(),
+  };
+  let client_socket = listener.connect();
+
+  let mut harness = Harness::new(1);
+  harness
+    .server
+    .add_existing_transport(listener, "relay", Some(Box::new(accept_connections)));
+  let token = harness.clients[0].add_existing_transport(client_socket, "relay", None);
+  harness.clients[0].write_data(token, b"hello").unwrap();
+
+  assert!(harness.drive(|h| !h.server_log.data.is_empty()));
+
+  assert_eq!(harness.server_log.peers, vec!["relay peer".to_string()]);
+  let accepted = harness.server_log.accepted[0];
+  assert_eq!(
+    packets(&harness.server_log, accepted),
+    vec![b"hello".to_vec()]
+  );
+}
+
+#[test]
+fn transports_are_closed_when_the_connection_is() {
+  let (socket, _peer) = RelaySocket::pair();
+  let closed = socket.closed.clone();
+  let mut network = MaatNetwork::new();
+  let token = network.add_existing_transport(socket, "relay", None);
+  network.poll();
+
+  network.close_connection(token);
+  network.poll();
+
+  assert!(closed.get());
+  assert!(network
+    .events()
+    .iter()
+    .any(|e| matches!(e, NetworkEvent::Disconnected(t, _) if *t == token)));
+}
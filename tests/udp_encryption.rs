@@ -0,0 +1,311 @@
+#![cfg(feature = "encryption")]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{add_udp_socket, udp_socket_pair, Harness};
+use maat_network::{
+  udp_encryption::{EncryptionError, Opened, SessionKey, UdpSession, REPLAY_WINDOW},
+  MaatNetwork,
+};
+const KEY: SessionKey = [7; 32];
+
+/// Two sessions that have swapped handshakes.
+fn established() -> (UdpSession, UdpSession) {
+  let mut a = UdpSession::new(&KEY);
+  let mut b = UdpSession::new(&KEY);
+  exchange(&mut a, &mut b);
+  (a, b)
+}
+
+/// Passes handshakes between the sessions, starting with `a`'s, until neither
+/// has anything left to answer. Returns the handshakes that were passed.
+fn exchange(a: &mut UdpSession, b: &mut UdpSession) -> Vec<Vec<u8>> {
+  let mut handshakes = vec![a.handshake_packet()];
+  let sides = [b, a];
+  while let Some(reply) = reply(
+    sides[(handshakes.len() - 1) % 2],
+    handshakes.last().unwrap(),
+  ) {
+    handshakes.push(reply);
+  }
+  assert_eq!(handshakes.len(), 3);
+  assert!(sides[0].is_established() && sides[1].is_established());
+  handshakes
+}
+
+fn reply(session: &mut UdpSession, packet: &[u8]) -> Option<Vec<u8>> {
+  match session.open(packet) {
+    Opened::Handshake(reply) => reply,
+    _ => panic!("handshake rejected"),
+  }
+}
+
+fn opened(session: &mut UdpSession, packet: &[u8]) -> Option<Vec<u8>> {
+  match session.open(packet) {
+    Opened::Data(data) => Some(data),
+    _ => None,
+  }
+}
+
+#[test]
+fn sealed_packets_open_on_the_peer_only() {
+  let (mut a, mut b) = established();
+
+  let packet = a.seal(b"position 10 20");
+  assert!(!packet.windows(b"position".len()).any(|w| w == b"position"));
+
+  assert_eq!(opened(&mut b, &packet).unwrap(), b"position 10 20");
+  // Each direction has its own key, so a packet can't be bounced back.
+  assert_eq!(opened(&mut a, &packet), None);
+
+  let packet = b.seal(b"input jump");
+  assert_eq!(opened(&mut a, &packet).unwrap(), b"input jump");
+}
+
+#[test]
+fn tampered_and_foreign_packets_are_rejected() {
+  let (mut a, mut b) = established();
+  let (mut other, _) = established();
+
+  let mut packet = a.seal(b"health 100");
+  let last = packet.len() - 1;
+  packet[last] ^= 1;
+  assert_eq!(opened(&mut b, &packet), None);
+
+  // Changing the sequence number in the header breaks the tag too.
+  let mut packet = a.seal(b"health 100");
+  packet[1] ^= 1;
+  assert_eq!(opened(&mut b, &packet), None);
+
+  assert_eq!(opened(&mut b, &other.seal(b"health 0")), None);
+  assert_eq!(opened(&mut b, b"health 0"), None);
+  assert_eq!(opened(&mut b, &[]), None);
+  assert_eq!(b.rejected_packets(), 5);
+}
+
+#[test]
+fn replayed_packets_are_rejected() {
+  let (mut a, mut b) = established();
+
+  let first = a.seal(b"fire");
+  let second = a.seal(b"fire");
+  assert_eq!(opened(&mut b, &second).unwrap(), b"fire");
+  // Out of order but within the window is fine, once.
+  assert_eq!(opened(&mut b, &first).unwrap(), b"fire");
+  assert_eq!(opened(&mut b, &first), None);
+  assert_eq!(opened(&mut b, &second), None);
+
+  let old = a.seal(b"old");
+  (0..REPLAY_WINDOW).for_each(|_| {
+    let packet = a.seal(b"new");
+    opened(&mut b, &packet).unwrap();
+  });
+  assert_eq!(opened(&mut b, &old), None);
+  assert_eq!(b.rejected_packets(), 3);
+}
+
+#[test]
+fn data_before_the_handshake_and_reflected_keys_are_rejected() {
+  let mut a = UdpSession::new(&KEY);
+  let (mut sender, _) = established();
+
+  assert_eq!(opened(&mut a, &sender.seal(b"early")), None);
+  assert!(matches!(a.open(&a.handshake_packet()), Opened::Rejected));
+  assert!(!a.is_established());
+
+  // Once established, a handshake with a different key is answered but
+  // doesn't replace the peer.
+  let (mut a, mut b) = established();
+  assert!(reply(&mut a, &UdpSession::new(&KEY).handshake_packet()).is_some());
+  assert_eq!(
+    opened(&mut a, &b.seal(b"still here")).unwrap(),
+    b"still here"
+  );
+}
+
+#[test]
+fn replayed_handshakes_dont_take_over_a_session() {
+  // Handshakes captured from another session between the same peers.
+  let eavesdropped = exchange(&mut UdpSession::new(&KEY), &mut UdpSession::new(&KEY));
+
+  // A fresh session sees the replays first, they never echo its nonce.
+  let mut fresh = UdpSession::new(&KEY);
+  for handshake in &eavesdropped {
+    fresh.open(handshake);
+  }
+  assert!(!fresh.is_established());
+
+  let mut b = UdpSession::new(&KEY);
+  exchange(&mut fresh, &mut b);
+  assert_eq!(opened(&mut b, &fresh.seal(b"mine")).unwrap(), b"mine");
+
+  // Replaying them after the exchange doesn't change the peer either.
+  for handshake in &eavesdropped {
+    fresh.open(handshake);
+  }
+  assert_eq!(opened(&mut fresh, &b.seal(b"yours")).unwrap(), b"yours");
+}
+
+#[test]
+fn a_restarted_peer_replaces_the_old_one() {
+  let (mut a, mut b) = established();
+  assert_eq!(opened(&mut a, &b.seal(b"before")).unwrap(), b"before");
+  // The old peer's last handshake, which echoes a's nonce.
+  let stale = b.handshake_packet();
+
+  let mut restarted = UdpSession::new(&KEY);
+  exchange(&mut restarted, &mut a);
+  assert_eq!(opened(&mut a, &restarted.seal(b"after")).unwrap(), b"after");
+  assert_eq!(
+    opened(&mut restarted, &a.seal(b"welcome")).unwrap(),
+    b"welcome"
+  );
+
+  // a picked a new nonce, so the old peer's handshake can't switch back.
+  a.open(&stale);
+  assert_eq!(opened(&mut a, &restarted.seal(b"still")).unwrap(), b"still");
+  assert_eq!(opened(&mut a, &b.seal(b"gone")), None);
+}
+
+#[test]
+#[should_panic(expected = "before the key exchange")]
+fn sealing_before_the_handshake_panics() {
+  UdpSession::new(&KEY).seal(b"too soon");
+}
+
+#[test]
+fn encrypted_udp_between_networks() {
+  let (mut harness, b_token, a_token) = Harness::udp_pair();
+  harness.clients[0].encrypt_udp(a_token, KEY).unwrap();
+  harness.server.encrypt_udp(b_token, KEY).unwrap();
+
+  // Queued until the key exchange is done.
+  harness.clients[0]
+    .write_data(a_token, b"snapshot 1")
+    .unwrap();
+  harness.clients[0]
+    .write_data(a_token, b"snapshot 2")
+    .unwrap();
+  assert!(harness.drive(|h| h.server_log.data.len() == 2));
+
+  assert_eq!(
+    harness.server_log.data,
+    vec![
+      (b_token, b"snapshot 1".to_vec()),
+      (b_token, b"snapshot 2".to_vec())
+    ]
+  );
+  assert!(harness.clients[0].is_encrypted(a_token) && harness.server.is_encrypted(b_token));
+  assert_eq!(harness.server.rejected_packets(b_token), 0);
+}
+
+#[test]
+fn encrypted_packets_can_also_be_checksummed() {
+  let (mut harness, b_token, a_token) = Harness::udp_pair();
+  for (network, token) in [
+    (&mut harness.clients[0], a_token),
+    (&mut harness.server, b_token),
+  ] {
+    network.enable_checksums(token, 7);
+    network.encrypt_udp(token, KEY).unwrap();
+  }
+  harness.clients[0].write_data(a_token, b"snapshot").unwrap();
+  assert!(harness.drive(|h| !h.server_log.data.is_empty()));
+
+  assert_eq!(
+    harness.server_log.data,
+    vec![(b_token, b"snapshot".to_vec())]
+  );
+  let stats = harness.server.connection_stats(b_token).unwrap();
+  // At least a handshake and the snapshot got through the check.
+  assert!(stats.packets_received >= 2);
+  assert_eq!(stats.dropped_packets(), 0);
+}
+
+#[test]
+fn plaintext_never_reaches_the_wire_or_the_application() {
+  let (socket, peer) = udp_socket_pair();
+  peer
+    .set_read_timeout(Some(Duration::from_millis(50)))
+    .unwrap();
+  let mut harness = Harness::new(0);
+
+  let token = add_udp_socket(&mut harness.server, socket);
+  harness.server.encrypt_udp(token, KEY).unwrap();
+  harness.server.write_data(token, b"secret").unwrap();
+  harness.tick();
+  harness.tick();
+
+  // The peer doesn't speak the protocol, so all it ever sees is the handshake.
+  let mut buf = [0; 1500];
+  let n = peer.recv(&mut buf).unwrap();
+  assert_eq!(n, 97);
+  assert_eq!(buf[0], 0);
+
+  peer.send(b"secret").unwrap();
+  assert!(harness.drive(|h| h.server.rejected_packets(token) > 0));
+
+  assert!(harness.server_log.data.is_empty());
+  assert_eq!(harness.server.rejected_packets(token), 1);
+  assert!(!harness.server.is_encrypted(token));
+  assert_eq!(harness.server.queued_bytes(token), 6);
+}
+
+#[test]
+fn unconnected_udp_sockets_cannot_be_encrypted() {
+  let mut network = MaatNetwork::new();
+  let token = network.host_udp_server("127.0.0.1", 0, None);
+  assert_eq!(
+    network.encrypt_udp(token, KEY),
+    Err(EncryptionError::NotConnectedUdp)
+  );
+  network.poll();
+  assert_eq!(
+    network.encrypt_udp(token, KEY),
+    Err(EncryptionError::NotConnectedUdp)
+  );
+  assert_eq!(
+    network.encrypt_udp(99, KEY),
+    Err(EncryptionError::UnknownToken)
+  );
+}
+
+#[test]
+fn handshakes_need_the_shared_key() {
+  let mut a = UdpSession::new(&KEY);
+  let mut stranger = UdpSession::new(&[8; 32]);
+  assert!(matches!(
+    a.open(&stranger.handshake_packet()),
+    Opened::Rejected
+  ));
+  assert!(matches!(
+    stranger.open(&a.handshake_packet()),
+    Opened::Rejected
+  ));
+
+  // A key swapped into an otherwise valid handshake breaks the mac.
+  let mut forged = UdpSession::new(&KEY).handshake_packet();
+  forged[1..33].copy_from_slice(&stranger.handshake_packet()[1..33]);
+  assert!(matches!(a.open(&forged), Opened::Rejected));
+  // So does turning it into a finished one.
+  let mut forged = UdpSession::new(&KEY).handshake_packet();
+  forged[0] = 2;
+  assert!(matches!(a.open(&forged), Opened::Rejected));
+  assert!(matches!(a.open(&forged[..33]), Opened::Rejected));
+  assert!(!a.is_established() && !stranger.is_established());
+  assert_eq!(a.rejected_packets(), 4);
+}
+
+#[test]
+fn networks_with_different_keys_never_exchange_data() {
+  let (mut harness, b_token, a_token) = Harness::udp_pair();
+  harness.clients[0].encrypt_udp(a_token, KEY).unwrap();
+  harness.server.encrypt_udp(b_token, [8; 32]).unwrap();
+  harness.clients[0].write_data(a_token, b"snapshot").unwrap();
+
+  assert!(harness.drive(|h| h.server.rejected_packets(b_token) >= 2));
+  assert!(harness.server_log.data.is_empty());
+  assert!(!harness.clients[0].is_encrypted(a_token) && !harness.server.is_encrypted(b_token));
+}
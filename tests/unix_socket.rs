@@ -0,0 +1,76 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::net::UnixDatagram, path::PathBuf, process};
+
+use common::Harness;
+use maat_network::{ConnectionState, MaatNetwork, NetworkEvent};
+
+fn socket_path(name: &str) -> PathBuf {
+  let path = std::env::temp_dir().join(format!("maat-{}-{}.sock", process::id(), name));
+  let _ = fs::remove_file(&path);
+  path
+}
+
+#[test]
+fn unix_stream_delivers_data() {
+  let path = socket_path("stream");
+  let mut harness = Harness::new(1);
+
+  let listener = harness.server.host_unix_server(&path, None);
+  harness.server.poll();
+  assert_eq!(
+    harness.server.state(listener),
+    Some(ConnectionState::Connected)
+  );
+
+  let token = harness.clients[0].connect_to_unix(&path, None);
+  harness.clients[0].write_data(token, b"matchmaker").unwrap();
+
+  let received = |h: &Harness| {
+    h.server_log
+      .data
+      .iter()
+      .map(|(_, d)| d.len())
+      .sum::<usize>()
+  };
+  assert!(harness.drive(|h| received(h) == 10));
+
+  let accepted = harness.server_log.accepted[0];
+  assert_eq!(harness.server_log.bytes_from(accepted), b"matchmaker");
+  assert!(harness.client_logs[0].has(&NetworkEvent::Connected(token)));
+  let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn unix_connect_to_missing_socket_fails() {
+  let path = socket_path("missing");
+  let mut client = MaatNetwork::new();
+
+  let token = client.connect_to_unix(&path, None);
+  client.poll();
+
+  assert!(matches!(
+    client.events().as_slice(),
+    [NetworkEvent::ConnectFailed(t, _)] if *t == token
+  ));
+  assert_eq!(client.state(token), Some(ConnectionState::Closed));
+}
+
+#[test]
+fn unix_datagram_receives_packets() {
+  let path = socket_path("datagram");
+  let mut harness = Harness::new(0);
+
+  let socket = harness.server.host_unix_datagram(&path, None);
+  harness.server.poll();
+
+  let sender = UnixDatagram::unbound().unwrap();
+  sender.send_to(b"control", &path).unwrap();
+
+  assert!(harness.drive(|h| !h.server_log.data.is_empty()));
+
+  assert_eq!(harness.server_log.bytes_from(socket), b"control");
+  let _ = fs::remove_file(&path);
+}
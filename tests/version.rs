@@ -0,0 +1,393 @@
+mod common;
+
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::{io::ErrorKind, net::UdpSocket as StdUdpSocket, time::Duration};
+
+use common::Harness;
+#[cfg(feature = "tls")]
+use maat_network::tls::{client_config, server_config};
+use maat_network::{
+  checksum::PacketChecksum,
+  version::{Negotiation, VersionHandshake},
+  DisconnectReason, LinkConditions, MaatNetwork, NetworkEvent, ProtocolVersion, VersionPolicy,
+};
+use mio::net::UdpSocket;
+
+const GAME: u32 = 0x6d61_6174;
+
+fn version(major: u16, minor: u16) -> ProtocolVersion {
+  ProtocolVersion::new(GAME, major, minor)
+}
+
+/// A pair of non-blocking udp sockets on loopback connected to each other.
+fn udp_pair() -> ((UdpSocket, u16), (UdpSocket, u16)) {
+  let a = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let b = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+  let (a_port, b_port) = (
+    a.local_addr().unwrap().port(),
+    b.local_addr().unwrap().port(),
+  );
+  a.connect(("127.0.0.1", b_port)).unwrap();
+  b.connect(("127.0.0.1", a_port)).unwrap();
+  a.set_nonblocking(true).unwrap();
+  b.set_nonblocking(true).unwrap();
+  (
+    (UdpSocket::from_std(a), b_port),
+    (UdpSocket::from_std(b), a_port),
+  )
+}
+
+fn mismatched(harness: &Harness, server_token: usize, client_token: usize) -> bool {
+  harness.server_log.has(&NetworkEvent::Disconnected(
+    server_token,
+    DisconnectReason::VersionMismatch,
+  )) && harness.client_logs[0].has(&NetworkEvent::Disconnected(
+    client_token,
+    DisconnectReason::VersionMismatch,
+  ))
+}
+
+#[test]
+fn compatible_tcp_peers_connect_and_exchange_data() {
+  let (mut harness, port) = Harness::tcp(1);
+  harness
+    .server
+    .set_protocol_version(version(1, 0), VersionPolicy::SameMajor);
+  harness.clients[0].set_protocol_version(version(1, 3), VersionPolicy::SameMajor);
+
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  // Queued until both sides accepted, then sent after the version packets.
+  harness.clients[0].write_data(token, b"early").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1));
+  let accepted = harness.server_log.accepted[0];
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted) == b"early"));
+  assert!(harness.client_logs[0].has(&NetworkEvent::Connected(token)));
+  assert!(harness.server_log.has(&NetworkEvent::Connected(accepted)));
+  assert_eq!(harness.clients[0].peer_version(token), Some(version(1, 0)));
+  assert_eq!(harness.server.peer_version(accepted), Some(version(1, 3)));
+
+  harness.server.write_data(accepted, b"welcome").unwrap();
+  assert!(harness.drive(|h| h.client_logs[0].bytes_from(token) == b"welcome"));
+}
+
+/// Gives both sides compatible versions, connects the client with `connect`
+/// and checks data queued straight away arrives after the exchange.
+fn exchange_over<F>(mut harness: Harness, connect: F)
+where
+  F: FnOnce(&mut MaatNetwork) -> usize,
+{
+  harness
+    .server
+    .set_protocol_version(version(5, 0), VersionPolicy::SameMajor);
+  harness.clients[0].set_protocol_version(version(5, 2), VersionPolicy::SameMajor);
+
+  let token = connect(&mut harness.clients[0]);
+  harness.clients[0].write_data(token, b"early").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1));
+  let accepted = harness.server_log.accepted[0];
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted) == b"early"));
+  assert_eq!(harness.clients[0].peer_version(token), Some(version(5, 0)));
+  assert_eq!(harness.server.peer_version(accepted), Some(version(5, 2)));
+}
+
+#[test]
+fn memory_streams_exchange_versions() {
+  let mut harness = Harness::new(1);
+  harness.server.host_memory_server("version-memory", None);
+  exchange_over(harness, |client| {
+    client.connect_to_memory("version-memory", None)
+  });
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_streams_exchange_versions() {
+  let path = std::env::temp_dir().join(format!("maat-{}-version.sock", std::process::id()));
+  let _ = std::fs::remove_file(&path);
+  let mut harness = Harness::new(1);
+  harness.server.host_unix_server(&path, None);
+  exchange_over(harness, |client| client.connect_to_unix(&path, None));
+  let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls_streams_exchange_versions_after_the_handshake() {
+  let cert = |name: &str| {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+      .join("tests")
+      .join("certs")
+      .join(name)
+  };
+  let mut harness = Harness::new(1);
+  let config = server_config(cert("server.pem"), cert("server.key")).unwrap();
+  let listener = harness.server.host_tls_server("127.0.0.1", 0, config, None);
+  harness.server.poll();
+  let port = harness.server.local_addr(listener).unwrap().port();
+
+  let config = client_config(cert("ca.pem")).unwrap();
+  exchange_over(harness, |client| {
+    client.connect_to_tls("127.0.0.1", port, config, None)
+  });
+}
+
+#[test]
+fn sockets_without_a_single_peer_cant_exchange_versions() {
+  let mut harness = Harness::new(0);
+  harness
+    .server
+    .set_protocol_version(version(1, 0), VersionPolicy::Exact);
+  let socket = harness.server.host_udp_server("127.0.0.1", 0, None);
+  let listener = harness.server.host_tcp_server("127.0.0.1", 0, None);
+
+  assert!(harness.drive(|h| !h.server_log.events.is_empty()));
+  assert_eq!(
+    harness.server_log.events,
+    vec![NetworkEvent::ConnectFailed(socket, ErrorKind::Unsupported)]
+  );
+  assert!(harness.server.local_addr(listener).is_some());
+}
+
+#[test]
+fn mismatched_tcp_versions_disconnect_both_sides() {
+  let (mut harness, port) = Harness::tcp(1);
+  harness
+    .server
+    .set_protocol_version(version(2, 0), VersionPolicy::Exact);
+  harness.clients[0].set_protocol_version(version(1, 0), VersionPolicy::SameMajor);
+
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  harness.clients[0].write_data(token, b"never").unwrap();
+
+  assert!(harness
+    .drive(|h| h.server_log.accepted.len() == 1 && mismatched(h, h.server_log.accepted[0], token)));
+  let accepted = harness.server_log.accepted[0];
+  assert!(harness.server_log.bytes_from(accepted).is_empty());
+  assert!(!harness.client_logs[0].has(&NetworkEvent::Connected(token)));
+}
+
+#[test]
+fn one_sided_rejections_reach_the_other_side() {
+  // The client would take any version, the server only its own.
+  let (mut harness, port) = Harness::tcp(1);
+  harness
+    .server
+    .set_protocol_version(version(1, 1), VersionPolicy::Exact);
+  harness.clients[0].set_protocol_version(version(1, 0), VersionPolicy::Custom(|_, _| true));
+
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  assert!(harness
+    .drive(|h| h.server_log.accepted.len() == 1 && mismatched(h, h.server_log.accepted[0], token)));
+}
+
+#[test]
+fn protocol_ids_have_to_match_whatever_the_policy() {
+  let (mut harness, port) = Harness::tcp(1);
+  harness
+    .server
+    .set_protocol_version(version(1, 0), VersionPolicy::Custom(|_, _| true));
+  harness.clients[0].set_protocol_version(
+    ProtocolVersion::new(GAME + 1, 1, 0),
+    VersionPolicy::Custom(|_, _| true),
+  );
+
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  assert!(harness
+    .drive(|h| h.server_log.accepted.len() == 1 && mismatched(h, h.server_log.accepted[0], token)));
+}
+
+#[test]
+fn peers_without_versions_are_rejected() {
+  let (mut harness, port) = Harness::tcp(1);
+  harness
+    .server
+    .set_protocol_version(version(1, 0), VersionPolicy::Exact);
+
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  assert!(harness.drive(|h| h.client_logs[0].has(&NetworkEvent::Connected(token))));
+  harness.clients[0].write_data(token, b"old client").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.accepted.len() == 1
+    && h.server_log.has(&NetworkEvent::Disconnected(
+      h.server_log.accepted[0],
+      DisconnectReason::VersionMismatch
+    ))));
+  assert!(harness.server_log.data.is_empty());
+}
+
+#[test]
+fn connected_udp_sockets_exchange_versions() {
+  let mut harness = Harness::new(1);
+  let ((server_socket, client_port), (client_socket, server_port)) = udp_pair();
+  harness
+    .server
+    .set_protocol_version(version(3, 0), VersionPolicy::SameMajor);
+  harness.clients[0].set_protocol_version(version(3, 9), VersionPolicy::SameMajor);
+
+  let server =
+    harness
+      .server
+      .add_existing_udp_connection(server_socket, "127.0.0.1", client_port, None);
+  let client =
+    harness.clients[0].add_existing_udp_connection(client_socket, "127.0.0.1", server_port, None);
+  harness.clients[0].write_data(client, b"ping").unwrap();
+
+  assert!(harness.drive(|h| h.server_log.bytes_from(server) == b"ping"));
+  assert!(harness.server_log.has(&NetworkEvent::Connected(server)));
+  assert!(harness.client_logs[0].has(&NetworkEvent::Connected(client)));
+  assert_eq!(harness.server.peer_version(server), Some(version(3, 9)));
+}
+
+#[test]
+fn mismatched_udp_versions_disconnect_both_sides() {
+  let mut harness = Harness::new(1);
+  let ((server_socket, client_port), (client_socket, server_port)) = udp_pair();
+  harness
+    .server
+    .set_protocol_version(version(4, 0), VersionPolicy::SameMajor);
+  harness.clients[0].set_protocol_version(version(3, 0), VersionPolicy::SameMajor);
+
+  let server =
+    harness
+      .server
+      .add_existing_udp_connection(server_socket, "127.0.0.1", client_port, None);
+  let client =
+    harness.clients[0].add_existing_udp_connection(client_socket, "127.0.0.1", server_port, None);
+
+  assert!(harness.drive(|h| mismatched(h, server, client)));
+}
+
+/// Checked payloads of the datagrams waiting on the socket.
+fn receive(socket: &UdpSocket, checksum: &mut PacketChecksum) -> Vec<Vec<u8>> {
+  let mut buffer = [0; 1500];
+  let mut payloads = Vec::new();
+  while let Ok(n) = socket.recv(&mut buffer) {
+    payloads.extend(checksum.check(&buffer[..n]).map(|payload| payload.to_vec()));
+  }
+  payloads
+}
+
+#[test]
+fn udp_hellos_are_answered_again_after_the_exchange() {
+  let mut harness = Harness::new(0);
+  let ((server_socket, peer_port), (peer, _)) = udp_pair();
+  harness
+    .server
+    .set_protocol_version(version(2, 0), VersionPolicy::SameMajor);
+  let server =
+    harness
+      .server
+      .add_existing_udp_connection(server_socket, "127.0.0.1", peer_port, None);
+  harness.server.enable_checksums(server, GAME);
+
+  // The peer is a raw socket, version packets reach it checksummed.
+  let mut handshake = VersionHandshake::new(version(2, 1), VersionPolicy::SameMajor, true);
+  let mut checksum = PacketChecksum::new(GAME);
+  peer
+    .send(&checksum.wrap(&handshake.hello_packet()))
+    .unwrap();
+  let mut received = Vec::new();
+  assert!(harness.drive(|_| {
+    received.extend(receive(&peer, &mut checksum));
+    received.len() >= 2
+  }));
+  assert!(matches!(
+    handshake.read_datagrams(received),
+    Negotiation::Done(rest) if rest.is_empty()
+  ));
+  for reply in handshake.take_replies() {
+    peer.send(&checksum.wrap(&reply)).unwrap();
+  }
+  assert!(harness.drive(|h| h.server_log.has(&NetworkEvent::Connected(server))));
+
+  // As if the server's accept was lost, the peer sends its hello again.
+  peer
+    .send(&checksum.wrap(&handshake.hello_packet()))
+    .unwrap();
+  peer.send(&checksum.wrap(b"data")).unwrap();
+  let mut answers = Vec::new();
+  assert!(harness.drive(|h| {
+    answers.extend(receive(&peer, &mut checksum));
+    !answers.is_empty() && !h.server_log.data.is_empty()
+  }));
+
+  assert_eq!(harness.server_log.data, vec![(server, b"data".to_vec())]);
+  assert_eq!(answers.len(), 1);
+  assert!(handshake.read_repeated(&answers[0]));
+  assert!(handshake.take_replies().is_empty());
+}
+
+#[test]
+fn version_packets_go_through_the_link_conditioner() {
+  let mut harness = Harness::new(1);
+  let ((server_socket, client_port), (client_socket, server_port)) = udp_pair();
+  let link = LinkConditions::new(Duration::from_millis(20), 0.0).with_duplication(1.0);
+  for network in [&mut harness.server, &mut harness.clients[0]] {
+    network.set_protocol_version(version(1, 0), VersionPolicy::Exact);
+    network.set_default_link_conditions(Some(link));
+  }
+
+  let server =
+    harness
+      .server
+      .add_existing_udp_connection(server_socket, "127.0.0.1", client_port, None);
+  let client =
+    harness.clients[0].add_existing_udp_connection(client_socket, "127.0.0.1", server_port, None);
+  harness.clients[0].write_data(client, b"ping").unwrap();
+
+  assert!(harness.drive(|h| !h.server_log.data.is_empty()));
+  // Sent twice by the link, the duplicate version packets aren't data.
+  assert!(harness.drive(|h| h.server_log.data.len() == 2));
+  assert!(harness
+    .server_log
+    .data
+    .iter()
+    .all(|entry| *entry == (server, b"ping".to_vec())));
+  let version_packets = harness.clients[0].link_stats(client).unwrap().packets - 1;
+  assert!(version_packets >= 2);
+}
+
+#[test]
+fn version_packets_split_across_reads_are_put_back_together() {
+  let mut server = VersionHandshake::new(version(1, 0), VersionPolicy::Exact, false);
+  let mut client = VersionHandshake::new(version(1, 0), VersionPolicy::Exact, false);
+
+  let mut stream = client.hello_packet();
+  for byte in &stream {
+    assert!(matches!(server.read_stream(&[*byte]), Negotiation::Pending));
+  }
+  let reply = server.take_replies().concat();
+  assert!(matches!(client.read_stream(&reply), Negotiation::Pending));
+  // The client has the server's accept, so its hello finishes the exchange.
+  assert!(matches!(
+    client.read_stream(&server.hello_packet()),
+    Negotiation::Done(rest) if rest.is_empty()
+  ));
+  assert!(!server.is_done());
+
+  // The client's accept and its first data arrive in one read.
+  stream = client.take_replies().concat();
+  stream.extend(b"data");
+  match server.read_stream(&stream) {
+    Negotiation::Done(rest) => assert_eq!(rest, vec![b"data".to_vec()]),
+    _ => panic!("the exchange should have finished"),
+  }
+  assert!(server.is_done());
+}
+
+#[test]
+fn policies_compare_versions() {
+  assert!(VersionPolicy::Exact.accepts(version(1, 2), version(1, 2)));
+  assert!(!VersionPolicy::Exact.accepts(version(1, 2), version(1, 3)));
+  assert!(VersionPolicy::SameMajor.accepts(version(1, 2), version(1, 0)));
+  assert!(!VersionPolicy::SameMajor.accepts(version(1, 2), version(2, 2)));
+
+  let newer_minor =
+    VersionPolicy::Custom(|local, peer| local.major == peer.major && peer.minor >= local.minor);
+  assert!(newer_minor.accepts(version(1, 2), version(1, 5)));
+  assert!(!newer_minor.accepts(version(1, 2), version(1, 1)));
+  assert!(!newer_minor.accepts(version(1, 2), ProtocolVersion::new(7, 1, 5)));
+}
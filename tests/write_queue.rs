@@ -0,0 +1,147 @@
+mod common;
+
+use std::{
+  io::{Error, ErrorKind, Read},
+  net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream},
+  time::Duration,
+};
+
+use common::Harness;
+use maat_network::{
+  DisconnectReason, NetworkEvent, OverflowPolicy, Transport, WriteError, WriteLimits,
+};
+
+const CHUNK: [u8; 16 * 1024] = [7; 16 * 1024];
+
+/// A client connected to a plain listener, returns the client's token and the
+/// peer, which only reads when told to.
+fn stalled_peer(limits: WriteLimits) -> (Harness, usize, StdTcpStream) {
+  let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+  let mut harness = Harness::new(1);
+  harness.clients[0].set_default_write_limits(limits);
+  let token = harness.clients[0].connect_to_tcp("127.0.0.1", port, None);
+  let (peer, _) = listener.accept().unwrap();
+  assert!(harness.drive(|h| h.client_logs[0].has(&NetworkEvent::Connected(token))));
+  (harness, token, peer)
+}
+
+/// Writes until the queue is full, polling so the socket buffers fill up too.
+fn fill(harness: &mut Harness, token: usize) -> WriteError {
+  let mut error = None;
+  let full = harness.drive(|h| {
+    error = h.clients[0].write_data(token, &CHUNK).err();
+    error.is_some()
+  });
+  assert!(full, "the queue never filled up");
+  error.unwrap()
+}
+
+#[test]
+fn writes_to_a_peer_that_isnt_reading_fill_the_queue() {
+  let limits = WriteLimits::new(256 * 1024, 64 * 1024);
+  let (mut harness, token, _peer) = stalled_peer(limits);
+
+  match fill(&mut harness, token) {
+    WriteError::QueueFull { queued, limit } => {
+      assert_eq!(limit, limits.max_queued_bytes);
+      assert!(queued + CHUNK.len() > limit);
+    }
+    err => panic!("expected a full queue, got {:?}", err),
+  }
+  assert!(!harness.client_logs[0].disconnected(token));
+}
+
+#[test]
+fn the_queue_drains_once_the_peer_reads() {
+  let (mut harness, token, mut peer) = stalled_peer(WriteLimits::new(256 * 1024, 64 * 1024));
+  fill(&mut harness, token);
+  assert!(!harness.client_logs[0].has(&NetworkEvent::WriteQueueDrained(token)));
+
+  peer.set_nonblocking(true).unwrap();
+  let mut buffer = vec![0; 64 * 1024];
+  let drained = harness.drive(|h| {
+    loop {
+      match peer.read(&mut buffer) {
+        Ok(n) if n > 0 => continue,
+        Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+        _ => break,
+      }
+    }
+    h.client_logs[0].has(&NetworkEvent::WriteQueueDrained(token))
+  });
+  assert!(drained);
+  harness.clients[0].write_data(token, &CHUNK).unwrap();
+}
+
+#[test]
+fn a_queue_that_stays_full_disconnects_with_the_disconnect_policy() {
+  let limits = WriteLimits {
+    policy: OverflowPolicy::Disconnect(Duration::from_millis(100)),
+    ..WriteLimits::new(256 * 1024, 64 * 1024)
+  };
+  let (mut harness, token, _peer) = stalled_peer(limits);
+  fill(&mut harness, token);
+
+  // Keeps the queue full until the socket buffers can't take any more either.
+  assert!(harness.drive(|h| {
+    let _ = h.clients[0].write_data(token, &CHUNK);
+    h.client_logs[0].disconnected(token)
+  }));
+  assert!(harness.client_logs[0].has(&NetworkEvent::Disconnected(
+    token,
+    DisconnectReason::WriteQueueOverflow
+  )));
+  assert_eq!(
+    harness.clients[0].write_data(token, b"x"),
+    Err(WriteError::TokenClosed)
+  );
+}
+
+/// A connection whose peer has gone, every write fails.
+struct BrokenPipe;
+
+impl Transport for BrokenPipe {
+  fn write(&mut self, _buf: &[u8]) -> Result<usize, Error> {
+    Err(Error::new(ErrorKind::BrokenPipe, "peer reset"))
+  }
+
+  fn readiness(&self) -> Option<(bool, bool)> {
+    Some((false, true))
+  }
+}
+
+#[test]
+fn write_errors_disconnect_the_connection() {
+  let mut harness = Harness::new(0);
+  let network = &mut harness.server;
+  let token = network.add_existing_transport(BrokenPipe, "gone", None);
+  let other = network.add_existing_transport(BrokenPipe, "also gone", None);
+  network.write_data(token, b"hello").unwrap();
+
+  assert!(harness.drive(|h| !h.server_log.events.is_empty()));
+  assert_eq!(
+    harness.server_log.events,
+    vec![NetworkEvent::Disconnected(
+      token,
+      DisconnectReason::Error(ErrorKind::BrokenPipe)
+    )]
+  );
+  assert_eq!(
+    harness.server.write_data(token, b"x"),
+    Err(WriteError::TokenClosed)
+  );
+  harness.server.write_data(other, b"x").unwrap();
+}
+
+#[test]
+fn empty_writes_dont_hold_up_the_queue() {
+  let (mut harness, port) = Harness::tcp(1);
+  let token = harness.connect_tcp(port)[0];
+  harness.clients[0].write_data(token, &[]).unwrap();
+  harness.clients[0].write_data(token, b"after").unwrap();
+
+  let accepted = harness.server_log.accepted[0];
+  assert!(harness.drive(|h| h.server_log.bytes_from(accepted) == b"after"));
+  assert_eq!(harness.clients[0].queued_bytes(token), 0);
+}